@@ -0,0 +1,474 @@
+use super::http::date::DateTime;
+use super::http::{json, Request, StatusCode};
+
+use serde::Deserialize;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    /// `127.0.0.1 - - [10/Oct/2000:13:55:36 +0000] "GET / HTTP/1.1" 200 2326`
+    Common,
+    /// Common, followed by the quoted `Referer` and `User-Agent`.
+    Combined,
+    /// One JSON object per line, with the duration in microseconds.
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "common" => Ok(Self::Common),
+            "combined" => Ok(Self::Combined),
+            "json" => Ok(Self::Json),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Writes one line per answered request to stdout or to a file, which can be
+/// rotated by size and at midnight UTC.
+pub struct AccessLog {
+    format: LogFormat,
+    max_size: Option<u64>,
+    daily: bool,
+    output: Mutex<Output>,
+}
+
+enum Output {
+    Stdout,
+    File(LogFile),
+}
+
+// What one line is made of; `request` is missing if it could not be parsed.
+struct Record<'a> {
+    remote_addr: Option<SocketAddr>,
+    request: Option<&'a Request<'a>>,
+    status_code: StatusCode,
+    bytes: u64,
+    duration: Duration,
+}
+
+struct LogFile {
+    path: PathBuf,
+    file: File,
+    size: u64,
+    day: u64,
+}
+
+impl AccessLog {
+    pub fn stdout(format: LogFormat) -> Self {
+        Self {
+            format,
+            max_size: None,
+            daily: false,
+            output: Mutex::new(Output::Stdout),
+        }
+    }
+
+    /// Appends to the file at `path`, creating it if needed.
+    pub fn file(path: impl AsRef<Path>, format: LogFormat) -> io::Result<Self> {
+        let log_file = LogFile::open(path.as_ref().to_path_buf())?;
+
+        Ok(Self {
+            format,
+            max_size: None,
+            daily: false,
+            output: Mutex::new(Output::File(log_file)),
+        })
+    }
+
+    /// Starts a new file once the current one would grow beyond `max_size` bytes.
+    pub fn with_max_size(mut self, max_size: u64) -> Self {
+        self.max_size = Some(max_size);
+        self
+    }
+
+    /// Starts a new file on the first request of every day (UTC).
+    pub fn with_daily_rotation(mut self, daily: bool) -> Self {
+        self.daily = daily;
+        self
+    }
+
+    /// `bytes` is the size of the body sent, not counting the head.
+    pub fn log(&self, request: &Request, status_code: StatusCode, bytes: u64, duration: Duration) {
+        self.write(&Record {
+            remote_addr: request.remote_addr(),
+            request: Some(request),
+            status_code,
+            bytes,
+            duration,
+        });
+    }
+
+    /// Logs the answer to a request that could not be parsed, with `-` (or
+    /// `null`) for everything but the client's address.
+    pub fn log_bad_request(
+        &self,
+        remote_addr: Option<SocketAddr>,
+        status_code: StatusCode,
+        bytes: u64,
+        duration: Duration,
+    ) {
+        self.write(&Record {
+            remote_addr,
+            request: None,
+            status_code,
+            bytes,
+            duration,
+        });
+    }
+
+    fn write(&self, record: &Record) {
+        let now = SystemTime::now();
+        let mut line = match self.format {
+            LogFormat::Common => common(record, now),
+            LogFormat::Combined => combined(record, now),
+            LogFormat::Json => to_json(record, now),
+        };
+        line.push('\n');
+
+        let mut output = self.output.lock().unwrap_or_else(|e| e.into_inner());
+        let result = match &mut *output {
+            Output::Stdout => io::stdout().lock().write_all(line.as_bytes()),
+            Output::File(log_file) => {
+                if let Err(e) = self.rotate_if_needed(log_file, line.len() as u64, now) {
+                    println!("Failed to rotate {}: {}", log_file.path.display(), e);
+                }
+                log_file.write(line.as_bytes())
+            }
+        };
+        if let Err(e) = result {
+            println!("Failed to write access log: {}", e);
+        }
+    }
+
+    fn rotate_if_needed(
+        &self,
+        log_file: &mut LogFile,
+        len: u64,
+        now: SystemTime,
+    ) -> io::Result<()> {
+        let day = days_since_epoch(now);
+        let new_day = self.daily && day != log_file.day;
+        let too_large = self
+            .max_size
+            .is_some_and(|max_size| log_file.size > 0 && log_file.size + len > max_size);
+
+        if new_day || too_large {
+            log_file.rotate()?;
+        }
+        log_file.day = day;
+        Ok(())
+    }
+}
+
+impl LogFile {
+    fn open(path: PathBuf) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let metadata = file.metadata()?;
+        let modified = metadata.modified().unwrap_or_else(|_| SystemTime::now());
+
+        Ok(Self {
+            path,
+            size: metadata.len(),
+            day: days_since_epoch(modified),
+            file,
+        })
+    }
+
+    fn write(&mut self, line: &[u8]) -> io::Result<()> {
+        self.file.write_all(line)?;
+        self.size += line.len() as u64;
+        Ok(())
+    }
+
+    // access.log becomes access.log.2026-10-18, or access.log.2026-10-18.1 and
+    // so on if that day was already rotated, and a fresh access.log is opened.
+    fn rotate(&mut self) -> io::Result<()> {
+        let date = DateTime::from(UNIX_EPOCH + Duration::from_secs(self.day * 86400));
+        let base = format!(
+            "{}.{}-{:02}-{:02}",
+            self.path.display(),
+            date.year,
+            date.month,
+            date.day
+        );
+
+        let mut rotated = PathBuf::from(&base);
+        let mut n = 1;
+        while rotated.exists() {
+            rotated = PathBuf::from(format!("{}.{}", base, n));
+            n += 1;
+        }
+
+        fs::rename(&self.path, &rotated)?;
+        *self = LogFile::open(self.path.clone())?;
+        Ok(())
+    }
+}
+
+fn common(record: &Record, now: SystemTime) -> String {
+    let remote_addr = match record.remote_addr {
+        Some(addr) => addr.ip().to_string(),
+        None => "-".to_string(),
+    };
+    let request_line = match record.request {
+        Some(request) => format!(
+            "{} {} {}",
+            request.method(),
+            escape(request.target()),
+            request.version()
+        ),
+        None => "-".to_string(),
+    };
+    // CLF writes "-" rather than 0 for an empty body
+    let bytes = match record.bytes {
+        0 => "-".to_string(),
+        bytes => bytes.to_string(),
+    };
+
+    format!(
+        "{} - - [{}] \"{}\" {} {}",
+        remote_addr,
+        clf_date(now),
+        request_line,
+        record.status_code,
+        bytes
+    )
+}
+
+fn combined(record: &Record, now: SystemTime) -> String {
+    let header = |name| match record
+        .request
+        .and_then(|request| request.headers().get(name))
+    {
+        Some(value) => escape(value),
+        None => "-".to_string(),
+    };
+
+    format!(
+        "{} \"{}\" \"{}\"",
+        common(record, now),
+        header("Referer"),
+        header("User-Agent")
+    )
+}
+
+fn to_json(record: &Record, now: SystemTime) -> String {
+    let string_or_null = |value: Option<&str>| match value {
+        Some(value) => format!("\"{}\"", json::escape(value)),
+        None => "null".to_string(),
+    };
+    let request = record.request;
+    let remote_addr = record.remote_addr.map(|addr| addr.ip().to_string());
+    let method = request.map(|request| request.method().to_string());
+    let version = request.map(|request| request.version().to_string());
+    let header = |name| request.and_then(|request| request.headers().get(name));
+    let date = DateTime::from(now);
+
+    format!(
+        "{{\"time\":\"{}-{:02}-{:02}T{:02}:{:02}:{:02}Z\",\"remote_addr\":{},\"method\":{},\"path\":{},\"version\":{},\"status\":{},\"bytes\":{},\"duration_us\":{},\"referer\":{},\"user_agent\":{}}}",
+        date.year,
+        date.month,
+        date.day,
+        date.hour,
+        date.minute,
+        date.second,
+        string_or_null(remote_addr.as_deref()),
+        string_or_null(method.as_deref()),
+        string_or_null(request.map(|request| request.target())),
+        string_or_null(version.as_deref()),
+        record.status_code,
+        record.bytes,
+        record.duration.as_micros(),
+        string_or_null(header("Referer")),
+        string_or_null(header("User-Agent"))
+    )
+}
+
+// 10/Oct/2000:13:55:36 +0000
+fn clf_date(time: SystemTime) -> String {
+    let date = DateTime::from(time);
+    format!(
+        "{:02}/{}/{}:{:02}:{:02}:{:02} +0000",
+        date.day,
+        date.month_name(),
+        date.year,
+        date.hour,
+        date.minute,
+        date.second
+    )
+}
+
+// Quotes and backslashes are escaped like Apache does, so fields stay parseable.
+fn escape(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            c if c.is_control() => escaped.push_str(&format!("\\x{:02x}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+fn days_since_epoch(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+        / 86400
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::convert::TryFrom;
+    use std::process;
+
+    // 2000-10-10 13:55:36 UTC
+    fn now() -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(971186136)
+    }
+
+    fn request(head: &[u8]) -> Request<'_> {
+        let mut request = Request::try_from(head).unwrap();
+        request.set_remote_addr("127.0.0.1:5000".parse().unwrap());
+        request
+    }
+
+    fn record<'a>(request: Option<&'a Request<'a>>) -> Record<'a> {
+        Record {
+            remote_addr: Some("127.0.0.1:5000".parse().unwrap()),
+            request,
+            status_code: StatusCode::Ok,
+            bytes: 2326,
+            duration: Duration::from_micros(1500),
+        }
+    }
+
+    fn log_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("access-log-{}-{}", process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn formats_common_and_combined_lines() {
+        let request = request(b"GET /a\"b HTTP/1.1\r\nUser-Agent: curl/8.0\r\n\r\n");
+        let record = record(Some(&request));
+
+        assert_eq!(
+            common(&record, now()),
+            "127.0.0.1 - - [10/Oct/2000:13:55:36 +0000] \"GET /a\\\"b HTTP/1.1\" 200 2326"
+        );
+        assert!(combined(&record, now()).ends_with("2326 \"-\" \"curl/8.0\""));
+
+        let empty = Record { bytes: 0, ..record };
+        assert!(common(&empty, now()).ends_with(" 200 -"));
+    }
+
+    #[test]
+    fn formats_json_lines() {
+        let request = request(b"GET /x HTTP/1.1\r\nReferer: http://a/\r\n\r\n");
+        let line = to_json(&record(Some(&request)), now());
+
+        assert_eq!(
+            line,
+            "{\"time\":\"2000-10-10T13:55:36Z\",\"remote_addr\":\"127.0.0.1\",\"method\":\"GET\",\"path\":\"/x\",\"version\":\"HTTP/1.1\",\"status\":200,\"bytes\":2326,\"duration_us\":1500,\"referer\":\"http://a/\",\"user_agent\":null}"
+        );
+    }
+
+    #[test]
+    fn unparsed_requests_are_logged_with_dashes() {
+        let record = Record {
+            status_code: StatusCode::BadRequest,
+            ..record(None)
+        };
+
+        assert_eq!(
+            combined(&record, now()),
+            "127.0.0.1 - - [10/Oct/2000:13:55:36 +0000] \"-\" 400 2326 \"-\" \"-\""
+        );
+        let json = to_json(&record, now());
+        assert!(json.contains("\"method\":null,\"path\":null,\"version\":null,\"status\":400"));
+
+        let unknown = Record {
+            remote_addr: None,
+            ..record
+        };
+        assert!(common(&unknown, now()).starts_with("- - - ["));
+    }
+
+    #[test]
+    fn escapes_quotes_backslashes_and_controls() {
+        assert_eq!(escape("a\"b\\c\x1bd"), "a\\\"b\\\\c\\x1bd");
+        assert_eq!(escape("plain /path?q=1"), "plain /path?q=1");
+    }
+
+    #[test]
+    fn rotates_by_size() {
+        let dir = log_dir("size");
+        let path = dir.join("access.log");
+        let log = AccessLog::file(&path, LogFormat::Common)
+            .unwrap()
+            .with_max_size(40);
+
+        for _ in 0..3 {
+            log.log_bad_request(None, StatusCode::BadRequest, 11, Duration::ZERO);
+        }
+
+        let mut names: Vec<_> = fs::read_dir(&dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect();
+        names.sort();
+        assert_eq!(names.len(), 3);
+        assert_eq!(names[0], "access.log");
+        assert!(names[1].starts_with("access.log.") && !names[1].ends_with(".1"));
+        assert!(names[2].ends_with(".1"));
+        for name in names {
+            let content = fs::read_to_string(dir.join(name)).unwrap();
+            assert_eq!(content.lines().count(), 1);
+            assert!(content.contains("\"-\" 400 11"));
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn rotated_file_is_named_after_the_day_it_covers() {
+        let dir = log_dir("daily");
+        let path = dir.join("access.log");
+        fs::write(&path, "old\n").unwrap();
+        let mut log_file = LogFile::open(path.clone()).unwrap();
+        log_file.day = days_since_epoch(now());
+
+        log_file.rotate().unwrap();
+
+        assert_eq!(
+            fs::read_to_string(dir.join("access.log.2000-10-10")).unwrap(),
+            "old\n"
+        );
+        assert_eq!(fs::read_to_string(&path).unwrap(), "");
+        assert_eq!(log_file.size, 0);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn parses_formats() {
+        assert_eq!("JSON".parse(), Ok(LogFormat::Json));
+        assert_eq!("combined".parse(), Ok(LogFormat::Combined));
+        assert_eq!("apache".parse::<LogFormat>(), Err(()));
+    }
+}
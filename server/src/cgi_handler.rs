@@ -0,0 +1,691 @@
+use super::http::response::SERVER_NAME;
+use super::http::{Request, Response, StatusCode};
+use super::server::Handler;
+
+use std::env;
+use std::fmt::{Display, Formatter, Result as FmtResult};
+use std::fs;
+use std::io::{Error as IoError, ErrorKind, Read, Write};
+use std::path::PathBuf;
+use std::process::{Child, Command, Stdio};
+use std::str;
+use std::sync::{mpsc, Arc};
+use std::thread;
+use std::time::Duration;
+
+mod fastcgi;
+
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(30);
+pub const DEFAULT_MAX_OUTPUT_SIZE: usize = 10 * 1024 * 1024;
+
+// Set by the server, or describing the body, rather than by the script.
+const NOT_FORWARDED: [&str; 5] = [
+    "Status",
+    "Connection",
+    "Keep-Alive",
+    "Transfer-Encoding",
+    "Content-Length",
+];
+
+/// Runs the scripts in a directory as CGI scripts (RFC 3875), or passes the
+/// same requests to a FastCGI server such as php-fpm. A request for
+/// `/cgi-bin/search.py/books?q=rust` runs `search.py` with `/books` as its
+/// `PATH_INFO`. Script output is read whole, up to `max_output_size`.
+pub struct CgiHandler {
+    // without a trailing slash, so "" for /
+    prefix: String,
+    root: PathBuf,
+    fastcgi: Option<String>,
+    timeout: Duration,
+    max_output_size: usize,
+    fallback: Option<Arc<dyn Handler>>,
+}
+
+// A script found for a request path.
+struct Script {
+    path: PathBuf,
+    script_name: String,
+    path_info: String,
+}
+
+#[derive(Debug)]
+enum GatewayError {
+    Io(IoError),
+    Timeout,
+    OutputTooLarge,
+}
+
+impl CgiHandler {
+    /// Runs executable files under `root` for requests below `prefix`.
+    pub fn new(prefix: &str, root: &str) -> Self {
+        // `find_script` compares canonical paths, so the root must be canonical too
+        let root = fs::canonicalize(root).unwrap_or_else(|_| PathBuf::from(root));
+
+        Self {
+            prefix: prefix.trim_end_matches('/').to_string(),
+            root,
+            fastcgi: None,
+            timeout: DEFAULT_TIMEOUT,
+            max_output_size: DEFAULT_MAX_OUTPUT_SIZE,
+            fallback: None,
+        }
+    }
+
+    /// Sends requests to the FastCGI server at `addr` (`host:port`, or
+    /// `unix:/path/to/socket`) instead of running the scripts, which then
+    /// need not be executable.
+    pub fn with_fastcgi(mut self, addr: &str) -> Self {
+        self.fastcgi = Some(addr.to_string());
+        self
+    }
+
+    /// How long a script may take to produce all of its output.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Larger output is discarded and answered with 502.
+    pub fn with_max_output_size(mut self, max_output_size: usize) -> Self {
+        self.max_output_size = max_output_size;
+        self
+    }
+
+    /// Handles requests outside of `prefix`, instead of answering 404.
+    pub fn with_fallback(mut self, handler: impl Handler + 'static) -> Self {
+        self.fallback = Some(Arc::new(handler));
+        self
+    }
+
+    // `/cgi-bin` matches `/cgi-bin` and `/cgi-bin/run`, but not `/cgi-bins`.
+    fn matches_path(&self, path: &str) -> bool {
+        path.strip_prefix(&self.prefix)
+            .is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+    }
+
+    // The first file along `path` is the script, the rest is its PATH_INFO.
+    fn find_script(&self, path: &str) -> Option<Script> {
+        let rest = path.strip_prefix(&self.prefix)?;
+        if !rest.starts_with('/') {
+            return None;
+        }
+
+        let mut file = self.root.clone();
+        let mut end = 0;
+        while end < rest.len() {
+            let start = end + 1;
+            end = rest[start..].find('/').map_or(rest.len(), |i| start + i);
+            let segment = &rest[start..end];
+            match segment {
+                "" => continue,
+                "." | ".." => return None,
+                _ => file.push(segment),
+            }
+
+            let metadata = fs::metadata(&file).ok()?;
+            if metadata.is_dir() {
+                continue;
+            }
+            if !metadata.is_file() {
+                return None;
+            }
+            // a symlink may still lead out of the root
+            if !fs::canonicalize(&file).is_ok_and(|path| path.starts_with(&self.root)) {
+                println!("Directory Traversal Attack Attempted: {}", path);
+                return None;
+            }
+            return Some(Script {
+                path: file,
+                script_name: format!("{}{}", self.prefix, &rest[..end]),
+                path_info: rest[end..].to_string(),
+            });
+        }
+        None
+    }
+
+    // The meta-variables of RFC 3875, section 4.1, and those PHP expects.
+    fn environment(&self, request: &Request, script: &Script) -> Vec<(String, String)> {
+        let headers = request.headers();
+        let host = headers.get("Host").map(str::trim);
+        let default_port = if request.is_secure() { "443" } else { "80" };
+        let port = host
+            .and_then(|host| host.rsplit_once(':'))
+            .filter(|(_, port)| !port.contains(']'))
+            .map_or(default_port, |(_, port)| port);
+        let query_string = request
+            .target()
+            .split_once('?')
+            .map_or("", |(_, query)| query);
+
+        let mut environment = vec![
+            ("GATEWAY_INTERFACE", "CGI/1.1".to_string()),
+            ("SERVER_SOFTWARE", SERVER_NAME.to_string()),
+            (
+                "SERVER_NAME",
+                request.host().unwrap_or("localhost").to_string(),
+            ),
+            ("SERVER_PORT", port.to_string()),
+            ("SERVER_PROTOCOL", request.version().to_string()),
+            ("REQUEST_METHOD", request.method().to_string()),
+            ("REQUEST_URI", request.target().to_string()),
+            ("QUERY_STRING", query_string.to_string()),
+            ("SCRIPT_NAME", script.script_name.clone()),
+            ("SCRIPT_FILENAME", script.path.display().to_string()),
+            ("PATH_INFO", script.path_info.clone()),
+            ("DOCUMENT_ROOT", self.root.display().to_string()),
+            // php-cgi refuses to run without it
+            ("REDIRECT_STATUS", "200".to_string()),
+        ];
+        if !script.path_info.is_empty() {
+            let translated = self.root.join(script.path_info.trim_start_matches('/'));
+            environment.push(("PATH_TRANSLATED", translated.display().to_string()));
+        }
+        if let Some(remote_addr) = request.remote_addr() {
+            environment.push(("REMOTE_ADDR", remote_addr.ip().to_string()));
+            environment.push(("REMOTE_PORT", remote_addr.port().to_string()));
+        }
+        if !request.body().is_empty() || headers.contains("Content-Length") {
+            environment.push(("CONTENT_LENGTH", request.body().len().to_string()));
+        }
+        if let Some(content_type) = headers.get("Content-Type") {
+            environment.push(("CONTENT_TYPE", content_type.to_string()));
+        }
+        if let Some(authorization) = headers.get("Authorization") {
+            let scheme = authorization.split_whitespace().next().unwrap_or("");
+            environment.push(("AUTH_TYPE", scheme.to_string()));
+        }
+        if request.is_secure() {
+            environment.push(("HTTPS", "on".to_string()));
+        }
+        let mut environment: Vec<(String, String)> = environment
+            .into_iter()
+            .map(|(name, value)| (name.to_string(), value))
+            .collect();
+
+        for (name, value) in headers.iter() {
+            // credentials stay with the server, and HTTP_PROXY would be
+            // taken for a proxy setting (httpoxy)
+            let skipped = [
+                "Content-Length",
+                "Content-Type",
+                "Authorization",
+                "Proxy-Authorization",
+                "Proxy",
+            ];
+            if skipped
+                .iter()
+                .any(|header| name.eq_ignore_ascii_case(header))
+            {
+                continue;
+            }
+            // X_Foo would pass for X-Foo, which a proxy in front may have
+            // set; Apache and nginx drop these too
+            if name.contains('_') {
+                continue;
+            }
+
+            let name: String = name
+                .chars()
+                .map(|c| match c {
+                    'a'..='z' | 'A'..='Z' | '0'..='9' => c.to_ascii_uppercase(),
+                    _ => '_',
+                })
+                .collect();
+            let name = format!("HTTP_{}", name);
+            match environment
+                .iter_mut()
+                .find(|(existing, _)| *existing == name)
+            {
+                Some((_, existing)) => {
+                    existing.push_str(", ");
+                    existing.push_str(value);
+                }
+                None => environment.push((name, value.to_string())),
+            }
+        }
+        environment
+    }
+
+    fn run_script(
+        &self,
+        script: &Script,
+        environment: Vec<(String, String)>,
+        body: &[u8],
+    ) -> Result<Vec<u8>, GatewayError> {
+        let mut command = Command::new(&script.path);
+        command
+            .env_clear()
+            .envs(environment)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::inherit());
+        // scripts find their interpreters through `#!/usr/bin/env`
+        if let Some(path) = env::var_os("PATH") {
+            command.env("PATH", path);
+        }
+        if let Some(dir) = script.path.parent() {
+            command.current_dir(dir);
+        }
+        // a group of its own, so that whatever it starts can be killed with it
+        #[cfg(unix)]
+        std::os::unix::process::CommandExt::process_group(&mut command, 0);
+        let mut child = command.spawn().map_err(GatewayError::Io)?;
+        let stdin = child.stdin.take();
+        let stdout = child.stdout.take();
+        let max_output_size = self.max_output_size;
+
+        // Neither thread is waited for: the script need not read its input,
+        // and a process it started may hold on to its output.
+        let body = body.to_vec();
+        thread::spawn(move || {
+            if let Some(mut stdin) = stdin {
+                let _ = stdin.write_all(&body);
+            }
+        });
+        let (sender, receiver) = mpsc::channel();
+        thread::spawn(move || {
+            let mut output = Vec::new();
+            let read = match stdout {
+                Some(stdout) => stdout
+                    .take(max_output_size as u64 + 1)
+                    .read_to_end(&mut output),
+                None => Ok(0),
+            };
+            let _ = sender.send(read.map(|_| output));
+        });
+
+        let result = match receiver.recv_timeout(self.timeout) {
+            Ok(Ok(output)) if output.len() > max_output_size => Err(GatewayError::OutputTooLarge),
+            Ok(Ok(output)) => Ok(output),
+            Ok(Err(e)) => Err(GatewayError::Io(e)),
+            Err(_) => Err(GatewayError::Timeout),
+        };
+
+        if result.is_ok() {
+            // the script may go on after closing its output
+            thread::spawn(move || child.wait());
+        } else {
+            // closes its pipes, which ends the threads
+            kill(&mut child);
+            let _ = child.wait();
+        }
+        result
+    }
+}
+
+impl Handler for CgiHandler {
+    fn streams_body(&self, request: &Request) -> bool {
+        match &self.fallback {
+            Some(fallback) if !self.matches_path(request.path()) => fallback.streams_body(request),
+            _ => false,
+        }
+    }
+
+    fn handle_request(&self, request: &Request) -> Response {
+        let script = match self.find_script(request.path()) {
+            Some(script) => script,
+            None if self.matches_path(request.path()) => {
+                return Response::error(StatusCode::NotFound)
+            }
+            None => {
+                return match &self.fallback {
+                    Some(fallback) => fallback.handle_request(request),
+                    None => Response::error(StatusCode::NotFound),
+                }
+            }
+        };
+        if self.fastcgi.is_none() && !is_executable(&script.path) {
+            return Response::error(StatusCode::Forbidden);
+        }
+
+        let environment = self.environment(request, &script);
+        let output = match &self.fastcgi {
+            Some(addr) => fastcgi::request(
+                addr,
+                &environment,
+                request.body(),
+                self.timeout,
+                self.max_output_size,
+            ),
+            None => self.run_script(&script, environment, request.body()),
+        };
+
+        let result = output.and_then(|output| {
+            parse_output(output).map_err(|message| GatewayError::Io(invalid(message)))
+        });
+        match result {
+            Ok(response) => response,
+            Err(e) => {
+                println!("CGI script {} failed: {}", script.script_name, e);
+                match e {
+                    GatewayError::Timeout => Response::error(StatusCode::GatewayTimeout),
+                    _ => Response::error(StatusCode::BadGateway),
+                }
+            }
+        }
+    }
+}
+
+impl From<IoError> for GatewayError {
+    fn from(e: IoError) -> Self {
+        match e.kind() {
+            ErrorKind::WouldBlock | ErrorKind::TimedOut => Self::Timeout,
+            _ => Self::Io(e),
+        }
+    }
+}
+
+impl Display for GatewayError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        match self {
+            Self::Io(e) => write!(f, "{}", e),
+            Self::Timeout => write!(f, "timed out"),
+            Self::OutputTooLarge => write!(f, "output too large"),
+        }
+    }
+}
+
+// Status: 404 Not Found\nContent-Type: text/html\n\n<body>
+fn parse_output(mut output: Vec<u8>) -> Result<Response, &'static str> {
+    let mut headers = Vec::new();
+    let mut start = 0;
+    let body_start = loop {
+        let end = match output[start..].iter().position(|&b| b == b'\n') {
+            Some(i) => start + i,
+            None => return Err("no blank line after the headers"),
+        };
+        let line = str::from_utf8(&output[start..end]).map_err(|_| "header is not UTF-8")?;
+        let line = line.strip_suffix('\r').unwrap_or(line);
+        if line.is_empty() {
+            break end + 1;
+        }
+        let (name, value) = line.split_once(':').ok_or("header without a colon")?;
+        headers.push((name.trim().to_string(), value.trim().to_string()));
+        start = end + 1;
+    };
+
+    let header = |name: &str| {
+        headers
+            .iter()
+            .find(|(header, _)| header.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    };
+    let status_code = match (header("Status"), header("Location")) {
+        (Some(status), _) => status
+            .split_whitespace()
+            .next()
+            .and_then(|code| code.parse().ok())
+            .and_then(StatusCode::from_u16)
+            .ok_or("invalid Status header")?,
+        (None, Some(_)) => StatusCode::Found,
+        (None, None) => StatusCode::Ok,
+    };
+
+    let mut response = Response::new(status_code, None);
+    for (name, value) in &headers {
+        if !NOT_FORWARDED
+            .iter()
+            .any(|header| name.eq_ignore_ascii_case(header))
+        {
+            response.add_header(name, value.as_str());
+        }
+    }
+    response.set_body(output.split_off(body_start));
+    Ok(response)
+}
+
+#[cfg(unix)]
+fn is_executable(path: &std::path::Path) -> bool {
+    use std::os::unix::fs::PermissionsExt;
+    fs::metadata(path).is_ok_and(|metadata| metadata.permissions().mode() & 0o111 != 0)
+}
+
+#[cfg(not(unix))]
+fn is_executable(_path: &std::path::Path) -> bool {
+    true
+}
+
+// The script and the processes it started, see `run_script`.
+#[cfg(unix)]
+fn kill(child: &mut Child) {
+    // SAFETY: kill(2) takes no pointers; a negative pid names the group
+    if unsafe { libc::kill(-(child.id() as libc::pid_t), libc::SIGKILL) } != 0 {
+        let _ = child.kill();
+    }
+}
+
+#[cfg(not(unix))]
+fn kill(child: &mut Child) {
+    let _ = child.kill();
+}
+
+fn invalid(message: &str) -> IoError {
+    IoError::new(ErrorKind::InvalidData, message.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::convert::TryFrom;
+    use std::path::Path;
+    use std::process;
+    use std::time::Instant;
+
+    // A fresh script directory per test, since tests run in parallel,
+    // removed when the test is done with it.
+    struct ScriptDir(PathBuf);
+
+    impl std::ops::Deref for ScriptDir {
+        type Target = Path;
+
+        fn deref(&self) -> &Path {
+            &self.0
+        }
+    }
+
+    impl Drop for ScriptDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn script_dir(name: &str) -> ScriptDir {
+        let dir = std::env::temp_dir().join(format!("cgi-{}-{}", process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        ScriptDir(dir)
+    }
+
+    #[cfg(unix)]
+    fn write_script(path: &Path, body: &str) {
+        use std::os::unix::fs::PermissionsExt;
+        fs::write(path, format!("#!/bin/sh\n{}\n", body)).unwrap();
+        fs::set_permissions(path, fs::Permissions::from_mode(0o755)).unwrap();
+    }
+
+    fn handler(dir: &Path) -> CgiHandler {
+        CgiHandler::new("/cgi-bin", dir.to_str().unwrap())
+    }
+
+    fn found(handler: &CgiHandler, path: &str) -> Option<(String, String)> {
+        handler
+            .find_script(path)
+            .map(|script| (script.script_name, script.path_info))
+    }
+
+    fn call(handler: &CgiHandler, head: &str, body: &[u8]) -> Response {
+        let mut request = Request::try_from(head.as_bytes()).unwrap();
+        request.set_body(body);
+        handler.handle_request(&request)
+    }
+
+    fn body(mut response: Response) -> String {
+        let mut body = Vec::new();
+        response.take_body().write_to(&mut body).unwrap();
+        String::from_utf8(body).unwrap()
+    }
+
+    #[test]
+    fn splits_the_path_into_script_and_path_info() {
+        let dir = script_dir("find");
+        fs::create_dir(dir.join("tools")).unwrap();
+        fs::write(dir.join("report.py"), "").unwrap();
+        fs::write(dir.join("tools").join("search"), "").unwrap();
+        let handler = handler(&dir);
+
+        let script = |name: &str, path_info: &str| Some((name.to_string(), path_info.to_string()));
+        assert_eq!(
+            found(&handler, "/cgi-bin/report.py/2024/05"),
+            script("/cgi-bin/report.py", "/2024/05")
+        );
+        assert_eq!(
+            found(&handler, "/cgi-bin/report.py"),
+            script("/cgi-bin/report.py", "")
+        );
+        assert_eq!(
+            found(&handler, "/cgi-bin/tools/search/"),
+            script("/cgi-bin/tools/search", "/")
+        );
+        assert_eq!(found(&handler, "/cgi-bin/tools"), None);
+        assert_eq!(found(&handler, "/cgi-bin/missing.py"), None);
+        assert_eq!(found(&handler, "/cgi-binx/report.py"), None);
+
+        let handler = CgiHandler::new("/", dir.to_str().unwrap());
+        assert_eq!(found(&handler, "/report.py/x"), script("/report.py", "/x"));
+    }
+
+    #[test]
+    fn stays_within_the_root() {
+        let dir = script_dir("traversal");
+        let root = dir.join("root");
+        fs::create_dir_all(root.join("sub")).unwrap();
+        fs::write(dir.join("secret"), "").unwrap();
+        fs::write(root.join("run"), "").unwrap();
+        let handler = handler(&root);
+
+        assert_eq!(found(&handler, "/cgi-bin/../secret"), None);
+        assert_eq!(found(&handler, "/cgi-bin/sub/../run"), None);
+        assert_eq!(found(&handler, "/cgi-bin/./run"), None);
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::symlink;
+            symlink(dir.join("secret"), root.join("outside")).unwrap();
+            symlink(&*dir, root.join("up")).unwrap();
+            symlink(root.join("run"), root.join("inside")).unwrap();
+            assert_eq!(found(&handler, "/cgi-bin/outside"), None);
+            assert_eq!(found(&handler, "/cgi-bin/up/secret"), None);
+            assert!(found(&handler, "/cgi-bin/inside").is_some());
+        }
+    }
+
+    #[test]
+    fn parses_script_output() {
+        let response = parse_output(b"Content-Type: text/plain\n\nhello".to_vec()).unwrap();
+        assert_eq!(response.status_code(), StatusCode::Ok);
+        assert_eq!(response.header("Content-Type"), Some("text/plain"));
+        assert_eq!(body(response), "hello");
+
+        let output = b"Status: 404 Not Found\r\nContent-Length: 3\r\nX-Custom:  a \r\n\r\nno\n";
+        let response = parse_output(output.to_vec()).unwrap();
+        assert_eq!(response.status_code(), StatusCode::NotFound);
+        assert_eq!(response.header("X-Custom"), Some("a"));
+        assert_eq!(response.header("Status"), None);
+        assert_eq!(response.header("Content-Length"), None);
+        assert_eq!(body(response), "no\n");
+
+        let response = parse_output(b"Location: /elsewhere\n\n".to_vec()).unwrap();
+        assert_eq!(response.status_code(), StatusCode::Found);
+        assert_eq!(response.header("Location"), Some("/elsewhere"));
+    }
+
+    #[test]
+    fn rejects_malformed_script_output() {
+        assert!(parse_output(b"Content-Type: text/plain\nhello".to_vec()).is_err());
+        assert!(parse_output(b"no colon\n\n".to_vec()).is_err());
+        assert!(parse_output(b"Status: 1000\n\n".to_vec()).is_err());
+        assert!(parse_output(b"Status: soon\n\n".to_vec()).is_err());
+        assert!(parse_output(b"X: \xff\n\n".to_vec()).is_err());
+    }
+
+    #[test]
+    fn passes_headers_without_underscores() {
+        let dir = script_dir("environment");
+        fs::write(dir.join("env"), "").unwrap();
+        let handler = handler(&dir);
+        let script = handler.find_script("/cgi-bin/env").unwrap();
+
+        let request = Request::try_from(
+            &b"GET /cgi-bin/env HTTP/1.1\r\nX-User: proxy\r\nX_User: client\r\nX-Tag: a\r\nx-tag: b\r\nProxy: evil\r\n\r\n"[..],
+        )
+        .unwrap();
+        let environment = handler.environment(&request, &script);
+        let get = |name: &str| {
+            environment
+                .iter()
+                .find(|(key, _)| key == name)
+                .map(|(_, value)| value.as_str())
+        };
+
+        assert_eq!(get("HTTP_X_USER"), Some("proxy"));
+        assert_eq!(get("HTTP_X_TAG"), Some("a, b"));
+        assert_eq!(get("HTTP_PROXY"), None);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn runs_scripts_with_the_request() {
+        let dir = script_dir("run");
+        write_script(
+            &dir.join("echo"),
+            "echo 'Content-Type: text/plain'\necho\n\
+             echo \"$REQUEST_METHOD $PATH_INFO $QUERY_STRING $CONTENT_LENGTH $HTTP_X_TOKEN\"\ncat",
+        );
+        fs::write(dir.join("plain"), "").unwrap();
+        let handler = handler(&dir);
+
+        let response = call(
+            &handler,
+            "POST /cgi-bin/echo/a/b?q=1 HTTP/1.1\r\nX-Token: t\r\nContent-Length: 4\r\n\r\n",
+            b"body",
+        );
+        assert_eq!(response.status_code(), StatusCode::Ok);
+        assert_eq!(body(response), "POST /a/b q=1 4 t\nbody");
+
+        let response = call(&handler, "GET /cgi-bin/plain HTTP/1.1\r\n\r\n", b"");
+        assert_eq!(response.status_code(), StatusCode::Forbidden);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn times_out_scripts_whose_children_hold_the_output() {
+        let dir = script_dir("timeout");
+        // the background sleep keeps stdout open after the script exits
+        write_script(&dir.join("forks"), "sleep 10 &\necho 'Status: 200'\necho");
+        // never reads its input, which fills the pipe
+        write_script(&dir.join("stalls"), "sleep 10");
+        let handler = handler(&dir).with_timeout(Duration::from_millis(300));
+
+        for (path, body) in [("forks", vec![]), ("stalls", vec![b'x'; 1024 * 1024])] {
+            let start = Instant::now();
+            let head = format!("POST /cgi-bin/{} HTTP/1.1\r\n\r\n", path);
+            let response = call(&handler, &head, &body);
+            assert_eq!(response.status_code(), StatusCode::GatewayTimeout);
+            assert!(start.elapsed() < Duration::from_secs(3), "{}", path);
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn limits_script_output() {
+        let dir = script_dir("output");
+        write_script(
+            &dir.join("big"),
+            "echo 'Status: 200'\necho\nhead -c 2000 /dev/zero",
+        );
+        let handler = handler(&dir).with_max_output_size(1000);
+
+        let response = call(&handler, "GET /cgi-bin/big HTTP/1.1\r\n\r\n", b"");
+        assert_eq!(response.status_code(), StatusCode::BadGateway);
+    }
+}
@@ -0,0 +1,320 @@
+use super::GatewayError;
+
+use std::io::{Error as IoError, ErrorKind, Read, Result as IoResult, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+#[cfg(unix)]
+use std::os::unix::net::UnixStream;
+use std::time::{Duration, Instant};
+
+// Record types and the responder role, from the FastCGI specification.
+const VERSION: u8 = 1;
+const BEGIN_REQUEST: u8 = 1;
+const END_REQUEST: u8 = 3;
+const PARAMS: u8 = 4;
+const STDIN: u8 = 5;
+const STDOUT: u8 = 6;
+const STDERR: u8 = 7;
+const RESPONDER: u16 = 1;
+const REQUEST_COMPLETE: u8 = 0;
+
+// One request per connection, so the id is always the same.
+const REQUEST_ID: u16 = 1;
+const MAX_RECORD_LEN: usize = u16::MAX as usize;
+
+enum Socket {
+    Tcp(TcpStream),
+    #[cfg(unix)]
+    Unix(UnixStream),
+}
+
+/// Sends one request to the FastCGI server at `addr` and returns what the
+/// script wrote to its standard output, in CGI format.
+pub fn request(
+    addr: &str,
+    params: &[(String, String)],
+    stdin: &[u8],
+    timeout: Duration,
+    max_output_size: usize,
+) -> Result<Vec<u8>, GatewayError> {
+    let deadline = Instant::now() + timeout;
+    let mut socket = Socket::connect(addr, timeout)?;
+
+    let mut records = Vec::new();
+    let mut begin_request = [0; 8];
+    begin_request[..2].copy_from_slice(&RESPONDER.to_be_bytes());
+    write_record(&mut records, BEGIN_REQUEST, &begin_request);
+    let mut encoded = Vec::new();
+    for (name, value) in params {
+        encode_length(&mut encoded, name.len());
+        encode_length(&mut encoded, value.len());
+        encoded.extend_from_slice(name.as_bytes());
+        encoded.extend_from_slice(value.as_bytes());
+    }
+    write_stream(&mut records, PARAMS, &encoded);
+    write_stream(&mut records, STDIN, stdin);
+    socket.set_timeout(timeout)?;
+    socket.write_all(&records)?;
+
+    let mut stdout = Vec::new();
+    loop {
+        // the timeout covers the whole response, not each read
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            return Err(GatewayError::Timeout);
+        }
+        socket.set_timeout(remaining)?;
+
+        let mut header = [0; 8];
+        socket.read_exact(&mut header)?;
+        let record_type = header[1];
+        let request_id = u16::from_be_bytes([header[2], header[3]]);
+        let len = u16::from_be_bytes([header[4], header[5]]) as usize;
+        let padding = header[6] as usize;
+        let mut content = vec![0; len + padding];
+        socket.read_exact(&mut content)?;
+        content.truncate(len);
+        if request_id != REQUEST_ID {
+            continue;
+        }
+
+        match record_type {
+            STDOUT if stdout.len() + len > max_output_size => {
+                return Err(GatewayError::OutputTooLarge)
+            }
+            STDOUT => stdout.extend_from_slice(&content),
+            STDERR => println!(
+                "FastCGI server {}: {}",
+                addr,
+                String::from_utf8_lossy(&content).trim_end()
+            ),
+            END_REQUEST => {
+                return match content.get(4) {
+                    Some(&REQUEST_COMPLETE) => Ok(stdout),
+                    status => Err(GatewayError::Io(IoError::other(format!(
+                        "request rejected with status {:?}",
+                        status
+                    )))),
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+// A stream is sent as records of up to 64 KiB, ended by an empty one.
+fn write_stream(records: &mut Vec<u8>, record_type: u8, content: &[u8]) {
+    for chunk in content.chunks(MAX_RECORD_LEN) {
+        write_record(records, record_type, chunk);
+    }
+    write_record(records, record_type, &[]);
+}
+
+fn write_record(records: &mut Vec<u8>, record_type: u8, content: &[u8]) {
+    records.extend_from_slice(&[VERSION, record_type]);
+    records.extend_from_slice(&REQUEST_ID.to_be_bytes());
+    records.extend_from_slice(&(content.len() as u16).to_be_bytes());
+    // no padding, and a reserved byte
+    records.extend_from_slice(&[0, 0]);
+    records.extend_from_slice(content);
+}
+
+// Lengths below 128 take one byte, longer ones four with the high bit set.
+fn encode_length(encoded: &mut Vec<u8>, len: usize) {
+    if len < 0x80 {
+        encoded.push(len as u8);
+    } else {
+        encoded.extend_from_slice(&(len as u32 | 0x8000_0000).to_be_bytes());
+    }
+}
+
+impl Socket {
+    fn connect(addr: &str, timeout: Duration) -> IoResult<Self> {
+        #[cfg(unix)]
+        if let Some(path) = addr.strip_prefix("unix:") {
+            return UnixStream::connect(path).map(Self::Unix);
+        }
+
+        let addr = addr
+            .to_socket_addrs()?
+            .next()
+            .ok_or_else(|| IoError::new(ErrorKind::NotFound, "address did not resolve"))?;
+        TcpStream::connect_timeout(&addr, timeout).map(Self::Tcp)
+    }
+
+    fn set_timeout(&self, timeout: Duration) -> IoResult<()> {
+        match self {
+            Self::Tcp(stream) => {
+                stream.set_read_timeout(Some(timeout))?;
+                stream.set_write_timeout(Some(timeout))
+            }
+            #[cfg(unix)]
+            Self::Unix(stream) => {
+                stream.set_read_timeout(Some(timeout))?;
+                stream.set_write_timeout(Some(timeout))
+            }
+        }
+    }
+}
+
+impl Read for Socket {
+    fn read(&mut self, buf: &mut [u8]) -> IoResult<usize> {
+        match self {
+            Self::Tcp(stream) => stream.read(buf),
+            #[cfg(unix)]
+            Self::Unix(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for Socket {
+    fn write(&mut self, buf: &[u8]) -> IoResult<usize> {
+        match self {
+            Self::Tcp(stream) => stream.write(buf),
+            #[cfg(unix)]
+            Self::Unix(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> IoResult<()> {
+        match self {
+            Self::Tcp(stream) => stream.flush(),
+            #[cfg(unix)]
+            Self::Unix(stream) => stream.flush(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+    use std::thread;
+
+    // (type, content)
+    type Record = (u8, Vec<u8>);
+
+    // Splits `records` up, checking each header.
+    fn parse_records(mut records: &[u8]) -> Vec<Record> {
+        let mut parsed = Vec::new();
+        while !records.is_empty() {
+            assert_eq!(records[0], VERSION);
+            assert_eq!(u16::from_be_bytes([records[2], records[3]]), REQUEST_ID);
+            let len = u16::from_be_bytes([records[4], records[5]]) as usize;
+            let padding = records[6] as usize;
+            parsed.push((records[1], records[8..8 + len].to_vec()));
+            records = &records[8 + len + padding..];
+        }
+        parsed
+    }
+
+    #[test]
+    fn encodes_short_and_long_lengths() {
+        let mut encoded = Vec::new();
+        encode_length(&mut encoded, 0x7f);
+        encode_length(&mut encoded, 0x80);
+        encode_length(&mut encoded, 70_000);
+        assert_eq!(encoded, [0x7f, 0x80, 0, 0, 0x80, 0x80, 0x01, 0x11, 0x70]);
+    }
+
+    #[test]
+    fn splits_streams_into_records() {
+        let mut records = Vec::new();
+        write_stream(&mut records, STDIN, &vec![1; MAX_RECORD_LEN + 10]);
+        write_stream(&mut records, PARAMS, &[]);
+
+        let parsed = parse_records(&records);
+        let lens: Vec<(u8, usize)> = parsed.iter().map(|(t, c)| (*t, c.len())).collect();
+        assert_eq!(
+            lens,
+            [
+                (STDIN, MAX_RECORD_LEN),
+                (STDIN, 10),
+                (STDIN, 0),
+                (PARAMS, 0)
+            ]
+        );
+    }
+
+    fn record(record_type: u8, content: &[u8], padding: u8) -> Vec<u8> {
+        let mut record = vec![VERSION, record_type];
+        record.extend_from_slice(&REQUEST_ID.to_be_bytes());
+        record.extend_from_slice(&(content.len() as u16).to_be_bytes());
+        record.extend_from_slice(&[padding, 0]);
+        record.extend_from_slice(content);
+        record.extend(std::iter::repeat_n(0, padding as usize));
+        record
+    }
+
+    // A FastCGI server that reads one request up to the end of its input
+    // and answers with `response`. Returns the records it got.
+    fn stub(response: Vec<u8>) -> (String, thread::JoinHandle<Vec<Record>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let server = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut received = Vec::new();
+            let mut buf = [0; 4096];
+            loop {
+                let len = stream.read(&mut buf).unwrap();
+                received.extend_from_slice(&buf[..len]);
+                let records = parse_records(&received);
+                if len == 0 || records.last() == Some(&(STDIN, Vec::new())) {
+                    stream.write_all(&response).unwrap();
+                    return records;
+                }
+            }
+        });
+        (addr, server)
+    }
+
+    #[test]
+    fn sends_the_request_and_reads_the_response() {
+        let mut response = record(STDOUT, b"Status: 200\r\n\r\nhel", 5);
+        response.extend(record(STDERR, b"notice", 0));
+        response.extend(record(STDOUT, b"lo", 0));
+        response.extend(record(END_REQUEST, &[0; 8], 0));
+        let (addr, server) = stub(response);
+
+        let params = [("SCRIPT_NAME".to_string(), "/index.php".to_string())];
+        let output = request(&addr, &params, b"input", Duration::from_secs(5), 100)
+            .ok()
+            .unwrap();
+        assert_eq!(output, b"Status: 200\r\n\r\nhello");
+
+        let records = server.join().unwrap();
+        assert_eq!(records[0], (BEGIN_REQUEST, vec![0, 1, 0, 0, 0, 0, 0, 0]));
+        assert_eq!(
+            records[1],
+            (PARAMS, b"\x0b\x0aSCRIPT_NAME/index.php".to_vec())
+        );
+        assert_eq!(records[2], (PARAMS, Vec::new()));
+        assert_eq!(records[3], (STDIN, b"input".to_vec()));
+        assert_eq!(records[4], (STDIN, Vec::new()));
+    }
+
+    #[test]
+    fn fails_on_large_output_and_rejected_requests() {
+        let mut response = record(STDOUT, &[b'x'; 101], 0);
+        response.extend(record(END_REQUEST, &[0; 8], 0));
+        let (addr, _) = stub(response);
+        let result = request(&addr, &[], b"", Duration::from_secs(5), 100);
+        assert!(matches!(result, Err(GatewayError::OutputTooLarge)));
+
+        // FCGI_OVERLOADED
+        let (addr, _) = stub(record(END_REQUEST, &[0, 0, 0, 0, 2, 0, 0, 0], 0));
+        let result = request(&addr, &[], b"", Duration::from_secs(5), 100);
+        assert!(matches!(result, Err(GatewayError::Io(_))));
+    }
+
+    #[test]
+    fn times_out_on_silent_servers() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        thread::spawn(move || {
+            let _stream = listener.accept().unwrap();
+            thread::sleep(Duration::from_secs(2));
+        });
+        let result = request(&addr, &[], b"", Duration::from_millis(200), 100);
+        assert!(matches!(result, Err(GatewayError::Timeout)));
+    }
+}
@@ -0,0 +1,796 @@
+use super::access_log::LogFormat;
+
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::env;
+use std::fmt::{Display, Formatter, Result as FmtResult};
+use std::fs;
+use std::net::ToSocketAddrs;
+use std::path::Path;
+use std::str::FromStr;
+
+const USAGE: &str = "Usage: server [--config FILE] [--FLAG VALUE]...
+
+Flags, each also read from the environment variable in brackets:
+  --config FILE                  [SERVER_CONFIG]
+  --listen ADDR                  [SERVER_LISTEN]
+  --public-path DIR              [PUBLIC_PATH]
+  --threads N                    [SERVER_THREADS]
+  --queue-size N                 [SERVER_QUEUE_SIZE]
+  --max-upgrades N               [SERVER_MAX_UPGRADES]
+  --keep-alive-timeout SECS      [SERVER_KEEP_ALIVE_TIMEOUT]
+  --shutdown-timeout SECS        [SERVER_SHUTDOWN_TIMEOUT]
+  --max-head-size BYTES          [SERVER_MAX_HEAD_SIZE]
+  --max-body-size BYTES          [SERVER_MAX_BODY_SIZE]
+  --compression true|false       [SERVER_COMPRESSION]
+  --directory-listing true|false [SERVER_DIRECTORY_LISTING]
+  --log-format common|combined|json [SERVER_LOG_FORMAT]
+  --log-file PATH                [SERVER_LOG_FILE]
+  --log-max-size BYTES           [SERVER_LOG_MAX_SIZE]
+  --log-daily true|false         [SERVER_LOG_DAILY]
+  --log-enabled true|false       [SERVER_LOG_ENABLED]
+  --tls-listen ADDR              [SERVER_TLS_LISTEN]
+  --tls-cert PATH                [SERVER_TLS_CERT]
+  --tls-key PATH                 [SERVER_TLS_KEY]
+  --tls-redirect-http true|false [SERVER_TLS_REDIRECT_HTTP]
+
+Flags take precedence over environment variables, which take precedence
+over the config file. --listen and --tls-listen may be repeated;
+SERVER_LISTEN and SERVER_TLS_LISTEN take a comma-separated list.
+Everything else (proxy, cgi, upload, virtual_host, mime_types, tls.sni)
+can only be set in the config file.";
+
+// Keys that can be overridden, with the environment variable for each.
+const OVERRIDES: &[(&str, &str)] = &[
+    ("listen", "SERVER_LISTEN"),
+    ("public_path", "PUBLIC_PATH"),
+    ("threads", "SERVER_THREADS"),
+    ("queue_size", "SERVER_QUEUE_SIZE"),
+    ("max_upgrades", "SERVER_MAX_UPGRADES"),
+    ("keep_alive_timeout", "SERVER_KEEP_ALIVE_TIMEOUT"),
+    ("shutdown_timeout", "SERVER_SHUTDOWN_TIMEOUT"),
+    ("max_head_size", "SERVER_MAX_HEAD_SIZE"),
+    ("max_body_size", "SERVER_MAX_BODY_SIZE"),
+    ("compression", "SERVER_COMPRESSION"),
+    ("directory_listing", "SERVER_DIRECTORY_LISTING"),
+    ("log.format", "SERVER_LOG_FORMAT"),
+    ("log.file", "SERVER_LOG_FILE"),
+    ("log.max_size", "SERVER_LOG_MAX_SIZE"),
+    ("log.daily", "SERVER_LOG_DAILY"),
+    ("log.enabled", "SERVER_LOG_ENABLED"),
+    ("tls.listen", "SERVER_TLS_LISTEN"),
+    ("tls.cert", "SERVER_TLS_CERT"),
+    ("tls.key", "SERVER_TLS_KEY"),
+    ("tls.redirect_http", "SERVER_TLS_REDIRECT_HTTP"),
+];
+
+/// Server settings, read from a TOML file and then overridden by environment
+/// variables and command line flags. See `server.example.toml`.
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub listen: Vec<String>,
+    pub public_path: String,
+    pub threads: usize,
+    pub queue_size: usize,
+    /// WebSocket and streaming connections open at once, each on a thread.
+    pub max_upgrades: usize,
+    /// In seconds; 0 disables keep-alive.
+    pub keep_alive_timeout: u64,
+    /// Seconds requests in flight get to finish on SIGTERM or SIGINT.
+    pub shutdown_timeout: u64,
+    pub max_head_size: usize,
+    pub max_body_size: usize,
+    pub compression: bool,
+    pub directory_listing: bool,
+    pub log: LogConfig,
+    pub tls: TlsConfig,
+    /// Requests forwarded to other HTTP servers.
+    pub proxy: Vec<ProxyConfig>,
+    /// File uploads, off unless the `[upload]` table is there.
+    pub upload: Option<UploadConfig>,
+    /// Directories of CGI scripts, or of scripts for a FastCGI server.
+    pub cgi: Vec<CgiConfig>,
+    /// Sites served for other hosts; the one above serves any host not listed.
+    pub virtual_host: Vec<VirtualHostConfig>,
+    /// Extension (without the dot) to MIME type.
+    pub mime_types: HashMap<String, String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LogConfig {
+    pub enabled: bool,
+    pub format: LogFormat,
+    /// Log to this file instead of stdout.
+    pub file: Option<String>,
+    /// Rotate the file once it reaches this many bytes.
+    pub max_size: Option<u64>,
+    /// Rotate the file every day.
+    pub daily: bool,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TlsConfig {
+    /// Addresses serving HTTPS; TLS is off while this is empty.
+    pub listen: Vec<String>,
+    /// PEM certificate chain and private key used unless an SNI entry matches.
+    pub cert: Option<String>,
+    pub key: Option<String>,
+    /// Redirect every request on the plain `listen` addresses to HTTPS.
+    pub redirect_http: bool,
+    pub sni: Vec<SniConfig>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SniConfig {
+    /// Server names this certificate is for; `*.example.com` matches one label.
+    pub hostnames: Vec<String>,
+    pub cert: String,
+    pub key: String,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ProxyConfig {
+    /// Requests are matched by path prefix or by `Host`, exactly one of the two.
+    pub prefix: Option<String>,
+    pub host: Option<String>,
+    /// Leave the prefix out of the path the upstream gets.
+    pub strip_prefix: bool,
+    pub upstreams: Vec<String>,
+    /// Path each upstream is asked for every `health_check_interval` seconds.
+    pub health_check: Option<String>,
+    pub health_check_interval: u64,
+    /// An upstream failing `max_fails` times in a row is skipped for
+    /// `fail_timeout` seconds.
+    pub max_fails: u32,
+    pub fail_timeout: u64,
+    /// Seconds an upstream may take to connect, and then to respond.
+    pub connect_timeout: u64,
+    pub timeout: u64,
+    /// Request bodies are passed on as they arrive, up to this many bytes;
+    /// the server's `max_body_size` does not apply to them.
+    pub max_body_size: u64,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct UploadConfig {
+    /// Path that takes `multipart/form-data` POST requests.
+    pub route: String,
+    /// Directory within `public_path` the files are saved to.
+    pub dir: String,
+    pub max_file_size: u64,
+    /// Bytes all files of one request may have together. Upload bodies are
+    /// limited by this rather than `max_body_size`.
+    pub max_total_size: u64,
+    pub max_files: usize,
+    /// Replace files of the same name instead of numbering the new ones.
+    pub overwrite: bool,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CgiConfig {
+    /// Requests below `prefix` run the scripts in `dir`.
+    pub prefix: String,
+    pub dir: String,
+    /// `host:port` or `unix:/path/to/socket` of a FastCGI server that runs
+    /// the scripts instead.
+    pub fastcgi: Option<String>,
+    /// Seconds a script gets to produce its output, of at most
+    /// `max_output_size` bytes.
+    pub timeout: u64,
+    pub max_output_size: usize,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct VirtualHostConfig {
+    /// Hosts served from `public_path`; `*.example.com` matches one label.
+    pub hostnames: Vec<String>,
+    pub public_path: String,
+    /// Defaults to the top-level `directory_listing`.
+    pub directory_listing: Option<bool>,
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    /// `--help` was given; the usage text is all there is to print.
+    Help,
+    Usage(String),
+    Read(String, std::io::Error),
+    Parse(String, toml::de::Error),
+    /// A value is out of range or malformed; `key` is its path in the config file.
+    Invalid {
+        key: String,
+        message: String,
+    },
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            listen: vec!["127.0.0.1:8080".to_string()],
+            public_path: format!("{}/public", env!("CARGO_MANIFEST_DIR")),
+            threads: crate::server::DEFAULT_THREADS,
+            queue_size: crate::server::DEFAULT_QUEUE_SIZE,
+            max_upgrades: crate::server::DEFAULT_MAX_UPGRADES,
+            keep_alive_timeout: crate::server::DEFAULT_KEEP_ALIVE_TIMEOUT.as_secs(),
+            shutdown_timeout: crate::server::DEFAULT_SHUTDOWN_TIMEOUT.as_secs(),
+            max_head_size: crate::http::reader::DEFAULT_MAX_HEAD_SIZE,
+            max_body_size: crate::http::reader::DEFAULT_MAX_BODY_SIZE,
+            compression: true,
+            directory_listing: false,
+            log: LogConfig::default(),
+            tls: TlsConfig::default(),
+            proxy: Vec::new(),
+            upload: None,
+            cgi: Vec::new(),
+            virtual_host: Vec::new(),
+            mime_types: HashMap::new(),
+        }
+    }
+}
+
+impl Default for ProxyConfig {
+    fn default() -> Self {
+        Self {
+            prefix: None,
+            host: None,
+            strip_prefix: false,
+            upstreams: Vec::new(),
+            health_check: None,
+            health_check_interval: 10,
+            max_fails: crate::proxy_handler::DEFAULT_MAX_FAILS,
+            fail_timeout: crate::proxy_handler::DEFAULT_FAIL_TIMEOUT.as_secs(),
+            connect_timeout: crate::proxy_handler::DEFAULT_CONNECT_TIMEOUT.as_secs(),
+            timeout: crate::proxy_handler::DEFAULT_TIMEOUT.as_secs(),
+            max_body_size: crate::proxy_handler::DEFAULT_MAX_BODY_SIZE,
+        }
+    }
+}
+
+impl Default for UploadConfig {
+    fn default() -> Self {
+        Self {
+            route: "/upload".to_string(),
+            dir: "uploads".to_string(),
+            max_file_size: crate::http::multipart::DEFAULT_MAX_PART_SIZE,
+            max_total_size: crate::http::multipart::DEFAULT_MAX_TOTAL_SIZE,
+            max_files: crate::upload_handler::DEFAULT_MAX_FILES,
+            overwrite: false,
+        }
+    }
+}
+
+impl Default for CgiConfig {
+    fn default() -> Self {
+        Self {
+            prefix: "/cgi-bin".to_string(),
+            dir: String::new(),
+            fastcgi: None,
+            timeout: crate::cgi_handler::DEFAULT_TIMEOUT.as_secs(),
+            max_output_size: crate::cgi_handler::DEFAULT_MAX_OUTPUT_SIZE,
+        }
+    }
+}
+
+impl Default for LogConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            format: LogFormat::Combined,
+            file: None,
+            max_size: None,
+            daily: false,
+        }
+    }
+}
+
+impl Config {
+    /// Builds the configuration from the command line arguments (without the
+    /// program name) and the environment.
+    pub fn load(args: impl IntoIterator<Item = String>) -> Result<Self, ConfigError> {
+        let flags = parse_args(args)?;
+
+        let config_file = flags
+            .iter()
+            .find(|(key, _)| key == "config")
+            .map(|(_, value)| value.clone())
+            .or_else(|| env::var("SERVER_CONFIG").ok());
+        let mut config = match config_file {
+            Some(path) => Self::from_file(&path)?,
+            None => Self::default(),
+        };
+
+        for &(key, var) in OVERRIDES {
+            if let Ok(value) = env::var(var) {
+                config.set(key, &value, var)?;
+            }
+        }
+
+        // repeated --listen flags replace the configured addresses together
+        let mut listen_from_flags = false;
+        let mut tls_listen_from_flags = false;
+        for (key, value) in flags.iter().filter(|(key, _)| key != "config") {
+            let source = format!("--{}", key.replace(['_', '.'], "-"));
+            if key == "listen" && !listen_from_flags {
+                config.listen.clear();
+                listen_from_flags = true;
+            }
+            if key == "tls.listen" && !tls_listen_from_flags {
+                config.tls.listen.clear();
+                tls_listen_from_flags = true;
+            }
+            config.set(key, value, &source)?;
+        }
+
+        config.validate()?;
+        Ok(config)
+    }
+
+    pub fn from_file(path: &str) -> Result<Self, ConfigError> {
+        let contents =
+            fs::read_to_string(path).map_err(|e| ConfigError::Read(path.to_string(), e))?;
+        toml::from_str(&contents).map_err(|e| ConfigError::Parse(path.to_string(), e))
+    }
+
+    // `source` names where the value came from, for error messages.
+    fn set(&mut self, key: &str, value: &str, source: &str) -> Result<(), ConfigError> {
+        let invalid = |message: String| ConfigError::Invalid {
+            key: key.to_string(),
+            message: format!("{} (from {})", message, source),
+        };
+
+        match key {
+            "listen" if source.starts_with("--") => self.listen.push(value.to_string()),
+            "listen" => self.listen = split_list(value),
+            "public_path" => self.public_path = value.to_string(),
+            "threads" => self.threads = parse(value).map_err(invalid)?,
+            "queue_size" => self.queue_size = parse(value).map_err(invalid)?,
+            "max_upgrades" => self.max_upgrades = parse(value).map_err(invalid)?,
+            "keep_alive_timeout" => self.keep_alive_timeout = parse(value).map_err(invalid)?,
+            "shutdown_timeout" => self.shutdown_timeout = parse(value).map_err(invalid)?,
+            "max_head_size" => self.max_head_size = parse(value).map_err(invalid)?,
+            "max_body_size" => self.max_body_size = parse(value).map_err(invalid)?,
+            "compression" => self.compression = parse(value).map_err(invalid)?,
+            "directory_listing" => self.directory_listing = parse(value).map_err(invalid)?,
+            "log.format" => self.log.format = parse(value).map_err(invalid)?,
+            "log.file" => self.log.file = Some(value.to_string()),
+            "log.max_size" => self.log.max_size = Some(parse(value).map_err(invalid)?),
+            "log.daily" => self.log.daily = parse(value).map_err(invalid)?,
+            "log.enabled" => self.log.enabled = parse(value).map_err(invalid)?,
+            "tls.listen" if source.starts_with("--") => self.tls.listen.push(value.to_string()),
+            "tls.listen" => self.tls.listen = split_list(value),
+            "tls.cert" => self.tls.cert = Some(value.to_string()),
+            "tls.key" => self.tls.key = Some(value.to_string()),
+            "tls.redirect_http" => self.tls.redirect_http = parse(value).map_err(invalid)?,
+            _ => return Err(ConfigError::Usage(format!("unknown option {}", source))),
+        }
+        Ok(())
+    }
+
+    fn validate(&self) -> Result<(), ConfigError> {
+        let invalid = |key: &str, message: &str| ConfigError::Invalid {
+            key: key.to_string(),
+            message: message.to_string(),
+        };
+
+        if self.listen.is_empty() {
+            return Err(invalid("listen", "needs at least one address"));
+        }
+        for (key, addrs) in [("listen", &self.listen), ("tls.listen", &self.tls.listen)] {
+            for (i, addr) in addrs.iter().enumerate() {
+                if addr.to_socket_addrs().is_err() {
+                    return Err(invalid(
+                        &format!("{}[{}]", key, i),
+                        &format!("{:?} is not a valid host:port address", addr),
+                    ));
+                }
+            }
+        }
+        if !Path::new(&self.public_path).is_dir() {
+            return Err(invalid(
+                "public_path",
+                &format!("{:?} is not a directory", self.public_path),
+            ));
+        }
+        if self.threads == 0 {
+            return Err(invalid("threads", "must be at least 1"));
+        }
+        if self.max_head_size == 0 {
+            return Err(invalid("max_head_size", "must be greater than 0"));
+        }
+        if self.log.max_size == Some(0) {
+            return Err(invalid("log.max_size", "must be greater than 0"));
+        }
+        if (self.log.max_size.is_some() || self.log.daily) && self.log.file.is_none() {
+            return Err(invalid("log.file", "is required to rotate the log"));
+        }
+        self.validate_tls()?;
+        self.validate_proxy()?;
+        self.validate_cgi()?;
+        if let Some(upload) = &self.upload {
+            if !upload.route.starts_with('/') {
+                return Err(invalid("upload.route", "must start with /"));
+            }
+            let dir = Path::new(&self.public_path).join(&upload.dir);
+            if Path::new(&upload.dir).is_absolute() || upload.dir.split('/').any(|s| s == "..") {
+                return Err(invalid("upload.dir", "must be a path within public_path"));
+            }
+            if !dir.is_dir() {
+                return Err(invalid(
+                    "upload.dir",
+                    &format!("{:?} is not a directory", dir.display().to_string()),
+                ));
+            }
+            if upload.max_files == 0 {
+                return Err(invalid("upload.max_files", "must be at least 1"));
+            }
+        }
+        for (i, virtual_host) in self.virtual_host.iter().enumerate() {
+            if virtual_host.hostnames.is_empty() {
+                return Err(invalid(
+                    &format!("virtual_host[{}].hostnames", i),
+                    "needs at least one hostname",
+                ));
+            }
+            if !Path::new(&virtual_host.public_path).is_dir() {
+                return Err(invalid(
+                    &format!("virtual_host[{}].public_path", i),
+                    &format!("{:?} is not a directory", virtual_host.public_path),
+                ));
+            }
+        }
+        for (extension, mime_type) in &self.mime_types {
+            if !mime_type.contains('/') {
+                return Err(invalid(
+                    &format!("mime_types.{}", extension),
+                    &format!("{:?} is not a MIME type", mime_type),
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    fn validate_tls(&self) -> Result<(), ConfigError> {
+        let invalid = |key: &str, message: &str| ConfigError::Invalid {
+            key: key.to_string(),
+            message: message.to_string(),
+        };
+        let tls = &self.tls;
+
+        if tls.cert.is_some() != tls.key.is_some() {
+            let missing = if tls.cert.is_none() {
+                "tls.cert"
+            } else {
+                "tls.key"
+            };
+            return Err(invalid(missing, "is required together with the other"));
+        }
+        for (i, sni) in tls.sni.iter().enumerate() {
+            if sni.hostnames.is_empty() {
+                return Err(invalid(
+                    &format!("tls.sni[{}].hostnames", i),
+                    "needs at least one hostname",
+                ));
+            }
+        }
+        if tls.listen.is_empty() {
+            if tls.redirect_http {
+                return Err(invalid("tls.listen", "is required to redirect to HTTPS"));
+            }
+            return Ok(());
+        }
+        if tls.cert.is_none() && tls.sni.is_empty() {
+            return Err(invalid("tls.cert", "is required to serve HTTPS"));
+        }
+        Ok(())
+    }
+
+    fn validate_cgi(&self) -> Result<(), ConfigError> {
+        let invalid = |i: usize, key: &str, message: &str| ConfigError::Invalid {
+            key: format!("cgi[{}].{}", i, key),
+            message: message.to_string(),
+        };
+
+        for (i, cgi) in self.cgi.iter().enumerate() {
+            if !cgi.prefix.starts_with('/') {
+                return Err(invalid(i, "prefix", "must start with /"));
+            }
+            if !Path::new(&cgi.dir).is_dir() {
+                return Err(invalid(
+                    i,
+                    "dir",
+                    &format!("{:?} is not a directory", cgi.dir),
+                ));
+            }
+            if let Some(addr) = &cgi.fastcgi {
+                if !addr.starts_with("unix:") && addr.to_socket_addrs().is_err() {
+                    return Err(invalid(
+                        i,
+                        "fastcgi",
+                        &format!("{:?} is neither host:port nor unix:/path", addr),
+                    ));
+                }
+            }
+            if cgi.timeout == 0 {
+                return Err(invalid(i, "timeout", "must be greater than 0"));
+            }
+            if cgi.max_output_size == 0 {
+                return Err(invalid(i, "max_output_size", "must be greater than 0"));
+            }
+        }
+        Ok(())
+    }
+
+    fn validate_proxy(&self) -> Result<(), ConfigError> {
+        let invalid = |i: usize, key: &str, message: &str| ConfigError::Invalid {
+            key: format!("proxy[{}].{}", i, key),
+            message: message.to_string(),
+        };
+
+        for (i, proxy) in self.proxy.iter().enumerate() {
+            match (&proxy.prefix, &proxy.host) {
+                (Some(_), Some(_)) | (None, None) => {
+                    return Err(invalid(i, "prefix", "or `host` is required, but not both"))
+                }
+                (Some(prefix), None) if !prefix.starts_with('/') => {
+                    return Err(invalid(i, "prefix", "must start with /"))
+                }
+                (None, Some(_)) if proxy.strip_prefix => {
+                    return Err(invalid(i, "strip_prefix", "needs a `prefix`"))
+                }
+                _ => {}
+            }
+            if proxy.upstreams.is_empty() {
+                return Err(invalid(i, "upstreams", "needs at least one address"));
+            }
+            for (j, addr) in proxy.upstreams.iter().enumerate() {
+                if addr.to_socket_addrs().is_err() {
+                    return Err(invalid(
+                        i,
+                        &format!("upstreams[{}]", j),
+                        &format!("{:?} is not a valid host:port address", addr),
+                    ));
+                }
+            }
+            if proxy
+                .health_check
+                .as_ref()
+                .is_some_and(|path| !path.starts_with('/'))
+            {
+                return Err(invalid(i, "health_check", "must start with /"));
+            }
+            for (key, secs) in [
+                ("health_check_interval", proxy.health_check_interval),
+                ("connect_timeout", proxy.connect_timeout),
+                ("timeout", proxy.timeout),
+            ] {
+                if secs == 0 {
+                    return Err(invalid(i, key, "must be greater than 0"));
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+// "a, b,,c" -> ["a", "b", "c"]
+fn split_list(value: &str) -> Vec<String> {
+    value
+        .split(',')
+        .map(|item| item.trim().to_string())
+        .filter(|item| !item.is_empty())
+        .collect()
+}
+
+// --key value or --key=value; keys come back with `-` turned into `_`,
+// except that --log-x becomes log.x and --tls-x becomes tls.x.
+fn parse_args(
+    args: impl IntoIterator<Item = String>,
+) -> Result<Vec<(String, String)>, ConfigError> {
+    let mut flags = Vec::new();
+    let mut args = args.into_iter();
+
+    while let Some(arg) = args.next() {
+        if arg == "--help" || arg == "-h" {
+            return Err(ConfigError::Help);
+        }
+        let flag = match arg.strip_prefix("--") {
+            Some(flag) => flag,
+            None => return Err(ConfigError::Usage(format!("unexpected argument {}", arg))),
+        };
+
+        let (name, value) = match flag.split_once('=') {
+            Some((name, value)) => (name.to_string(), value.to_string()),
+            None => match args.next() {
+                Some(value) => (flag.to_string(), value),
+                None => return Err(ConfigError::Usage(format!("--{} needs a value", flag))),
+            },
+        };
+
+        let key = match name.split_once('-') {
+            Some((table @ ("log" | "tls"), rest)) => {
+                format!("{}.{}", table, rest.replace('-', "_"))
+            }
+            _ => name.replace('-', "_"),
+        };
+        flags.push((key, value));
+    }
+    Ok(flags)
+}
+
+fn parse<T: FromStr>(value: &str) -> Result<T, String> {
+    value
+        .trim()
+        .parse()
+        .map_err(|_| format!("{:?} is not a valid value", value))
+}
+
+impl Display for ConfigError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        match self {
+            Self::Help => write!(f, "{}", USAGE),
+            Self::Usage(message) => write!(f, "{}\n\n{}", message, USAGE),
+            Self::Read(path, e) => write!(f, "Failed to read config file {}: {}", path, e),
+            // the TOML error already shows the line and the offending key
+            Self::Parse(path, e) => write!(f, "Invalid config file {}: {}", path, e),
+            Self::Invalid { key, message } => write!(f, "Invalid `{}`: {}", key, message),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    fn invalid_key(result: Result<(), ConfigError>) -> String {
+        match result {
+            Err(ConfigError::Invalid { key, .. }) => key,
+            other => panic!(
+                "expected an invalid key, got {:?}",
+                other.map_err(|e| e.to_string())
+            ),
+        }
+    }
+
+    #[test]
+    fn parses_flags_into_keys() {
+        let flags = parse_args(args(&[
+            "--max-body-size",
+            "10",
+            "--log-format=json",
+            "--tls-redirect-http",
+            "true",
+        ]))
+        .unwrap();
+
+        assert_eq!(
+            flags,
+            vec![
+                ("max_body_size".to_string(), "10".to_string()),
+                ("log.format".to_string(), "json".to_string()),
+                ("tls.redirect_http".to_string(), "true".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn rejects_malformed_arguments() {
+        assert!(matches!(parse_args(args(&["-h"])), Err(ConfigError::Help)));
+        assert!(matches!(
+            parse_args(args(&["--threads"])),
+            Err(ConfigError::Usage(message)) if message == "--threads needs a value"
+        ));
+        assert!(matches!(
+            parse_args(args(&["threads"])),
+            Err(ConfigError::Usage(_))
+        ));
+    }
+
+    #[test]
+    fn flags_override_and_repeat() {
+        let config = Config::load(args(&[
+            "--listen",
+            "127.0.0.1:1",
+            "--listen=127.0.0.1:2",
+            "--threads",
+            "3",
+            "--log-daily",
+            "false",
+        ]))
+        .unwrap();
+
+        assert_eq!(config.listen, ["127.0.0.1:1", "127.0.0.1:2"]);
+        assert_eq!(config.threads, 3);
+    }
+
+    #[test]
+    fn names_the_source_of_bad_values() {
+        let mut config = Config::default();
+
+        let e = config.set("threads", "many", "--threads").unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            "Invalid `threads`: \"many\" is not a valid value (from --threads)"
+        );
+        assert!(matches!(
+            config.set("proxy", "x", "--proxy"),
+            Err(ConfigError::Usage(message)) if message == "unknown option --proxy"
+        ));
+
+        config.set("listen", "a:1, b:2,", "SERVER_LISTEN").unwrap();
+        assert_eq!(config.listen, ["a:1", "b:2"]);
+    }
+
+    #[test]
+    fn usage_lists_every_flag_and_variable() {
+        for (key, var) in OVERRIDES {
+            let flag = format!("--{} ", key.replace(['_', '.'], "-"));
+            assert!(USAGE.contains(&flag), "{} is missing", flag);
+            assert!(USAGE.contains(&format!("[{}]", var)), "{} is missing", var);
+        }
+    }
+
+    #[test]
+    fn validates_settings() {
+        assert!(Config::default().validate().is_ok());
+
+        let config = Config {
+            listen: vec!["nowhere".to_string()],
+            ..Config::default()
+        };
+        assert_eq!(invalid_key(config.validate()), "listen[0]");
+
+        let config = Config {
+            threads: 0,
+            ..Config::default()
+        };
+        assert_eq!(invalid_key(config.validate()), "threads");
+
+        let mut config = Config::default();
+        config.log.daily = true;
+        assert_eq!(invalid_key(config.validate()), "log.file");
+
+        let mut config = Config::default();
+        config.proxy.push(ProxyConfig {
+            host: Some("a".to_string()),
+            strip_prefix: true,
+            upstreams: vec!["127.0.0.1:1".to_string()],
+            ..ProxyConfig::default()
+        });
+        assert_eq!(invalid_key(config.validate()), "proxy[0].strip_prefix");
+
+        let mut config: Config = toml::from_str(
+            "[[virtual_host]]\nhostnames = [\"*.example.com\"]\npublic_path = \".\"\n",
+        )
+        .unwrap();
+        assert!(config.validate().is_ok());
+        assert_eq!(config.virtual_host[0].directory_listing, None);
+        config.virtual_host[0].public_path = "missing".to_string();
+        assert_eq!(
+            invalid_key(config.validate()),
+            "virtual_host[0].public_path"
+        );
+        config.virtual_host[0].hostnames.clear();
+        assert_eq!(invalid_key(config.validate()), "virtual_host[0].hostnames");
+    }
+
+    #[test]
+    fn rejects_unknown_keys_in_files() {
+        let error = toml::from_str::<Config>("threads = 2\nthread = 3\n").unwrap_err();
+        assert!(error.to_string().contains("unknown field `thread`"));
+
+        let config: Config = toml::from_str("[log]\nformat = \"combined\"\n").unwrap();
+        assert_eq!(config.log.format, crate::access_log::LogFormat::Combined);
+    }
+}
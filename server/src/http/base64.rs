@@ -0,0 +1,92 @@
+const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+/// Standard base64 with padding.
+pub fn encode(data: &[u8]) -> String {
+    let mut encoded = String::with_capacity(data.len().div_ceil(3) * 4);
+
+    for chunk in data.chunks(3) {
+        let b = [
+            chunk[0],
+            chunk.get(1).copied().unwrap_or(0),
+            chunk.get(2).copied().unwrap_or(0),
+        ];
+        let n = (b[0] as u32) << 16 | (b[1] as u32) << 8 | b[2] as u32;
+
+        for i in 0..4 {
+            if i <= chunk.len() {
+                encoded.push(ALPHABET[(n >> (18 - 6 * i) & 0x3f) as usize] as char);
+            } else {
+                encoded.push('=');
+            }
+        }
+    }
+    encoded
+}
+
+/// Decodes standard base64; padding is optional. Returns `None` on invalid input.
+pub fn decode(s: &str) -> Option<Vec<u8>> {
+    let s = s.trim_end_matches('=');
+    let mut decoded = Vec::with_capacity(s.len() * 3 / 4);
+    let mut n: u32 = 0;
+    let mut bits = 0;
+
+    for c in s.bytes() {
+        let value = ALPHABET.iter().position(|&a| a == c)? as u32;
+        n = n << 6 | value;
+        bits += 6;
+        if bits >= 8 {
+            bits -= 8;
+            decoded.push((n >> bits) as u8);
+            n &= (1 << bits) - 1;
+        }
+    }
+
+    // a single leftover character cannot encode a whole byte
+    if bits >= 6 {
+        return None;
+    }
+    Some(decoded)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // RFC 4648, section 10
+    const VECTORS: [(&str, &str); 7] = [
+        ("", ""),
+        ("f", "Zg=="),
+        ("fo", "Zm8="),
+        ("foo", "Zm9v"),
+        ("foob", "Zm9vYg=="),
+        ("fooba", "Zm9vYmE="),
+        ("foobar", "Zm9vYmFy"),
+    ];
+
+    #[test]
+    fn encodes_the_rfc_vectors() {
+        for (data, encoded) in VECTORS {
+            assert_eq!(encode(data.as_bytes()), encoded);
+        }
+    }
+
+    #[test]
+    fn decodes_with_or_without_padding() {
+        for (data, encoded) in VECTORS {
+            assert_eq!(decode(encoded).as_deref(), Some(data.as_bytes()));
+            assert_eq!(
+                decode(encoded.trim_end_matches('=')).as_deref(),
+                Some(data.as_bytes())
+            );
+        }
+        assert_eq!(decode("+/8=").as_deref(), Some(&[0xfb, 0xff][..]));
+    }
+
+    #[test]
+    fn rejects_invalid_input() {
+        assert_eq!(decode("Zm9v!"), None);
+        assert_eq!(decode("Z"), None);
+        assert_eq!(decode("Zm9vY"), None);
+        assert_eq!(decode("Zm 9v"), None);
+    }
+}
@@ -0,0 +1,205 @@
+use std::fmt::{Debug, Formatter, Result as FmtResult};
+use std::fs::File;
+use std::io::{self, Read, Result as IoResult, Write};
+
+const STREAM_CHUNK_SIZE: usize = 8192;
+
+#[derive(Default)]
+pub enum Body {
+    #[default]
+    Empty,
+    Bytes(Vec<u8>),
+    /// Streamed from the reader, which must yield exactly `len` bytes.
+    Reader(Box<dyn Read + Send>, u64),
+    /// Of unknown length, sent chunked as the reader yields it until it
+    /// returns 0. Each read becomes a chunk and is flushed right away.
+    Stream(Box<dyn Read + Send>),
+}
+
+impl Body {
+    pub fn file(file: File) -> IoResult<Self> {
+        let len = file.metadata()?.len();
+        Ok(Self::Reader(Box::new(file), len))
+    }
+
+    pub fn stream(reader: impl Read + Send + 'static) -> Self {
+        Self::Stream(Box::new(reader))
+    }
+
+    /// 0 for a stream, whose length is not known up front.
+    pub fn len(&self) -> u64 {
+        match self {
+            Self::Empty | Self::Stream(_) => 0,
+            Self::Bytes(bytes) => bytes.len() as u64,
+            Self::Reader(_, len) => *len,
+        }
+    }
+
+    pub fn is_stream(&self) -> bool {
+        matches!(self, Self::Stream(_))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn write_to(self, stream: &mut impl Write) -> IoResult<()> {
+        match self {
+            Self::Empty => Ok(()),
+            Self::Bytes(bytes) => stream.write_all(&bytes),
+            Self::Reader(reader, len) => {
+                let copied = io::copy(&mut reader.take(len), stream)?;
+                if copied < len {
+                    return Err(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        "body ended before its announced length",
+                    ));
+                }
+                Ok(())
+            }
+            Self::Stream(mut reader) => {
+                let mut chunk = vec![0; STREAM_CHUNK_SIZE];
+                loop {
+                    let read = match reader.read(&mut chunk) {
+                        Ok(0) => break,
+                        Ok(read) => read,
+                        Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                        Err(e) => return Err(e),
+                    };
+                    stream.write_all(&encode_chunk(&chunk[..read]))?;
+                    stream.flush()?;
+                }
+                stream.write_all(b"0\r\n\r\n")?;
+                stream.flush()
+            }
+        }
+    }
+
+    /// Like `write_to`, but a stream goes out as is, for a connection that
+    /// is closed to end it.
+    pub fn write_unframed_to(self, stream: &mut impl Write) -> IoResult<()> {
+        let Self::Stream(mut reader) = self else {
+            return self.write_to(stream);
+        };
+        let mut chunk = vec![0; STREAM_CHUNK_SIZE];
+        loop {
+            let read = match reader.read(&mut chunk) {
+                Ok(0) => break,
+                Ok(read) => read,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            };
+            stream.write_all(&chunk[..read])?;
+            stream.flush()?;
+        }
+        Ok(())
+    }
+}
+
+/// Frames `data` as one chunk of a chunked body.
+pub fn encode_chunk(data: &[u8]) -> Vec<u8> {
+    let mut chunk = format!("{:x}\r\n", data.len()).into_bytes();
+    chunk.extend_from_slice(data);
+    chunk.extend_from_slice(b"\r\n");
+    chunk
+}
+
+impl From<String> for Body {
+    fn from(s: String) -> Self {
+        Self::Bytes(s.into_bytes())
+    }
+}
+
+impl From<&str> for Body {
+    fn from(s: &str) -> Self {
+        Self::Bytes(s.as_bytes().to_vec())
+    }
+}
+
+impl From<Vec<u8>> for Body {
+    fn from(bytes: Vec<u8>) -> Self {
+        Self::Bytes(bytes)
+    }
+}
+
+impl Debug for Body {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        match self {
+            Self::Empty => write!(f, "Empty"),
+            Self::Bytes(bytes) => write!(f, "Bytes({} bytes)", bytes.len()),
+            Self::Reader(_, len) => write!(f, "Reader({} bytes)", len),
+            Self::Stream(_) => write!(f, "Stream"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Hands out its pieces one read at a time.
+    struct Pieces(Vec<&'static [u8]>);
+
+    impl Read for Pieces {
+        fn read(&mut self, buf: &mut [u8]) -> IoResult<usize> {
+            if self.0.is_empty() {
+                return Ok(0);
+            }
+            let piece = self.0.remove(0);
+            buf[..piece.len()].copy_from_slice(piece);
+            Ok(piece.len())
+        }
+    }
+
+    fn written(body: Body) -> IoResult<Vec<u8>> {
+        let mut out = Vec::new();
+        body.write_to(&mut out)?;
+        Ok(out)
+    }
+
+    #[test]
+    fn writes_streams_as_chunks() {
+        let body = Body::stream(Pieces(vec![b"hello", b"", b"world wide"]));
+        assert!(body.is_stream());
+        assert_eq!(body.len(), 0);
+
+        // a read of nothing ends the stream
+        assert_eq!(written(body).unwrap(), b"5\r\nhello\r\n0\r\n\r\n");
+
+        let body = Body::stream(Pieces(vec![b"hello", &[b'x'; 26]]));
+        let mut expected = b"5\r\nhello\r\n1a\r\n".to_vec();
+        expected.extend_from_slice(&[b'x'; 26]);
+        expected.extend_from_slice(b"\r\n0\r\n\r\n");
+        assert_eq!(written(body).unwrap(), expected);
+    }
+
+    #[test]
+    fn writes_streams_unframed() {
+        let body = Body::stream(Pieces(vec![b"hello", b" world", b""]));
+        let mut out = Vec::new();
+        body.write_unframed_to(&mut out).unwrap();
+        assert_eq!(out, b"hello world");
+
+        let mut out = Vec::new();
+        Body::from("abc").write_unframed_to(&mut out).unwrap();
+        assert_eq!(out, b"abc");
+    }
+
+    #[test]
+    fn fails_when_a_reader_is_short() {
+        let body = Body::Reader(Box::new(&b"abc"[..]), 3);
+        assert_eq!(written(body).unwrap(), b"abc");
+
+        let body = Body::Reader(Box::new(&b"abc"[..]), 5);
+        assert_eq!(
+            written(body).unwrap_err().kind(),
+            io::ErrorKind::UnexpectedEof
+        );
+    }
+
+    #[test]
+    fn encodes_chunks_in_hex() {
+        assert_eq!(encode_chunk(b""), b"0\r\n\r\n");
+        assert_eq!(encode_chunk(&[0; 255])[..4], *b"ff\r\n");
+    }
+}
@@ -0,0 +1,157 @@
+use super::ParseError;
+use std::io::{BufRead, Error as IoError, ErrorKind, Read, Result as IoResult};
+use std::str;
+
+// Chunk size lines and trailer lines longer than this are rejected.
+const MAX_LINE_LEN: usize = 4096;
+
+/// Decodes a chunked body as it is read, for passing it on without
+/// buffering it whole. Trailers are dropped.
+pub struct ChunkedReader<R> {
+    inner: R,
+    // bytes left in the current chunk
+    remaining: u64,
+    done: bool,
+}
+
+impl<R: BufRead> ChunkedReader<R> {
+    pub fn new(inner: R) -> Self {
+        Self {
+            inner,
+            remaining: 0,
+            done: false,
+        }
+    }
+
+    /// Whether the last chunk and the trailers have been read.
+    pub fn is_done(&self) -> bool {
+        self.done
+    }
+
+    // A line without its CRLF; a bare LF does not end one.
+    fn read_line(&mut self) -> IoResult<Vec<u8>> {
+        let mut line = Vec::new();
+        (&mut self.inner)
+            .take(MAX_LINE_LEN as u64)
+            .read_until(b'\n', &mut line)?;
+        if !line.ends_with(b"\r\n") {
+            return Err(invalid(
+                "chunk line not ended by CRLF, too long or truncated",
+            ));
+        }
+        line.truncate(line.len() - 2);
+        Ok(line)
+    }
+
+    // Reads the size line of the next chunk; at the last one, also the trailers.
+    fn next_chunk(&mut self) -> IoResult<()> {
+        let line = self.read_line()?;
+        self.remaining = parse_chunk_size(&line).map_err(|_| invalid("invalid chunk size"))?;
+
+        if self.remaining == 0 {
+            while !self.read_line()?.is_empty() {}
+            self.done = true;
+        }
+        Ok(())
+    }
+}
+
+impl<R: BufRead> Read for ChunkedReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> IoResult<usize> {
+        if self.done || buf.is_empty() {
+            return Ok(0);
+        }
+        if self.remaining == 0 {
+            self.next_chunk()?;
+            if self.done {
+                return Ok(0);
+            }
+        }
+
+        let len = buf.len().min(self.remaining as usize);
+        let read = self.inner.read(&mut buf[..len])?;
+        if read == 0 {
+            return Err(IoError::new(
+                ErrorKind::UnexpectedEof,
+                "chunked body ended early",
+            ));
+        }
+        self.remaining -= read as u64;
+
+        if self.remaining == 0 && !self.read_line()?.is_empty() {
+            return Err(invalid("missing CRLF after chunk"));
+        }
+        Ok(read)
+    }
+}
+
+/// The size from the size line of a chunk, without its CRLF. Extensions are
+/// ignored, and only hex digits are taken: no sign, as `from_str_radix`
+/// would allow. Shared with `ChunkedDecoder`, so that buffered and streamed
+/// bodies are read alike.
+pub fn parse_chunk_size(line: &[u8]) -> Result<u64, ParseError> {
+    let line = str::from_utf8(line).map_err(|_| ParseError::InvalidBody)?;
+    let size = line.split(';').next().unwrap_or("").trim();
+    if size.is_empty() || !size.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return Err(ParseError::InvalidBody);
+    }
+    u64::from_str_radix(size, 16).map_err(|_| ParseError::PayloadTooLarge)
+}
+
+fn invalid(message: &str) -> IoError {
+    IoError::new(ErrorKind::InvalidData, message.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn decode(input: &[u8]) -> IoResult<Vec<u8>> {
+        let mut body = Vec::new();
+        ChunkedReader::new(input).read_to_end(&mut body)?;
+        Ok(body)
+    }
+
+    #[test]
+    fn decodes_chunks_and_drops_trailers() {
+        assert_eq!(
+            decode(b"4\r\nWiki\r\n5;ext=1\r\npedia\r\n0\r\nTrailer: x\r\n\r\n").unwrap(),
+            b"Wikipedia"
+        );
+        let mut reader = ChunkedReader::new(&b"1\r\na\r\n0\r\n\r\nnext"[..]);
+        let mut body = Vec::new();
+        reader.read_to_end(&mut body).unwrap();
+        assert!(reader.is_done());
+    }
+
+    #[test]
+    fn rejects_what_the_buffered_decoder_rejects() {
+        // a sign, as `from_str_radix` would take
+        assert!(decode(b"+1a\r\n").is_err());
+        assert!(decode(b"-0\r\n\r\n").is_err());
+        assert!(decode(b"0x5\r\nhello\r\n0\r\n\r\n").is_err());
+        // bare LF after the size, the data or a trailer
+        assert!(decode(b"5\nhello\r\n0\r\n\r\n").is_err());
+        assert!(decode(b"5\r\nhello\n0\r\n\r\n").is_err());
+        assert!(decode(b"5\r\nhello\r\n0\r\n\n").is_err());
+        assert!(decode(b"5\r\nhello").is_err());
+    }
+
+    #[test]
+    fn parses_chunk_sizes() {
+        assert_eq!(parse_chunk_size(b"1A").ok(), Some(26));
+        assert_eq!(parse_chunk_size(b"ff ; name=value").ok(), Some(255));
+        assert!(matches!(
+            parse_chunk_size(b"+1a"),
+            Err(ParseError::InvalidBody)
+        ));
+        assert!(matches!(
+            parse_chunk_size(b""),
+            Err(ParseError::InvalidBody)
+        ));
+        assert!(matches!(
+            parse_chunk_size(b"ffffffffffffffffffff"),
+            Err(ParseError::PayloadTooLarge)
+        ));
+    }
+}
@@ -0,0 +1,306 @@
+use super::{Body, Method, Request, Response, StatusCode};
+
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use std::io::{self, Read, Write};
+
+/// Bodies smaller than this are not worth the compression overhead.
+pub const MIN_COMPRESS_SIZE: u64 = 1024;
+/// Larger bodies are sent as they are rather than compressed in memory.
+pub const MAX_COMPRESS_SIZE: u64 = 16 * 1024 * 1024;
+
+const BROTLI_QUALITY: u32 = 5;
+const BROTLI_WINDOW: u32 = 22;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Encoding {
+    Brotli,
+    Gzip,
+}
+
+impl Encoding {
+    /// Content codings we produce, in order of preference.
+    pub const ALL: [Encoding; 2] = [Encoding::Brotli, Encoding::Gzip];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Brotli => "br",
+            Self::Gzip => "gzip",
+        }
+    }
+
+    /// The extension of precompressed siblings, e.g. `app.js.br`.
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Self::Brotli => "br",
+            Self::Gzip => "gz",
+        }
+    }
+
+    pub fn compress(&self, data: &[u8]) -> io::Result<Vec<u8>> {
+        match self {
+            Self::Brotli => {
+                let mut compressed = Vec::new();
+                {
+                    let mut encoder = brotli::CompressorWriter::new(
+                        &mut compressed,
+                        4096,
+                        BROTLI_QUALITY,
+                        BROTLI_WINDOW,
+                    );
+                    encoder.write_all(data)?;
+                }
+                Ok(compressed)
+            }
+            Self::Gzip => {
+                let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+                encoder.write_all(data)?;
+                encoder.finish()
+            }
+        }
+    }
+}
+
+// gzip, deflate;q=0.5, br;q=1.0, *;q=0
+/// Picks the most preferred of `available` that the client accepts.
+/// Ties are broken by the order of `available`.
+pub fn negotiate(accept_encoding: &str, available: &[Encoding]) -> Option<Encoding> {
+    let mut best: Option<(Encoding, f32)> = None;
+
+    for encoding in available {
+        let mut quality = None;
+        let mut wildcard = None;
+
+        for item in accept_encoding.split(',') {
+            let mut parts = item.split(';');
+            let name = parts.next().unwrap_or("").trim();
+            let q = parts
+                .filter_map(|param| param.trim().strip_prefix("q="))
+                .find_map(|q| q.trim().parse::<f32>().ok())
+                .unwrap_or(1.0);
+
+            if name.eq_ignore_ascii_case(encoding.as_str())
+                || (*encoding == Encoding::Gzip && name.eq_ignore_ascii_case("x-gzip"))
+            {
+                quality = Some(q);
+            } else if name == "*" {
+                wildcard = Some(q);
+            }
+        }
+
+        let q = quality.or(wildcard).unwrap_or(0.0);
+        if q > 0.0 && best.is_none_or(|(_, best_q)| q > best_q) {
+            best = Some((*encoding, q));
+        }
+    }
+
+    best.map(|(encoding, _)| encoding)
+}
+
+/// Whether a body of this type benefits from compression. Images, audio,
+/// video, fonts and archives are already compressed.
+pub fn is_compressible(content_type: &str) -> bool {
+    let mime_type = content_type
+        .split(';')
+        .next()
+        .unwrap_or("")
+        .trim()
+        .to_ascii_lowercase();
+
+    mime_type.starts_with("text/")
+        || mime_type.ends_with("+json")
+        || mime_type.ends_with("+xml")
+        || matches!(
+            mime_type.as_str(),
+            "application/json"
+                | "application/javascript"
+                | "application/xml"
+                | "application/wasm"
+                | "image/svg+xml"
+                | "image/x-icon"
+                | "image/bmp"
+                | "font/ttf"
+                | "font/otf"
+        )
+}
+
+/// Compresses the response body according to the request's
+/// `Accept-Encoding`, if the response is compressible and not already encoded.
+pub fn compress_response(response: &mut Response, request: &Request) {
+    // streams are sent as they are produced, there is nothing to compress up front
+    let compressible = response.allows_compression()
+        && response.status_code().allows_body()
+        && !response.body().is_stream()
+        && response.header("Content-Encoding").is_none()
+        && response.header("Content-Range").is_none()
+        && response.header("Content-Type").is_some_and(is_compressible);
+    if !compressible {
+        return;
+    }
+
+    // caches must keep compressed and uncompressed variants apart
+    response.add_vary("Accept-Encoding");
+
+    // a HEAD response has no body to compress, and reading a whole file
+    // just to learn its compressed length is not worth it
+    let len = response.body().len();
+    if !(MIN_COMPRESS_SIZE..=MAX_COMPRESS_SIZE).contains(&len)
+        || matches!(request.method(), Method::HEAD)
+    {
+        return;
+    }
+    let accept_encoding = request.headers().get("Accept-Encoding");
+    let encoding = match accept_encoding.and_then(|accept| negotiate(accept, &Encoding::ALL)) {
+        Some(encoding) => encoding,
+        None => return,
+    };
+
+    let data = match response.take_body() {
+        Body::Bytes(bytes) => bytes,
+        Body::Reader(reader, len) => {
+            let mut data = Vec::with_capacity(len as usize);
+            if let Err(e) = reader.take(len).read_to_end(&mut data) {
+                println!("Failed to read body for compression: {}", e);
+                *response = Response::error(StatusCode::InternalServerError);
+                return;
+            }
+            data
+        }
+        Body::Empty | Body::Stream(_) => return,
+    };
+
+    match encoding.compress(&data) {
+        Ok(compressed) => {
+            response.set_header("Content-Encoding", encoding.as_str());
+            // the compressed bytes are a different representation
+            if let Some(etag) = response.header("ETag") {
+                if !etag.starts_with("W/") {
+                    let etag = format!("W/{}", etag);
+                    response.set_header("ETag", etag);
+                }
+            }
+            response.set_body(compressed);
+        }
+        Err(e) => {
+            println!("Failed to compress body: {}", e);
+            response.set_body(data);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::read::GzDecoder;
+    use std::convert::TryFrom;
+    use std::io::Cursor;
+
+    fn request(head: &str) -> Request<'_> {
+        Request::try_from(head.as_bytes()).unwrap()
+    }
+
+    fn text_response(len: usize) -> Response {
+        Response::new(StatusCode::Ok, Some("a".repeat(len)))
+            .with_header("Content-Type", "text/plain")
+            .with_header("ETag", "\"abc\"")
+    }
+
+    #[test]
+    fn negotiates_by_quality_and_preference() {
+        let negotiate = |accept| negotiate(accept, &Encoding::ALL);
+
+        assert_eq!(negotiate("gzip, br"), Some(Encoding::Brotli));
+        assert_eq!(
+            negotiate("gzip, deflate;q=0.5, br;q=0.4"),
+            Some(Encoding::Gzip)
+        );
+        assert_eq!(negotiate("x-gzip"), Some(Encoding::Gzip));
+        assert_eq!(negotiate("*"), Some(Encoding::Brotli));
+        assert_eq!(negotiate("br;q=0, *;q=0.1"), Some(Encoding::Gzip));
+        assert_eq!(negotiate("identity"), None);
+        assert_eq!(negotiate("gzip;q=0"), None);
+        assert_eq!(negotiate(""), None);
+    }
+
+    #[test]
+    fn recognizes_compressible_types() {
+        assert!(is_compressible("text/html; charset=utf-8"));
+        assert!(is_compressible("application/ld+json"));
+        assert!(is_compressible("image/svg+xml"));
+        assert!(!is_compressible("image/png"));
+        assert!(!is_compressible("application/zip"));
+    }
+
+    #[test]
+    fn compresses_and_weakens_the_etag() {
+        let mut response = text_response(4096);
+        compress_response(
+            &mut response,
+            &request("GET / HTTP/1.1\r\nAccept-Encoding: gzip\r\n\r\n"),
+        );
+
+        assert_eq!(response.header("Content-Encoding"), Some("gzip"));
+        assert_eq!(response.header("ETag"), Some("W/\"abc\""));
+        assert_eq!(response.header("Vary"), Some("Accept-Encoding"));
+
+        let mut compressed = Vec::new();
+        response.take_body().write_to(&mut compressed).unwrap();
+        let mut decompressed = String::new();
+        GzDecoder::new(Cursor::new(compressed))
+            .read_to_string(&mut decompressed)
+            .unwrap();
+        assert_eq!(decompressed, "a".repeat(4096));
+    }
+
+    #[test]
+    fn compresses_readers() {
+        let mut response = Response::new(StatusCode::Ok, None)
+            .with_header("Content-Type", "application/json")
+            .with_body(Body::Reader(Box::new(Cursor::new(vec![b'1'; 2048])), 2048));
+        compress_response(
+            &mut response,
+            &request("GET / HTTP/1.1\r\nAccept-Encoding: br\r\n\r\n"),
+        );
+        assert_eq!(response.header("Content-Encoding"), Some("br"));
+        assert!(response.body().len() < 2048);
+    }
+
+    #[test]
+    fn leaves_small_encoded_and_partial_bodies_alone() {
+        let accepting = "GET / HTTP/1.1\r\nAccept-Encoding: gzip\r\n\r\n";
+
+        let mut response = text_response(100);
+        compress_response(&mut response, &request(accepting));
+        assert_eq!(response.header("Content-Encoding"), None);
+        assert_eq!(response.header("Vary"), Some("Accept-Encoding"));
+
+        let mut response = text_response(4096).with_header("Content-Encoding", "br");
+        compress_response(&mut response, &request(accepting));
+        assert_eq!(response.header("Content-Encoding"), Some("br"));
+
+        let mut response = text_response(4096).with_header("Content-Range", "bytes 0-4095/5000");
+        compress_response(&mut response, &request(accepting));
+        assert_eq!(response.header("Content-Encoding"), None);
+
+        let mut response = text_response(4096).with_header("Content-Type", "image/png");
+        compress_response(&mut response, &request(accepting));
+        assert_eq!(response.header("Vary"), None);
+
+        let mut response = text_response(4096);
+        response.set_allows_compression(false);
+        compress_response(&mut response, &request(accepting));
+        assert_eq!(response.header("Content-Encoding"), None);
+        assert_eq!(response.body().len(), 4096);
+    }
+
+    #[test]
+    fn does_not_compress_for_head_requests() {
+        let mut response = text_response(4096);
+        compress_response(
+            &mut response,
+            &request("HEAD / HTTP/1.1\r\nAccept-Encoding: gzip\r\n\r\n"),
+        );
+        assert_eq!(response.header("Content-Encoding"), None);
+        assert_eq!(response.body().len(), 4096);
+    }
+}
@@ -0,0 +1,111 @@
+use super::ParseError;
+use std::convert::TryFrom;
+
+// Host: localhost:8080\r\nAccept: */*\r\nCookie: a=1\r\nCookie: b=2\r\n\r\n
+#[derive(Clone, Debug, Default)]
+pub struct Headers<'buf> {
+    entries: Vec<(&'buf str, &'buf str)>,
+}
+
+impl<'buf> Headers<'buf> {
+    /// Returns the first value of the header, ignoring the case of `name`.
+    pub fn get(&self, name: &str) -> Option<&'buf str> {
+        self.get_all(name).next()
+    }
+
+    /// Returns every value of the header in the order they were received.
+    pub fn get_all<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'buf str> + 'a {
+        self.entries
+            .iter()
+            .filter(move |(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| *value)
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.get(name).is_some()
+    }
+
+    /// Whether any value of a comma-separated header such as `Connection` lists `token`.
+    pub fn contains_token(&self, name: &str, token: &str) -> bool {
+        self.get_all(name)
+            .flat_map(|value| value.split(','))
+            .any(|item| item.trim().eq_ignore_ascii_case(token))
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&'buf str, &'buf str)> + '_ {
+        self.entries.iter().copied()
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+// Parses header lines up to (and excluding) the first empty line.
+impl<'buf> TryFrom<&'buf str> for Headers<'buf> {
+    type Error = ParseError;
+
+    fn try_from(s: &'buf str) -> Result<Self, Self::Error> {
+        let mut entries = Vec::new();
+
+        for line in s.split("\r\n") {
+            if line.is_empty() {
+                break;
+            }
+
+            let (name, value) = line.split_once(':').ok_or(ParseError::InvalidHeader)?;
+            if name.is_empty() || name.contains(|c: char| c.is_ascii_whitespace()) {
+                return Err(ParseError::InvalidHeader);
+            }
+
+            entries.push((name, value.trim()));
+        }
+
+        Ok(Headers { entries })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lookups_ignore_case_and_keep_order() {
+        let headers =
+            Headers::try_from("Host: localhost\r\nCookie: a=1\r\ncookie:  b=2 \r\n\r\n").unwrap();
+
+        assert_eq!(headers.len(), 3);
+        assert_eq!(headers.get("HOST"), Some("localhost"));
+        assert_eq!(
+            headers.get_all("Cookie").collect::<Vec<_>>(),
+            ["a=1", "b=2"]
+        );
+        assert!(headers.contains("cookie"));
+        assert!(!headers.contains("Accept"));
+    }
+
+    #[test]
+    fn stops_at_the_empty_line() {
+        let headers = Headers::try_from("Accept: */*\r\n\r\nnot: a header").unwrap();
+        assert_eq!(headers.len(), 1);
+        assert!(Headers::try_from("").unwrap().is_empty());
+    }
+
+    #[test]
+    fn finds_tokens_in_lists() {
+        let headers = Headers::try_from("Connection: keep-alive, Upgrade\r\n").unwrap();
+        assert!(headers.contains_token("connection", "upgrade"));
+        assert!(!headers.contains_token("Connection", "close"));
+    }
+
+    #[test]
+    fn rejects_malformed_lines() {
+        assert!(Headers::try_from("no colon\r\n").is_err());
+        assert!(Headers::try_from(": empty name\r\n").is_err());
+        assert!(Headers::try_from("Bad Name: x\r\n").is_err());
+    }
+}
@@ -1,6 +1,8 @@
+use std::fmt::{Display, Formatter, Result as FmtResult};
 use std::str::FromStr;
 
-#[derive(Debug)]
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum Method {
     GET,
     DELETE,
@@ -10,7 +12,23 @@ pub enum Method {
     CONNECT,
     OPTIONS,
     TRACE,
-    PATCH,S
+    PATCH,
+}
+
+impl Method {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::GET => "GET",
+            Self::DELETE => "DELETE",
+            Self::POST => "POST",
+            Self::PUT => "PUT",
+            Self::HEAD => "HEAD",
+            Self::CONNECT => "CONNECT",
+            Self::OPTIONS => "OPTIONS",
+            Self::TRACE => "TRACE",
+            Self::PATCH => "PATCH",
+        }
+    }
 }
 
 impl FromStr for Method {
@@ -32,4 +50,10 @@ impl FromStr for Method {
     }
 }
 
+impl Display for Method {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        write!(f, "{}", self.as_str())
+    }
+}
+
 pub struct MethodError;
@@ -1,12 +1,34 @@
+pub use body::Body;
+pub use headers::Headers;
 pub use method::Method;
+pub use params::Params;
 pub use query_strings::{QueryString, Value as QueryStringValue};
+pub use reader::{ReadError, RequestReader};
 pub use request::ParseError;
-pub use request::Request;
+pub use request::{BodyReader, Request};
 pub use response::Response;
 pub use status_code::StatusCode;
+pub use upgrade::{OnUpgrade, Upgraded};
+pub use version::Version;
 
+pub mod base64;
+pub mod body;
+pub mod chunked;
+pub mod compression;
+pub mod date;
+pub mod headers;
+pub mod json;
 pub mod method;
+pub mod mime;
+pub mod multipart;
+pub mod params;
+pub mod percent_encoding;
 pub mod query_strings;
+pub mod range;
+pub mod reader;
 pub mod request;
 pub mod response;
+pub mod sha1;
 pub mod status_code;
+pub mod upgrade;
+pub mod version;
@@ -0,0 +1,714 @@
+use super::percent_encoding;
+use super::Request;
+
+use std::error::Error;
+use std::fmt::{Display, Formatter, Result as FmtResult};
+use std::io::{BufRead, BufReader, Error as IoError, ErrorKind, Read, Result as IoResult};
+
+pub const DEFAULT_MAX_PART_SIZE: u64 = 10 * 1024 * 1024;
+pub const DEFAULT_MAX_TOTAL_SIZE: u64 = 50 * 1024 * 1024;
+pub const DEFAULT_MAX_PARTS: usize = 100;
+pub const DEFAULT_MAX_HEADER_SIZE: usize = 8 * 1024;
+
+// RFC 2046, section 5.1.1
+const MAX_BOUNDARY_LEN: usize = 70;
+const MAX_FILENAME_LEN: usize = 255;
+// Only this much is read at a time, so that the buffer stays small.
+const READ_CHUNK_SIZE: usize = 8192;
+
+/// A `multipart/form-data` body (RFC 7578), parsed part by part as it is
+/// read, so that a file can be written out without holding it in memory.
+///
+/// ```ignore
+/// let mut multipart = Multipart::from_request(request)?;
+/// while let Some(mut part) = multipart.next_part()? {
+///     io::copy(&mut part, &mut file)?;
+/// }
+/// ```
+pub struct Multipart<R> {
+    reader: R,
+    // "\r\n--" followed by the boundary
+    delimiter: Vec<u8>,
+    buf: Vec<u8>,
+    state: State,
+    parts: usize,
+    total_size: u64,
+    max_part_size: u64,
+    max_total_size: u64,
+    max_parts: usize,
+    max_header_size: usize,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum State {
+    Preamble,
+    // the body of the current part, with the bytes read of it so far
+    Body(u64),
+    // right after a delimiter
+    Delimiter,
+    Done,
+}
+
+/// One part of the body. Reading it yields its contents; the next call to
+/// `next_part` skips whatever is left unread.
+pub struct Part<'a, R> {
+    multipart: &'a mut Multipart<R>,
+    headers: Vec<(String, String)>,
+    name: String,
+    filename: Option<String>,
+}
+
+#[derive(Debug)]
+pub enum MultipartError {
+    Io(IoError),
+    /// The request is not `multipart/form-data` or has no usable boundary.
+    NotMultipart,
+    Malformed(&'static str),
+    PartTooLarge,
+    TooLarge,
+    TooManyParts,
+}
+
+impl<'buf> Multipart<Box<dyn BufRead + 'buf>> {
+    /// Parses the body of `request`, with the boundary from its `Content-Type`.
+    /// The body is read from `Request::body_reader` if the handler streams it.
+    pub fn from_request(request: &Request<'buf>) -> Result<Self, MultipartError> {
+        let content_type = request
+            .headers()
+            .get("Content-Type")
+            .ok_or(MultipartError::NotMultipart)?;
+        let boundary = boundary(content_type).ok_or(MultipartError::NotMultipart)?;
+        let body: Box<dyn BufRead + 'buf> = match request.body_reader() {
+            Some(reader) => Box::new(BufReader::new(reader)),
+            None => Box::new(request.body()),
+        };
+        Ok(Self::new(body, &boundary))
+    }
+}
+
+impl<R: BufRead> Multipart<R> {
+    pub fn new(reader: R, boundary: &str) -> Self {
+        Self {
+            reader,
+            delimiter: format!("\r\n--{}", boundary).into_bytes(),
+            // the first delimiter has no line break before it
+            buf: b"\r\n".to_vec(),
+            state: State::Preamble,
+            parts: 0,
+            total_size: 0,
+            max_part_size: DEFAULT_MAX_PART_SIZE,
+            max_total_size: DEFAULT_MAX_TOTAL_SIZE,
+            max_parts: DEFAULT_MAX_PARTS,
+            max_header_size: DEFAULT_MAX_HEADER_SIZE,
+        }
+    }
+
+    /// Bytes one part may have, not counting its headers.
+    pub fn with_max_part_size(mut self, max_part_size: u64) -> Self {
+        self.max_part_size = max_part_size;
+        self
+    }
+
+    /// Bytes all parts together may have, not counting their headers but
+    /// counting any text before the first part.
+    pub fn with_max_total_size(mut self, max_total_size: u64) -> Self {
+        self.max_total_size = max_total_size;
+        self
+    }
+
+    pub fn with_max_parts(mut self, max_parts: usize) -> Self {
+        self.max_parts = max_parts;
+        self
+    }
+
+    /// Bytes the headers of one part may have.
+    pub fn with_max_header_size(mut self, max_header_size: usize) -> Self {
+        self.max_header_size = max_header_size;
+        self
+    }
+
+    /// The next part, or `None` after the closing delimiter.
+    pub fn next_part(&mut self) -> Result<Option<Part<'_, R>>, MultipartError> {
+        loop {
+            match self.state {
+                State::Done => return Ok(None),
+                State::Preamble => {
+                    // text before the first delimiter is ignored
+                    while self.read_body(&mut [0; 1024])? > 0 {}
+                }
+                State::Body(_) => {
+                    let mut skipped = [0; 8192];
+                    while self.read_body(&mut skipped)? > 0 {}
+                }
+                State::Delimiter => break,
+            }
+        }
+
+        // "--" after the delimiter closes the body; otherwise a line break
+        // (after optional whitespace) starts the headers of the next part
+        self.fill(2)?;
+        if self.buf.starts_with(b"--") {
+            self.state = State::Done;
+            return Ok(None);
+        }
+        let line_end = self.find(b"\r\n", MAX_BOUNDARY_LEN, "text after a boundary")?;
+        if !self.buf[..line_end]
+            .iter()
+            .all(|&b| b == b' ' || b == b'\t')
+        {
+            return Err(MultipartError::Malformed("text after a boundary"));
+        }
+        self.buf.drain(..line_end + 2);
+
+        self.parts += 1;
+        if self.parts > self.max_parts {
+            return Err(MultipartError::TooManyParts);
+        }
+        let headers = self.read_headers()?;
+        let (name, filename) = headers
+            .iter()
+            .find(|(name, _)| name.eq_ignore_ascii_case("Content-Disposition"))
+            .and_then(|(_, value)| parse_content_disposition(value))
+            .ok_or(MultipartError::Malformed("part without a form-data name"))?;
+
+        self.state = State::Body(0);
+        Ok(Some(Part {
+            multipart: self,
+            headers,
+            name,
+            filename,
+        }))
+    }
+
+    // Header lines up to the blank line, the way they are in a request.
+    fn read_headers(&mut self) -> Result<Vec<(String, String)>, MultipartError> {
+        self.fill(2)?;
+        if self.buf.starts_with(b"\r\n") {
+            self.buf.drain(..2);
+            return Ok(Vec::new());
+        }
+        let end = self.find(b"\r\n\r\n", self.max_header_size, "part headers too large")?;
+
+        let head = String::from_utf8_lossy(&self.buf[..end]).into_owned();
+        self.buf.drain(..end + 4);
+        head.split("\r\n")
+            .map(|line| {
+                let (name, value) = line
+                    .split_once(':')
+                    .ok_or(MultipartError::Malformed("header without a colon"))?;
+                Ok((name.trim().to_string(), value.trim().to_string()))
+            })
+            .collect()
+    }
+
+    // Reads the current part (or the preamble) up to the next delimiter.
+    fn read_body(&mut self, out: &mut [u8]) -> Result<usize, MultipartError> {
+        if !matches!(self.state, State::Preamble | State::Body(_)) || out.is_empty() {
+            return Ok(0);
+        }
+
+        // everything but a possible start of the delimiter can be given out
+        let mut available = loop {
+            if let Some(i) = find(&self.buf, &self.delimiter) {
+                break i;
+            }
+            let safe = self.buf.len().saturating_sub(self.delimiter.len() - 1);
+            if safe > 0 {
+                break safe;
+            }
+            if self.read_more()? == 0 {
+                return Err(MultipartError::Malformed(
+                    "body ends before the closing boundary",
+                ));
+            }
+        };
+        if available == 0 {
+            // at the delimiter: this part is done
+            self.buf.drain(..self.delimiter.len());
+            self.state = State::Delimiter;
+            return Ok(0);
+        }
+        available = available.min(out.len());
+
+        self.total_size += available as u64;
+        if let State::Body(read) = self.state {
+            let read = read + available as u64;
+            if read > self.max_part_size {
+                return Err(MultipartError::PartTooLarge);
+            }
+            self.state = State::Body(read);
+        }
+        if self.total_size > self.max_total_size {
+            return Err(MultipartError::TooLarge);
+        }
+        out[..available].copy_from_slice(&self.buf[..available]);
+        self.buf.drain(..available);
+        Ok(available)
+    }
+
+    // Where `needle` starts in the buffer, reading until it is there, or
+    // failing with `message` if it does not start within `limit` bytes.
+    fn find(
+        &mut self,
+        needle: &[u8],
+        limit: usize,
+        message: &'static str,
+    ) -> Result<usize, MultipartError> {
+        loop {
+            if let Some(i) = find(&self.buf, needle) {
+                if i > limit {
+                    return Err(MultipartError::Malformed(message));
+                }
+                return Ok(i);
+            }
+            if self.buf.len() > limit + needle.len() {
+                return Err(MultipartError::Malformed(message));
+            }
+            if self.read_more()? == 0 {
+                return Err(MultipartError::Malformed(
+                    "body ends before the closing boundary",
+                ));
+            }
+        }
+    }
+
+    fn fill(&mut self, len: usize) -> Result<(), MultipartError> {
+        while self.buf.len() < len {
+            if self.read_more()? == 0 {
+                return Err(MultipartError::Malformed(
+                    "body ends before the closing boundary",
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    fn read_more(&mut self) -> IoResult<usize> {
+        let available = self.reader.fill_buf()?;
+        let len = available.len().min(READ_CHUNK_SIZE);
+        let available = &available[..len];
+        self.buf.extend_from_slice(available);
+        self.reader.consume(len);
+        Ok(len)
+    }
+}
+
+impl<R> Part<'_, R> {
+    /// The form field this part is for.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// The file name the client gave, as sent; see `sanitize_filename`.
+    pub fn filename(&self) -> Option<&str> {
+        self.filename.as_deref()
+    }
+
+    pub fn content_type(&self) -> Option<&str> {
+        self.header("Content-Type")
+    }
+
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(header, _)| header.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    pub fn headers(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.headers
+            .iter()
+            .map(|(name, value)| (name.as_str(), value.as_str()))
+    }
+}
+
+impl<R: BufRead> Part<'_, R> {
+    /// Reads the rest of the part as text, for fields that are not files.
+    pub fn text(&mut self) -> Result<String, MultipartError> {
+        let mut bytes = Vec::new();
+        self.read_to_end(&mut bytes)?;
+        Ok(String::from_utf8_lossy(&bytes).into_owned())
+    }
+}
+
+impl<R: BufRead> Read for Part<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> IoResult<usize> {
+        self.multipart.read_body(buf).map_err(|e| match e {
+            MultipartError::Io(e) => e,
+            e => IoError::new(ErrorKind::InvalidData, e),
+        })
+    }
+}
+
+impl From<IoError> for MultipartError {
+    // limits hit while reading a `Part` come back wrapped in an `io::Error`
+    fn from(e: IoError) -> Self {
+        if !e
+            .get_ref()
+            .is_some_and(|inner| inner.is::<MultipartError>())
+        {
+            return Self::Io(e);
+        }
+        match e
+            .into_inner()
+            .map(|inner| inner.downcast::<MultipartError>())
+        {
+            Some(Ok(e)) => *e,
+            _ => unreachable!("checked above"),
+        }
+    }
+}
+
+impl Display for MultipartError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        match self {
+            Self::Io(e) => write!(f, "{}", e),
+            Self::NotMultipart => write!(f, "not a multipart/form-data body"),
+            Self::Malformed(message) => write!(f, "malformed multipart body: {}", message),
+            Self::PartTooLarge => write!(f, "part too large"),
+            Self::TooLarge => write!(f, "multipart body too large"),
+            Self::TooManyParts => write!(f, "too many parts"),
+        }
+    }
+}
+
+impl Error for MultipartError {}
+
+// multipart/form-data; boundary="----abc"
+fn boundary(content_type: &str) -> Option<String> {
+    let (mime_type, params) = content_type.split_once(';')?;
+    if !mime_type.trim().eq_ignore_ascii_case("multipart/form-data") {
+        return None;
+    }
+
+    let boundary = parse_params(params)
+        .into_iter()
+        .find(|(name, _)| name.eq_ignore_ascii_case("boundary"))
+        .map(|(_, value)| value)?;
+    let valid = !boundary.is_empty()
+        && boundary.len() <= MAX_BOUNDARY_LEN
+        && !boundary.ends_with(' ')
+        && boundary
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b"'()+_,-./:=? ".contains(&b));
+    valid.then_some(boundary)
+}
+
+// form-data; name="avatar"; filename="me.png", where filename* (RFC 5987)
+// takes precedence over filename.
+fn parse_content_disposition(value: &str) -> Option<(String, Option<String>)> {
+    let (disposition, params) = value.split_once(';')?;
+    if !disposition.trim().eq_ignore_ascii_case("form-data") {
+        return None;
+    }
+
+    let params = parse_params(params);
+    let param = |name: &str| {
+        params
+            .iter()
+            .find(|(param, _)| param.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.clone())
+    };
+    let extended_filename = param("filename*").and_then(|value| {
+        let (charset, rest) = value.split_once('\'')?;
+        let (_language, encoded) = rest.split_once('\'')?;
+        charset
+            .eq_ignore_ascii_case("UTF-8")
+            .then(|| percent_encoding::decode(encoded, false).into_owned())
+    });
+
+    Some((
+        param("name")?,
+        extended_filename.or_else(|| param("filename")),
+    ))
+}
+
+// ; a=b; c="d; \"e\"" -> [("a", "b"), ("c", "d; \"e\"")]
+fn parse_params(params: &str) -> Vec<(String, String)> {
+    let mut parsed = Vec::new();
+    let mut chars = params.chars().peekable();
+
+    loop {
+        while chars.next_if(|&c| c == ';' || c.is_whitespace()).is_some() {}
+        if chars.peek().is_none() {
+            return parsed;
+        }
+        let mut name = String::new();
+        while let Some(c) = chars.next_if(|&c| c != '=' && c != ';') {
+            name.push(c);
+        }
+        if chars.next_if_eq(&'=').is_none() {
+            // a parameter without a value
+            continue;
+        }
+
+        let mut value = String::new();
+        while chars.next_if(|&c| c.is_whitespace()).is_some() {}
+        if chars.next_if_eq(&'"').is_some() {
+            while let Some(c) = chars.next() {
+                match c {
+                    '"' => break,
+                    // browsers send backslashes in Windows paths unescaped,
+                    // so only quotes and backslashes are unescaped
+                    '\\' => match chars.next_if(|&c| c == '"' || c == '\\') {
+                        Some(escaped) => value.push(escaped),
+                        None => value.push('\\'),
+                    },
+                    c => value.push(c),
+                }
+            }
+            while chars.next_if(|&c| c != ';').is_some() {}
+        } else {
+            value = chars.by_ref().take_while(|&c| c != ';').collect();
+        }
+        parsed.push((name.trim().to_string(), value.trim().to_string()));
+    }
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack
+        .windows(needle.len())
+        .position(|window| window == needle)
+}
+
+/// Makes a file name sent by a client safe to create in a directory: only
+/// the last path component is kept, characters that are special in paths
+/// or on common file systems are replaced, and leading dots (hidden files,
+/// `..`) are dropped. `None` if nothing usable is left.
+pub fn sanitize_filename(filename: &str) -> Option<String> {
+    let filename = filename.rsplit(['/', '\\']).next().unwrap_or("");
+
+    let mut sanitized: String = filename
+        .chars()
+        .map(|c| match c {
+            '<' | '>' | ':' | '"' | '|' | '?' | '*' => '_',
+            c if c.is_control() => '_',
+            c => c,
+        })
+        .collect();
+    sanitized = sanitized
+        .trim_start_matches(|c: char| c == '.' || c.is_whitespace())
+        .trim_end_matches(|c: char| c == '.' || c.is_whitespace())
+        .to_string();
+
+    if sanitized.len() > MAX_FILENAME_LEN {
+        // shorten the name, keeping a short extension
+        let extension_start = sanitized
+            .rfind('.')
+            .filter(|&dot| sanitized.len() - dot <= 16)
+            .unwrap_or(sanitized.len());
+        let extension = sanitized.split_off(extension_start);
+        let mut end = MAX_FILENAME_LEN - extension.len();
+        while !sanitized.is_char_boundary(end) {
+            end -= 1;
+        }
+        sanitized.truncate(end);
+        sanitized.push_str(&extension);
+    }
+
+    (!sanitized.is_empty()).then_some(sanitized)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BODY: &[u8] = b"preamble\r\n--XyZ\r\n\
+        Content-Disposition: form-data; name=\"title\"\r\n\r\n\
+        Holiday\r\n--XyZ \r\n\
+        Content-Disposition: form-data; name=\"photo\"; filename=\"beach.jpg\"\r\n\
+        Content-Type: image/jpeg\r\n\r\n\
+        \xff\xd8--XyZ-not-a-delimiter\r\n\r\n--XyZ--\r\nepilogue";
+
+    // Hands out `data` at most `step` bytes at a time.
+    fn multipart(data: &[u8], step: usize) -> Multipart<BufReader<&[u8]>> {
+        Multipart::new(BufReader::with_capacity(step, data), "XyZ")
+    }
+
+    // name, filename, contents
+    type Parsed = (String, Option<String>, Vec<u8>);
+
+    fn parts<R: BufRead>(mut multipart: Multipart<R>) -> Result<Vec<Parsed>, MultipartError> {
+        let mut parts = Vec::new();
+        while let Some(mut part) = multipart.next_part()? {
+            let mut contents = Vec::new();
+            part.read_to_end(&mut contents)?;
+            parts.push((
+                part.name().to_string(),
+                part.filename().map(str::to_string),
+                contents,
+            ));
+        }
+        Ok(parts)
+    }
+
+    #[test]
+    fn parses_parts_split_across_reads() {
+        for step in [1, 2, 5, 13, 8192] {
+            let parts = parts(multipart(BODY, step)).unwrap();
+            assert_eq!(
+                parts,
+                [
+                    ("title".to_string(), None, b"Holiday".to_vec()),
+                    (
+                        "photo".to_string(),
+                        Some("beach.jpg".to_string()),
+                        b"\xff\xd8--XyZ-not-a-delimiter\r\n".to_vec()
+                    ),
+                ],
+                "step {}",
+                step
+            );
+        }
+    }
+
+    #[test]
+    fn skips_what_is_left_unread() {
+        let mut multipart = multipart(BODY, 3);
+        let mut part = multipart.next_part().unwrap().unwrap();
+        assert!(part.read(&mut [0; 2]).unwrap() > 0);
+
+        let part = multipart.next_part().unwrap().unwrap();
+        assert_eq!(part.name(), "photo");
+        assert_eq!(part.content_type(), Some("image/jpeg"));
+        assert!(multipart.next_part().unwrap().is_none());
+    }
+
+    #[test]
+    fn reads_boundaries_and_file_names() {
+        assert_eq!(
+            boundary("multipart/form-data; boundary=\"a b'c\"").as_deref(),
+            Some("a b'c")
+        );
+        assert_eq!(
+            boundary("Multipart/Form-Data;charset=utf-8;BOUNDARY=x").as_deref(),
+            Some("x")
+        );
+        assert_eq!(boundary("multipart/mixed; boundary=x"), None);
+        assert_eq!(boundary("multipart/form-data; boundary=\"\""), None);
+        assert_eq!(
+            boundary(&format!("multipart/form-data; boundary={}", "x".repeat(71))),
+            None
+        );
+
+        let disposition = |value| parse_content_disposition(value).unwrap();
+        assert_eq!(
+            disposition("form-data; name=\"doc\"; filename=\"C:\\Users\\me\\a \\\"b\\\".txt\""),
+            (
+                "doc".to_string(),
+                Some("C:\\Users\\me\\a \"b\".txt".to_string())
+            )
+        );
+        // filename* wins, and is percent-decoded UTF-8
+        assert_eq!(
+            disposition(
+                "form-data; name=doc; filename=\"naive.txt\"; filename*=UTF-8''na%C3%AFve.txt"
+            ),
+            ("doc".to_string(), Some("naïve.txt".to_string()))
+        );
+        assert_eq!(
+            disposition("form-data; name=doc; filename=\"a.txt\"; filename*=ISO-8859-1''b.txt"),
+            ("doc".to_string(), Some("a.txt".to_string()))
+        );
+        assert_eq!(parse_content_disposition("attachment; name=doc"), None);
+        assert_eq!(parse_content_disposition("form-data; filename=a.txt"), None);
+    }
+
+    #[test]
+    fn sanitizes_file_names() {
+        assert_eq!(
+            sanitize_filename("../../etc/passwd").as_deref(),
+            Some("passwd")
+        );
+        assert_eq!(
+            sanitize_filename("..\\..\\boot.ini").as_deref(),
+            Some("boot.ini")
+        );
+        assert_eq!(
+            sanitize_filename("C:\\Users\\me\\cv.pdf").as_deref(),
+            Some("cv.pdf")
+        );
+        assert_eq!(sanitize_filename("...hidden").as_deref(), Some("hidden"));
+        assert_eq!(sanitize_filename(" . name. ").as_deref(), Some("name"));
+        assert_eq!(
+            sanitize_filename("a<b>:c|d?.txt").as_deref(),
+            Some("a_b__c_d_.txt")
+        );
+        assert_eq!(sanitize_filename("tab\there").as_deref(), Some("tab_here"));
+        assert_eq!(sanitize_filename(".."), None);
+        assert_eq!(sanitize_filename("dir/"), None);
+        assert_eq!(sanitize_filename(""), None);
+    }
+
+    #[test]
+    fn shortens_long_file_names_on_a_char_boundary() {
+        // 2 bytes per char, so the cut at 251 falls inside one
+        let name = format!("{}.txt", "é".repeat(200));
+        let sanitized = sanitize_filename(&name).unwrap();
+        assert_eq!(sanitized.len(), 254);
+        assert!(sanitized.ends_with("é.txt"));
+
+        // too long to be an extension
+        let name = format!("{}.{}", "a".repeat(250), "b".repeat(20));
+        let sanitized = sanitize_filename(&name).unwrap();
+        assert_eq!(sanitized.len(), MAX_FILENAME_LEN);
+        assert!(sanitized.starts_with("aaa"));
+    }
+
+    fn body_with(parts: &[(&str, &[u8])]) -> Vec<u8> {
+        let mut body = Vec::new();
+        for (name, contents) in parts {
+            body.extend_from_slice(
+                format!(
+                    "--XyZ\r\nContent-Disposition: form-data; name=\"{}\"\r\n\r\n",
+                    name
+                )
+                .as_bytes(),
+            );
+            body.extend_from_slice(contents);
+            body.extend_from_slice(b"\r\n");
+        }
+        body.extend_from_slice(b"--XyZ--\r\n");
+        body
+    }
+
+    #[test]
+    fn enforces_limits() {
+        let body = body_with(&[("a", &[b'x'; 10]), ("b", &[b'y'; 10])]);
+
+        let result = parts(multipart(&body, 4).with_max_part_size(9));
+        assert!(matches!(result, Err(MultipartError::PartTooLarge)));
+        assert!(parts(multipart(&body, 4).with_max_part_size(10)).is_ok());
+
+        let result = parts(multipart(&body, 4).with_max_total_size(19));
+        assert!(matches!(result, Err(MultipartError::TooLarge)));
+        assert!(parts(multipart(&body, 4).with_max_total_size(20)).is_ok());
+
+        let result = parts(multipart(&body, 4).with_max_parts(1));
+        assert!(matches!(result, Err(MultipartError::TooManyParts)));
+
+        let result = parts(multipart(&body, 4).with_max_header_size(20));
+        assert!(matches!(result, Err(MultipartError::Malformed(_))));
+
+        // text before the first part counts too
+        let mut padded = vec![b'p'; 100];
+        padded.extend_from_slice(b"\r\n");
+        padded.extend_from_slice(&body);
+        let result = parts(multipart(&padded, 4).with_max_total_size(50));
+        assert!(matches!(result, Err(MultipartError::TooLarge)));
+    }
+
+    #[test]
+    fn rejects_truncated_bodies() {
+        let body = &BODY[..BODY.len() - 20];
+        assert!(matches!(
+            parts(multipart(body, 7)),
+            Err(MultipartError::Malformed(_))
+        ));
+        let body = b"--XyZ\r\nContent-Type: text/plain\r\n\r\nx\r\n--XyZ--";
+        assert!(matches!(
+            parts(multipart(body, 7)),
+            Err(MultipartError::Malformed(_))
+        ));
+    }
+}
@@ -0,0 +1,28 @@
+// /users/:id/files/*rest matched against /users/42/files/a/b.txt
+#[derive(Clone, Debug, Default)]
+pub struct Params<'buf> {
+    entries: Vec<(&'buf str, &'buf str)>,
+}
+
+impl<'buf> Params<'buf> {
+    pub fn get(&self, name: &str) -> Option<&'buf str> {
+        self.entries
+            .iter()
+            .find(|(key, _)| *key == name)
+            .map(|(_, value)| *value)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&'buf str, &'buf str)> + '_ {
+        self.entries.iter().copied()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+impl<'buf> From<Vec<(&'buf str, &'buf str)>> for Params<'buf> {
+    fn from(entries: Vec<(&'buf str, &'buf str)>) -> Self {
+        Params { entries }
+    }
+}
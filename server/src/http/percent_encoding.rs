@@ -0,0 +1,95 @@
+use std::borrow::Cow;
+
+/// Decodes `%XX` escapes (and `+` as space if `plus_as_space`), borrowing
+/// `s` when there is nothing to decode. Malformed escapes are kept as they
+/// are and invalid UTF-8 is replaced, so decoding never fails.
+pub fn decode(s: &str, plus_as_space: bool) -> Cow<'_, str> {
+    if !s.bytes().any(|b| b == b'%' || (plus_as_space && b == b'+')) {
+        return Cow::Borrowed(s);
+    }
+
+    let bytes = s.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+
+    while i < bytes.len() {
+        if bytes[i] == b'%' && i + 2 < bytes.len() {
+            if let (Some(high), Some(low)) = (hex_value(bytes[i + 1]), hex_value(bytes[i + 2])) {
+                decoded.push(high << 4 | low);
+                i += 3;
+                continue;
+            }
+        }
+        if bytes[i] == b'+' && plus_as_space {
+            decoded.push(b' ');
+        } else {
+            decoded.push(bytes[i]);
+        }
+        i += 1;
+    }
+
+    match String::from_utf8(decoded) {
+        Ok(decoded) => Cow::Owned(decoded),
+        Err(e) => Cow::Owned(String::from_utf8_lossy(e.as_bytes()).into_owned()),
+    }
+}
+
+/// Escapes everything but unreserved characters, so that `s` can be used as
+/// a single path segment or query value.
+pub fn encode(s: &str) -> Cow<'_, str> {
+    if s.bytes().all(is_unreserved) {
+        return Cow::Borrowed(s);
+    }
+
+    let mut encoded = String::with_capacity(s.len() * 3);
+    for b in s.bytes() {
+        if is_unreserved(b) {
+            encoded.push(b as char);
+        } else {
+            encoded.push_str(&format!("%{:02X}", b));
+        }
+    }
+    Cow::Owned(encoded)
+}
+
+fn is_unreserved(b: u8) -> bool {
+    b.is_ascii_alphanumeric() || matches!(b, b'-' | b'.' | b'_' | b'~')
+}
+
+fn hex_value(b: u8) -> Option<u8> {
+    (b as char).to_digit(16).map(|digit| digit as u8)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decodes_escapes() {
+        assert_eq!(decode("J%C3%BCrgen", false), "Jürgen");
+        assert_eq!(decode("a+b%20c", false), "a+b c");
+        assert_eq!(decode("a+b%20c", true), "a b c");
+        assert_eq!(decode("%2f%2F", false), "//");
+    }
+
+    #[test]
+    fn borrows_when_there_is_nothing_to_decode() {
+        assert!(matches!(decode("/plain/path", false), Cow::Borrowed(_)));
+        assert!(matches!(decode("a+b", false), Cow::Borrowed(_)));
+    }
+
+    #[test]
+    fn keeps_malformed_escapes_and_replaces_invalid_utf8() {
+        assert_eq!(decode("100%", false), "100%");
+        assert_eq!(decode("%zz%4", false), "%zz%4");
+        assert_eq!(decode("%41%", false), "A%");
+        assert_eq!(decode("%ff", false), "\u{fffd}");
+    }
+
+    #[test]
+    fn encodes_all_but_unreserved_characters() {
+        assert_eq!(encode("a-b_c.d~e"), "a-b_c.d~e");
+        assert_eq!(encode("a b/ü?"), "a%20b%2F%C3%BC%3F");
+        assert_eq!(decode(&encode("x y&z=ü"), false), "x y&z=ü");
+    }
+}
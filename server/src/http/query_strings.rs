@@ -1,45 +1,114 @@
+use super::percent_encoding;
+use std::borrow::Cow;
 use std::collections::HashMap;
+use std::str::FromStr;
 
-// a=1&b=2&c&d=&e===&d=7&d=abc
-#[derive(Debug)]
+// a=1&b=2&c&d=&e===&d=7&d=abc&name=J%C3%BCrgen+M
+#[derive(Clone, Debug)]
 pub struct QueryString<'buf> {
-    data: HashMap<&'buf str, Value<'buf>>,
+    data: HashMap<Cow<'buf, str>, Value<'buf>>,
 }
 
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub enum Value<'buf> {
-    Single(&'buf str),
-    Multiple(Vec<&'buf str>),
+    Single(Cow<'buf, str>),
+    Multiple(Vec<Cow<'buf, str>>),
 }
 
 impl<'buf> QueryString<'buf> {
-    pub fn get(&self, key: &str) -> Option<&Value> {
+    pub fn get(&self, key: &str) -> Option<&Value<'_>> {
         self.data.get(key)
     }
+
+    /// Returns every value of `key` in the order they appeared.
+    pub fn get_all(&self, key: &str) -> Vec<&str> {
+        match self.data.get(key) {
+            Some(Value::Single(val)) => vec![val],
+            Some(Value::Multiple(vec)) => vec.iter().map(|val| val.as_ref()).collect(),
+            None => Vec::new(),
+        }
+    }
+
+    pub fn get_first(&self, key: &str) -> Option<&str> {
+        match self.data.get(key)? {
+            Value::Single(val) => Some(val),
+            Value::Multiple(vec) => vec.first().map(|val| val.as_ref()),
+        }
+    }
+
+    /// Parses the first value of `key`, e.g. `query.get_parsed::<u32>("page")`.
+    pub fn get_parsed<T: FromStr>(&self, key: &str) -> Option<Result<T, T::Err>> {
+        self.get_first(key).map(str::parse)
+    }
+
+    pub fn contains_key(&self, key: &str) -> bool {
+        self.data.contains_key(key)
+    }
+
+    pub fn keys(&self) -> impl Iterator<Item = &str> {
+        self.data.keys().map(|key| key.as_ref())
+    }
 }
 
-// a=1&b=2&c&d=&e===&d=7&d=abc
+// Keys and values are percent-decoded with `+` as space, which covers both
+// query strings and application/x-www-form-urlencoded bodies.
 impl<'buf> From<&'buf str> for QueryString<'buf> {
     fn from(s: &'buf str) -> Self {
         let mut data = HashMap::new();
 
         for sub_str in s.split('&') {
+            if sub_str.is_empty() {
+                continue;
+            }
+
             let mut key = sub_str;
             let mut val = "";
             if let Some(i) = sub_str.find('=') {
                 key = &sub_str[..i];
                 val = &sub_str[i + 1..];
             }
+            let val = percent_encoding::decode(val, true);
 
-            data.entry(key)
+            data.entry(percent_encoding::decode(key, true))
                 .and_modify(|existing: &mut Value| match existing {
                     Value::Single(prev_val) => {
-                        *existing = Value::Multiple(vec![prev_val, val]);
+                        let prev_val = std::mem::take(prev_val);
+                        *existing = Value::Multiple(vec![prev_val, val.clone()]);
                     }
-                    Value::Multiple(vec) => vec.push(val),
+                    Value::Multiple(vec) => vec.push(val.clone()),
                 })
                 .or_insert(Value::Single(val));
         }
         QueryString { data }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_single_multiple_and_empty_values() {
+        let query = QueryString::from("a=1&b=2&c&d=&e===&d=7&d=abc&name=J%C3%BCrgen+M");
+
+        assert_eq!(query.get_first("a"), Some("1"));
+        assert_eq!(query.get_first("c"), Some(""));
+        assert_eq!(query.get_first("e"), Some("=="));
+        assert_eq!(query.get_all("d"), ["", "7", "abc"]);
+        assert_eq!(query.get_first("d"), Some(""));
+        assert_eq!(query.get_first("name"), Some("Jürgen M"));
+        assert!(query.get_all("missing").is_empty());
+        assert!(matches!(query.get("b"), Some(Value::Single(_))));
+    }
+
+    #[test]
+    fn decodes_keys_and_parses_values() {
+        let query = QueryString::from("page%5B%5D=2&&size=x");
+
+        assert!(query.contains_key("page[]"));
+        assert_eq!(query.get_parsed::<u32>("page[]").unwrap().ok(), Some(2));
+        assert!(query.get_parsed::<u32>("size").unwrap().is_err());
+        assert!(query.get_parsed::<u32>("missing").is_none());
+        assert_eq!(query.keys().count(), 2);
+    }
+}
@@ -0,0 +1,268 @@
+use std::collections::VecDeque;
+use std::io::{Read, Result as IoResult, Seek, SeekFrom};
+
+/// More ranges than this in one request are treated as abuse and ignored.
+const MAX_RANGES: usize = 16;
+
+/// An inclusive byte range that lies within the representation.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ByteRange {
+    pub start: u64,
+    pub end: u64,
+}
+
+impl ByteRange {
+    pub fn len(&self) -> u64 {
+        self.end - self.start + 1
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.start > self.end
+    }
+
+    /// The `Content-Range` value for this range of a `total` byte long representation.
+    pub fn content_range(&self, total: u64) -> String {
+        format!("bytes {}-{}/{}", self.start, self.end, total)
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum RangeError {
+    /// The header is malformed or not in bytes; it should be ignored.
+    Invalid,
+    /// None of the ranges overlap the representation; answer 416.
+    Unsatisfiable,
+}
+
+// bytes=0-499, 500-999, -500, 9500-
+pub fn parse_range(header: &str, len: u64) -> Result<Vec<ByteRange>, RangeError> {
+    let (unit, specs) = header.split_once('=').ok_or(RangeError::Invalid)?;
+    if !unit.trim().eq_ignore_ascii_case("bytes") {
+        return Err(RangeError::Invalid);
+    }
+
+    let mut ranges = Vec::new();
+    for spec in specs.split(',') {
+        let spec = spec.trim();
+        if spec.is_empty() {
+            continue;
+        }
+        let (first, last) = spec.split_once('-').ok_or(RangeError::Invalid)?;
+        let (first, last) = (first.trim(), last.trim());
+
+        let range = if first.is_empty() {
+            // suffix range: the last `last` bytes
+            let suffix: u64 = last.parse().map_err(|_| RangeError::Invalid)?;
+            if suffix == 0 || len == 0 {
+                continue;
+            }
+            ByteRange {
+                start: len.saturating_sub(suffix),
+                end: len - 1,
+            }
+        } else {
+            let start: u64 = first.parse().map_err(|_| RangeError::Invalid)?;
+            let end = match last {
+                "" => u64::MAX,
+                last => last.parse().map_err(|_| RangeError::Invalid)?,
+            };
+            if end < start {
+                return Err(RangeError::Invalid);
+            }
+            if start >= len {
+                continue;
+            }
+            ByteRange {
+                start,
+                end: end.min(len - 1),
+            }
+        };
+        ranges.push(range);
+    }
+
+    if ranges.len() > MAX_RANGES {
+        return Err(RangeError::Invalid);
+    }
+    if ranges.is_empty() {
+        return Err(RangeError::Unsatisfiable);
+    }
+    Ok(ranges)
+}
+
+/// Streams a `multipart/byteranges` body, seeking `reader` to each range in turn.
+pub struct MultipartRanges<R> {
+    reader: R,
+    parts: VecDeque<Part>,
+    len: u64,
+}
+
+enum Part {
+    Bytes(Vec<u8>, usize),
+    Range(ByteRange),
+}
+
+impl<R: Read + Seek> MultipartRanges<R> {
+    pub fn new(
+        reader: R,
+        ranges: &[ByteRange],
+        total: u64,
+        content_type: &str,
+        boundary: &str,
+    ) -> Self {
+        let mut parts = VecDeque::new();
+
+        for (i, range) in ranges.iter().enumerate() {
+            let separator = if i == 0 { "" } else { "\r\n" };
+            let header = format!(
+                "{}--{}\r\nContent-Type: {}\r\nContent-Range: {}\r\n\r\n",
+                separator,
+                boundary,
+                content_type,
+                range.content_range(total)
+            );
+            parts.push_back(Part::Bytes(header.into_bytes(), 0));
+            parts.push_back(Part::Range(*range));
+        }
+        parts.push_back(Part::Bytes(
+            format!("\r\n--{}--\r\n", boundary).into_bytes(),
+            0,
+        ));
+
+        let len = parts
+            .iter()
+            .map(|part| match part {
+                Part::Bytes(bytes, _) => bytes.len() as u64,
+                Part::Range(range) => range.len(),
+            })
+            .sum();
+
+        Self { reader, parts, len }
+    }
+
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+}
+
+impl<R: Read + Seek> Read for MultipartRanges<R> {
+    fn read(&mut self, buf: &mut [u8]) -> IoResult<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+
+        loop {
+            let part = match self.parts.front_mut() {
+                Some(part) => part,
+                None => return Ok(0),
+            };
+
+            let read = match part {
+                Part::Bytes(bytes, pos) => {
+                    let read = (bytes.len() - *pos).min(buf.len());
+                    buf[..read].copy_from_slice(&bytes[*pos..*pos + read]);
+                    *pos += read;
+                    read
+                }
+                Part::Range(range) => {
+                    self.reader.seek(SeekFrom::Start(range.start))?;
+                    let wanted = range.len().min(buf.len() as u64) as usize;
+                    let read = self.reader.read(&mut buf[..wanted])?;
+                    if read == 0 {
+                        // the file shrank since the ranges were computed
+                        self.parts.clear();
+                        return Ok(0);
+                    }
+                    range.start += read as u64;
+                    read
+                }
+            };
+
+            let finished = match part {
+                Part::Bytes(bytes, pos) => *pos == bytes.len(),
+                Part::Range(range) => range.start > range.end,
+            };
+            if finished {
+                self.parts.pop_front();
+            }
+            if read > 0 {
+                return Ok(read);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    fn range(start: u64, end: u64) -> ByteRange {
+        ByteRange { start, end }
+    }
+
+    #[test]
+    fn parses_ranges_suffixes_and_open_ends() {
+        assert_eq!(parse_range("bytes=0-499", 1000), Ok(vec![range(0, 499)]));
+        assert_eq!(parse_range("bytes=-500", 1000), Ok(vec![range(500, 999)]));
+        assert_eq!(
+            parse_range("bytes=9500-", 10000),
+            Ok(vec![range(9500, 9999)])
+        );
+        assert_eq!(
+            parse_range("Bytes = 0-0, -1 ,", 10),
+            Ok(vec![range(0, 0), range(9, 9)])
+        );
+    }
+
+    #[test]
+    fn clamps_to_the_representation() {
+        assert_eq!(parse_range("bytes=5-100", 10), Ok(vec![range(5, 9)]));
+        assert_eq!(parse_range("bytes=-100", 10), Ok(vec![range(0, 9)]));
+    }
+
+    #[test]
+    fn tells_invalid_from_unsatisfiable() {
+        assert_eq!(parse_range("items=0-1", 10), Err(RangeError::Invalid));
+        assert_eq!(parse_range("bytes=5-1", 10), Err(RangeError::Invalid));
+        assert_eq!(parse_range("bytes=a-b", 10), Err(RangeError::Invalid));
+        assert_eq!(parse_range("bytes", 10), Err(RangeError::Invalid));
+
+        assert_eq!(parse_range("bytes=10-", 10), Err(RangeError::Unsatisfiable));
+        assert_eq!(parse_range("bytes=-0", 10), Err(RangeError::Unsatisfiable));
+        assert_eq!(parse_range("bytes=0-", 0), Err(RangeError::Unsatisfiable));
+    }
+
+    #[test]
+    fn ignores_too_many_ranges() {
+        let header = format!("bytes={}", vec!["0-0"; MAX_RANGES + 1].join(","));
+        assert_eq!(parse_range(&header, 10), Err(RangeError::Invalid));
+    }
+
+    #[test]
+    fn streams_multipart_byteranges() {
+        let ranges = [range(0, 1), range(5, 7)];
+        let mut body =
+            MultipartRanges::new(Cursor::new(b"0123456789"), &ranges, 10, "text/plain", "B");
+        let len = body.len();
+
+        let mut out = Vec::new();
+        let mut buf = [0; 3];
+        loop {
+            let read = body.read(&mut buf).unwrap();
+            if read == 0 {
+                break;
+            }
+            out.extend_from_slice(&buf[..read]);
+        }
+
+        let expected = "--B\r\nContent-Type: text/plain\r\nContent-Range: bytes 0-1/10\r\n\r\n01\
+            \r\n--B\r\nContent-Type: text/plain\r\nContent-Range: bytes 5-7/10\r\n\r\n567\
+            \r\n--B--\r\n";
+        assert_eq!(String::from_utf8(out).unwrap(), expected);
+        assert_eq!(len, expected.len() as u64);
+    }
+}
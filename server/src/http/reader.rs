@@ -0,0 +1,719 @@
+use super::chunked::{parse_chunk_size, ChunkedReader};
+use super::{Headers, ParseError};
+use std::fmt::{Display, Formatter, Result as FmtResult};
+use std::io::{BufRead, Error as IoError, ErrorKind, Read, Result as IoResult, Take, Write};
+use std::str;
+
+const READ_CHUNK_SIZE: usize = 8192;
+
+pub const DEFAULT_MAX_HEAD_SIZE: usize = 8 * 1024;
+pub const DEFAULT_MAX_BODY_SIZE: usize = 1024 * 1024;
+
+/// Reads requests from a stream: first the head, then the body it announces.
+pub struct RequestReader<S> {
+    stream: S,
+    parser: RequestParser,
+}
+
+impl<S: Read + Write> RequestReader<S> {
+    pub fn new(stream: S, max_head_size: usize, max_body_size: usize) -> Self {
+        Self {
+            stream,
+            parser: RequestParser::new(max_head_size, max_body_size),
+        }
+    }
+
+    /// Continues reading where the reader that `parser` came from left off.
+    pub fn with_parser(stream: S, parser: RequestParser) -> Self {
+        Self { stream, parser }
+    }
+
+    pub fn into_parser(self) -> RequestParser {
+        self.parser
+    }
+
+    pub fn stream(&mut self) -> &mut S {
+        &mut self.stream
+    }
+
+    /// The stream back, with whatever was read past the last request.
+    pub fn into_parts(self) -> (S, Vec<u8>) {
+        (self.stream, self.parser.into_buffered())
+    }
+
+    /// Reads up to and including the empty line that ends the request head.
+    /// Returns `None` if the peer closed the connection without sending anything.
+    pub fn read_head(&mut self) -> Result<Option<Vec<u8>>, ReadError> {
+        loop {
+            if let Some(head) = self.parser.parse_head()? {
+                return Ok(Some(head));
+            }
+            if self.fill()? == 0 {
+                return self.parser.closed_before_head();
+            }
+        }
+    }
+
+    /// Reads the body announced by `headers`, de-chunking it if necessary.
+    pub fn read_body(&mut self, headers: &Headers) -> Result<Vec<u8>, ReadError> {
+        // Expect: 100-continue clients wait for this before sending the body
+        if self.parser.start_body(headers)? {
+            self.stream.write_all(CONTINUE)?;
+            self.stream.flush()?;
+        }
+
+        loop {
+            if let Some(body) = self.parser.parse_body()? {
+                return Ok(body);
+            }
+            if self.fill()? == 0 {
+                return Err(unexpected_eof().into());
+            }
+        }
+    }
+
+    /// Like `read_body`, but the body is read as the caller reads the
+    /// returned stream, and without `max_body_size`: the caller has a limit
+    /// of its own.
+    pub fn body_stream(&mut self, headers: &Headers) -> Result<BodyStream<'_, S>, ParseError> {
+        let length = BodyLength::try_from(headers)?;
+        // the client waits for 100 Continue until the first read needs the body
+        let send_continue = expects_continue(headers) && length != BodyLength::None;
+        let buffered = Buffered {
+            reader: self,
+            send_continue,
+        };
+
+        Ok(match length {
+            BodyLength::None => BodyStream::Empty,
+            BodyLength::Fixed(len) => BodyStream::Fixed(buffered.take(len as u64)),
+            BodyLength::Chunked => BodyStream::Chunked(ChunkedReader::new(buffered)),
+        })
+    }
+
+    fn fill(&mut self) -> Result<usize, IoError> {
+        let mut chunk = [0; READ_CHUNK_SIZE];
+        let len = self.stream.read(&mut chunk)?;
+        self.parser.feed(&chunk[..len]);
+        Ok(len)
+    }
+}
+
+/// A request body read from the connection as it is read from here, see
+/// `RequestReader::body_stream`. Chunked bodies come out decoded.
+pub enum BodyStream<'a, S> {
+    Empty,
+    Fixed(Take<Buffered<'a, S>>),
+    Chunked(ChunkedReader<Buffered<'a, S>>),
+}
+
+impl<S: Read + Write> BodyStream<'_, S> {
+    /// Whether the whole body was read, so that the next request follows.
+    pub fn is_done(&self) -> bool {
+        match self {
+            Self::Empty => true,
+            Self::Fixed(body) => body.limit() == 0,
+            Self::Chunked(body) => body.is_done(),
+        }
+    }
+}
+
+impl<S: Read + Write> Read for BodyStream<'_, S> {
+    fn read(&mut self, buf: &mut [u8]) -> IoResult<usize> {
+        match self {
+            Self::Empty => Ok(0),
+            Self::Fixed(body) => {
+                let len = body.read(buf)?;
+                if len == 0 && body.limit() > 0 && !buf.is_empty() {
+                    return Err(unexpected_eof());
+                }
+                Ok(len)
+            }
+            Self::Chunked(body) => body.read(buf),
+        }
+    }
+}
+
+// What was read past the head first, then the stream.
+pub struct Buffered<'a, S> {
+    reader: &'a mut RequestReader<S>,
+    send_continue: bool,
+}
+
+impl<S: Read + Write> BufRead for Buffered<'_, S> {
+    fn fill_buf(&mut self) -> IoResult<&[u8]> {
+        if self.reader.parser.buf.is_empty() {
+            if self.send_continue {
+                self.send_continue = false;
+                self.reader.stream.write_all(CONTINUE)?;
+                self.reader.stream.flush()?;
+            }
+            self.reader.fill()?;
+        }
+        Ok(&self.reader.parser.buf)
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.reader.parser.buf.drain(..amt);
+    }
+}
+
+impl<S: Read + Write> Read for Buffered<'_, S> {
+    fn read(&mut self, buf: &mut [u8]) -> IoResult<usize> {
+        let available = self.fill_buf()?;
+        let len = available.len().min(buf.len());
+        buf[..len].copy_from_slice(&available[..len]);
+        self.consume(len);
+        Ok(len)
+    }
+}
+
+pub const CONTINUE: &[u8] = b"HTTP/1.1 100 Continue\r\n\r\n";
+
+/// The parsing behind `RequestReader` and the async server's reader, free of
+/// I/O: the reader feeds in what it reads and gets heads and bodies out once
+/// they are complete.
+pub struct RequestParser {
+    buf: Vec<u8>,
+    // how much of `buf` is known not to hold the end of the head
+    searched: usize,
+    body: Option<BodyState>,
+    max_head_size: usize,
+    max_body_size: usize,
+}
+
+enum BodyState {
+    Fixed(usize),
+    Chunked(ChunkedDecoder),
+}
+
+impl RequestParser {
+    pub fn new(max_head_size: usize, max_body_size: usize) -> Self {
+        Self {
+            buf: Vec::new(),
+            searched: 0,
+            body: None,
+            max_head_size,
+            max_body_size,
+        }
+    }
+
+    pub fn feed(&mut self, bytes: &[u8]) {
+        self.buf.extend_from_slice(bytes);
+    }
+
+    /// What was fed in but not parsed yet.
+    pub fn into_buffered(self) -> Vec<u8> {
+        self.buf
+    }
+
+    /// The head, including the empty line that ends it, once it was fed in whole.
+    pub fn parse_head(&mut self) -> Result<Option<Vec<u8>>, ParseError> {
+        match find_head_end(&self.buf, self.searched) {
+            Some(end) if end > self.max_head_size => Err(ParseError::HeadersTooLarge),
+            Some(end) => {
+                self.searched = 0;
+                Ok(Some(self.buf.drain(..end).collect()))
+            }
+            None if self.buf.len() > self.max_head_size => Err(ParseError::HeadersTooLarge),
+            None => {
+                self.searched = self.buf.len().saturating_sub(3);
+                Ok(None)
+            }
+        }
+    }
+
+    /// What `read_head` returns when the peer closes the connection: nothing
+    /// if it sent nothing, otherwise the head is incomplete.
+    pub fn closed_before_head(&self) -> Result<Option<Vec<u8>>, ReadError> {
+        if self.buf.is_empty() {
+            return Ok(None);
+        }
+        Err(ParseError::InvalidRequest.into())
+    }
+
+    /// Prepares for the body announced by `headers`. Returns whether the
+    /// client waits for `100 Continue` before sending it.
+    pub fn start_body(&mut self, headers: &Headers) -> Result<bool, ParseError> {
+        let (body, len) = match BodyLength::try_from(headers)? {
+            BodyLength::None => (None, 0),
+            BodyLength::Fixed(len) if len > self.max_body_size => {
+                return Err(ParseError::PayloadTooLarge)
+            }
+            BodyLength::Fixed(len) => (Some(BodyState::Fixed(len)), len),
+            BodyLength::Chunked => (Some(BodyState::Chunked(ChunkedDecoder::default())), 1),
+        };
+        self.body = body;
+
+        Ok(expects_continue(headers) && self.buf.len() < len)
+    }
+
+    /// The body prepared for by `start_body`, once it was fed in whole.
+    pub fn parse_body(&mut self) -> Result<Option<Vec<u8>>, ParseError> {
+        let body = match &mut self.body {
+            None => Some(Vec::new()),
+            Some(BodyState::Fixed(len)) if self.buf.len() < *len => None,
+            Some(BodyState::Fixed(len)) => Some(self.buf.drain(..*len).collect()),
+            Some(BodyState::Chunked(decoder)) => {
+                match decoder.decode(&self.buf, self.max_body_size)? {
+                    Some(used) => {
+                        self.buf.drain(..used);
+                        Some(std::mem::take(decoder).into_body())
+                    }
+                    // chunk size lines and trailers are bounded by the head limit
+                    None if self.buf.len() > self.max_body_size + self.max_head_size => {
+                        return Err(ParseError::PayloadTooLarge)
+                    }
+                    None => None,
+                }
+            }
+        };
+        if body.is_some() {
+            self.body = None;
+        }
+        Ok(body)
+    }
+}
+
+fn expects_continue(headers: &Headers) -> bool {
+    headers
+        .get("Expect")
+        .is_some_and(|value| value.eq_ignore_ascii_case("100-continue"))
+}
+
+pub fn unexpected_eof() -> IoError {
+    IoError::new(
+        ErrorKind::UnexpectedEof,
+        "connection closed before the body was complete",
+    )
+}
+
+/// Returns the index just past the `\r\n\r\n` ending the head, starting the search at `from`.
+pub fn find_head_end(buf: &[u8], from: usize) -> Option<usize> {
+    buf.get(from..)?
+        .windows(4)
+        .position(|window| window == b"\r\n\r\n")
+        .map(|i| from + i + 4)
+}
+
+/// How the length of a request body is determined (RFC 9112, section 6.3).
+#[derive(Debug, PartialEq)]
+pub enum BodyLength {
+    None,
+    Fixed(usize),
+    Chunked,
+}
+
+impl TryFrom<&Headers<'_>> for BodyLength {
+    type Error = ParseError;
+
+    fn try_from(headers: &Headers) -> Result<Self, Self::Error> {
+        if let Some(encoding) = headers.get_all("Transfer-Encoding").last() {
+            let last = encoding.rsplit(',').next().unwrap_or("").trim();
+            if last.eq_ignore_ascii_case("chunked") {
+                return Ok(Self::Chunked);
+            }
+            return Err(ParseError::InvalidBody);
+        }
+
+        let mut length = None;
+        for value in headers.get_all("Content-Length") {
+            for value in value.split(',') {
+                // `parse` would take "+5" too
+                let value = value.trim();
+                if value.is_empty() || !value.bytes().all(|b| b.is_ascii_digit()) {
+                    return Err(ParseError::InvalidBody);
+                }
+                let value: usize = value.parse().map_err(|_| ParseError::InvalidBody)?;
+                if length.is_some_and(|length| length != value) {
+                    return Err(ParseError::InvalidBody);
+                }
+                length = Some(value);
+            }
+        }
+
+        match length {
+            Some(0) | None => Ok(Self::None),
+            Some(len) => Ok(Self::Fixed(len)),
+        }
+    }
+}
+
+// 4\r\nWiki\r\n5;ext=1\r\npedia\r\n0\r\nTrailer: x\r\n\r\n
+#[derive(Debug, Default)]
+pub struct ChunkedDecoder {
+    pos: usize,
+    body: Vec<u8>,
+    in_trailers: bool,
+}
+
+impl ChunkedDecoder {
+    /// Continues decoding `buf`, which must start with the bytes passed on earlier calls.
+    /// Returns how many bytes of `buf` the encoded body used once it is complete.
+    pub fn decode(&mut self, buf: &[u8], max_size: usize) -> Result<Option<usize>, ParseError> {
+        loop {
+            let line_len = match find_crlf(&buf[self.pos..]) {
+                Some(len) => len,
+                None => return Ok(None),
+            };
+            let line = &buf[self.pos..self.pos + line_len];
+
+            if self.in_trailers {
+                self.pos += line_len + 2;
+                if line.is_empty() {
+                    return Ok(Some(self.pos));
+                }
+                continue;
+            }
+
+            let size = parse_chunk_size(line)?;
+            let size = usize::try_from(size).map_err(|_| ParseError::PayloadTooLarge)?;
+
+            if size == 0 {
+                self.pos += line_len + 2;
+                self.in_trailers = true;
+                continue;
+            }
+            if size > max_size - self.body.len() {
+                return Err(ParseError::PayloadTooLarge);
+            }
+
+            let data_start = self.pos + line_len + 2;
+            if buf.len() < data_start + size + 2 {
+                return Ok(None);
+            }
+            if &buf[data_start + size..data_start + size + 2] != b"\r\n" {
+                return Err(ParseError::InvalidBody);
+            }
+
+            self.body
+                .extend_from_slice(&buf[data_start..data_start + size]);
+            self.pos = data_start + size + 2;
+        }
+    }
+
+    pub fn into_body(self) -> Vec<u8> {
+        self.body
+    }
+}
+
+fn find_crlf(buf: &[u8]) -> Option<usize> {
+    buf.windows(2).position(|window| window == b"\r\n")
+}
+
+pub enum ReadError {
+    Io(IoError),
+    Parse(ParseError),
+}
+
+impl From<IoError> for ReadError {
+    fn from(e: IoError) -> Self {
+        Self::Io(e)
+    }
+}
+
+impl From<ParseError> for ReadError {
+    fn from(e: ParseError) -> Self {
+        Self::Parse(e)
+    }
+}
+
+impl Display for ReadError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        match self {
+            Self::Io(e) => write!(f, "{}", e),
+            Self::Parse(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Result as IoResult;
+
+    // Hands out the input a few bytes at a time and records what is written.
+    struct Client {
+        input: Vec<u8>,
+        pos: usize,
+        step: usize,
+        output: Vec<u8>,
+    }
+
+    impl Client {
+        fn new(input: &[u8], step: usize) -> Self {
+            Self {
+                input: input.to_vec(),
+                pos: 0,
+                step,
+                output: Vec::new(),
+            }
+        }
+    }
+
+    impl Read for Client {
+        fn read(&mut self, buf: &mut [u8]) -> IoResult<usize> {
+            let end = self.input.len().min(self.pos + self.step.min(buf.len()));
+            let len = end - self.pos;
+            buf[..len].copy_from_slice(&self.input[self.pos..end]);
+            self.pos = end;
+            Ok(len)
+        }
+    }
+
+    impl Write for Client {
+        fn write(&mut self, buf: &[u8]) -> IoResult<usize> {
+            self.output.extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> IoResult<()> {
+            Ok(())
+        }
+    }
+
+    fn request_reader(input: &[u8], step: usize) -> RequestReader<Client> {
+        RequestReader::new(Client::new(input, step), 64, 16)
+    }
+
+    fn headers(head: &str) -> Headers<'_> {
+        Headers::try_from(head).unwrap()
+    }
+
+    #[test]
+    fn reads_pipelined_requests_split_across_reads() {
+        let input = b"POST / HTTP/1.1\r\nContent-Length: 5\r\n\r\nhelloGET / HTTP/1.1\r\n\r\n";
+        for step in [1, 3, 7, 100] {
+            let mut reader = request_reader(input, step);
+
+            let head = reader.read_head().ok().flatten().unwrap();
+            assert!(head.ends_with(b"Content-Length: 5\r\n\r\n"));
+            let body = reader.read_body(&headers("Content-Length: 5\r\n")).ok();
+            assert_eq!(body.as_deref(), Some(&b"hello"[..]));
+
+            let head = reader.read_head().ok().flatten().unwrap();
+            assert_eq!(head, b"GET / HTTP/1.1\r\n\r\n");
+            assert!(matches!(reader.read_head(), Ok(None)));
+        }
+    }
+
+    #[test]
+    fn decodes_chunked_bodies() {
+        let input = b"4\r\nWiki\r\n5;ext=1\r\npedia\r\n0\r\nTrailer: x\r\n\r\nrest";
+        for step in [1, 5, 100] {
+            let mut reader = request_reader(input, step);
+            let body = reader
+                .read_body(&headers("Transfer-Encoding: chunked\r\n"))
+                .ok();
+            assert_eq!(body.as_deref(), Some(&b"Wikipedia"[..]));
+            // only what was read along with the body stays buffered
+            assert!(b"rest".starts_with(&reader.into_parts().1));
+        }
+    }
+
+    #[test]
+    fn rejects_bodies_over_the_limit() {
+        let mut reader = request_reader(b"", 100);
+        let result = reader.read_body(&headers("Content-Length: 17\r\n"));
+        assert!(matches!(
+            result,
+            Err(ReadError::Parse(ParseError::PayloadTooLarge))
+        ));
+
+        let mut reader = request_reader(b"9\r\n123456789\r\n9\r\n123456789\r\n0\r\n\r\n", 100);
+        let result = reader.read_body(&headers("Transfer-Encoding: chunked\r\n"));
+        assert!(matches!(
+            result,
+            Err(ReadError::Parse(ParseError::PayloadTooLarge))
+        ));
+    }
+
+    #[test]
+    fn rejects_heads_over_the_limit() {
+        let input = format!("GET / HTTP/1.1\r\nX: {}\r\n\r\n", "a".repeat(64));
+        let mut reader = request_reader(input.as_bytes(), 10);
+        assert!(matches!(
+            reader.read_head(),
+            Err(ReadError::Parse(ParseError::HeadersTooLarge))
+        ));
+    }
+
+    #[test]
+    fn fails_on_truncated_input() {
+        let mut reader = request_reader(b"GET / HTTP/1.1\r\n", 100);
+        assert!(matches!(
+            reader.read_head(),
+            Err(ReadError::Parse(ParseError::InvalidRequest))
+        ));
+
+        let mut reader = request_reader(b"abc", 100);
+        let result = reader.read_body(&headers("Content-Length: 5\r\n"));
+        assert!(matches!(result, Err(ReadError::Io(e)) if e.kind() == ErrorKind::UnexpectedEof));
+    }
+
+    #[test]
+    fn answers_expect_continue_only_while_the_body_is_missing() {
+        let expect = headers("Content-Length: 2\r\nExpect: 100-continue\r\n");
+
+        let mut reader = request_reader(b"hi", 1);
+        assert!(reader.read_body(&expect).is_ok());
+        assert_eq!(reader.stream().output, b"HTTP/1.1 100 Continue\r\n\r\n");
+
+        let mut reader = request_reader(b"hi", 100);
+        reader.fill().unwrap();
+        assert!(reader.read_body(&expect).is_ok());
+        assert!(reader.stream().output.is_empty());
+    }
+
+    #[test]
+    fn determines_the_body_length() {
+        let length = |head| BodyLength::try_from(&headers(head));
+
+        assert_eq!(length("").ok(), Some(BodyLength::None));
+        assert_eq!(length("Content-Length: 0\r\n").ok(), Some(BodyLength::None));
+        assert_eq!(
+            length("Content-Length: 7\r\n").ok(),
+            Some(BodyLength::Fixed(7))
+        );
+        assert_eq!(
+            length("Content-Length: 7, 7\r\nContent-Length: 7\r\n").ok(),
+            Some(BodyLength::Fixed(7))
+        );
+        assert_eq!(
+            length("Transfer-Encoding: gzip, chunked\r\nContent-Length: 7\r\n").ok(),
+            Some(BodyLength::Chunked)
+        );
+        assert!(length("Content-Length: 7, 8\r\n").is_err());
+        assert!(length("Content-Length: -1\r\n").is_err());
+        assert!(length("Content-Length: +5\r\n").is_err());
+        assert!(length("Content-Length: 0x5\r\n").is_err());
+        assert!(length("Content-Length: 5 5\r\n").is_err());
+        assert!(length("Content-Length: 7,\r\n").is_err());
+        assert!(length("Transfer-Encoding: gzip\r\n").is_err());
+    }
+
+    #[test]
+    fn rejects_malformed_chunks() {
+        let decode = |input: &[u8]| ChunkedDecoder::default().decode(input, 100);
+
+        assert!(decode(b"x\r\n").is_err());
+        assert!(decode(b"+1a\r\n").is_err());
+        assert!(decode(b"\r\n").is_err());
+        assert!(decode(b"3\r\nabcd\r\n").is_err());
+        assert!(matches!(
+            decode(b"ffffffffffffffffffff\r\n"),
+            Err(ParseError::PayloadTooLarge)
+        ));
+        assert!(matches!(decode(b"3\r\nab"), Ok(None)));
+        assert!(matches!(decode(b"0\r\n\r\n"), Ok(Some(5))));
+    }
+
+    #[test]
+    fn finds_the_end_of_the_head() {
+        assert_eq!(find_head_end(b"GET / HTTP/1.1\r\n\r\nbody", 0), Some(18));
+        assert_eq!(find_head_end(b"GET / HTTP/1.1\r\n\r\n", 14), Some(18));
+        assert_eq!(find_head_end(b"GET / HTTP/1.1\r\n", 0), None);
+        assert_eq!(find_head_end(b"", 5), None);
+    }
+
+    #[test]
+    fn parses_what_is_fed_in_without_io() {
+        let mut parser = RequestParser::new(64, 16);
+        parser.feed(b"POST / HTTP/1.1\r\nContent-Length: 3\r");
+        assert!(matches!(parser.parse_head(), Ok(None)));
+        parser.feed(b"\n\r\nab");
+        let head = parser.parse_head().ok().flatten().unwrap();
+        assert!(head.ends_with(b"\r\n\r\n"));
+
+        let headers = headers("Content-Length: 3\r\nExpect: 100-continue\r\n");
+        assert!(matches!(parser.start_body(&headers), Ok(true)));
+        assert!(matches!(parser.parse_body(), Ok(None)));
+        parser.feed(b"cGET");
+        assert_eq!(
+            parser.parse_body().ok().flatten().as_deref(),
+            Some(&b"abc"[..])
+        );
+
+        // the next request starts with no body until one is announced
+        assert_eq!(parser.parse_body().ok().flatten(), Some(Vec::new()));
+        assert!(parser.closed_before_head().is_err());
+        assert_eq!(parser.into_buffered(), b"GET");
+    }
+
+    #[test]
+    fn expects_no_continue_once_the_body_is_there() {
+        let mut parser = RequestParser::new(64, 16);
+        parser.feed(b"abc");
+        let headers = headers("Content-Length: 3\r\nExpect: 100-continue\r\n");
+        assert!(matches!(parser.start_body(&headers), Ok(false)));
+        assert!(matches!(
+            RequestParser::new(64, 16).closed_before_head(),
+            Ok(None)
+        ));
+    }
+
+    fn read_all(body: &mut impl Read) -> IoResult<Vec<u8>> {
+        let mut data = Vec::new();
+        body.read_to_end(&mut data)?;
+        Ok(data)
+    }
+
+    #[test]
+    fn streams_bodies_past_the_limit() {
+        let input = b"POST / HTTP/1.1\r\nContent-Length: 20\r\n\r\n0123456789abcdefghijGET / HTTP/1.1\r\n\r\n";
+        for step in [1, 7, 100] {
+            let mut reader = request_reader(input, step);
+            reader.read_head().ok().flatten().unwrap();
+
+            let mut body = reader
+                .body_stream(&headers("Content-Length: 20\r\n"))
+                .ok()
+                .unwrap();
+            assert!(!body.is_done());
+            assert_eq!(read_all(&mut body).unwrap(), b"0123456789abcdefghij");
+            assert!(body.is_done());
+
+            let head = reader.read_head().ok().flatten().unwrap();
+            assert_eq!(head, b"GET / HTTP/1.1\r\n\r\n");
+        }
+    }
+
+    #[test]
+    fn streams_chunked_bodies_decoded() {
+        let input = b"4\r\nWiki\r\n5;ext=1\r\npedia\r\n0\r\nTrailer: x\r\n\r\nrest";
+        for step in [1, 5, 100] {
+            let mut reader = request_reader(input, step);
+            let mut body = reader
+                .body_stream(&headers("Transfer-Encoding: chunked\r\n"))
+                .ok()
+                .unwrap();
+            assert_eq!(read_all(&mut body).unwrap(), b"Wikipedia");
+            assert!(body.is_done());
+            assert!(b"rest".starts_with(&reader.into_parts().1));
+        }
+    }
+
+    #[test]
+    fn sends_100_continue_once_the_body_is_read() {
+        let mut reader = request_reader(b"hello", 100);
+        let headers = headers("Content-Length: 5\r\nExpect: 100-continue\r\n");
+        assert!(reader.body_stream(&headers).is_ok());
+        assert!(reader.stream().output.is_empty());
+
+        let mut body = reader.body_stream(&headers).ok().unwrap();
+        assert_eq!(read_all(&mut body).unwrap(), b"hello");
+        assert_eq!(reader.stream().output, CONTINUE);
+    }
+
+    #[test]
+    fn fails_streams_cut_short() {
+        let mut reader = request_reader(b"hel", 100);
+        let mut body = reader
+            .body_stream(&headers("Content-Length: 5\r\n"))
+            .ok()
+            .unwrap();
+        let e = read_all(&mut body).unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::UnexpectedEof);
+        assert!(!body.is_done());
+    }
+}
@@ -1,32 +1,154 @@
 use crate::http::method;
 
 use super::method::{Method, MethodError};
-use super::QueryString;
+use super::version::{Version, VersionError};
+use super::{percent_encoding, Headers, Params, QueryString, StatusCode};
 use core::str;
+use std::borrow::Cow;
 use std::convert::TryFrom;
 use std::error::Error;
 use std::fmt::{Debug, Display, Formatter, Result as FmtResult};
+use std::io::{Read, Result as IoResult};
+use std::net::SocketAddr;
 use std::str::Utf8Error;
 
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub struct Request<'buf> {
-    path: &'buf str,
+    target: &'buf str,
+    path: Cow<'buf, str>,
     query_string: Option<QueryString<'buf>>,
     method: Method,
+    version: Version,
+    headers: Headers<'buf>,
+    body: &'buf [u8],
+    body_reader: Option<BodyReader<'buf>>,
+    params: Params<'buf>,
+    remote_addr: Option<SocketAddr>,
+    secure: bool,
 }
 
 impl<'buf> Request<'buf> {
+    /// The percent-decoded path, without the query string.
     pub fn path(&self) -> &str {
         &self.path
     }
 
-    pub fn method(&self) -> &Method{
+    /// The request target exactly as received, e.g. `/search?name=J%C3%BCrgen`.
+    pub fn target(&self) -> &'buf str {
+        self.target
+    }
+
+    pub fn method(&self) -> &Method {
         &self.method
     }
 
-    pub fn query_string(&self) -> Option<&QueryString> {
+    pub fn version(&self) -> Version {
+        self.version
+    }
+
+    /// Whether the client allows the connection to stay open after this request.
+    pub fn keep_alive(&self) -> bool {
+        match self.version {
+            Version::Http10 => self.headers.contains_token("Connection", "keep-alive"),
+            Version::Http11 => !self.headers.contains_token("Connection", "close"),
+        }
+    }
+
+    pub fn query_string(&self) -> Option<&QueryString<'_>> {
         self.query_string.as_ref()
     }
+
+    pub fn headers(&self) -> &Headers<'buf> {
+        &self.headers
+    }
+
+    pub fn body(&self) -> &'buf [u8] {
+        self.body
+    }
+
+    /// Parses an `application/x-www-form-urlencoded` body like a query string.
+    pub fn form(&self) -> Option<QueryString<'buf>> {
+        let content_type = self.headers.get("Content-Type")?;
+        let mime_type = content_type.split(';').next().unwrap_or("").trim();
+        if !mime_type.eq_ignore_ascii_case("application/x-www-form-urlencoded") {
+            return None;
+        }
+
+        str::from_utf8(self.body).ok().map(QueryString::from)
+    }
+
+    /// Parameters captured from the path by the `Router`.
+    pub fn params(&self) -> &Params<'buf> {
+        &self.params
+    }
+
+    pub fn set_params(&mut self, params: Params<'buf>) {
+        self.params = params;
+    }
+
+    /// The address of the client, if the request came over a connection.
+    pub fn remote_addr(&self) -> Option<SocketAddr> {
+        self.remote_addr
+    }
+
+    pub fn set_remote_addr(&mut self, remote_addr: SocketAddr) {
+        self.remote_addr = Some(remote_addr);
+    }
+
+    /// The `Host` header without the port, e.g. `example.com` for `example.com:8080`.
+    pub fn host(&self) -> Option<&'buf str> {
+        self.headers.get("Host").map(|host| strip_port(host.trim()))
+    }
+
+    /// Whether the request came over TLS.
+    pub fn is_secure(&self) -> bool {
+        self.secure
+    }
+
+    pub fn set_secure(&mut self, secure: bool) {
+        self.secure = secure;
+    }
+
+    /// Attaches the body, which the server reads separately from the head.
+    pub fn set_body(&mut self, body: &'buf [u8]) {
+        self.body = body;
+    }
+
+    /// The body, still to be read from the connection, of a request whose
+    /// handler asked for that with `Handler::streams_body`. `body` is empty then.
+    pub fn body_reader(&self) -> Option<BodyReader<'buf>> {
+        self.body_reader
+    }
+
+    pub fn set_body_reader(&mut self, body_reader: BodyReader<'buf>) {
+        self.body_reader = Some(body_reader);
+    }
+}
+
+/// Reads a request body from the connection as it arrives. Copies share
+/// the same connection, so each byte is read only once.
+#[derive(Clone, Copy)]
+pub struct BodyReader<'buf> {
+    read: &'buf (dyn Fn(&mut [u8]) -> IoResult<usize> + Sync + 'buf),
+}
+
+impl<'buf> BodyReader<'buf> {
+    /// `read` works like `Read::read` and returns 0 at the end of the body.
+    pub fn new(read: &'buf (dyn Fn(&mut [u8]) -> IoResult<usize> + Sync + 'buf)) -> Self {
+        Self { read }
+    }
+}
+
+impl Read for BodyReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> IoResult<usize> {
+        (self.read)(buf)
+    }
+}
+
+impl Debug for BodyReader<'_> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        write!(f, "BodyReader")
+    }
 }
 
 impl<'buf> TryFrom<&'buf [u8]> for Request<'buf> {
@@ -37,15 +159,17 @@ impl<'buf> TryFrom<&'buf [u8]> for Request<'buf> {
         let request = str::from_utf8(buf)?;
 
         let (method, request) = get_next_word(request).ok_or(ParseError::InvalidRequest)?;
-        let (mut path, request) = get_next_word(request).ok_or(ParseError::InvalidRequest)?;
-        let (protocol, _) = get_next_word(request).ok_or(ParseError::InvalidRequest)?;
-
-        if protocol != "HTTP/1.1" {
-            return Err(ParseError::InvalidProtocol);
-        }
+        let (target, request) = get_next_word(request).ok_or(ParseError::InvalidRequest)?;
+        let (protocol, request) = get_next_word(request).ok_or(ParseError::InvalidRequest)?;
 
+        let version: Version = protocol.parse()?;
         let method: Method = method.parse()?;
+        let request = request
+            .strip_prefix('\n')
+            .ok_or(ParseError::InvalidRequest)?;
+        let headers = Headers::try_from(request)?;
 
+        let mut path = target;
         let mut query_string = None;
         if let Some(i) = path.find('?') {
             query_string = Some(QueryString::from(&path[i + 1..]));
@@ -53,15 +177,23 @@ impl<'buf> TryFrom<&'buf [u8]> for Request<'buf> {
         }
 
         Ok(Self {
-            path,
+            target,
+            path: percent_encoding::decode(path, false),
             query_string,
             method,
+            version,
+            headers,
+            body: &[],
+            body_reader: None,
+            params: Params::default(),
+            remote_addr: None,
+            secure: false,
         })
     }
 }
 
 fn get_next_word(request: &str) -> Option<(&str, &str)> {
-    for (i, c) in request.chars().enumerate() {
+    for (i, c) in request.char_indices() {
         if c == ' ' || c == '\r' {
             return Some((&request[..i], &request[i + 1..]));
         }
@@ -70,20 +202,43 @@ fn get_next_word(request: &str) -> Option<(&str, &str)> {
     None
 }
 
+#[allow(clippy::enum_variant_names)]
 pub enum ParseError {
     InvalidRequest,
     InvalidEncoding,
     InvalidProtocol,
     InvalidMethod,
+    InvalidHeader,
+    InvalidBody,
+    HeadersTooLarge,
+    PayloadTooLarge,
 }
 
 impl ParseError {
+    /// The status code a bad request answer should carry.
+    pub fn status_code(&self) -> StatusCode {
+        match self {
+            Self::InvalidProtocol => StatusCode::HttpVersionNotSupported,
+            Self::InvalidMethod => StatusCode::NotImplemented,
+            Self::HeadersTooLarge => StatusCode::RequestHeaderFieldsTooLarge,
+            Self::PayloadTooLarge => StatusCode::PayloadTooLarge,
+            Self::InvalidRequest
+            | Self::InvalidEncoding
+            | Self::InvalidHeader
+            | Self::InvalidBody => StatusCode::BadRequest,
+        }
+    }
+
     fn message(&self) -> &str {
         match self {
             Self::InvalidRequest => "Invalid Request",
             Self::InvalidEncoding => "Invalid Encoding",
             Self::InvalidProtocol => "Invalid Protocol",
             Self::InvalidMethod => "Invalid Method",
+            Self::InvalidHeader => "Invalid Header",
+            Self::InvalidBody => "Invalid Body",
+            Self::HeadersTooLarge => "Headers Too Large",
+            Self::PayloadTooLarge => "Payload Too Large",
         }
     }
 }
@@ -94,6 +249,12 @@ impl From<MethodError> for ParseError {
     }
 }
 
+impl From<VersionError> for ParseError {
+    fn from(_: VersionError) -> Self {
+        Self::InvalidProtocol
+    }
+}
+
 impl From<Utf8Error> for ParseError {
     fn from(_: Utf8Error) -> Self {
         Self::InvalidEncoding
@@ -113,3 +274,109 @@ impl Debug for ParseError {
 }
 
 impl Error for ParseError {}
+
+// example.com:8080 -> example.com, [::1]:8080 -> [::1]
+fn strip_port(host: &str) -> &str {
+    match host.rfind(':') {
+        Some(i) if !host[i..].contains(']') => &host[..i],
+        _ => host,
+    }
+}
+
+/// Whether `host` is matched by `pattern`, both lowercase. `*.example.com`
+/// matches `www.example.com`, but neither `example.com` nor `a.b.example.com`.
+pub fn matches_host(pattern: &str, host: &str) -> bool {
+    match (pattern.strip_prefix("*."), host.split_once('.')) {
+        (Some(suffix), Some((label, rest))) => !label.is_empty() && rest == suffix,
+        _ => pattern == host,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_the_request_line_and_headers() {
+        let head = b"GET /search?q=rust HTTP/1.1\r\nHost: example.com:8080\r\nAccept: */*\r\n\r\n";
+        let request = Request::try_from(&head[..]).unwrap();
+
+        assert_eq!(*request.method(), Method::GET);
+        assert_eq!(request.version(), Version::Http11);
+        assert_eq!(request.target(), "/search?q=rust");
+        assert_eq!(request.path(), "/search");
+        assert_eq!(request.headers().get("accept"), Some("*/*"));
+        assert_eq!(request.host(), Some("example.com"));
+        assert!(request.body().is_empty());
+    }
+
+    #[test]
+    fn rejects_bad_request_lines() {
+        let parse = |head: &'static [u8]| Request::try_from(head).map(|_| ());
+
+        assert!(matches!(parse(b"GET /"), Err(ParseError::InvalidRequest)));
+        assert!(matches!(
+            parse(b"GET / HTTP/2.0\r\n\r\n"),
+            Err(ParseError::InvalidProtocol)
+        ));
+        assert!(matches!(
+            parse(b"BREW / HTTP/1.1\r\n\r\n"),
+            Err(ParseError::InvalidMethod)
+        ));
+        assert!(matches!(
+            parse(b"GET / HTTP/1.1\r\nbroken\r\n\r\n"),
+            Err(ParseError::InvalidHeader)
+        ));
+        assert!(matches!(
+            parse(b"GET /\xff HTTP/1.1\r\n\r\n"),
+            Err(ParseError::InvalidEncoding)
+        ));
+    }
+
+    #[test]
+    fn decodes_the_path_and_form_bodies() {
+        let head = b"POST /a%20b/c%2Fd?x=%41 HTTP/1.1\r\nContent-Type: application/x-www-form-urlencoded; charset=utf-8\r\n\r\n";
+        let mut request = Request::try_from(&head[..]).unwrap();
+
+        assert_eq!(request.path(), "/a b/c/d");
+        assert_eq!(request.target(), "/a%20b/c%2Fd?x=%41");
+        assert_eq!(request.query_string().unwrap().get_first("x"), Some("A"));
+
+        request.set_body(b"name=J%C3%BCrgen+M&tag=a&tag=b");
+        let form = request.form().unwrap();
+        assert_eq!(form.get_first("name"), Some("Jürgen M"));
+        assert_eq!(form.get_all("tag"), ["a", "b"]);
+
+        let head = b"POST / HTTP/1.1\r\nContent-Type: text/plain\r\n\r\n";
+        assert!(Request::try_from(&head[..]).unwrap().form().is_none());
+    }
+
+    #[test]
+    fn keeps_alive_by_version_and_connection_header() {
+        let keep_alive = |head: &'static [u8]| Request::try_from(head).unwrap().keep_alive();
+
+        assert!(keep_alive(b"GET / HTTP/1.1\r\n\r\n"));
+        assert!(!keep_alive(b"GET / HTTP/1.1\r\nConnection: Close\r\n\r\n"));
+        assert!(!keep_alive(b"GET / HTTP/1.0\r\n\r\n"));
+        assert!(keep_alive(
+            b"GET / HTTP/1.0\r\nConnection: keep-alive\r\n\r\n"
+        ));
+    }
+
+    #[test]
+    fn strips_ports_from_hosts() {
+        assert_eq!(strip_port("example.com:8080"), "example.com");
+        assert_eq!(strip_port("example.com"), "example.com");
+        assert_eq!(strip_port("[::1]:8080"), "[::1]");
+        assert_eq!(strip_port("[::1]"), "[::1]");
+    }
+
+    #[test]
+    fn matches_wildcard_hosts() {
+        assert!(matches_host("example.com", "example.com"));
+        assert!(matches_host("*.example.com", "www.example.com"));
+        assert!(!matches_host("*.example.com", "example.com"));
+        assert!(!matches_host("*.example.com", "a.b.example.com"));
+        assert!(!matches_host("*.example.com", ".example.com"));
+    }
+}
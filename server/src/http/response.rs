@@ -2,31 +2,345 @@ use std::fmt::{Display, Formatter, Result as FmtResult};
 use std::io::{Result as IoResult, Write};
 use std::net::TcpStream;
 
-use super::StatusCode;
+use super::date::fmt_http_date;
+use super::{Body, OnUpgrade, ParseError, StatusCode, Upgraded};
+
+use std::time::SystemTime;
+
+pub const SERVER_NAME: &str = concat!(env!("CARGO_PKG_NAME"), "/", env!("CARGO_PKG_VERSION"));
 
 #[derive(Debug)]
 pub struct Response {
     status_code: StatusCode,
-    body: Option<String>,
+    headers: Vec<(String, String)>,
+    body: Body,
+    upgrade: Option<OnUpgrade>,
+    allows_compression: bool,
+    close_delimited: bool,
 }
 
 impl Response {
     pub fn new(status_code: StatusCode, body: Option<String>) -> Self {
-        Response { status_code, body }
+        Response {
+            status_code,
+            headers: Vec::new(),
+            body: body.map(Body::from).unwrap_or_default(),
+            upgrade: None,
+            allows_compression: true,
+            close_delimited: false,
+        }
+    }
+
+    /// A `101 Switching Protocols` response to an `Upgrade` request. Once it
+    /// is sent, the server hands the connection to `on_upgrade`, which owns
+    /// it from then on.
+    pub fn upgrade(protocol: &str, on_upgrade: impl FnOnce(Upgraded) + Send + 'static) -> Self {
+        let mut response = Response::new(StatusCode::SwitchingProtocols, None)
+            .with_header("Upgrade", protocol)
+            .with_header("Connection", "Upgrade");
+        response.upgrade = Some(OnUpgrade::new(on_upgrade));
+        response
+    }
+
+    /// Takes out what `upgrade` was given, if anything.
+    pub fn take_upgrade(&mut self) -> Option<OnUpgrade> {
+        self.upgrade.take()
     }
 
-    pub fn send(&self, stream: &mut impl Write) -> IoResult<()> {
-        let body = match &self.body {
-            Some(b) => b,
-            None => "",
+    /// A response with a small HTML page describing the status.
+    pub fn error(status_code: StatusCode) -> Self {
+        Self::error_with_message(status_code, "")
+    }
+
+    pub fn error_with_message(status_code: StatusCode, message: &str) -> Self {
+        let title = format!("{} {}", status_code, status_code.reason_phrase());
+        let message = match message {
+            "" => String::new(),
+            message => format!("<p>{}</p>", escape_html(message)),
         };
+        let body = format!(
+            "<!DOCTYPE html>\n<html><head><title>{0}</title></head>\n<body><h1>{0}</h1>{1}</body></html>\n",
+            title, message
+        );
+
+        Response::new(status_code, Some(body))
+            .with_header("Content-Type", "text/html; charset=utf-8")
+    }
+
+    pub fn with_body(mut self, body: impl Into<Body>) -> Self {
+        self.body = body.into();
+        self
+    }
+
+    pub fn set_body(&mut self, body: impl Into<Body>) {
+        self.body = body.into();
+    }
+
+    pub fn body(&self) -> &Body {
+        &self.body
+    }
+
+    /// Builder variant of `set_header`.
+    pub fn with_header(mut self, name: &str, value: impl Into<String>) -> Self {
+        self.set_header(name, value);
+        self
+    }
+
+    /// Sets a header, replacing any previous values with the same name.
+    pub fn set_header(&mut self, name: &str, value: impl Into<String>) {
+        self.remove_header(name);
+        self.add_header(name, value);
+    }
+
+    /// Adds a header, keeping previous values with the same name (e.g. `Set-Cookie`).
+    pub fn add_header(&mut self, name: &str, value: impl Into<String>) {
+        self.headers.push((name.to_string(), value.into()));
+    }
+
+    /// Adds `name` to `Vary` unless it is already listed.
+    pub fn add_vary(&mut self, name: &str) {
+        match self.header("Vary") {
+            Some(vary)
+                if vary.split(',').any(|field| {
+                    field.trim().eq_ignore_ascii_case(name) || field.trim() == "*"
+                }) => {}
+            Some(vary) => {
+                let vary = format!("{}, {}", vary, name);
+                self.set_header("Vary", vary);
+            }
+            None => self.set_header("Vary", name),
+        }
+    }
+
+    pub fn remove_header(&mut self, name: &str) {
+        self.headers
+            .retain(|(key, _)| !key.eq_ignore_ascii_case(name));
+    }
+
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    pub fn headers(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.headers
+            .iter()
+            .map(|(key, value)| (key.as_str(), value.as_str()))
+    }
+
+    /// Whether the server may compress the body, see `Server::compression`.
+    pub fn allows_compression(&self) -> bool {
+        self.allows_compression
+    }
+
+    pub fn set_allows_compression(&mut self, allows_compression: bool) {
+        self.allows_compression = allows_compression;
+    }
+
+    /// Leaves out `Content-Length` and `Transfer-Encoding`, for a body that
+    /// ends when the connection is closed.
+    pub fn set_close_delimited(&mut self, close_delimited: bool) {
+        self.close_delimited = close_delimited;
+    }
+
+    pub fn status_code(&self) -> StatusCode {
+        self.status_code
+    }
+
+    pub fn set_status_code(&mut self, status_code: StatusCode) {
+        self.status_code = status_code;
+    }
+
+    pub fn send(&mut self, stream: &mut impl Write) -> IoResult<()> {
+        self.write(stream, true)
+    }
+
+    /// Sends the head only, as the answer to a HEAD request.
+    pub fn send_head(&mut self, stream: &mut impl Write) -> IoResult<()> {
+        self.write(stream, false)
+    }
 
-        write!(
-            stream,
-            "HTTP/1.1 {} {}\r\n\r\n{}",
+    /// Takes the body out, leaving an empty one behind.
+    pub fn take_body(&mut self) -> Body {
+        std::mem::take(&mut self.body)
+    }
+
+    /// Serializes the status line and headers, including the automatic ones.
+    pub fn head(&self) -> String {
+        let mut head = format!(
+            "HTTP/1.1 {} {}\r\n",
             self.status_code,
-            self.status_code.reason_phrase(),
-            body
-        )
+            self.status_code.reason_phrase()
+        );
+
+        if self.header("Date").is_none() {
+            head.push_str(&format!("Date: {}\r\n", fmt_http_date(SystemTime::now())));
+        }
+        if self.header("Server").is_none() {
+            head.push_str(&format!("Server: {}\r\n", SERVER_NAME));
+        }
+        let framed =
+            self.header("Content-Length").is_some() || self.header("Transfer-Encoding").is_some();
+        if !framed && !self.close_delimited && self.status_code.allows_body() {
+            if self.body.is_stream() {
+                head.push_str("Transfer-Encoding: chunked\r\n");
+            } else {
+                head.push_str(&format!("Content-Length: {}\r\n", self.body.len()));
+            }
+        }
+        for (name, value) in self.headers() {
+            if is_valid_header(name, value) {
+                head.push_str(&format!("{}: {}\r\n", name, value));
+            } else {
+                println!("Dropping invalid response header: {:?}", name);
+            }
+        }
+        head.push_str("\r\n");
+        head
+    }
+
+    fn write(&mut self, stream: &mut impl Write, with_body: bool) -> IoResult<()> {
+        stream.write_all(self.head().as_bytes())?;
+        let body = self.take_body();
+        if with_body && self.status_code.allows_body() {
+            body.write_to(stream)?;
+        }
+        stream.flush()
+    }
+}
+
+impl From<&ParseError> for Response {
+    fn from(e: &ParseError) -> Self {
+        Response::error_with_message(e.status_code(), &e.to_string())
+    }
+}
+
+pub fn escape_html(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+// Guards against response splitting through user-controlled header values.
+fn is_valid_header(name: &str, value: &str) -> bool {
+    !name.is_empty()
+        && name.bytes().all(|b| b.is_ascii_graphic() && b != b':')
+        && !value.bytes().any(|b| b == b'\r' || b == b'\n')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sent(mut response: Response, with_body: bool) -> String {
+        let mut out = Vec::new();
+        response.write(&mut out, with_body).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn adds_the_automatic_headers() {
+        let response = Response::new(StatusCode::Ok, Some("hello".to_string()))
+            .with_header("Content-Type", "text/plain");
+        let out = sent(response, true);
+
+        assert!(out.starts_with("HTTP/1.1 200 OK\r\nDate: "));
+        assert!(out.contains(&format!("\r\nServer: {}\r\n", SERVER_NAME)));
+        assert!(out.contains("\r\nContent-Length: 5\r\n"));
+        assert!(out.ends_with("\r\nContent-Type: text/plain\r\n\r\nhello"));
+    }
+
+    #[test]
+    fn keeps_headers_set_by_the_handler() {
+        let response = Response::new(StatusCode::Ok, None)
+            .with_header("Server", "custom")
+            .with_header("Date", "yesterday");
+        let out = sent(response, true);
+
+        assert_eq!(out.matches("Server: ").count(), 1);
+        assert_eq!(out.matches("Date: ").count(), 1);
+        assert!(out.contains("Server: custom\r\n"));
+    }
+
+    #[test]
+    fn sends_only_the_head_for_head_requests() {
+        let response = Response::new(StatusCode::Ok, Some("hello".to_string()));
+        let out = sent(response, false);
+        assert!(out.contains("Content-Length: 5\r\n"));
+        assert!(out.ends_with("\r\n\r\n"));
+    }
+
+    #[test]
+    fn leaves_out_the_body_where_the_status_forbids_one() {
+        let response = Response::new(StatusCode::NotModified, Some("hello".to_string()));
+        let out = sent(response, true);
+        assert!(!out.contains("Content-Length"));
+        assert!(out.ends_with("\r\n\r\n"));
+    }
+
+    #[test]
+    fn drops_headers_that_would_split_the_response() {
+        let response = Response::new(StatusCode::Ok, None)
+            .with_header("X-Evil", "a\r\nSet-Cookie: b")
+            .with_header("Bad:Name", "c")
+            .with_header("X-Good", "d");
+        let out = sent(response, true);
+        assert!(!out.contains("Set-Cookie"));
+        assert!(!out.contains("Bad:Name"));
+        assert!(out.contains("X-Good: d\r\n"));
+    }
+
+    #[test]
+    fn sets_adds_and_removes_headers() {
+        let mut response = Response::new(StatusCode::Ok, None);
+        response.add_header("Set-Cookie", "a=1");
+        response.add_header("set-cookie", "b=2");
+        assert_eq!(response.headers().count(), 2);
+
+        response.set_header("SET-COOKIE", "c=3");
+        assert_eq!(
+            response.headers().collect::<Vec<_>>(),
+            [("SET-COOKIE", "c=3")]
+        );
+        assert_eq!(response.header("set-cookie"), Some("c=3"));
+
+        response.remove_header("Set-Cookie");
+        assert_eq!(response.headers().count(), 0);
+    }
+
+    #[test]
+    fn lists_each_vary_field_once() {
+        let mut response = Response::new(StatusCode::Ok, None);
+        response.add_vary("Accept-Encoding");
+        response.add_vary("accept-encoding");
+        response.add_vary("Origin");
+        assert_eq!(response.header("Vary"), Some("Accept-Encoding, Origin"));
+
+        response.set_header("Vary", "*");
+        response.add_vary("Origin");
+        assert_eq!(response.header("Vary"), Some("*"));
+    }
+
+    #[test]
+    fn escapes_error_messages() {
+        assert_eq!(
+            escape_html("<a href=\"x\">'&'</a>"),
+            "&lt;a href=&quot;x&quot;&gt;&#39;&amp;&#39;&lt;/a&gt;"
+        );
+        let response = Response::error_with_message(StatusCode::NotFound, "<script>");
+        let out = sent(response, true);
+        assert!(out.starts_with("HTTP/1.1 404 Not Found\r\n"));
+        assert!(out.contains("<p>&lt;script&gt;</p>"));
     }
 }
@@ -1,24 +1,190 @@
 use std::fmt::{Display, Formatter, Result as FmtResult};
 
-#[derive(Clone, Copy, Debug)]
-pub enum StatusCode {
-    Ok = 200,
-    BadRequest = 400,
-    NotFound = 404,
+/// A status code outside the registry, always in `100..=999`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct UnregisteredCode(u16);
+
+// Declares the enum together with its numeric codes and reason phrases.
+macro_rules! status_codes {
+    ($($variant:ident = $code:literal, $phrase:literal;)+) => {
+        #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+        pub enum StatusCode {
+            $($variant,)+
+            /// Any other code in `100..=999`, without a known reason phrase.
+            /// Only `from_u16` makes these.
+            Other(UnregisteredCode),
+        }
+
+        impl StatusCode {
+            pub fn as_u16(&self) -> u16 {
+                match self {
+                    $(Self::$variant => $code,)+
+                    Self::Other(code) => code.0,
+                }
+            }
+
+            pub fn reason_phrase(&self) -> &str {
+                match self {
+                    $(Self::$variant => $phrase,)+
+                    Self::Other(_) => "",
+                }
+            }
+
+            /// Returns the matching variant, or `Other` for unregistered codes.
+            /// Codes outside `100..=999` cannot appear in a status line.
+            pub fn from_u16(code: u16) -> Option<Self> {
+                match code {
+                    $($code => Some(Self::$variant),)+
+                    100..=999 => Some(Self::Other(UnregisteredCode(code))),
+                    _ => None,
+                }
+            }
+        }
+    };
+}
+
+// https://www.iana.org/assignments/http-status-codes
+status_codes! {
+    Continue = 100, "Continue";
+    SwitchingProtocols = 101, "Switching Protocols";
+    Processing = 102, "Processing";
+    EarlyHints = 103, "Early Hints";
+
+    Ok = 200, "OK";
+    Created = 201, "Created";
+    Accepted = 202, "Accepted";
+    NonAuthoritativeInformation = 203, "Non-Authoritative Information";
+    NoContent = 204, "No Content";
+    ResetContent = 205, "Reset Content";
+    PartialContent = 206, "Partial Content";
+    MultiStatus = 207, "Multi-Status";
+    AlreadyReported = 208, "Already Reported";
+    ImUsed = 226, "IM Used";
+
+    MultipleChoices = 300, "Multiple Choices";
+    MovedPermanently = 301, "Moved Permanently";
+    Found = 302, "Found";
+    SeeOther = 303, "See Other";
+    NotModified = 304, "Not Modified";
+    UseProxy = 305, "Use Proxy";
+    TemporaryRedirect = 307, "Temporary Redirect";
+    PermanentRedirect = 308, "Permanent Redirect";
+
+    BadRequest = 400, "Bad Request";
+    Unauthorized = 401, "Unauthorized";
+    PaymentRequired = 402, "Payment Required";
+    Forbidden = 403, "Forbidden";
+    NotFound = 404, "Not Found";
+    MethodNotAllowed = 405, "Method Not Allowed";
+    NotAcceptable = 406, "Not Acceptable";
+    ProxyAuthenticationRequired = 407, "Proxy Authentication Required";
+    RequestTimeout = 408, "Request Timeout";
+    Conflict = 409, "Conflict";
+    Gone = 410, "Gone";
+    LengthRequired = 411, "Length Required";
+    PreconditionFailed = 412, "Precondition Failed";
+    PayloadTooLarge = 413, "Payload Too Large";
+    UriTooLong = 414, "URI Too Long";
+    UnsupportedMediaType = 415, "Unsupported Media Type";
+    RangeNotSatisfiable = 416, "Range Not Satisfiable";
+    ExpectationFailed = 417, "Expectation Failed";
+    ImATeapot = 418, "I'm a teapot";
+    MisdirectedRequest = 421, "Misdirected Request";
+    UnprocessableContent = 422, "Unprocessable Content";
+    Locked = 423, "Locked";
+    FailedDependency = 424, "Failed Dependency";
+    TooEarly = 425, "Too Early";
+    UpgradeRequired = 426, "Upgrade Required";
+    PreconditionRequired = 428, "Precondition Required";
+    TooManyRequests = 429, "Too Many Requests";
+    RequestHeaderFieldsTooLarge = 431, "Request Header Fields Too Large";
+    UnavailableForLegalReasons = 451, "Unavailable For Legal Reasons";
+
+    InternalServerError = 500, "Internal Server Error";
+    NotImplemented = 501, "Not Implemented";
+    BadGateway = 502, "Bad Gateway";
+    ServiceUnavailable = 503, "Service Unavailable";
+    GatewayTimeout = 504, "Gateway Timeout";
+    HttpVersionNotSupported = 505, "HTTP Version Not Supported";
+    VariantAlsoNegotiates = 506, "Variant Also Negotiates";
+    InsufficientStorage = 507, "Insufficient Storage";
+    LoopDetected = 508, "Loop Detected";
+    NotExtended = 510, "Not Extended";
+    NetworkAuthenticationRequired = 511, "Network Authentication Required";
 }
 
 impl StatusCode {
-    pub fn reason_phrase(&self) -> &str {
-        match self {
-            Self::Ok => "Ok",
-            Self::BadRequest => "Bad Request",
-            Self::NotFound => "Not Found",
-        }
+    pub fn is_informational(&self) -> bool {
+        (100..200).contains(&self.as_u16())
+    }
+
+    pub fn is_success(&self) -> bool {
+        (200..300).contains(&self.as_u16())
+    }
+
+    pub fn is_redirection(&self) -> bool {
+        (300..400).contains(&self.as_u16())
+    }
+
+    pub fn is_client_error(&self) -> bool {
+        (400..500).contains(&self.as_u16())
+    }
+
+    pub fn is_server_error(&self) -> bool {
+        (500..1000).contains(&self.as_u16())
+    }
+
+    /// 1xx, 204 and 304 responses never carry a body.
+    pub fn allows_body(&self) -> bool {
+        !(self.is_informational() || matches!(self, Self::NoContent | Self::NotModified))
     }
 }
 
 impl Display for StatusCode {
     fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
-        write!(f, "{}", *self as u16)
+        write!(f, "{}", self.as_u16())
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn maps_registered_codes_to_variants() {
+        assert_eq!(StatusCode::from_u16(200), Some(StatusCode::Ok));
+        assert_eq!(StatusCode::from_u16(418), Some(StatusCode::ImATeapot));
+        assert_eq!(StatusCode::NotFound.as_u16(), 404);
+        assert_eq!(StatusCode::NotFound.reason_phrase(), "Not Found");
+        assert_eq!(StatusCode::GatewayTimeout.to_string(), "504");
+    }
+
+    #[test]
+    fn keeps_unregistered_codes_within_range() {
+        let code = StatusCode::from_u16(299).unwrap();
+        assert!(matches!(code, StatusCode::Other(_)));
+        assert_eq!(code.as_u16(), 299);
+        assert_eq!(code.reason_phrase(), "");
+        assert!(code.is_success());
+
+        assert_eq!(StatusCode::from_u16(99), None);
+        assert_eq!(StatusCode::from_u16(1000), None);
+        assert_eq!(
+            StatusCode::from_u16(999).map(|code| code.as_u16()),
+            Some(999)
+        );
+    }
+
+    #[test]
+    fn classifies_codes() {
+        assert!(StatusCode::EarlyHints.is_informational());
+        assert!(StatusCode::Found.is_redirection());
+        assert!(StatusCode::Gone.is_client_error());
+        assert!(StatusCode::from_u16(599).unwrap().is_server_error());
+
+        assert!(StatusCode::Ok.allows_body());
+        assert!(!StatusCode::Continue.allows_body());
+        assert!(!StatusCode::NoContent.allows_body());
+        assert!(!StatusCode::NotModified.allows_body());
+    }
+}
@@ -0,0 +1,84 @@
+use std::fmt::{Debug, Formatter, Result as FmtResult};
+use std::io::{Read, Result as IoResult, Write};
+use std::net::SocketAddr;
+use std::time::Duration;
+
+/// A client connection as the server holds it, plain or TLS.
+pub trait Connection: Read + Write + Send {
+    fn peer_addr(&self) -> IoResult<SocketAddr>;
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> IoResult<()>;
+
+    /// Ends the connection cleanly (for TLS, with close_notify).
+    fn close(&mut self);
+}
+
+/// A connection handed over by the server after a `101 Switching Protocols`
+/// response. It is closed when dropped.
+pub struct Upgraded {
+    stream: Box<dyn Connection>,
+    // read by the server past the end of the request
+    buffered: Vec<u8>,
+}
+
+/// What a response wants done with the connection once it is sent.
+pub struct OnUpgrade(Box<dyn FnOnce(Upgraded) + Send>);
+
+impl Upgraded {
+    pub fn new(stream: Box<dyn Connection>, buffered: Vec<u8>) -> Self {
+        Self { stream, buffered }
+    }
+
+    pub fn peer_addr(&self) -> IoResult<SocketAddr> {
+        self.stream.peer_addr()
+    }
+
+    /// `None` waits forever, which is the default after the upgrade.
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> IoResult<()> {
+        self.stream.set_read_timeout(timeout)
+    }
+}
+
+impl Read for Upgraded {
+    fn read(&mut self, buf: &mut [u8]) -> IoResult<usize> {
+        if self.buffered.is_empty() {
+            return self.stream.read(buf);
+        }
+        let len = buf.len().min(self.buffered.len());
+        buf[..len].copy_from_slice(&self.buffered[..len]);
+        self.buffered.drain(..len);
+        Ok(len)
+    }
+}
+
+impl Write for Upgraded {
+    fn write(&mut self, buf: &[u8]) -> IoResult<usize> {
+        self.stream.write(buf)
+    }
+
+    fn flush(&mut self) -> IoResult<()> {
+        self.stream.flush()
+    }
+}
+
+impl Drop for Upgraded {
+    fn drop(&mut self) {
+        self.stream.close();
+    }
+}
+
+impl OnUpgrade {
+    pub fn new(on_upgrade: impl FnOnce(Upgraded) + Send + 'static) -> Self {
+        Self(Box::new(on_upgrade))
+    }
+
+    pub fn call(self, upgraded: Upgraded) {
+        (self.0)(upgraded)
+    }
+}
+
+impl Debug for OnUpgrade {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        write!(f, "OnUpgrade")
+    }
+}
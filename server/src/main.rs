@@ -2,21 +2,185 @@
 #![allow(unused_imports)]
 #![allow(unused_variables)]
 
-use server::Server;
-use std::{default, env};
+use access_log::{AccessLog, LogFormat};
+use cgi_handler::CgiHandler;
+use config::Config;
+use http::Request;
+use middleware::{Stack, Timing};
+use proxy_handler::{ProxyHandler, UpstreamPool};
+use router::Router;
+use server::{Handler, Server, Tls};
+use std::sync::Arc;
+use std::time::Duration;
+use std::{default, env, process};
+use upload_handler::UploadHandler;
+use virtual_hosts::VirtualHosts;
 use website_handler::WebsiteHandler;
 
+mod access_log;
+mod cgi_handler;
+mod config;
 mod http;
+mod middleware;
+mod proxy_handler;
+mod router;
 mod server;
+mod sse;
+mod thread_pool;
+mod upload_handler;
+mod virtual_hosts;
 mod website_handler;
+mod websocket;
 
 fn main() {
-    let default_path = format!("{}/public", env!("CARGO_MANIFEST_DIR"));
-    let public_path = env::var("PUBLIC_PATH").unwrap_or(default_path);
+    let config = match Config::load(env::args().skip(1)) {
+        Ok(config) => config,
+        Err(config::ConfigError::Help) => {
+            println!("{}", config::ConfigError::Help);
+            return;
+        }
+        Err(e) => {
+            println!("{}", e);
+            process::exit(2);
+        }
+    };
 
-    println!("public path {}", public_path);
-    let server_address: String = String::from("127.0.0.1:8080");
-    let server: Server = Server::new(server_address);
+    println!("public path {}", config.public_path);
+    let mut listen = config.listen.iter().cloned();
+    let mut server = Server::new(listen.next().unwrap_or_default())
+        .threads(config.threads)
+        .queue_size(config.queue_size)
+        .max_upgrades(config.max_upgrades)
+        .keep_alive_timeout(Duration::from_secs(config.keep_alive_timeout))
+        .shutdown_timeout(Duration::from_secs(config.shutdown_timeout))
+        .max_head_size(config.max_head_size)
+        .max_body_size(config.max_body_size)
+        .compression(config.compression);
+    for addr in listen {
+        server = server.listen(addr);
+    }
+    if config.log.enabled {
+        server = server.access_log(access_log(&config));
+    }
+    if !config.tls.listen.is_empty() {
+        server = server
+            .tls(tls(&config))
+            .redirect_to_https(config.tls.redirect_http);
+        for addr in &config.tls.listen {
+            server = server.tls_listen(addr.clone());
+        }
+    }
 
-    server.run(WebsiteHandler::new(public_path));
+    let website = Arc::new(site(&config, &config.public_path, config.directory_listing));
+    let hello = Arc::clone(&website);
+    let mut router = Router::new().get("/hello", move |request: &Request| {
+        hello.serve_file(request, "hello.html")
+    });
+    if let Some(upload) = &config.upload {
+        let uploads = UploadHandler::new(config.public_path.clone(), &upload.dir)
+            .with_max_file_size(upload.max_file_size)
+            .with_max_total_size(upload.max_total_size)
+            .with_max_files(upload.max_files)
+            .with_overwrite(upload.overwrite);
+        router = router.post(&upload.route, uploads);
+    }
+    let router = router.fallback(website);
+
+    let mut sites = VirtualHosts::new().with_default(router);
+    for virtual_host in &config.virtual_host {
+        let directory_listing = virtual_host
+            .directory_listing
+            .unwrap_or(config.directory_listing);
+        let website = site(&config, &virtual_host.public_path, directory_listing);
+        sites = sites.with_host(&virtual_host.hostnames, website);
+    }
+    let mut sites: Arc<dyn Handler> = Arc::new(sites);
+    for cgi in config.cgi.iter().rev() {
+        let mut scripts = CgiHandler::new(&cgi.prefix, &cgi.dir)
+            .with_timeout(Duration::from_secs(cgi.timeout))
+            .with_max_output_size(cgi.max_output_size);
+        if let Some(addr) = &cgi.fastcgi {
+            scripts = scripts.with_fastcgi(addr);
+        }
+        sites = Arc::new(scripts.with_fallback(sites));
+    }
+    let app = Stack::new(proxy(&config).with_fallback(sites)).with(Timing);
+
+    #[cfg(feature = "async")]
+    if env::var("SERVER_MODE").is_ok_and(|mode| mode == "async") {
+        return server.run_async(server::Blocking(app));
+    }
+
+    server.run(app);
+}
+
+fn site(config: &Config, public_path: &str, directory_listing: bool) -> WebsiteHandler {
+    let mut website =
+        WebsiteHandler::new(public_path.to_string()).with_directory_listing(directory_listing);
+    for (extension, mime_type) in &config.mime_types {
+        website = website.with_mime_type(extension, mime_type);
+    }
+    website
+}
+
+fn access_log(config: &Config) -> AccessLog {
+    let log = &config.log;
+    let file = match &log.file {
+        Some(file) => file,
+        None => return AccessLog::stdout(log.format),
+    };
+
+    match AccessLog::file(file, log.format) {
+        Ok(access_log) => {
+            let access_log = access_log.with_daily_rotation(log.daily);
+            match log.max_size {
+                Some(max_size) => access_log.with_max_size(max_size),
+                None => access_log,
+            }
+        }
+        Err(e) => {
+            println!("Failed to open access log {}: {}", file, e);
+            process::exit(1);
+        }
+    }
+}
+
+fn tls(config: &Config) -> Tls {
+    let mut tls = Tls::new();
+    if let (Some(cert), Some(key)) = (&config.tls.cert, &config.tls.key) {
+        tls = tls.with_default(cert, key);
+    }
+    for sni in &config.tls.sni {
+        tls = tls.with_sni(&sni.hostnames, &sni.cert, &sni.key);
+    }
+
+    if let Err(e) = tls.reload() {
+        println!("Failed to load TLS certificates: {}", e);
+        process::exit(1);
+    }
+    tls
+}
+
+fn proxy(config: &Config) -> ProxyHandler {
+    let mut proxy = ProxyHandler::new();
+    for route in &config.proxy {
+        let mut pool = UpstreamPool::new(route.upstreams.iter().cloned())
+            .with_max_fails(route.max_fails)
+            .with_fail_timeout(Duration::from_secs(route.fail_timeout))
+            .with_connect_timeout(Duration::from_secs(route.connect_timeout))
+            .with_timeout(Duration::from_secs(route.timeout))
+            .with_max_body_size(route.max_body_size);
+        if let Some(path) = &route.health_check {
+            let interval = Duration::from_secs(route.health_check_interval);
+            pool = pool.with_health_check(path, interval);
+        }
+
+        proxy = match (&route.host, &route.prefix) {
+            (Some(host), _) => proxy.with_host(host, pool),
+            (None, Some(prefix)) if route.strip_prefix => proxy.with_stripped_prefix(prefix, pool),
+            (None, Some(prefix)) => proxy.with_prefix(prefix, pool),
+            (None, None) => proxy,
+        };
+    }
+    proxy
 }
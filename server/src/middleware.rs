@@ -0,0 +1,188 @@
+use super::http::compression;
+use super::http::{ParseError, Request, Response};
+use super::server::Handler;
+
+pub use basic_auth::BasicAuth;
+pub use cors::Cors;
+
+use std::sync::Arc;
+use std::time::Instant;
+
+mod basic_auth;
+mod cors;
+
+/// Wraps a handler: inspect or answer the request before calling `next`, and
+/// adjust the response it returns.
+pub trait Middleware: Send + Sync {
+    fn handle(&self, request: &Request, next: &dyn Handler) -> Response;
+}
+
+impl<F> Middleware for F
+where
+    F: Fn(&Request, &dyn Handler) -> Response + Send + Sync,
+{
+    fn handle(&self, request: &Request, next: &dyn Handler) -> Response {
+        self(request, next)
+    }
+}
+
+impl<M: Middleware + ?Sized> Middleware for Arc<M> {
+    fn handle(&self, request: &Request, next: &dyn Handler) -> Response {
+        (**self).handle(request, next)
+    }
+}
+
+/// A handler wrapped in middleware. The first middleware added is the
+/// outermost: it sees the request first and the response last.
+pub struct Stack<H> {
+    middleware: Vec<Box<dyn Middleware>>,
+    handler: H,
+}
+
+impl<H: Handler> Stack<H> {
+    pub fn new(handler: H) -> Self {
+        Self {
+            middleware: Vec::new(),
+            handler,
+        }
+    }
+
+    pub fn with(mut self, middleware: impl Middleware + 'static) -> Self {
+        self.middleware.push(Box::new(middleware));
+        self
+    }
+}
+
+impl<H: Handler> Handler for Stack<H> {
+    fn handle_request(&self, request: &Request) -> Response {
+        Next {
+            middleware: &self.middleware,
+            handler: &self.handler,
+        }
+        .handle_request(request)
+    }
+
+    fn streams_body(&self, request: &Request) -> bool {
+        self.handler.streams_body(request)
+    }
+
+    fn handle_bad_request(&self, e: &ParseError) -> Response {
+        self.handler.handle_bad_request(e)
+    }
+}
+
+// The rest of the chain, as seen by one middleware.
+struct Next<'a> {
+    middleware: &'a [Box<dyn Middleware>],
+    handler: &'a dyn Handler,
+}
+
+impl Handler for Next<'_> {
+    fn handle_request(&self, request: &Request) -> Response {
+        match self.middleware.split_first() {
+            Some((first, rest)) => first.handle(
+                request,
+                &Next {
+                    middleware: rest,
+                    handler: self.handler,
+                },
+            ),
+            None => self.handler.handle_request(request),
+        }
+    }
+}
+
+/// Prints one line per request with the status and how long it took.
+pub struct Logger;
+
+impl Middleware for Logger {
+    fn handle(&self, request: &Request, next: &dyn Handler) -> Response {
+        let start = Instant::now();
+        let response = next.handle_request(request);
+        println!(
+            "{} {} -> {} in {:?}",
+            request.method(),
+            request.target(),
+            response.status_code(),
+            start.elapsed()
+        );
+        response
+    }
+}
+
+/// Reports the time spent in the wrapped handler in a `Server-Timing` header.
+pub struct Timing;
+
+impl Middleware for Timing {
+    fn handle(&self, request: &Request, next: &dyn Handler) -> Response {
+        let start = Instant::now();
+        let mut response = next.handle_request(request);
+        let millis = start.elapsed().as_secs_f64() * 1000.0;
+        response.add_header("Server-Timing", format!("app;dur={:.3}", millis));
+        response
+    }
+}
+
+/// Compresses responses of the wrapped handler, for when `Server` compression
+/// is turned off or only part of the site should be compressed.
+pub struct Compression;
+
+impl Middleware for Compression {
+    fn handle(&self, request: &Request, next: &dyn Handler) -> Response {
+        let mut response = next.handle_request(request);
+        compression::compress_response(&mut response, request);
+        response
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http::StatusCode;
+    use std::convert::TryFrom;
+
+    // Appends its tag to a header on the way in and out.
+    fn tag(tag: &'static str) -> impl Middleware {
+        move |request: &Request, next: &dyn Handler| {
+            let mut response = next.handle_request(request);
+            let order = response.header("X-Order").unwrap_or("").to_string();
+            response.set_header("X-Order", format!("{}{}", order, tag));
+            response
+        }
+    }
+
+    #[test]
+    fn runs_the_first_middleware_outermost() {
+        let stack = Stack::new(|_: &Request| Response::new(StatusCode::Ok, None))
+            .with(tag("a"))
+            .with(tag("b"));
+        let request = Request::try_from(&b"GET / HTTP/1.1\r\n\r\n"[..]).unwrap();
+
+        let response = stack.handle_request(&request);
+        assert_eq!(response.header("X-Order"), Some("ba"));
+    }
+
+    #[test]
+    fn lets_middleware_answer_early() {
+        let stack = Stack::new(|_: &Request| -> Response { unreachable!() })
+            .with(|_: &Request, _: &dyn Handler| Response::new(StatusCode::Forbidden, None))
+            .with(Timing);
+        let request = Request::try_from(&b"GET / HTTP/1.1\r\n\r\n"[..]).unwrap();
+
+        let response = stack.handle_request(&request);
+        assert_eq!(response.status_code(), StatusCode::Forbidden);
+        assert!(response.header("Server-Timing").is_none());
+    }
+
+    #[test]
+    fn reports_timing() {
+        let stack = Stack::new(|_: &Request| Response::new(StatusCode::Ok, None)).with(Timing);
+        let request = Request::try_from(&b"GET / HTTP/1.1\r\n\r\n"[..]).unwrap();
+
+        let response = stack.handle_request(&request);
+        assert!(response
+            .header("Server-Timing")
+            .unwrap()
+            .starts_with("app;dur="));
+    }
+}
@@ -0,0 +1,127 @@
+use super::Middleware;
+use crate::http::{base64, Request, Response, StatusCode};
+use crate::server::Handler;
+
+/// Lets requests through only with `Authorization: Basic` credentials of a
+/// known user; everyone else gets 401 with a challenge for `realm`.
+pub struct BasicAuth {
+    realm: String,
+    users: Vec<(String, String)>,
+}
+
+impl BasicAuth {
+    pub fn new(realm: &str) -> Self {
+        Self {
+            realm: realm.to_string(),
+            users: Vec::new(),
+        }
+    }
+
+    pub fn with_user(mut self, user: &str, password: &str) -> Self {
+        self.users.push((user.to_string(), password.to_string()));
+        self
+    }
+
+    fn is_authorized(&self, request: &Request) -> bool {
+        let credentials = request
+            .headers()
+            .get("Authorization")
+            .and_then(|value| value.split_once(' '))
+            .filter(|(scheme, _)| scheme.eq_ignore_ascii_case("Basic"))
+            .and_then(|(_, credentials)| base64::decode(credentials.trim()))
+            .and_then(|credentials| String::from_utf8(credentials).ok());
+        let credentials = match credentials {
+            Some(credentials) => credentials,
+            None => return false,
+        };
+        let (user, password) = match credentials.split_once(':') {
+            Some(pair) => pair,
+            None => return false,
+        };
+
+        // check every entry so the timing does not reveal which user exists
+        self.users
+            .iter()
+            .fold(false, |found, (known_user, known_password)| {
+                let matches = constant_time_eq(user.as_bytes(), known_user.as_bytes())
+                    & constant_time_eq(password.as_bytes(), known_password.as_bytes());
+                found | matches
+            })
+    }
+}
+
+impl Middleware for BasicAuth {
+    fn handle(&self, request: &Request, next: &dyn Handler) -> Response {
+        if self.is_authorized(request) {
+            return next.handle_request(request);
+        }
+
+        Response::error(StatusCode::Unauthorized).with_header(
+            "WWW-Authenticate",
+            format!(
+                "Basic realm=\"{}\", charset=\"UTF-8\"",
+                self.realm.replace(['\\', '"'], "")
+            ),
+        )
+    }
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::convert::TryFrom;
+
+    fn handle(auth: &BasicAuth, authorization: Option<&str>) -> Response {
+        let head = match authorization {
+            Some(value) => format!("GET / HTTP/1.1\r\nAuthorization: {}\r\n\r\n", value),
+            None => "GET / HTTP/1.1\r\n\r\n".to_string(),
+        };
+        let request = Request::try_from(head.as_bytes()).unwrap();
+        auth.handle(&request, &|_: &Request| Response::new(StatusCode::Ok, None))
+    }
+
+    #[test]
+    fn lets_known_users_through() {
+        let auth = BasicAuth::new("admin").with_user("alice", "secret:with:colons");
+        let credentials = format!("basic {}", base64::encode(b"alice:secret:with:colons"));
+        assert_eq!(
+            handle(&auth, Some(&credentials)).status_code(),
+            StatusCode::Ok
+        );
+    }
+
+    #[test]
+    fn challenges_everyone_else() {
+        let auth = BasicAuth::new("a \"quoted\" realm").with_user("alice", "secret");
+
+        for authorization in [
+            None,
+            Some(format!("Basic {}", base64::encode(b"alice:wrong"))),
+            Some(format!("Basic {}", base64::encode(b"bob:secret"))),
+            Some(format!("Basic {}", base64::encode(b"alice"))),
+            Some(format!("Bearer {}", base64::encode(b"alice:secret"))),
+            Some("Basic !!!".to_string()),
+        ] {
+            let response = handle(&auth, authorization.as_deref());
+            assert_eq!(response.status_code(), StatusCode::Unauthorized);
+            assert_eq!(
+                response.header("WWW-Authenticate"),
+                Some("Basic realm=\"a quoted realm\", charset=\"UTF-8\"")
+            );
+        }
+    }
+
+    #[test]
+    fn compares_whole_values() {
+        assert!(constant_time_eq(b"abc", b"abc"));
+        assert!(!constant_time_eq(b"abc", b"abd"));
+        assert!(!constant_time_eq(b"abc", b"ab"));
+    }
+}
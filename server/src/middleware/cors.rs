@@ -0,0 +1,257 @@
+use super::Middleware;
+use crate::http::{Method, Request, Response, StatusCode};
+use crate::server::Handler;
+
+use std::time::Duration;
+
+const DEFAULT_METHODS: &str = "GET, HEAD, POST";
+// allowed in preflight answers unless `with_header` names others
+const DEFAULT_HEADERS: &str = "Accept, Accept-Language, Content-Language, Content-Type";
+
+/// Adds the CORS headers that let pages from the allowed origins read
+/// responses, and answers preflight requests itself.
+pub struct Cors {
+    origins: Vec<String>,
+    methods: Vec<Method>,
+    headers: Vec<String>,
+    expose_headers: Vec<String>,
+    credentials: bool,
+    max_age: Option<Duration>,
+}
+
+impl Cors {
+    /// Allows no origin until `with_origin` is called.
+    pub fn new() -> Self {
+        Self {
+            origins: Vec::new(),
+            methods: Vec::new(),
+            headers: Vec::new(),
+            expose_headers: Vec::new(),
+            credentials: false,
+            max_age: None,
+        }
+    }
+
+    /// An origin such as `https://example.com`, or `*` for any. With
+    /// credentials, `*` allows none: those origins must be listed.
+    pub fn with_origin(mut self, origin: &str) -> Self {
+        self.origins.push(origin.to_string());
+        self
+    }
+
+    /// Methods allowed in preflight answers; GET, HEAD and POST by default.
+    pub fn with_method(mut self, method: Method) -> Self {
+        self.methods.push(method);
+        self
+    }
+
+    /// A request header the client may send, e.g. `Content-Type`. Without
+    /// any, preflight answers allow a few common ones.
+    pub fn with_header(mut self, header: &str) -> Self {
+        self.headers.push(header.to_string());
+        self
+    }
+
+    /// A response header scripts may read beyond the safelisted ones.
+    pub fn with_expose_header(mut self, header: &str) -> Self {
+        self.expose_headers.push(header.to_string());
+        self
+    }
+
+    /// Whether cookies and `Authorization` may be included. Only explicitly
+    /// listed origins are then allowed, since browsers refuse `*` for them.
+    pub fn with_credentials(mut self, credentials: bool) -> Self {
+        self.credentials = credentials;
+        self
+    }
+
+    /// How long browsers may cache a preflight answer.
+    pub fn with_max_age(mut self, max_age: Duration) -> Self {
+        self.max_age = Some(max_age);
+        self
+    }
+
+    fn allow_origin<'a>(&self, origin: &'a str) -> Option<&'a str> {
+        if self.origins.iter().any(|allowed| allowed == origin) {
+            Some(origin)
+        } else if !self.credentials && self.origins.iter().any(|allowed| allowed == "*") {
+            Some("*")
+        } else {
+            None
+        }
+    }
+
+    fn preflight(&self) -> Response {
+        let methods = if self.methods.is_empty() {
+            DEFAULT_METHODS.to_string()
+        } else {
+            let methods: Vec<&str> = self.methods.iter().map(|method| method.as_str()).collect();
+            methods.join(", ")
+        };
+
+        let headers = if self.headers.is_empty() {
+            DEFAULT_HEADERS.to_string()
+        } else {
+            self.headers.join(", ")
+        };
+
+        let mut response = Response::new(StatusCode::NoContent, None)
+            .with_header("Access-Control-Allow-Methods", methods)
+            .with_header("Access-Control-Allow-Headers", headers);
+        if let Some(max_age) = self.max_age {
+            response.set_header("Access-Control-Max-Age", max_age.as_secs().to_string());
+        }
+        response
+    }
+}
+
+impl Default for Cors {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Middleware for Cors {
+    fn handle(&self, request: &Request, next: &dyn Handler) -> Response {
+        let origin = match request.headers().get("Origin") {
+            Some(origin) => origin,
+            None => return next.handle_request(request),
+        };
+        let allowed = match self.allow_origin(origin) {
+            Some(allowed) => allowed,
+            None => {
+                let mut response = next.handle_request(request);
+                response.add_vary("Origin");
+                return response;
+            }
+        };
+
+        let is_preflight = *request.method() == Method::OPTIONS
+            && request.headers().contains("Access-Control-Request-Method");
+        let mut response = if is_preflight {
+            self.preflight()
+        } else {
+            next.handle_request(request)
+        };
+
+        response.set_header("Access-Control-Allow-Origin", allowed);
+        if allowed != "*" {
+            response.add_vary("Origin");
+        }
+        if self.credentials {
+            response.set_header("Access-Control-Allow-Credentials", "true");
+        }
+        if !is_preflight && !self.expose_headers.is_empty() {
+            response.set_header(
+                "Access-Control-Expose-Headers",
+                self.expose_headers.join(", "),
+            );
+        }
+        response
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::convert::TryFrom;
+
+    fn ok(_: &Request) -> Response {
+        Response::new(StatusCode::Ok, None)
+    }
+
+    fn handle(cors: &Cors, head: &str) -> Response {
+        let head = format!("{}\r\n\r\n", head);
+        cors.handle(&Request::try_from(head.as_bytes()).unwrap(), &ok)
+    }
+
+    #[test]
+    fn allows_listed_origins() {
+        let cors = Cors::new()
+            .with_origin("https://a.example")
+            .with_expose_header("X-Total");
+
+        let response = handle(&cors, "GET / HTTP/1.1\r\nOrigin: https://a.example");
+        assert_eq!(
+            response.header("Access-Control-Allow-Origin"),
+            Some("https://a.example")
+        );
+        assert_eq!(
+            response.header("Access-Control-Expose-Headers"),
+            Some("X-Total")
+        );
+        assert_eq!(response.header("Vary"), Some("Origin"));
+
+        let response = handle(&cors, "GET / HTTP/1.1\r\nOrigin: https://b.example");
+        assert_eq!(response.header("Access-Control-Allow-Origin"), None);
+        assert_eq!(response.header("Vary"), Some("Origin"));
+
+        let response = handle(&cors, "GET / HTTP/1.1");
+        assert_eq!(response.header("Vary"), None);
+    }
+
+    #[test]
+    fn allows_any_origin_without_credentials() {
+        let cors = Cors::new().with_origin("*");
+        let response = handle(&cors, "GET / HTTP/1.1\r\nOrigin: https://a.example");
+        assert_eq!(response.header("Access-Control-Allow-Origin"), Some("*"));
+        assert_eq!(response.header("Vary"), None);
+    }
+
+    #[test]
+    fn allows_only_listed_origins_with_credentials() {
+        let cors = Cors::new()
+            .with_origin("*")
+            .with_origin("https://a.example")
+            .with_credentials(true);
+
+        let response = handle(&cors, "GET / HTTP/1.1\r\nOrigin: https://evil.example");
+        assert_eq!(response.header("Access-Control-Allow-Origin"), None);
+        assert_eq!(response.header("Access-Control-Allow-Credentials"), None);
+
+        let response = handle(&cors, "GET / HTTP/1.1\r\nOrigin: https://a.example");
+        assert_eq!(
+            response.header("Access-Control-Allow-Origin"),
+            Some("https://a.example")
+        );
+        assert_eq!(
+            response.header("Access-Control-Allow-Credentials"),
+            Some("true")
+        );
+    }
+
+    #[test]
+    fn answers_preflights_with_fixed_lists() {
+        let cors = Cors::new()
+            .with_origin("https://a.example")
+            .with_max_age(Duration::from_secs(600));
+        let preflight = "OPTIONS /api HTTP/1.1\r\nOrigin: https://a.example\r\n\
+            Access-Control-Request-Method: PUT\r\nAccess-Control-Request-Headers: X-Anything";
+
+        let response = handle(&cors, preflight);
+        assert_eq!(response.status_code(), StatusCode::NoContent);
+        assert_eq!(
+            response.header("Access-Control-Allow-Methods"),
+            Some(DEFAULT_METHODS)
+        );
+        assert_eq!(
+            response.header("Access-Control-Allow-Headers"),
+            Some(DEFAULT_HEADERS)
+        );
+        assert_eq!(response.header("Access-Control-Max-Age"), Some("600"));
+
+        let cors = cors
+            .with_method(Method::PUT)
+            .with_method(Method::DELETE)
+            .with_header("X-Token");
+        let response = handle(&cors, preflight);
+        assert_eq!(
+            response.header("Access-Control-Allow-Methods"),
+            Some("PUT, DELETE")
+        );
+        assert_eq!(
+            response.header("Access-Control-Allow-Headers"),
+            Some("X-Token")
+        );
+    }
+}
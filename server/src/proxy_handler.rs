@@ -0,0 +1,753 @@
+use super::http::body::encode_chunk;
+use super::http::chunked::ChunkedReader;
+use super::http::reader::{find_head_end, BodyLength};
+use super::http::request::matches_host;
+use super::http::{Body, BodyReader, Headers, Method, Request, Response, StatusCode};
+use super::server::Handler;
+
+use std::convert::TryFrom;
+use std::io::{BufReader, Cursor, Error as IoError, ErrorKind, Read, Result as IoResult, Write};
+use std::net::TcpStream;
+use std::str;
+use std::sync::Arc;
+use upstream::Upstream;
+
+pub use upstream::{
+    UpstreamPool, DEFAULT_CONNECT_TIMEOUT, DEFAULT_FAIL_TIMEOUT, DEFAULT_MAX_BODY_SIZE,
+    DEFAULT_MAX_FAILS, DEFAULT_TIMEOUT,
+};
+
+mod upstream;
+
+const MAX_RESPONSE_HEAD_SIZE: usize = 64 * 1024;
+const READ_CHUNK_SIZE: usize = 8192;
+
+// Headers that only concern one connection and are not passed on (RFC 9110,
+// section 7.6.1), along with those the proxy sets itself.
+const NOT_FORWARDED: [&str; 11] = [
+    "Connection",
+    "Keep-Alive",
+    "Proxy-Connection",
+    "Proxy-Authenticate",
+    "Proxy-Authorization",
+    "TE",
+    "Trailer",
+    "Transfer-Encoding",
+    "Upgrade",
+    "Content-Length",
+    "Expect",
+];
+
+/// Forwards requests to upstream HTTP servers, chosen by `Host` or by path
+/// prefix. Request and response bodies are passed on as they arrive, and
+/// are left uncompressed.
+pub struct ProxyHandler {
+    routes: Vec<ProxyRoute>,
+    fallback: Option<Arc<dyn Handler>>,
+}
+
+struct ProxyRoute {
+    // lowercase, `*.example.com` matches any single label
+    host: Option<String>,
+    prefix: String,
+    strip_prefix: bool,
+    pool: Arc<UpstreamPool>,
+}
+
+impl ProxyHandler {
+    pub fn new() -> Self {
+        Self {
+            routes: Vec::new(),
+            fallback: None,
+        }
+    }
+
+    /// Forwards requests for `prefix` and the paths below it to `pool`.
+    pub fn with_prefix(self, prefix: &str, pool: UpstreamPool) -> Self {
+        self.route(None, prefix, false, pool)
+    }
+
+    /// Like `with_prefix`, but the upstream gets the path without the prefix,
+    /// e.g. `/api/users` becomes `/users`.
+    pub fn with_stripped_prefix(self, prefix: &str, pool: UpstreamPool) -> Self {
+        self.route(None, prefix, true, pool)
+    }
+
+    /// Forwards every request for `host` to `pool`. Host routes are tried
+    /// before prefix routes.
+    pub fn with_host(self, host: &str, pool: UpstreamPool) -> Self {
+        self.route(Some(host), "/", false, pool)
+    }
+
+    /// Handles requests that no route matches, instead of answering 404.
+    pub fn with_fallback(mut self, handler: impl Handler + 'static) -> Self {
+        self.fallback = Some(Arc::new(handler));
+        self
+    }
+
+    fn route(
+        mut self,
+        host: Option<&str>,
+        prefix: &str,
+        strip_prefix: bool,
+        pool: UpstreamPool,
+    ) -> Self {
+        let pool = Arc::new(pool);
+        pool.start_health_checks();
+
+        let prefix = match prefix.trim_end_matches('/') {
+            "" => "/".to_string(),
+            prefix => prefix.to_string(),
+        };
+        self.routes.push(ProxyRoute {
+            host: host.map(|host| host.to_ascii_lowercase()),
+            prefix,
+            strip_prefix,
+            pool,
+        });
+        self
+    }
+
+    // Host routes first, then the longest matching prefix.
+    fn find_route(&self, request: &Request) -> Option<&ProxyRoute> {
+        let host = request.host().map(str::to_ascii_lowercase);
+        let by_host = self.routes.iter().find(|route| match (&route.host, &host) {
+            (Some(pattern), Some(host)) => matches_host(pattern, host),
+            _ => false,
+        });
+
+        by_host.or_else(|| {
+            self.routes
+                .iter()
+                .filter(|route| route.host.is_none() && route.matches_path(request.path()))
+                .max_by_key(|route| route.prefix.len())
+        })
+    }
+
+    // Sends the request and reads the head of the response; the body is read
+    // as the server sends it on.
+    fn forward(
+        &self,
+        mut stream: TcpStream,
+        head: &str,
+        request: &Request,
+        body: RequestBody,
+        max_body_size: u64,
+    ) -> Result<Response, Failure> {
+        stream.write_all(head.as_bytes())?;
+        send_body(&mut stream, body, max_body_size)?;
+        stream.flush()?;
+
+        let mut buf = Vec::new();
+        loop {
+            let end = read_head(&mut stream, &mut buf)?;
+            let (status_code, headers) = parse_head(&buf[..end])?;
+            // interim responses such as 103 Early Hints are not passed on
+            if status_code.is_informational() {
+                buf.drain(..end);
+                continue;
+            }
+
+            // compressing would mean reading the whole body first
+            let mut response = Response::new(status_code, None);
+            response.set_allows_compression(false);
+            let connection_headers = headers.get_all("Connection").collect::<Vec<_>>().join(",");
+            for (name, value) in headers.iter() {
+                if !is_forwarded(name, &connection_headers) {
+                    continue;
+                }
+                response.add_header(name, value);
+            }
+            let chunked = headers.contains_token("Transfer-Encoding", "chunked");
+            let content_length = headers
+                .get("Content-Length")
+                .map(|len| len.trim().to_string());
+
+            let body = BufReader::new(Cursor::new(buf.split_off(end)).chain(stream));
+            let no_body = matches!(request.method(), Method::HEAD) || !status_code.allows_body();
+
+            if no_body {
+                // tells a HEAD request how long the body would be
+                if let (Some(len), true) = (content_length, status_code.allows_body()) {
+                    response.set_header("Content-Length", &len);
+                }
+            } else if chunked {
+                response.set_body(Body::stream(ChunkedReader::new(body)));
+            } else if let Some(len) = content_length {
+                let len = len
+                    .parse()
+                    .map_err(|_| invalid("invalid Content-Length from upstream"))?;
+                response.set_body(Body::Reader(Box::new(body), len));
+            } else {
+                // the body ends when the upstream closes the connection
+                response.set_body(Body::stream(body));
+            }
+            return Ok(response);
+        }
+    }
+}
+
+impl Default for ProxyHandler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Handler for ProxyHandler {
+    fn streams_body(&self, request: &Request) -> bool {
+        match (self.find_route(request), &self.fallback) {
+            (Some(_), _) => true,
+            (None, Some(fallback)) => fallback.streams_body(request),
+            (None, None) => false,
+        }
+    }
+
+    fn handle_request(&self, request: &Request) -> Response {
+        let route = match self.find_route(request) {
+            Some(route) => route,
+            None => {
+                return match &self.fallback {
+                    Some(fallback) => fallback.handle_request(request),
+                    None => Response::error(StatusCode::NotFound),
+                }
+            }
+        };
+        let target = route.upstream_target(request);
+        let max_body_size = route.pool.max_body_size();
+        let body = match RequestBody::new(request, max_body_size) {
+            Ok(body) => body,
+            Err(response) => return response,
+        };
+
+        for upstream in route.pool.candidates() {
+            let stream = match route.pool.connect(upstream) {
+                Ok(stream) => stream,
+                Err(e) => {
+                    println!("Failed to connect to upstream {}: {}", upstream.addr(), e);
+                    route.pool.report_failure(upstream);
+                    continue;
+                }
+            };
+
+            // only failed connections are retried: once the request is sent,
+            // the upstream may have acted on it
+            let head = request_head(request, &target, upstream, &body);
+            return match self.forward(stream, &head, request, body, max_body_size) {
+                Ok(response) => {
+                    route.pool.report_success(upstream);
+                    response
+                }
+                Err(Failure::TooLarge) => Response::error(StatusCode::PayloadTooLarge),
+                Err(Failure::Client(e)) => {
+                    println!("Failed to read request body: {}", e);
+                    Response::error(StatusCode::BadRequest)
+                }
+                Err(Failure::Upstream(e)) => {
+                    println!("Upstream {} failed: {}", upstream.addr(), e);
+                    route.pool.report_failure(upstream);
+                    match e.kind() {
+                        ErrorKind::WouldBlock | ErrorKind::TimedOut => {
+                            Response::error(StatusCode::GatewayTimeout)
+                        }
+                        _ => Response::error(StatusCode::BadGateway),
+                    }
+                }
+            };
+        }
+        Response::error_with_message(StatusCode::BadGateway, "No upstream server is reachable")
+    }
+}
+
+impl ProxyRoute {
+    // `/api` matches `/api` and `/api/users`, but not `/apis`.
+    fn matches_path(&self, path: &str) -> bool {
+        self.prefix == "/"
+            || path
+                .strip_prefix(&self.prefix)
+                .is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+    }
+
+    // The request target (path and query) as the upstream gets it.
+    fn upstream_target<'a>(&self, request: &Request<'a>) -> String {
+        let target = request.target();
+        if !self.strip_prefix || self.prefix == "/" {
+            return target.to_string();
+        }
+
+        match target.strip_prefix(&self.prefix) {
+            Some(rest) if rest.starts_with('/') => rest.to_string(),
+            Some(rest) => format!("/{}", rest),
+            // the prefix was percent-encoded in the request
+            None => target.to_string(),
+        }
+    }
+}
+
+// How the request body gets to the upstream.
+enum RequestBody<'a> {
+    // read by the server already
+    Buffered(&'a [u8]),
+    Fixed(BodyReader<'a>, u64),
+    // decoded as read and encoded again for the upstream
+    Chunked(BodyReader<'a>),
+}
+
+impl<'a> RequestBody<'a> {
+    // Bodies announced larger than `max_body_size` get a response right away.
+    fn new(request: &Request<'a>, max_body_size: u64) -> Result<Self, Response> {
+        let too_large = Response::error(StatusCode::PayloadTooLarge);
+        let reader = match request.body_reader() {
+            Some(reader) => reader,
+            None if request.body().len() as u64 > max_body_size => return Err(too_large),
+            None => return Ok(Self::Buffered(request.body())),
+        };
+
+        match BodyLength::try_from(request.headers()) {
+            Ok(BodyLength::None) => Ok(Self::Buffered(&[])),
+            Ok(BodyLength::Fixed(len)) if len as u64 > max_body_size => Err(too_large),
+            Ok(BodyLength::Fixed(len)) => Ok(Self::Fixed(reader, len as u64)),
+            Ok(BodyLength::Chunked) => Ok(Self::Chunked(reader)),
+            Err(e) => Err(Response::from(&e)),
+        }
+    }
+}
+
+// Why a request could not be forwarded.
+enum Failure {
+    TooLarge,
+    // reading the body from the client failed, the upstream is not to blame
+    Client(IoError),
+    Upstream(IoError),
+}
+
+impl From<IoError> for Failure {
+    fn from(e: IoError) -> Self {
+        Self::Upstream(e)
+    }
+}
+
+fn send_body(stream: &mut TcpStream, body: RequestBody, max_body_size: u64) -> Result<(), Failure> {
+    let (mut reader, chunked) = match body {
+        RequestBody::Buffered(body) => return Ok(stream.write_all(body)?),
+        RequestBody::Fixed(reader, _) => (reader, false),
+        RequestBody::Chunked(reader) => (reader, true),
+    };
+
+    let mut buf = [0; READ_CHUNK_SIZE];
+    let mut sent = 0;
+    loop {
+        let len = reader.read(&mut buf).map_err(Failure::Client)?;
+        sent += len as u64;
+        if sent > max_body_size {
+            return Err(Failure::TooLarge);
+        }
+        // the empty chunk ends a chunked body
+        if chunked {
+            stream.write_all(&encode_chunk(&buf[..len]))?;
+        } else {
+            stream.write_all(&buf[..len])?;
+        }
+        if len == 0 {
+            return Ok(());
+        }
+    }
+}
+
+fn request_head(
+    request: &Request,
+    target: &str,
+    upstream: &Upstream,
+    body: &RequestBody,
+) -> String {
+    let headers = request.headers();
+    let connection_headers = headers.get_all("Connection").collect::<Vec<_>>().join(",");
+    let mut head = format!("{} {} HTTP/1.1\r\n", request.method(), target);
+
+    for (name, value) in headers.iter() {
+        let set_below = ["X-Forwarded-For", "X-Forwarded-Proto", "X-Forwarded-Host"]
+            .iter()
+            .any(|header| name.eq_ignore_ascii_case(header));
+        if set_below || !is_forwarded(name, &connection_headers) {
+            continue;
+        }
+        head.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    if !headers.contains("Host") {
+        head.push_str(&format!("Host: {}\r\n", upstream.addr()));
+    }
+
+    // earlier proxies' addresses come first
+    let forwarded_for = headers.get_all("X-Forwarded-For").collect::<Vec<_>>();
+    if let Some(remote_addr) = request.remote_addr() {
+        let mut forwarded_for = forwarded_for.join(", ");
+        if !forwarded_for.is_empty() {
+            forwarded_for.push_str(", ");
+        }
+        forwarded_for.push_str(&remote_addr.ip().to_string());
+        head.push_str(&format!("X-Forwarded-For: {}\r\n", forwarded_for));
+    }
+    let proto = if request.is_secure() { "https" } else { "http" };
+    head.push_str(&format!("X-Forwarded-Proto: {}\r\n", proto));
+    if let Some(host) = headers.get("Host") {
+        head.push_str(&format!("X-Forwarded-Host: {}\r\n", host));
+    }
+
+    match body {
+        RequestBody::Buffered(body) if !body.is_empty() || headers.contains("Content-Length") => {
+            head.push_str(&format!("Content-Length: {}\r\n", body.len()));
+        }
+        RequestBody::Buffered(_) => {}
+        RequestBody::Fixed(_, len) => head.push_str(&format!("Content-Length: {}\r\n", len)),
+        RequestBody::Chunked(_) => head.push_str("Transfer-Encoding: chunked\r\n"),
+    }
+    // one request per connection keeps reading the response simple
+    head.push_str("Connection: close\r\n\r\n");
+    head
+}
+
+// Hop-by-hop headers and those listed in `Connection` stay behind.
+fn is_forwarded(name: &str, connection_headers: &str) -> bool {
+    !NOT_FORWARDED
+        .iter()
+        .any(|header| name.eq_ignore_ascii_case(header))
+        && !connection_headers
+            .split(',')
+            .any(|header| header.trim().eq_ignore_ascii_case(name))
+}
+
+// Reads until `buf` holds a whole response head and returns where it ends.
+fn read_head(stream: &mut TcpStream, buf: &mut Vec<u8>) -> IoResult<usize> {
+    let mut searched = 0;
+    loop {
+        if let Some(end) = find_head_end(buf, searched) {
+            return Ok(end);
+        }
+        if buf.len() > MAX_RESPONSE_HEAD_SIZE {
+            return Err(invalid("response head from upstream too large"));
+        }
+
+        searched = buf.len().saturating_sub(3);
+        let mut chunk = [0; READ_CHUNK_SIZE];
+        let len = stream.read(&mut chunk)?;
+        if len == 0 {
+            return Err(IoError::new(
+                ErrorKind::UnexpectedEof,
+                "upstream closed the connection before responding",
+            ));
+        }
+        buf.extend_from_slice(&chunk[..len]);
+    }
+}
+
+// HTTP/1.1 200 OK\r\nContent-Type: text/html\r\n...
+fn parse_head(head: &[u8]) -> IoResult<(StatusCode, Headers<'_>)> {
+    let head = str::from_utf8(head).map_err(|_| invalid("response head is not UTF-8"))?;
+    let (status_line, headers) = head.split_once("\r\n").unwrap_or((head, ""));
+
+    let mut parts = status_line.splitn(3, ' ');
+    let version = parts.next().unwrap_or("");
+    let status_code = parts
+        .next()
+        .and_then(|code| code.parse().ok())
+        .and_then(StatusCode::from_u16);
+    let status_code = match status_code {
+        Some(status_code) if version.starts_with("HTTP/1.") => status_code,
+        _ => return Err(invalid("invalid status line from upstream")),
+    };
+
+    let headers =
+        Headers::try_from(headers).map_err(|_| invalid("invalid header from upstream"))?;
+    Ok((status_code, headers))
+}
+
+fn invalid(message: &str) -> IoError {
+    IoError::new(ErrorKind::InvalidData, message.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+    use std::sync::mpsc::{self, Receiver};
+    use std::sync::Mutex;
+    use std::thread;
+    use std::time::Duration;
+
+    // An upstream that answers every request with `response` and passes on
+    // the requests it got.
+    fn stub(response: &'static str) -> (String, Receiver<Vec<u8>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        (addr, serve(listener, response))
+    }
+
+    fn serve(listener: TcpListener, response: &'static str) -> Receiver<Vec<u8>> {
+        let (sender, receiver) = mpsc::channel();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let request = read_request(&mut stream);
+                let _ = stream.write_all(response.as_bytes());
+                let _ = sender.send(request);
+            }
+        });
+        receiver
+    }
+
+    fn read_request(stream: &mut TcpStream) -> Vec<u8> {
+        let mut buf = Vec::new();
+        let mut chunk = [0; READ_CHUNK_SIZE];
+        loop {
+            if let Some(end) = find_head_end(&buf, 0) {
+                let head = str::from_utf8(&buf[..end]).unwrap().to_ascii_lowercase();
+                let complete = match head.split("content-length: ").nth(1) {
+                    Some(len) => {
+                        buf.len() - end >= len.split("\r\n").next().unwrap().parse().unwrap()
+                    }
+                    None if head.contains("transfer-encoding: chunked") => {
+                        buf.ends_with(b"\r\n0\r\n\r\n")
+                    }
+                    None => true,
+                };
+                if complete {
+                    return buf;
+                }
+            }
+            match stream.read(&mut chunk).unwrap() {
+                0 => return buf,
+                len => buf.extend_from_slice(&chunk[..len]),
+            }
+        }
+    }
+
+    fn free_addr() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        listener.local_addr().unwrap().to_string()
+    }
+
+    fn get(proxy: &ProxyHandler, head: &str) -> Response {
+        proxy.handle_request(&Request::try_from(head.as_bytes()).unwrap())
+    }
+
+    fn body(mut response: Response) -> String {
+        let mut body = Vec::new();
+        match response.take_body() {
+            Body::Empty => {}
+            Body::Bytes(bytes) => body = bytes,
+            Body::Reader(mut reader, _) | Body::Stream(mut reader) => {
+                reader.read_to_end(&mut body).unwrap();
+            }
+        }
+        String::from_utf8(body).unwrap()
+    }
+
+    fn received(requests: &Receiver<Vec<u8>>) -> String {
+        let request = requests.recv_timeout(Duration::from_secs(5)).unwrap();
+        String::from_utf8(request).unwrap()
+    }
+
+    const OK: &str = "HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok";
+
+    #[test]
+    fn upstreams_take_turns() {
+        let (a, _) = stub("HTTP/1.1 200 OK\r\nContent-Length: 1\r\n\r\na");
+        let (b, _) = stub("HTTP/1.1 200 OK\r\nContent-Length: 1\r\n\r\nb");
+        let proxy = ProxyHandler::new().with_prefix("/", UpstreamPool::new([a, b]));
+
+        let bodies: Vec<String> = (0..4)
+            .map(|_| body(get(&proxy, "GET / HTTP/1.1\r\n\r\n")))
+            .collect();
+        assert_eq!(bodies, ["a", "b", "a", "b"]);
+    }
+
+    #[test]
+    fn skips_failed_upstreams_until_the_fail_timeout() {
+        let down = free_addr();
+        let (b, _) = stub("HTTP/1.1 200 OK\r\nContent-Length: 1\r\n\r\nb");
+        let pool = UpstreamPool::new([down.clone(), b])
+            .with_max_fails(1)
+            .with_fail_timeout(Duration::from_millis(300));
+        let proxy = ProxyHandler::new().with_prefix("/", pool);
+
+        // the first upstream is tried and fails, the second answers
+        assert_eq!(body(get(&proxy, "GET / HTTP/1.1\r\n\r\n")), "b");
+
+        // back up, but left out until the fail timeout is over
+        let _a = serve(
+            TcpListener::bind(&down).unwrap(),
+            "HTTP/1.1 200 OK\r\nContent-Length: 1\r\n\r\na",
+        );
+        assert_eq!(body(get(&proxy, "GET / HTTP/1.1\r\n\r\n")), "b");
+        assert_eq!(body(get(&proxy, "GET / HTTP/1.1\r\n\r\n")), "b");
+
+        thread::sleep(Duration::from_millis(400));
+        assert_eq!(body(get(&proxy, "GET / HTTP/1.1\r\n\r\n")), "b");
+        assert_eq!(body(get(&proxy, "GET / HTTP/1.1\r\n\r\n")), "a");
+    }
+
+    #[test]
+    fn answers_502_when_no_upstream_is_reachable() {
+        let proxy = ProxyHandler::new().with_prefix("/", UpstreamPool::new([free_addr()]));
+        let response = get(&proxy, "GET / HTTP/1.1\r\n\r\n");
+        assert_eq!(response.status_code(), StatusCode::BadGateway);
+    }
+
+    #[test]
+    fn sets_x_forwarded_headers() {
+        let (addr, requests) = stub(OK);
+        let proxy = ProxyHandler::new().with_prefix("/", UpstreamPool::new([addr]));
+
+        let head = "GET / HTTP/1.1\r\nHost: example.com\r\nX-Forwarded-For: 10.0.0.1\r\n\
+                    X-Forwarded-Proto: http\r\nX-Forwarded-Host: evil.example\r\n\r\n";
+        let mut request = Request::try_from(head.as_bytes()).unwrap();
+        request.set_remote_addr("192.0.2.7:1234".parse().unwrap());
+        request.set_secure(true);
+        proxy.handle_request(&request);
+
+        let request = received(&requests);
+        assert!(request.contains("\r\nHost: example.com\r\n"));
+        assert!(request.contains("\r\nX-Forwarded-For: 10.0.0.1, 192.0.2.7\r\n"));
+        assert!(request.contains("\r\nX-Forwarded-Proto: https\r\n"));
+        assert!(request.contains("\r\nX-Forwarded-Host: example.com\r\n"));
+        assert!(!request.contains("http\r\n"));
+        assert!(!request.contains("evil.example"));
+    }
+
+    #[test]
+    fn strips_hop_by_hop_headers() {
+        let (addr, requests) = stub(
+            "HTTP/1.1 200 OK\r\nConnection: X-Internal\r\nX-Internal: 1\r\n\
+             Keep-Alive: timeout=5\r\nX-Kept: 1\r\nContent-Length: 2\r\n\r\nok",
+        );
+        let proxy = ProxyHandler::new().with_prefix("/", UpstreamPool::new([addr]));
+
+        let response = get(
+            &proxy,
+            "GET / HTTP/1.1\r\nConnection: keep-alive, X-Secret\r\nX-Secret: 1\r\n\
+             Keep-Alive: 5\r\nProxy-Authorization: Basic eDp5\r\nTE: trailers\r\n\
+             X-Kept: 1\r\n\r\n",
+        );
+        assert_eq!(response.header("X-Kept"), Some("1"));
+        assert_eq!(response.header("X-Internal"), None);
+        assert_eq!(response.header("Keep-Alive"), None);
+        assert_eq!(response.header("Connection"), None);
+
+        let request = received(&requests);
+        assert!(request.contains("\r\nX-Kept: 1\r\n"));
+        assert!(request.contains("\r\nConnection: close\r\n"));
+        for header in [
+            "X-Secret",
+            "Keep-Alive",
+            "Proxy-Authorization",
+            "TE:",
+            "keep-alive",
+        ] {
+            assert!(!request.contains(header), "{} was forwarded", header);
+        }
+    }
+
+    #[test]
+    fn passes_on_chunked_responses() {
+        let (addr, _) = stub(
+            "HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\nContent-Type: text/plain\r\n\r\n\
+             4\r\nWiki\r\n5\r\npedia\r\n0\r\n\r\n",
+        );
+        let proxy = ProxyHandler::new().with_prefix("/", UpstreamPool::new([addr]));
+
+        let response = get(&proxy, "GET / HTTP/1.1\r\n\r\n");
+        assert!(response.body().is_stream());
+        assert!(!response.allows_compression());
+        assert_eq!(response.header("Transfer-Encoding"), None);
+        assert_eq!(body(response), "Wikipedia");
+    }
+
+    #[test]
+    fn strips_the_prefix_if_asked_to() {
+        let (addr, requests) = stub(OK);
+        let proxy = ProxyHandler::new()
+            .with_stripped_prefix("/api/", UpstreamPool::new([addr.clone()]))
+            .with_prefix("/static", UpstreamPool::new([addr]));
+
+        for (target, expected) in [
+            ("/api/users?page=2", "GET /users?page=2 HTTP/1.1\r\n"),
+            ("/api", "GET / HTTP/1.1\r\n"),
+            ("/static/app.js", "GET /static/app.js HTTP/1.1\r\n"),
+        ] {
+            get(&proxy, &format!("GET {} HTTP/1.1\r\n\r\n", target));
+            assert!(received(&requests).starts_with(expected), "{}", target);
+        }
+        let response = get(&proxy, "GET /apis HTTP/1.1\r\n\r\n");
+        assert_eq!(response.status_code(), StatusCode::NotFound);
+    }
+
+    // Like the server does for a handler that streams the body.
+    fn with_body(proxy: &ProxyHandler, head: &str, body: &[u8]) -> Response {
+        let mut request = Request::try_from(head.as_bytes()).unwrap();
+        assert!(proxy.streams_body(&request));
+        let body = Mutex::new(body);
+        let read = |buf: &mut [u8]| body.lock().unwrap().read(buf);
+        request.set_body_reader(BodyReader::new(&read));
+        proxy.handle_request(&request)
+    }
+
+    #[test]
+    fn streams_request_bodies() {
+        let (addr, requests) = stub(OK);
+        let proxy = ProxyHandler::new().with_prefix("/", UpstreamPool::new([addr]));
+
+        let data = vec![b'x'; 3 * 1024 * 1024];
+        let head = format!("POST / HTTP/1.1\r\nContent-Length: {}\r\n\r\n", data.len());
+        let response = with_body(&proxy, &head, &data);
+        assert_eq!(response.status_code(), StatusCode::Ok);
+
+        let request = received(&requests);
+        let (head, body) = request.split_once("\r\n\r\n").unwrap();
+        assert!(head.contains(&format!("\r\nContent-Length: {}", data.len())));
+        assert_eq!(body.len(), data.len());
+    }
+
+    #[test]
+    fn encodes_chunked_request_bodies_again() {
+        let (addr, requests) = stub(OK);
+        let proxy = ProxyHandler::new().with_prefix("/", UpstreamPool::new([addr]));
+
+        // the server hands over the body decoded
+        let head = "POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n";
+        with_body(&proxy, head, b"Wikipedia");
+
+        let request = received(&requests);
+        assert!(request.contains("\r\nTransfer-Encoding: chunked\r\n"));
+        assert!(!request.contains("Content-Length"));
+        assert!(request.ends_with("\r\n\r\n9\r\nWikipedia\r\n0\r\n\r\n"));
+    }
+
+    #[test]
+    fn limits_request_bodies() {
+        // announced too large: answered without connecting
+        let pool = UpstreamPool::new([free_addr()]).with_max_body_size(4);
+        let proxy = ProxyHandler::new().with_prefix("/", pool);
+        let response = with_body(
+            &proxy,
+            "POST / HTTP/1.1\r\nContent-Length: 5\r\n\r\n",
+            b"hello",
+        );
+        assert_eq!(response.status_code(), StatusCode::PayloadTooLarge);
+
+        let (addr, _) = stub(OK);
+        let pool = UpstreamPool::new([addr]).with_max_body_size(4);
+        let proxy = ProxyHandler::new().with_prefix("/", pool);
+        let head = "POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n";
+        let response = with_body(&proxy, head, b"hello");
+        assert_eq!(response.status_code(), StatusCode::PayloadTooLarge);
+    }
+
+    #[test]
+    fn leaves_unrouted_bodies_to_the_fallback() {
+        let proxy = ProxyHandler::new()
+            .with_prefix("/api", UpstreamPool::new([free_addr()]))
+            .with_fallback(|_: &Request| Response::new(StatusCode::Ok, None));
+
+        let request = Request::try_from(&b"POST /upload HTTP/1.1\r\n\r\n"[..]).unwrap();
+        assert!(!proxy.streams_body(&request));
+    }
+}
@@ -0,0 +1,245 @@
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+use std::thread;
+use std::time::{Duration, Instant};
+
+pub const DEFAULT_MAX_FAILS: u32 = 3;
+pub const DEFAULT_FAIL_TIMEOUT: Duration = Duration::from_secs(10);
+pub const DEFAULT_CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(30);
+pub const DEFAULT_MAX_BODY_SIZE: u64 = 100 * 1024 * 1024;
+
+/// Upstream servers that take turns (round-robin). An upstream is skipped
+/// after `max_fails` failed requests in a row for `fail_timeout` (passive
+/// checks), and while its health check fails (active checks).
+pub struct UpstreamPool {
+    upstreams: Vec<Upstream>,
+    next: AtomicUsize,
+    max_fails: u32,
+    fail_timeout: Duration,
+    connect_timeout: Duration,
+    timeout: Duration,
+    max_body_size: u64,
+    health_check: Option<HealthCheck>,
+}
+
+pub struct Upstream {
+    addr: String,
+    state: Mutex<UpstreamState>,
+}
+
+#[derive(Default)]
+struct UpstreamState {
+    fails: u32,
+    down_until: Option<Instant>,
+    // set by the active health check
+    unhealthy: bool,
+}
+
+struct HealthCheck {
+    path: String,
+    interval: Duration,
+}
+
+impl UpstreamPool {
+    /// `addrs` are `host:port` pairs of plain HTTP servers.
+    pub fn new<S: Into<String>>(addrs: impl IntoIterator<Item = S>) -> Self {
+        Self {
+            upstreams: addrs
+                .into_iter()
+                .map(|addr| Upstream {
+                    addr: addr.into(),
+                    state: Mutex::new(UpstreamState::default()),
+                })
+                .collect(),
+            next: AtomicUsize::new(0),
+            max_fails: DEFAULT_MAX_FAILS,
+            fail_timeout: DEFAULT_FAIL_TIMEOUT,
+            connect_timeout: DEFAULT_CONNECT_TIMEOUT,
+            timeout: DEFAULT_TIMEOUT,
+            max_body_size: DEFAULT_MAX_BODY_SIZE,
+            health_check: None,
+        }
+    }
+
+    /// Failed requests in a row after which an upstream is taken out.
+    pub fn with_max_fails(mut self, max_fails: u32) -> Self {
+        self.max_fails = max_fails.max(1);
+        self
+    }
+
+    /// How long an upstream stays out after `max_fails`.
+    pub fn with_fail_timeout(mut self, fail_timeout: Duration) -> Self {
+        self.fail_timeout = fail_timeout;
+        self
+    }
+
+    pub fn with_connect_timeout(mut self, connect_timeout: Duration) -> Self {
+        self.connect_timeout = connect_timeout;
+        self
+    }
+
+    /// How long an upstream may take to accept the request, and then between
+    /// any two reads of its response.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Requests with a larger body are answered with 413 Payload Too Large.
+    /// Bodies are passed on as they arrive, the server's `max_body_size`
+    /// does not apply to them.
+    pub fn with_max_body_size(mut self, max_body_size: u64) -> Self {
+        self.max_body_size = max_body_size;
+        self
+    }
+
+    pub fn max_body_size(&self) -> u64 {
+        self.max_body_size
+    }
+
+    /// Requests `path` from every upstream each `interval` and takes out those
+    /// that do not answer with a 2xx or 3xx status until they do again.
+    pub fn with_health_check(mut self, path: &str, interval: Duration) -> Self {
+        self.health_check = Some(HealthCheck {
+            path: path.to_string(),
+            interval,
+        });
+        self
+    }
+
+    /// Runs the health checks on a thread that ends with the pool.
+    pub fn start_health_checks(self: &Arc<Self>) {
+        let interval = match &self.health_check {
+            Some(health_check) => health_check.interval,
+            None => return,
+        };
+        let pool = Arc::downgrade(self);
+
+        thread::spawn(move || loop {
+            match Weak::upgrade(&pool) {
+                Some(pool) => pool.check_health(),
+                None => return,
+            }
+            thread::sleep(interval);
+        });
+    }
+
+    /// The upstreams to try for one request, the next in turn first. The
+    /// ones taken out come last, as a last resort.
+    pub fn candidates(&self) -> Vec<&Upstream> {
+        let len = self.upstreams.len();
+        if len == 0 {
+            return Vec::new();
+        }
+        let start = self.next.fetch_add(1, Ordering::Relaxed) % len;
+        let (mut available, down): (Vec<&Upstream>, Vec<&Upstream>) = (0..len)
+            .map(|i| &self.upstreams[(start + i) % len])
+            .partition(|upstream| upstream.is_available());
+
+        available.extend(down);
+        available
+    }
+
+    /// Connects to `upstream`, with the pool's timeouts set on the stream.
+    pub fn connect(&self, upstream: &Upstream) -> std::io::Result<TcpStream> {
+        let addr: SocketAddr = upstream.addr.to_socket_addrs()?.next().ok_or_else(|| {
+            std::io::Error::new(std::io::ErrorKind::NotFound, "address did not resolve")
+        })?;
+        let stream = TcpStream::connect_timeout(&addr, self.connect_timeout)?;
+        stream.set_read_timeout(Some(self.timeout))?;
+        stream.set_write_timeout(Some(self.timeout))?;
+        Ok(stream)
+    }
+
+    pub fn report_success(&self, upstream: &Upstream) {
+        let mut state = upstream.lock();
+        state.fails = 0;
+        state.down_until = None;
+    }
+
+    pub fn report_failure(&self, upstream: &Upstream) {
+        let mut state = upstream.lock();
+        state.fails += 1;
+        if state.fails >= self.max_fails && state.down_until.is_none() {
+            println!(
+                "Upstream {} failed {} times, taking it out for {:?}",
+                upstream.addr, state.fails, self.fail_timeout
+            );
+            state.down_until = Some(Instant::now() + self.fail_timeout);
+        }
+    }
+
+    fn check_health(&self) {
+        let health_check = match &self.health_check {
+            Some(health_check) => health_check,
+            None => return,
+        };
+
+        for upstream in &self.upstreams {
+            let healthy = self.probe(upstream, &health_check.path);
+            let mut state = upstream.lock();
+            if state.unhealthy == healthy {
+                println!(
+                    "Upstream {} is {}",
+                    upstream.addr,
+                    if healthy {
+                        "healthy again"
+                    } else {
+                        "unhealthy"
+                    }
+                );
+            }
+            state.unhealthy = !healthy;
+        }
+    }
+
+    // GET `path` and look at the status line only.
+    fn probe(&self, upstream: &Upstream, path: &str) -> bool {
+        let mut stream = match self.connect(upstream) {
+            Ok(stream) => stream,
+            Err(_) => return false,
+        };
+        let request = format!(
+            "GET {} HTTP/1.1\r\nHost: {}\r\nConnection: close\r\nUser-Agent: {}\r\n\r\n",
+            path,
+            upstream.addr,
+            crate::http::response::SERVER_NAME
+        );
+        let mut status_line = [0; 12];
+        let answered = stream.write_all(request.as_bytes()).is_ok()
+            && stream.read_exact(&mut status_line).is_ok();
+
+        // "HTTP/1.1 200"
+        answered && status_line.starts_with(b"HTTP/1.") && matches!(status_line[9], b'2' | b'3')
+    }
+}
+
+impl Upstream {
+    pub fn addr(&self) -> &str {
+        &self.addr
+    }
+
+    fn is_available(&self) -> bool {
+        let mut state = self.lock();
+        if state.unhealthy {
+            return false;
+        }
+        match state.down_until {
+            Some(until) if Instant::now() < until => false,
+            Some(_) => {
+                // back on probation: one more failure takes it out again
+                state.down_until = None;
+                state.fails = state.fails.saturating_sub(1);
+                true
+            }
+            None => true,
+        }
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, UpstreamState> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
@@ -0,0 +1,287 @@
+use super::http::{Method, Params, Request, Response, StatusCode};
+use super::server::Handler;
+
+use std::sync::Arc;
+
+/// Dispatches requests to handlers by method and path pattern.
+///
+/// Patterns are matched segment by segment: `:name` captures one segment and
+/// `*name` (only as the last segment) captures the rest of the path. Routes
+/// are tried in the order they were added.
+pub struct Router {
+    routes: Vec<Route>,
+    fallback: Option<Arc<dyn Handler>>,
+}
+
+struct Route {
+    method: Method,
+    pattern: Pattern,
+    handler: Arc<dyn Handler>,
+}
+
+impl Router {
+    pub fn new() -> Self {
+        Self {
+            routes: Vec::new(),
+            fallback: None,
+        }
+    }
+
+    pub fn route(mut self, method: Method, pattern: &str, handler: impl Handler + 'static) -> Self {
+        self.routes.push(Route {
+            method,
+            pattern: Pattern::from(pattern),
+            handler: Arc::new(handler),
+        });
+        self
+    }
+
+    /// GET routes also answer HEAD requests.
+    pub fn get(self, pattern: &str, handler: impl Handler + 'static) -> Self {
+        self.route(Method::GET, pattern, handler)
+    }
+
+    pub fn post(self, pattern: &str, handler: impl Handler + 'static) -> Self {
+        self.route(Method::POST, pattern, handler)
+    }
+
+    pub fn put(self, pattern: &str, handler: impl Handler + 'static) -> Self {
+        self.route(Method::PUT, pattern, handler)
+    }
+
+    pub fn patch(self, pattern: &str, handler: impl Handler + 'static) -> Self {
+        self.route(Method::PATCH, pattern, handler)
+    }
+
+    pub fn delete(self, pattern: &str, handler: impl Handler + 'static) -> Self {
+        self.route(Method::DELETE, pattern, handler)
+    }
+
+    /// Handles requests whose path matches no route, instead of answering 404.
+    pub fn fallback(mut self, handler: impl Handler + 'static) -> Self {
+        self.fallback = Some(Arc::new(handler));
+        self
+    }
+}
+
+impl Default for Router {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Router {
+    // The matching route and the params it captured, or else the methods
+    // the path would be allowed with.
+    fn find<'a>(&'a self, request: &'a Request) -> Result<(&'a Route, Params<'a>), Vec<Method>> {
+        let mut allowed: Vec<Method> = Vec::new();
+
+        for route in &self.routes {
+            let params = match route.pattern.matches(request.path()) {
+                Some(params) => params,
+                None => continue,
+            };
+
+            let method = *request.method();
+            if route.method == method || (route.method == Method::GET && method == Method::HEAD) {
+                return Ok((route, Params::from(params)));
+            }
+
+            if !allowed.contains(&route.method) {
+                allowed.push(route.method);
+                if route.method == Method::GET {
+                    allowed.push(Method::HEAD);
+                }
+            }
+        }
+        Err(allowed)
+    }
+}
+
+impl Handler for Router {
+    fn handle_request(&self, request: &Request) -> Response {
+        let allowed = match self.find(request) {
+            Ok((route, params)) => {
+                let mut routed = request.clone();
+                routed.set_params(params);
+                return route.handler.handle_request(&routed);
+            }
+            Err(allowed) => allowed,
+        };
+
+        if !allowed.is_empty() {
+            let allow: Vec<&str> = allowed.iter().map(|method| method.as_str()).collect();
+            return Response::error(StatusCode::MethodNotAllowed)
+                .with_header("Allow", allow.join(", "));
+        }
+
+        match &self.fallback {
+            Some(handler) => handler.handle_request(request),
+            None => Response::error(StatusCode::NotFound),
+        }
+    }
+
+    fn streams_body(&self, request: &Request) -> bool {
+        match (self.find(request), &self.fallback) {
+            (Ok((route, _)), _) => route.handler.streams_body(request),
+            (Err(allowed), Some(handler)) if allowed.is_empty() => handler.streams_body(request),
+            _ => false,
+        }
+    }
+}
+
+enum Segment {
+    Literal(String),
+    Param(String),
+    Wildcard(String),
+}
+
+struct Pattern {
+    segments: Vec<Segment>,
+}
+
+impl Pattern {
+    fn matches<'a>(&'a self, path: &'a str) -> Option<Vec<(&'a str, &'a str)>> {
+        let path = path.strip_prefix('/').unwrap_or(path);
+        let mut parts = path.split('/');
+        let mut offset = 0;
+        let mut params = Vec::new();
+
+        for segment in &self.segments {
+            if let Segment::Wildcard(name) = segment {
+                params.push((name.as_str(), &path[offset.min(path.len())..]));
+                return Some(params);
+            }
+
+            let part = parts.next()?;
+            offset += part.len() + 1;
+
+            match segment {
+                Segment::Literal(literal) if part != literal => return None,
+                Segment::Param(_) if part.is_empty() => return None,
+                Segment::Param(name) => params.push((name.as_str(), part)),
+                _ => {}
+            }
+        }
+
+        match parts.next() {
+            Some(_) => None,
+            None => Some(params),
+        }
+    }
+}
+
+impl From<&str> for Pattern {
+    fn from(s: &str) -> Self {
+        let s = s.strip_prefix('/').unwrap_or(s);
+
+        let segments = s
+            .split('/')
+            .map(|segment| {
+                if let Some(name) = segment.strip_prefix(':') {
+                    Segment::Param(name.to_string())
+                } else if let Some(name) = segment.strip_prefix('*') {
+                    Segment::Wildcard(name.to_string())
+                } else {
+                    Segment::Literal(segment.to_string())
+                }
+            })
+            .collect();
+
+        Pattern { segments }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::convert::TryFrom;
+
+    fn route(router: &Router, head: &str) -> Response {
+        let head = format!("{}\r\n\r\n", head);
+        router.handle_request(&Request::try_from(head.as_bytes()).unwrap())
+    }
+
+    fn body(mut response: Response) -> String {
+        let mut body = Vec::new();
+        response.take_body().write_to(&mut body).unwrap();
+        String::from_utf8(body).unwrap()
+    }
+
+    fn params(request: &Request) -> Response {
+        let params: Vec<String> = request
+            .params()
+            .iter()
+            .map(|(name, value)| format!("{}={}", name, value))
+            .collect();
+        Response::new(StatusCode::Ok, Some(params.join(",")))
+    }
+
+    #[test]
+    fn captures_params_and_wildcards() {
+        let router = Router::new()
+            .get("/users/:id", params)
+            .get("/users/:id/files/*rest", params)
+            .get("/static/*path", params);
+
+        assert_eq!(body(route(&router, "GET /users/42 HTTP/1.1")), "id=42");
+        assert_eq!(
+            body(route(&router, "GET /users/42/files/a/b.txt HTTP/1.1")),
+            "id=42,rest=a/b.txt"
+        );
+        assert_eq!(body(route(&router, "GET /static/ HTTP/1.1")), "path=");
+        assert_eq!(body(route(&router, "GET /static HTTP/1.1")), "path=");
+    }
+
+    #[test]
+    fn matches_whole_segments() {
+        let router = Router::new().get("/users/:id", params);
+
+        for path in ["/users", "/users/", "/users/42/more", "/usersx/42"] {
+            let response = route(&router, &format!("GET {} HTTP/1.1", path));
+            assert_eq!(response.status_code(), StatusCode::NotFound, "{}", path);
+        }
+    }
+
+    #[test]
+    fn tries_routes_in_order() {
+        let router = Router::new()
+            .get("/users/me", |_: &Request| {
+                Response::new(StatusCode::Ok, Some("me".to_string()))
+            })
+            .get("/users/:id", params);
+
+        assert_eq!(body(route(&router, "GET /users/me HTTP/1.1")), "me");
+        assert_eq!(body(route(&router, "GET /users/7 HTTP/1.1")), "id=7");
+    }
+
+    #[test]
+    fn answers_405_with_the_allowed_methods() {
+        let router = Router::new()
+            .get("/items", params)
+            .post("/items", params)
+            .delete("/items/:id", params);
+
+        let response = route(&router, "PUT /items HTTP/1.1");
+        assert_eq!(response.status_code(), StatusCode::MethodNotAllowed);
+        assert_eq!(response.header("Allow"), Some("GET, HEAD, POST"));
+
+        let response = route(&router, "HEAD /items HTTP/1.1");
+        assert_eq!(response.status_code(), StatusCode::Ok);
+    }
+
+    #[test]
+    fn falls_back_for_unknown_paths() {
+        let router = Router::new().get("/a", params);
+        assert_eq!(
+            route(&router, "GET /b HTTP/1.1").status_code(),
+            StatusCode::NotFound
+        );
+
+        let router = router.fallback(|_: &Request| Response::new(StatusCode::Accepted, None));
+        assert_eq!(
+            route(&router, "GET /b HTTP/1.1").status_code(),
+            StatusCode::Accepted
+        );
+    }
+}
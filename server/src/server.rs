@@ -1,56 +1,1189 @@
-use crate::http::{response, ParseError, Request, Response, StatusCode};
+use crate::access_log::AccessLog;
+use crate::http::compression;
+use crate::http::reader::{DEFAULT_MAX_BODY_SIZE, DEFAULT_MAX_HEAD_SIZE};
+use crate::http::{
+    BodyReader, Method, OnUpgrade, ParseError, ReadError, Request, RequestReader, Response,
+    StatusCode, Upgraded, Version,
+};
+use crate::thread_pool::ThreadPool;
+
+#[cfg(feature = "async")]
+pub use async_server::{AsyncHandler, Blocking};
+pub use tls::Tls;
+
+use connections::{Connection, Connections};
+use rustls::{ServerConnection, StreamOwned};
+use signal_hook::consts::{SIGHUP, SIGINT, SIGTERM};
+use signal_hook::flag;
+use stream::Stream;
 
 use std::convert::TryFrom;
-use std::io::{Read, Write};
-use std::net::TcpListener;
+use std::io::{Error as IoError, ErrorKind, Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+#[cfg(feature = "async")]
+mod async_server;
+mod connections;
+mod stream;
+mod tls;
+
+pub const DEFAULT_KEEP_ALIVE_TIMEOUT: Duration = Duration::from_secs(5);
+pub const DEFAULT_THREADS: usize = 8;
+pub const DEFAULT_QUEUE_SIZE: usize = 32;
+pub const DEFAULT_SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(10);
+pub const DEFAULT_MAX_UPGRADES: usize = 256;
+const DEFAULT_READ_TIMEOUT: Duration = Duration::from_secs(30);
+const REJECT_TIMEOUT: Duration = Duration::from_secs(1);
+// how often the accept loops and the drain check for shutdown
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
 
-pub trait Handler {
-    fn handle_request(&mut self, request: &Request) -> Response;
+/// Handlers are shared by all worker threads, so they must be `Send + Sync`.
+pub trait Handler: Send + Sync {
+    fn handle_request(&self, request: &Request) -> Response;
 
-    fn handle_bad_request(&mut self, e: &ParseError) -> Response {
+    /// Whether the request's body is left on the connection for the handler
+    /// to read from `Request::body_reader`, as it arrives and without the
+    /// server's `max_body_size`, instead of being read into `Request::body`.
+    fn streams_body(&self, _request: &Request) -> bool {
+        false
+    }
+
+    fn handle_bad_request(&self, e: &ParseError) -> Response {
         println!("Failed to parse request: {}", e);
-        Response::new(StatusCode::BadRequest, None)
+        Response::from(e)
+    }
+}
+
+impl<F> Handler for F
+where
+    F: Fn(&Request) -> Response + Send + Sync,
+{
+    fn handle_request(&self, request: &Request) -> Response {
+        self(request)
+    }
+}
+
+impl<H: Handler + ?Sized> Handler for Arc<H> {
+    fn handle_request(&self, request: &Request) -> Response {
+        (**self).handle_request(request)
+    }
+
+    fn streams_body(&self, request: &Request) -> bool {
+        (**self).streams_body(request)
+    }
+
+    fn handle_bad_request(&self, e: &ParseError) -> Response {
+        (**self).handle_bad_request(e)
     }
 }
 
 pub struct Server {
-    addr: String,
+    addrs: Vec<String>,
+    max_head_size: usize,
+    max_body_size: usize,
+    keep_alive_timeout: Duration,
+    threads: usize,
+    queue_size: usize,
+    max_upgrades: usize,
+    upgrades: Arc<AtomicUsize>,
+    compression: bool,
+    access_log: Option<AccessLog>,
+    shutdown_timeout: Duration,
+    shutdown: Arc<AtomicBool>,
+    connections: Arc<Connections>,
+    tls: Option<Tls>,
+    tls_addrs: Vec<String>,
+    redirect_to_https: bool,
+    reload: Arc<AtomicBool>,
 }
 
 impl Server {
     pub fn new(addr: String) -> Self {
-        Self { addr }
+        Self {
+            addrs: vec![addr],
+            max_head_size: DEFAULT_MAX_HEAD_SIZE,
+            max_body_size: DEFAULT_MAX_BODY_SIZE,
+            keep_alive_timeout: DEFAULT_KEEP_ALIVE_TIMEOUT,
+            threads: DEFAULT_THREADS,
+            queue_size: DEFAULT_QUEUE_SIZE,
+            max_upgrades: DEFAULT_MAX_UPGRADES,
+            upgrades: Arc::new(AtomicUsize::new(0)),
+            compression: true,
+            access_log: None,
+            shutdown_timeout: DEFAULT_SHUTDOWN_TIMEOUT,
+            shutdown: Arc::new(AtomicBool::new(false)),
+            connections: Arc::default(),
+            tls: None,
+            tls_addrs: Vec::new(),
+            redirect_to_https: false,
+            reload: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    /// Also accepts connections on `addr`.
+    pub fn listen(mut self, addr: String) -> Self {
+        self.addrs.push(addr);
+        self
+    }
+
+    /// Certificates for the `tls_listen` addresses, reloaded on SIGHUP.
+    pub fn tls(mut self, tls: Tls) -> Self {
+        self.tls = Some(tls);
+        self
+    }
+
+    /// Accepts HTTPS connections on `addr`; needs `tls`.
+    pub fn tls_listen(mut self, addr: String) -> Self {
+        self.tls_addrs.push(addr);
+        self
+    }
+
+    /// Answers every request on the plain HTTP addresses with a redirect to
+    /// the same URL on the first HTTPS address.
+    pub fn redirect_to_https(mut self, redirect_to_https: bool) -> Self {
+        self.redirect_to_https = redirect_to_https;
+        self
+    }
+
+    /// Requests with a larger body are answered with 413 Payload Too Large.
+    pub fn max_body_size(mut self, max_body_size: usize) -> Self {
+        self.max_body_size = max_body_size;
+        self
+    }
+
+    /// Requests with a larger head are answered with 431 Request Header Fields Too Large.
+    pub fn max_head_size(mut self, max_head_size: usize) -> Self {
+        self.max_head_size = max_head_size;
+        self
+    }
+
+    /// How long an idle persistent connection is kept open. Zero disables keep-alive.
+    pub fn keep_alive_timeout(mut self, keep_alive_timeout: Duration) -> Self {
+        self.keep_alive_timeout = keep_alive_timeout;
+        self
+    }
+
+    /// Number of worker threads handling connections.
+    pub fn threads(mut self, threads: usize) -> Self {
+        self.threads = threads.max(1);
+        self
+    }
+
+    /// Connections waiting for a worker; beyond that they get 503 Service Unavailable.
+    pub fn queue_size(mut self, queue_size: usize) -> Self {
+        self.queue_size = queue_size;
+        self
+    }
+
+    /// Connections handed over at once, each on a thread of its own, to a
+    /// WebSocket or a streamed body; beyond that they get 503 Service Unavailable.
+    pub fn max_upgrades(mut self, max_upgrades: usize) -> Self {
+        self.max_upgrades = max_upgrades;
+        self
+    }
+
+    /// Whether compressible responses are gzip or brotli encoded when the client accepts it.
+    pub fn compression(mut self, compression: bool) -> Self {
+        self.compression = compression;
+        self
+    }
+
+    /// Records every answered request; nothing is logged without one.
+    pub fn access_log(mut self, access_log: AccessLog) -> Self {
+        self.access_log = Some(access_log);
+        self
+    }
+
+    /// How long requests in flight may take to finish once shutdown has begun.
+    pub fn shutdown_timeout(mut self, shutdown_timeout: Duration) -> Self {
+        self.shutdown_timeout = shutdown_timeout;
+        self
+    }
+
+    /// Setting the returned flag shuts the server down like SIGTERM does.
+    pub fn shutdown_handle(&self) -> Arc<AtomicBool> {
+        Arc::clone(&self.shutdown)
+    }
+
+    fn is_shutting_down(&self) -> bool {
+        self.shutdown.load(Ordering::SeqCst)
+    }
+
+    // The first SIGINT or SIGTERM starts a graceful shutdown, a second one exits at once.
+    fn register_signals(&self) {
+        for signal in [SIGINT, SIGTERM] {
+            let result = flag::register_conditional_shutdown(signal, 1, Arc::clone(&self.shutdown))
+                .and_then(|_| flag::register(signal, Arc::clone(&self.shutdown)));
+            if let Err(e) = result {
+                println!("Failed to register signal handler: {}", e);
+            }
+        }
+        if let Err(e) = flag::register(SIGHUP, Arc::clone(&self.reload)) {
+            println!("Failed to register signal handler: {}", e);
+        }
+    }
+
+    // Only one of the accept loops gets to see each SIGHUP.
+    fn reload_if_requested(&self) {
+        if !self.reload.swap(false, Ordering::SeqCst) {
+            return;
+        }
+        if let Some(tls) = &self.tls {
+            match tls.reload() {
+                Ok(()) => println!("Reloaded TLS certificates"),
+                Err(e) => println!("Failed to reload TLS certificates: {}", e),
+            }
+        }
+    }
+
+    // Plain listeners first, then HTTPS ones, each with whether it is secure.
+    fn listen_addrs(&self) -> impl Iterator<Item = (&String, bool)> {
+        let tls_addrs = match self.tls {
+            Some(_) => &self.tls_addrs[..],
+            None => &[],
+        };
+        self.addrs
+            .iter()
+            .map(|addr| (addr, false))
+            .chain(tls_addrs.iter().map(|addr| (addr, true)))
+    }
+
+    /// Serves until SIGINT or SIGTERM, then stops accepting, lets the requests
+    /// in flight finish within the shutdown timeout and returns.
+    pub fn run(self, handler: impl Handler + 'static) {
+        let listeners: Vec<(TcpListener, bool)> = self
+            .listen_addrs()
+            .map(|(addr, secure)| (TcpListener::bind(addr).unwrap(), secure))
+            .collect();
+        // non-blocking, so that the accept loops notice shutdown
+        for (listener, _) in &listeners {
+            listener.set_nonblocking(true).unwrap();
+        }
+        let addrs: Vec<String> = self
+            .listen_addrs()
+            .map(|(addr, secure)| match secure {
+                true => format!("https://{}", addr),
+                false => format!("http://{}", addr),
+            })
+            .collect();
+        println!(
+            "Listening on {} with {} workers",
+            addrs.join(", "),
+            self.threads
+        );
+        self.register_signals();
+
+        let pool = Arc::new(ThreadPool::new(self.threads, self.queue_size));
+        let server = Arc::new(self);
+        let handler = Arc::new(handler);
+
+        // every listener but the first accepts on a thread of its own
+        let mut listeners = listeners.into_iter();
+        let first = listeners.next().expect("no address to listen on");
+        let mut accept_threads = Vec::new();
+        for (listener, secure) in listeners {
+            let pool = Arc::clone(&pool);
+            let server = Arc::clone(&server);
+            let handler = Arc::clone(&handler);
+            accept_threads.push(thread::spawn(move || {
+                accept(listener, secure, &pool, &server, &handler)
+            }));
+        }
+        accept(first.0, first.1, &pool, &server, &handler);
+        for accept_thread in accept_threads {
+            let _ = accept_thread.join();
+        }
+
+        println!("Shutting down");
+        server.drain();
+        // dropping the pool waits for the workers to finish
+    }
+
+    fn drain(&self) {
+        let deadline = Instant::now() + self.shutdown_timeout;
+
+        // repeated, since a worker may go idle between two passes
+        self.connections.close_idle();
+        while !self.connections.is_empty() && Instant::now() < deadline {
+            thread::sleep(POLL_INTERVAL);
+            self.connections.close_idle();
+        }
+
+        if !self.connections.is_empty() {
+            println!(
+                "Closing {} connections still busy after the shutdown timeout",
+                self.connections.len()
+            );
+            self.connections.close_all();
+        }
     }
 
-    pub fn run(self, mut handler: impl Handler) {
-        println!("Listening on {}", self.addr);
+    fn handle_connection(
+        self: &Arc<Self>,
+        stream: TcpStream,
+        connection: Connection,
+        secure: bool,
+        handler: &impl Handler,
+    ) {
+        // the idle timeout doubles as the timeout for reading a request
+        let read_timeout = if self.keep_alive_timeout.is_zero() {
+            DEFAULT_READ_TIMEOUT
+        } else {
+            self.keep_alive_timeout
+        };
+        if let Err(e) = stream.set_read_timeout(Some(read_timeout)) {
+            println!("Failed to set read timeout: {}", e);
+        }
 
-        let listener = TcpListener::bind(&self.addr).unwrap();
+        // the handshake happens on the first read
+        let stream = match &self.tls {
+            Some(tls) if secure => match ServerConnection::new(tls.server_config()) {
+                Ok(connection) => Stream::Tls(Box::new(StreamOwned::new(connection, stream))),
+                Err(e) => {
+                    println!("Failed to start TLS: {}", e);
+                    return;
+                }
+            },
+            _ => Stream::Plain(stream),
+        };
 
+        let mut reader = RequestReader::new(stream, self.max_head_size, self.max_body_size);
+        // even on shutdown every connection gets one request answered, with
+        // `Connection: close`; idle ones are then closed by `drain`
         loop {
-            match listener.accept() {
-                Ok((mut stream, _)) => {
-                    let a = [1, 2, 3, 4, 5];
-                    let mut buffer = [0; 1024];
-
-                    match stream.read(&mut buffer) {
-                        Ok(_) => {
-                            println!("Received request: {}", String::from_utf8_lossy(&buffer));
-
-                            let response = match Request::try_from(&buffer[..]) {
-                                Ok(request) => handler.handle_request(&request),
-                                Err(e) => handler.handle_bad_request(&e),
-                            };
-
-                            if let Err(e) = response.send(&mut stream) {
-                                println!("Failed to send response: {}", e);
-                            }
-                        }
-                        Err(e) => println!("Failed to read from  connection: {}", e),
+            match self.handle_request(&mut reader, handler, &connection) {
+                Exchange::KeepAlive => connection.set_idle(true),
+                Exchange::Close => break,
+                Exchange::Upgrade(upgrade, slot) => {
+                    // on a thread of its own, so that long-lived connections
+                    // do not take up the workers; it stays busy, so shutdown
+                    // only closes it after the timeout
+                    let (stream, buffered) = reader.into_parts();
+                    if let Err(e) = stream.tcp().set_read_timeout(None) {
+                        println!("Failed to set read timeout: {}", e);
+                    }
+                    thread::spawn(move || {
+                        upgrade.call(Upgraded::new(Box::new(stream), buffered));
+                        drop(connection);
+                        drop(slot);
+                    });
+                    return;
+                }
+            }
+        }
+        reader.stream().close();
+    }
+
+    /// Reads, handles and answers one request. Returns what becomes of the connection.
+    fn handle_request(
+        &self,
+        reader: &mut RequestReader<Stream>,
+        handler: &impl Handler,
+        connection: &Connection,
+    ) -> Exchange {
+        let head = match reader.read_head() {
+            Ok(Some(head)) => head,
+            Ok(None) => return Exchange::Close,
+            Err(ReadError::Parse(e)) => {
+                let start = Instant::now();
+                let response = handler.handle_bad_request(&e);
+                let (status_code, bytes) = (response.status_code(), body_len(&response, false));
+                send(reader.stream(), response, false, false);
+                self.log_bad_request(reader.stream().peer_addr().ok(), status_code, bytes, start);
+                return Exchange::Close;
+            }
+            Err(ReadError::Io(e)) => {
+                if !is_timeout(&e) {
+                    println!("Failed to read from connection: {}", e);
+                }
+                return Exchange::Close;
+            }
+        };
+        let start = Instant::now();
+        connection.set_idle(false);
+
+        let mut request = match Request::try_from(&head[..]) {
+            Ok(request) => request,
+            Err(e) => {
+                let response = handler.handle_bad_request(&e);
+                let (status_code, bytes) = (response.status_code(), body_len(&response, false));
+                send(reader.stream(), response, false, false);
+                self.log_bad_request(reader.stream().peer_addr().ok(), status_code, bytes, start);
+                return Exchange::Close;
+            }
+        };
+        if let Ok(remote_addr) = reader.stream().peer_addr() {
+            request.set_remote_addr(remote_addr);
+        }
+        request.set_secure(reader.stream().is_secure());
+
+        let redirect = self.redirect_to_https && !reader.stream().is_secure();
+        let body;
+        let mut response = if !redirect && handler.streams_body(&request) {
+            let body = match reader.body_stream(request.headers()) {
+                Ok(body) => Mutex::new(body),
+                Err(e) => return self.reject_body(reader, handler, &request, e.into(), start),
+            };
+            let read = |buf: &mut [u8]| body.lock().unwrap().read(buf);
+            let mut streaming = request.clone();
+            streaming.set_body_reader(BodyReader::new(&read));
+            let mut response = handler.handle_request(&streaming);
+
+            // what the handler left unread is in the way of the next request
+            if !body.into_inner().unwrap().is_done() {
+                response.set_header("Connection", "close");
+            }
+            response
+        } else {
+            body = match reader.read_body(request.headers()) {
+                Ok(body) => body,
+                Err(e) => return self.reject_body(reader, handler, &request, e, start),
+            };
+            request.set_body(&body);
+
+            if redirect {
+                self.https_redirect(&request)
+            } else {
+                handler.handle_request(&request)
+            }
+        };
+        self.compress(&request, &mut response);
+
+        let head_only = matches!(request.method(), Method::HEAD);
+        let upgrade = self.take_upgrade(&mut response, head_only, request.version());
+        let keep_alive = upgrade.is_some() || self.set_connection(&request, &mut response);
+        let (status_code, bytes) = (response.status_code(), body_len(&response, head_only));
+        let keep_alive = send(reader.stream(), response, head_only, keep_alive);
+
+        self.log(&request, status_code, bytes, start);
+        match (keep_alive, upgrade) {
+            (true, Some((upgrade, slot))) => Exchange::Upgrade(upgrade, slot),
+            (true, None) => Exchange::KeepAlive,
+            (false, _) => Exchange::Close,
+        }
+    }
+
+    // Answers a request whose body could not be read.
+    fn reject_body(
+        &self,
+        reader: &mut RequestReader<Stream>,
+        handler: &impl Handler,
+        request: &Request,
+        e: ReadError,
+        start: Instant,
+    ) -> Exchange {
+        match e {
+            ReadError::Parse(e) => {
+                let response = handler.handle_bad_request(&e);
+                let (status_code, bytes) = (response.status_code(), body_len(&response, false));
+                send(reader.stream(), response, false, false);
+                self.log(request, status_code, bytes, start);
+            }
+            ReadError::Io(e) => println!("Failed to read from connection: {}", e),
+        }
+        Exchange::Close
+    }
+
+    /// A permanent redirect to the same URL on the first HTTPS address.
+    fn https_redirect(&self, request: &Request) -> Response {
+        let host = match request.host() {
+            Some(host) => host,
+            None => return Response::error(StatusCode::BadRequest),
+        };
+        let port = self
+            .tls_addrs
+            .first()
+            .and_then(|addr| addr.to_socket_addrs().ok()?.next())
+            .map(|addr| addr.port())
+            .unwrap_or(443);
+
+        let location = match port {
+            443 => format!("https://{}{}", host, request.target()),
+            port => format!("https://{}:{}{}", host, port, request.target()),
+        };
+        Response::error(StatusCode::MovedPermanently).with_header("Location", location)
+    }
+
+    fn log(&self, request: &Request, status_code: StatusCode, bytes: u64, start: Instant) {
+        if let Some(access_log) = &self.access_log {
+            access_log.log(request, status_code, bytes, start.elapsed());
+        }
+    }
+
+    /// Like `log`, for requests that could not be parsed.
+    fn log_bad_request(
+        &self,
+        remote_addr: Option<SocketAddr>,
+        status_code: StatusCode,
+        bytes: u64,
+        start: Instant,
+    ) {
+        if let Some(access_log) = &self.access_log {
+            access_log.log_bad_request(remote_addr, status_code, bytes, start.elapsed());
+        }
+    }
+
+    fn compress(&self, request: &Request, response: &mut Response) {
+        if self.compression {
+            compression::compress_response(response, request);
+        }
+    }
+
+    /// Like `take_upgrade`, but answers 503 Service Unavailable instead once
+    /// `max_upgrades` connections are handed over.
+    fn take_upgrade(
+        &self,
+        response: &mut Response,
+        head_only: bool,
+        version: Version,
+    ) -> Option<(OnUpgrade, UpgradeSlot)> {
+        let upgrade = take_upgrade(response, head_only, version)?;
+        let reserved = self
+            .upgrades
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| {
+                (n < self.max_upgrades).then_some(n + 1)
+            });
+        if reserved.is_err() {
+            println!("Too many upgraded connections, rejecting request");
+            *response =
+                Response::error(StatusCode::ServiceUnavailable).with_header("Retry-After", "1");
+            return None;
+        }
+        Some((upgrade, UpgradeSlot(Arc::clone(&self.upgrades))))
+    }
+
+    /// Decides whether the connection outlives this exchange and sets `Connection` to match.
+    fn set_connection(&self, request: &Request, response: &mut Response) -> bool {
+        let keep_alive = !self.keep_alive_timeout.is_zero()
+            && !self.is_shutting_down()
+            && request.keep_alive()
+            && !response
+                .header("Connection")
+                .is_some_and(|value| value.eq_ignore_ascii_case("close"));
+
+        if !keep_alive {
+            response.set_header("Connection", "close");
+        } else if request.version() == Version::Http10 {
+            response.set_header("Connection", "keep-alive");
+        }
+        keep_alive
+    }
+}
+
+/// What becomes of a connection after a request has been answered.
+enum Exchange {
+    KeepAlive,
+    Close,
+    /// Handed over, see `Response::upgrade` and `Body::Stream`.
+    Upgrade(OnUpgrade, UpgradeSlot),
+}
+
+/// Counts a handed over connection against `max_upgrades` until dropped.
+struct UpgradeSlot(Arc<AtomicUsize>);
+
+impl Drop for UpgradeSlot {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// The connection is handed over along with a 101 response, and to send a
+/// streamed body, which can take as long as the stream lasts. HTTP/1.0
+/// clients cannot decode chunked, so theirs ends with the connection.
+fn take_upgrade(response: &mut Response, head_only: bool, version: Version) -> Option<OnUpgrade> {
+    match response.status_code() {
+        StatusCode::SwitchingProtocols => response.take_upgrade(),
+        _ if response.body().is_stream() && !head_only => {
+            let body = response.take_body();
+            let chunked = version != Version::Http10;
+            if chunked {
+                response.set_header("Transfer-Encoding", "chunked");
+            } else {
+                response.set_close_delimited(true);
+            }
+            response.set_header("Connection", "close");
+            Some(OnUpgrade::new(move |mut stream| {
+                let result = if chunked {
+                    body.write_to(&mut stream)
+                } else {
+                    body.write_unframed_to(&mut stream)
+                };
+                // the client going away is how most streams end
+                if let Err(e) = result {
+                    if !is_disconnect(&e) {
+                        println!("Failed to stream response: {}", e);
+                    }
+                }
+            }))
+        }
+        _ => None,
+    }
+}
+
+fn accept<H: Handler + 'static>(
+    listener: TcpListener,
+    secure: bool,
+    pool: &ThreadPool,
+    server: &Arc<Server>,
+    handler: &Arc<H>,
+) {
+    while !server.is_shutting_down() {
+        server.reload_if_requested();
+
+        match listener.accept() {
+            Ok((stream, _)) => {
+                if let Err(e) = stream.set_nonblocking(false) {
+                    println!("Failed to configure connection: {}", e);
+                    continue;
+                }
+                if pool.is_full() {
+                    let tls = server.tls.as_ref().filter(|_| secure);
+                    reject(stream, tls);
+                    continue;
+                }
+
+                // tracked from here, so that shutdown waits for queued connections too
+                let connection = match server.connections.add(&stream) {
+                    Ok(connection) => connection,
+                    Err(e) => {
+                        println!("Failed to track connection: {}", e);
+                        continue;
                     }
+                };
+                let server = Arc::clone(server);
+                let handler = Arc::clone(handler);
+                pool.execute(move || {
+                    server.handle_connection(stream, connection, secure, &*handler)
+                });
+            }
+            Err(e) if e.kind() == ErrorKind::WouldBlock => thread::sleep(POLL_INTERVAL),
+            Err(e) => println!("Failed to establish a connection: {}", e),
+        }
+    }
+}
+
+// Answers on the accepting thread, so it must not wait long on a slow client,
+// which for TLS includes the handshake.
+fn reject(stream: TcpStream, tls: Option<&Tls>) {
+    println!("All workers are busy, rejecting connection");
+
+    let mut response = Response::error(StatusCode::ServiceUnavailable)
+        .with_header("Retry-After", "1")
+        .with_header("Connection", "close");
+    let result = stream
+        .set_read_timeout(Some(REJECT_TIMEOUT))
+        .and_then(|_| stream.set_write_timeout(Some(REJECT_TIMEOUT)))
+        .and_then(|_| match tls {
+            // the handshake happens on the first write
+            Some(tls) => ServerConnection::new(tls.server_config())
+                .map(|connection| Stream::Tls(Box::new(StreamOwned::new(connection, stream))))
+                .map_err(IoError::other),
+            None => Ok(Stream::Plain(stream)),
+        })
+        .and_then(|mut stream| {
+            response.send(&mut stream)?;
+            stream.close();
+            let _ = stream.tcp().shutdown(Shutdown::Write);
+            discard_input(stream.tcp());
+            Ok(())
+        });
+    if let Err(e) = result {
+        println!("Failed to send response: {}", e);
+    }
+}
+
+// Closing with the request unread makes the kernel answer it with a reset,
+// which can cost the client the response it was sent. So the request is read
+// and dropped until the client closes, for at most `REJECT_TIMEOUT`.
+fn discard_input(mut stream: &TcpStream) {
+    let deadline = Instant::now() + REJECT_TIMEOUT;
+    let mut chunk = [0; 4096];
+    while let Some(left) = deadline.checked_duration_since(Instant::now()) {
+        if left.is_zero() || stream.set_read_timeout(Some(left)).is_err() {
+            break;
+        }
+        match stream.read(&mut chunk) {
+            Ok(0) | Err(_) => break,
+            Ok(_) => {}
+        }
+    }
+}
+
+/// Sends the response and returns whether the connection stays open.
+fn send(stream: &mut Stream, mut response: Response, head_only: bool, keep_alive: bool) -> bool {
+    if !keep_alive {
+        response.set_header("Connection", "close");
+    }
+
+    let result = if head_only {
+        response.send_head(stream)
+    } else {
+        response.send(stream)
+    };
+
+    match result {
+        Ok(()) => keep_alive,
+        Err(e) => {
+            println!("Failed to send response: {}", e);
+            false
+        }
+    }
+}
+
+/// How many body bytes `send` writes for this response.
+fn body_len(response: &Response, head_only: bool) -> u64 {
+    if head_only || !response.status_code().allows_body() {
+        0
+    } else {
+        response.body().len()
+    }
+}
+
+fn is_timeout(e: &IoError) -> bool {
+    matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut)
+}
+
+fn is_disconnect(e: &IoError) -> bool {
+    matches!(
+        e.kind(),
+        ErrorKind::BrokenPipe | ErrorKind::ConnectionReset | ErrorKind::ConnectionAborted
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpStream;
+    use std::thread::JoinHandle;
+
+    // The port is free when asked for, which is good enough for tests.
+    fn free_addr() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        listener.local_addr().unwrap().to_string()
+    }
+
+    struct Running {
+        addr: String,
+        shutdown: Arc<AtomicBool>,
+        thread: Option<JoinHandle<()>>,
+    }
+
+    impl Running {
+        fn start(server: Server, handler: impl Handler + 'static) -> Self {
+            let addr = server.addrs[0].clone();
+            let shutdown = server.shutdown_handle();
+            let thread = thread::spawn(move || server.run(handler));
+            for _ in 0..100 {
+                if TcpStream::connect(&addr).is_ok() {
+                    break;
+                }
+                thread::sleep(Duration::from_millis(20));
+            }
+            Self {
+                addr,
+                shutdown,
+                thread: Some(thread),
+            }
+        }
+
+        fn connect(&self) -> TcpStream {
+            let stream = TcpStream::connect(&self.addr).unwrap();
+            stream
+                .set_read_timeout(Some(Duration::from_secs(5)))
+                .unwrap();
+            stream
+        }
+
+        // Sends `requests` on one connection and reads until the server closes it.
+        fn exchange(&self, requests: &str) -> String {
+            let mut stream = self.connect();
+            stream.write_all(requests.as_bytes()).unwrap();
+            let mut response = Vec::new();
+            let _ = stream.read_to_end(&mut response);
+            String::from_utf8_lossy(&response).into_owned()
+        }
+
+        fn stop(mut self) {
+            self.shutdown.store(true, Ordering::SeqCst);
+            if let Some(thread) = self.thread.take() {
+                thread.join().unwrap();
+            }
+        }
+    }
+
+    fn echo_path(request: &Request) -> Response {
+        Response::new(StatusCode::Ok, Some(request.path().to_string()))
+    }
+
+    // Answers with the length of the body it streams, except at /unread.
+    struct StreamedLength;
+
+    impl Handler for StreamedLength {
+        fn streams_body(&self, _request: &Request) -> bool {
+            true
+        }
+
+        fn handle_request(&self, request: &Request) -> Response {
+            let mut body = request.body_reader().unwrap();
+            let len = match request.path() {
+                "/unread" => 0,
+                _ => std::io::copy(&mut body, &mut std::io::sink()).unwrap(),
+            };
+            Response::new(StatusCode::Ok, Some(len.to_string()))
+        }
+    }
+
+    #[test]
+    fn streams_bodies_to_handlers_that_ask() {
+        let server = Running::start(Server::new(free_addr()), StreamedLength);
+
+        // past max_body_size, and chunked
+        let data = vec![b'x'; 2 * 1024 * 1024];
+        let mut stream = server.connect();
+        let head = format!("POST / HTTP/1.1\r\nContent-Length: {}\r\n\r\n", data.len());
+        stream.write_all(head.as_bytes()).unwrap();
+        stream.write_all(&data).unwrap();
+        stream
+            .write_all(
+                b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n3\r\nabc\r\n0\r\n\r\n",
+            )
+            .unwrap();
+        stream
+            .write_all(
+                b"POST /unread HTTP/1.1\r\nContent-Length: 2\r\n\r\nhiGET / HTTP/1.1\r\n\r\n",
+            )
+            .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+
+        assert!(response.contains("\r\n\r\n2097152HTTP/1.1 200 OK\r\n"));
+        assert!(response.contains("\r\n\r\n3HTTP/1.1 200 OK\r\n"));
+        // the unread body ends the connection
+        assert!(response.ends_with("Connection: close\r\n\r\n0"));
+        server.stop();
+    }
+
+    #[test]
+    fn answers_pipelined_requests_in_order() {
+        let server = Running::start(Server::new(free_addr()), echo_path);
+
+        let response = server.exchange(
+            "GET /first HTTP/1.1\r\nHost: x\r\n\r\nGET /second HTTP/1.1\r\nHost: x\r\nConnection: close\r\n\r\n",
+        );
+        let first = response.find("\r\n\r\n/first").unwrap();
+        let second = response.find("\r\n\r\n/second").unwrap();
+        assert!(first < second);
+        assert_eq!(response.matches("HTTP/1.1 200 OK").count(), 2);
+        assert!(response.ends_with("Connection: close\r\n\r\n/second"));
+        server.stop();
+    }
+
+    #[test]
+    fn rejects_content_lengths_that_are_not_digits() {
+        let server = Running::start(Server::new(free_addr()), echo_path);
+
+        // answered at once rather than waiting for five bytes
+        let response = server.exchange("POST /a HTTP/1.1\r\nContent-Length: +5\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 400 Bad Request\r\n"));
+        server.stop();
+    }
+
+    #[test]
+    fn streams_bodies_to_http_1_0_clients_until_closing() {
+        let server = Running::start(Server::new(free_addr()), |_: &Request| {
+            Response::new(StatusCode::Ok, None)
+                .with_body(crate::http::Body::stream(&b"data: hello\n\n"[..]))
+        });
+
+        let response = server.exchange("GET /events HTTP/1.0\r\n\r\n");
+        assert!(!response.contains("Transfer-Encoding"));
+        assert!(!response.contains("Content-Length"));
+        assert!(response.contains("Connection: close\r\n"));
+        assert!(response.ends_with("\r\n\r\ndata: hello\n\n"));
+
+        let response = server.exchange("GET /events HTTP/1.1\r\n\r\n");
+        assert!(response.contains("Transfer-Encoding: chunked\r\n"));
+        assert!(response.ends_with("\r\n\r\nd\r\ndata: hello\n\n\r\n0\r\n\r\n"));
+        server.stop();
+    }
+
+    #[test]
+    fn closes_http_1_0_connections_unless_kept_alive() {
+        let server = Running::start(Server::new(free_addr()), echo_path);
+
+        let response = server.exchange("GET /a HTTP/1.0\r\n\r\nGET /b HTTP/1.0\r\n\r\n");
+        assert!(response.contains("Connection: close\r\n"));
+        assert!(!response.contains("/b"));
+
+        let response = server
+            .exchange("GET /a HTTP/1.0\r\nConnection: keep-alive\r\n\r\nGET /b HTTP/1.0\r\n\r\n");
+        assert!(response.contains("Connection: keep-alive\r\n"));
+        assert!(response.ends_with("/b"));
+        server.stop();
+    }
+
+    #[test]
+    fn closes_connections_without_keep_alive_timeout() {
+        let server = Running::start(
+            Server::new(free_addr()).keep_alive_timeout(Duration::ZERO),
+            echo_path,
+        );
+        let response = server.exchange("GET /a HTTP/1.1\r\n\r\nGET /b HTTP/1.1\r\n\r\n");
+        assert!(response.contains("Connection: close\r\n"));
+        assert!(!response.contains("/b"));
+        server.stop();
+    }
+
+    #[test]
+    fn rejects_connections_while_every_worker_is_busy() {
+        let (started, wait_started) = std::sync::mpsc::channel();
+        let started = std::sync::Mutex::new(started);
+        let server = Running::start(
+            Server::new(free_addr()).threads(1).queue_size(0),
+            move |request: &Request| {
+                let _ = started.lock().unwrap().send(());
+                thread::sleep(Duration::from_millis(500));
+                echo_path(request)
+            },
+        );
+
+        // the connection that checked for the server may still hold the worker
+        let mut busy = loop {
+            let mut busy = server.connect();
+            busy.write_all(b"GET /slow HTTP/1.1\r\nConnection: close\r\n\r\n")
+                .unwrap();
+            if wait_started.recv_timeout(Duration::from_secs(1)).is_ok() {
+                break busy;
+            }
+        };
+
+        let response = server.exchange("GET /fast HTTP/1.1\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 503 Service Unavailable\r\n"));
+        assert!(response.contains("Retry-After: 1\r\n"));
+
+        let mut response = String::new();
+        busy.read_to_string(&mut response).unwrap();
+        assert!(response.ends_with("/slow"));
+        server.stop();
+    }
+
+    #[test]
+    fn answers_queued_connections_during_shutdown() {
+        let (started, wait_started) = std::sync::mpsc::channel();
+        let started = std::sync::Mutex::new(started);
+        let server = Running::start(
+            Server::new(free_addr()).threads(1).queue_size(1),
+            move |request: &Request| {
+                if request.path() == "/slow" {
+                    let _ = started.lock().unwrap().send(());
+                    thread::sleep(Duration::from_millis(300));
                 }
-                Err(e) => print!("Failed to establish a connection: {}", e),
+                echo_path(request)
+            },
+        );
+        let mut busy = loop {
+            let mut busy = server.connect();
+            busy.write_all(b"GET /slow HTTP/1.1\r\n\r\n").unwrap();
+            if wait_started.recv_timeout(Duration::from_secs(1)).is_ok() {
+                break busy;
             }
+        };
+        let mut queued = server.connect();
+        queued.write_all(b"GET /queued HTTP/1.1\r\n\r\n").unwrap();
+        thread::sleep(Duration::from_millis(50));
+
+        server.shutdown.store(true, Ordering::SeqCst);
+        let mut response = String::new();
+        queued.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.contains("Connection: close\r\n"));
+        assert!(response.ends_with("/queued"));
+
+        let mut response = String::new();
+        busy.read_to_string(&mut response).unwrap();
+        assert!(response.contains("Connection: close\r\n"));
+        assert!(response.ends_with("/slow"));
+        server.stop();
+    }
+
+    #[test]
+    fn closes_connections_whose_handler_panics() {
+        let server = Running::start(
+            Server::new(free_addr()).shutdown_timeout(Duration::from_secs(10)),
+            |request: &Request| match request.path() {
+                "/panic" => panic!("handler failed"),
+                _ => echo_path(request),
+            },
+        );
+
+        // the client sees the connection end, rather than waiting for an answer
+        let mut stream = server.connect();
+        stream.write_all(b"GET /panic HTTP/1.1\r\n\r\n").unwrap();
+        let mut response = Vec::new();
+        stream.read_to_end(&mut response).unwrap();
+        assert!(response.is_empty());
+
+        // and shutdown does not wait for it
+        let start = Instant::now();
+        server.stop();
+        assert!(start.elapsed() < Duration::from_secs(5));
+    }
+
+    // Reads up to and including the blank line after the head.
+    fn read_head(stream: &mut TcpStream) -> String {
+        let mut head = Vec::new();
+        let mut byte = [0];
+        while !head.ends_with(b"\r\n\r\n") && stream.read(&mut byte).unwrap() == 1 {
+            head.push(byte[0]);
+        }
+        String::from_utf8(head).unwrap()
+    }
+
+    #[test]
+    fn limits_upgraded_connections() {
+        let echo = crate::websocket::WebSocketHandler::new(|mut websocket| {
+            while let Ok(Some(message)) = websocket.read_message() {
+                let _ = websocket.send(&message);
+            }
+        });
+        let server = Running::start(Server::new(free_addr()).max_upgrades(1), echo);
+        let handshake = "GET / HTTP/1.1\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\nSec-WebSocket-Version: 13\r\n\r\n";
+
+        let mut first = server.connect();
+        first.write_all(handshake.as_bytes()).unwrap();
+        assert!(read_head(&mut first).starts_with("HTTP/1.1 101 Switching Protocols\r\n"));
+        // "hi", masked with zeros
+        first
+            .write_all(&[0x81, 0x82, 0, 0, 0, 0, b'h', b'i'])
+            .unwrap();
+        let mut echoed = [0; 4];
+        first.read_exact(&mut echoed).unwrap();
+        assert_eq!(echoed, [0x81, 0x02, b'h', b'i']);
+
+        let mut second = server.connect();
+        second.write_all(handshake.as_bytes()).unwrap();
+        let response = read_head(&mut second);
+        assert!(response.starts_with("HTTP/1.1 503 Service Unavailable\r\n"));
+        assert!(response.contains("Retry-After: 1\r\n"));
+
+        // closing the first frees its place
+        first
+            .write_all(&[0x88, 0x82, 0, 0, 0, 0, 0x03, 0xe8])
+            .unwrap();
+        let mut rest = Vec::new();
+        first.read_to_end(&mut rest).unwrap();
+        assert_eq!(rest, [0x88, 0x02, 0x03, 0xe8]);
+        let mut upgraded = false;
+        for _ in 0..50 {
+            let mut next = server.connect();
+            next.write_all(handshake.as_bytes()).unwrap();
+            if read_head(&mut next).starts_with("HTTP/1.1 101 ") {
+                upgraded = true;
+                break;
+            }
+            thread::sleep(Duration::from_millis(20));
         }
+        assert!(upgraded);
+        server.stop();
+    }
+
+    // A server on a plain and an HTTPS address, the latter returned too.
+    fn start_with_tls(server: Server, handler: impl Handler + 'static) -> (Running, String) {
+        let dir = tls::tests::cert_dir("server");
+        let (cert, key, _) = tls::tests::certificate(&dir, "localhost", &["localhost"]);
+        let tls = Tls::new().with_default(cert, key);
+        tls.reload().unwrap();
+        let tls_addr = free_addr();
+        let server = server.tls(tls).tls_listen(tls_addr.clone());
+        (Running::start(server, handler), tls_addr)
+    }
+
+    fn tls_exchange(addr: &str, request: &str) -> String {
+        let stream = TcpStream::connect(addr).unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let mut stream = StreamOwned::new(tls::tests::client("localhost"), stream);
+        stream.write_all(request.as_bytes()).unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        response
+    }
+
+    #[test]
+    fn redirects_plain_requests_to_https() {
+        let (server, tls_addr) =
+            start_with_tls(Server::new(free_addr()).redirect_to_https(true), echo_path);
+        let port = tls_addr.rsplit(':').next().unwrap();
+
+        let response = server
+            .exchange("GET /a?b=1 HTTP/1.1\r\nHost: example.com:80\r\nConnection: close\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 301 Moved Permanently\r\n"));
+        assert!(response.contains(&format!("Location: https://example.com:{}/a?b=1\r\n", port)));
+
+        let response = server.exchange("GET / HTTP/1.1\r\nConnection: close\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 400 Bad Request\r\n"));
+
+        let response = tls_exchange(&tls_addr, "GET /a HTTP/1.1\r\nConnection: close\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.ends_with("/a"));
+        server.stop();
+    }
+
+    #[test]
+    fn rejects_tls_connections_while_every_worker_is_busy() {
+        let (started, wait_started) = std::sync::mpsc::channel();
+        let started = std::sync::Mutex::new(started);
+        let (server, tls_addr) = start_with_tls(
+            Server::new(free_addr()).threads(1).queue_size(0),
+            move |request: &Request| {
+                let _ = started.lock().unwrap().send(());
+                thread::sleep(Duration::from_millis(500));
+                echo_path(request)
+            },
+        );
+        let mut busy = loop {
+            let mut busy = server.connect();
+            busy.write_all(b"GET /slow HTTP/1.1\r\nConnection: close\r\n\r\n")
+                .unwrap();
+            if wait_started.recv_timeout(Duration::from_secs(1)).is_ok() {
+                break busy;
+            }
+        };
+
+        let response = tls_exchange(&tls_addr, "GET /fast HTTP/1.1\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 503 Service Unavailable\r\n"));
+        assert!(response.contains("Retry-After: 1\r\n"));
+
+        let mut response = String::new();
+        busy.read_to_string(&mut response).unwrap();
+        assert!(response.ends_with("/slow"));
+        server.stop();
+    }
+
+    #[test]
+    fn logs_requests_that_cannot_be_parsed() {
+        let path = std::env::temp_dir().join(format!("server-{}-access.log", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let access_log = AccessLog::file(&path, crate::access_log::LogFormat::Common).unwrap();
+        let server = Running::start(Server::new(free_addr()).access_log(access_log), echo_path);
+
+        let response = server.exchange("GET / HTTP/9.9\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 505 "));
+        let response = server.exchange("GET /ok HTTP/1.1\r\nConnection: close\r\n\r\n");
+        assert!(response.ends_with("/ok"));
+        server.stop();
+
+        let log = std::fs::read_to_string(&path).unwrap();
+        let lines: Vec<_> = log.lines().collect();
+        assert_eq!(lines.len(), 2);
+        assert!(lines[0].starts_with("127.0.0.1 - - ["));
+        assert!(lines[0].contains("] \"-\" 505 "));
+        assert!(lines[1].contains("] \"GET /ok HTTP/1.1\" 200 3"));
+        std::fs::remove_file(&path).unwrap();
     }
 }
@@ -0,0 +1,698 @@
+use super::stream::AsyncStream;
+use super::{body_len, is_timeout, Exchange, Handler, Server};
+use crate::http::reader::{unexpected_eof, RequestParser, CONTINUE};
+use crate::http::{
+    Body, BodyReader, Headers, Method, ParseError, ReadError, Request, RequestReader, Response,
+    Upgraded,
+};
+
+use std::convert::TryFrom;
+use std::future::Future;
+use std::io::{Error as IoError, ErrorKind, Read, Write};
+use std::mem;
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::runtime::{Builder, Handle};
+use tokio::sync::watch;
+use tokio::task::{self, JoinSet};
+use tokio::time;
+use tokio_rustls::TlsAcceptor;
+
+const READ_CHUNK_SIZE: usize = 8192;
+
+/// Like `Handler`, but the response is produced by a future so that the
+/// handler can wait without holding on to a thread.
+pub trait AsyncHandler: Send + Sync + 'static {
+    fn handle_request(&self, request: &Request) -> impl Future<Output = Response> + Send;
+
+    fn handle_bad_request(&self, e: &ParseError) -> impl Future<Output = Response> + Send {
+        println!("Failed to parse request: {}", e);
+        let response = Response::from(e);
+        async move { response }
+    }
+
+    /// Like `Handler::streams_body`. The body is read with blocking reads,
+    /// which only handlers run with `Blocking` can make.
+    fn streams_body(&self, _request: &Request) -> bool {
+        false
+    }
+}
+
+/// Runs a blocking `Handler` on the async server, moving it off the
+/// runtime's worker thread while it works.
+pub struct Blocking<H>(pub H);
+
+impl<H: Handler + 'static> AsyncHandler for Blocking<H> {
+    async fn handle_request(&self, request: &Request<'_>) -> Response {
+        task::block_in_place(|| self.0.handle_request(request))
+    }
+
+    fn streams_body(&self, request: &Request) -> bool {
+        self.0.streams_body(request)
+    }
+
+    async fn handle_bad_request(&self, e: &ParseError) -> Response {
+        self.0.handle_bad_request(e)
+    }
+}
+
+impl Server {
+    /// Serves connections as tokio tasks on `threads` runtime threads instead
+    /// of dedicating a worker thread to each connection.
+    pub fn run_async(self, handler: impl AsyncHandler) {
+        let runtime = Builder::new_multi_thread()
+            .worker_threads(self.threads)
+            .enable_all()
+            .build()
+            .expect("failed to build the tokio runtime");
+
+        runtime.block_on(self.serve_async(handler));
+        // handlers stuck past the shutdown timeout are not waited for
+        runtime.shutdown_background();
+    }
+
+    async fn serve_async(self, handler: impl AsyncHandler) {
+        let mut listeners = Vec::new();
+        let mut addrs = Vec::new();
+        for (addr, secure) in self.listen_addrs() {
+            listeners.push((TcpListener::bind(addr).await.unwrap(), secure));
+            addrs.push(match secure {
+                true => format!("https://{}", addr),
+                false => format!("http://{}", addr),
+            });
+        }
+        println!("Listening on {} (async)", addrs.join(", "));
+        self.register_signals();
+
+        let server = Arc::new(self);
+        let handler = Arc::new(handler);
+
+        // turns the flag set by the signal handler into something tasks can
+        // await, and reloads the certificates on SIGHUP
+        let (shutdown_sender, shutdown) = watch::channel(false);
+        let poller = Arc::clone(&server);
+        tokio::spawn(async move {
+            while !poller.is_shutting_down() {
+                task::block_in_place(|| poller.reload_if_requested());
+                time::sleep(super::POLL_INTERVAL).await;
+            }
+            println!("Shutting down");
+            let _ = shutdown_sender.send(true);
+        });
+
+        let mut accept_tasks = JoinSet::new();
+        for (listener, secure) in listeners {
+            accept_tasks.spawn(accept(
+                listener,
+                secure,
+                Arc::clone(&server),
+                Arc::clone(&handler),
+                shutdown.clone(),
+            ));
+        }
+        while accept_tasks.join_next().await.is_some() {}
+    }
+
+    async fn handle_connection_async(
+        &self,
+        stream: TcpStream,
+        secure: bool,
+        handler: &impl AsyncHandler,
+        mut shutdown: watch::Receiver<bool>,
+    ) {
+        let stream = match &self.tls {
+            Some(tls) if secure => {
+                let acceptor = TlsAcceptor::from(tls.server_config());
+                match time::timeout(super::DEFAULT_READ_TIMEOUT, acceptor.accept(stream)).await {
+                    Ok(Ok(stream)) => AsyncStream::Tls(Box::new(stream)),
+                    Ok(Err(e)) => {
+                        println!("TLS handshake failed: {}", e);
+                        return;
+                    }
+                    Err(_) => return,
+                }
+            }
+            _ => AsyncStream::Plain(stream),
+        };
+
+        let mut reader = AsyncRequestReader::new(stream, self.max_head_size, self.max_body_size);
+        let mut first = true;
+        loop {
+            match self
+                .handle_request_async(&mut reader, handler, &mut shutdown, first)
+                .await
+            {
+                Exchange::KeepAlive => first = false,
+                Exchange::Close => break,
+                Exchange::Upgrade(upgrade, slot) => {
+                    // upgraded connections are served like on the blocking server
+                    let (stream, buffered) = reader.into_parts();
+                    match stream.into_blocking() {
+                        Ok(stream) => {
+                            let upgraded = Upgraded::new(Box::new(stream), buffered);
+                            task::spawn_blocking(move || {
+                                upgrade.call(upgraded);
+                                drop(slot);
+                            });
+                        }
+                        Err(e) => println!("Failed to hand over connection: {}", e),
+                    }
+                    return;
+                }
+            }
+        }
+        let _ = reader.stream().shutdown().await;
+    }
+
+    /// Reads, handles and answers one request. Returns what becomes of the connection.
+    async fn handle_request_async(
+        &self,
+        reader: &mut AsyncRequestReader<AsyncStream>,
+        handler: &impl AsyncHandler,
+        shutdown: &mut watch::Receiver<bool>,
+        first: bool,
+    ) -> Exchange {
+        let read_timeout = if self.keep_alive_timeout.is_zero() {
+            super::DEFAULT_READ_TIMEOUT
+        } else {
+            self.keep_alive_timeout
+        };
+
+        // a connection idle between requests is closed as soon as shutdown
+        // begins, but every connection gets its first request answered
+        let read_head = time::timeout(read_timeout, reader.read_head());
+        let head = if first {
+            read_head.await
+        } else {
+            tokio::select! {
+                head = read_head => head,
+                _ = shutdown.wait_for(|shutdown| *shutdown) => return Exchange::Close,
+            }
+        };
+        let head = match head {
+            Ok(Ok(Some(head))) => head,
+            Ok(Ok(None)) | Err(_) => return Exchange::Close,
+            Ok(Err(ReadError::Parse(e))) => {
+                let start = Instant::now();
+                let response = handler.handle_bad_request(&e).await;
+                let (status_code, bytes) = (response.status_code(), body_len(&response, false));
+                send(reader.stream(), response, false, false).await;
+                self.log_bad_request(reader.stream().peer_addr().ok(), status_code, bytes, start);
+                return Exchange::Close;
+            }
+            Ok(Err(ReadError::Io(e))) => {
+                if !is_timeout(&e) {
+                    println!("Failed to read from connection: {}", e);
+                }
+                return Exchange::Close;
+            }
+        };
+        let start = Instant::now();
+
+        let mut request = match Request::try_from(&head[..]) {
+            Ok(request) => request,
+            Err(e) => {
+                let response = handler.handle_bad_request(&e).await;
+                let (status_code, bytes) = (response.status_code(), body_len(&response, false));
+                send(reader.stream(), response, false, false).await;
+                self.log_bad_request(reader.stream().peer_addr().ok(), status_code, bytes, start);
+                return Exchange::Close;
+            }
+        };
+        if let Ok(remote_addr) = reader.stream().peer_addr() {
+            request.set_remote_addr(remote_addr);
+        }
+        request.set_secure(reader.stream().is_secure());
+
+        let redirect = self.redirect_to_https && !reader.stream().is_secure();
+        let body;
+        let mut response = if !redirect && handler.streams_body(&request) {
+            // the handler reads the body with blocking reads, see `AsyncHandler::streams_body`
+            let parser = mem::replace(&mut reader.parser, RequestParser::new(0, 0));
+            let stream = BlockingStream {
+                stream: &mut reader.stream,
+                timeout: read_timeout,
+            };
+            let mut blocking = RequestReader::with_parser(stream, parser);
+            let body = match blocking.body_stream(request.headers()) {
+                Ok(body) => Mutex::new(body),
+                Err(e) => {
+                    reader.parser = blocking.into_parser();
+                    return self
+                        .reject_body_async(reader, handler, &request, e.into(), start)
+                        .await;
+                }
+            };
+            let read = |buf: &mut [u8]| body.lock().unwrap().read(buf);
+            let mut streaming = request.clone();
+            streaming.set_body_reader(BodyReader::new(&read));
+            let mut response = handler.handle_request(&streaming).await;
+
+            // what the handler left unread is in the way of the next request
+            if !body.into_inner().unwrap().is_done() {
+                response.set_header("Connection", "close");
+            }
+            reader.parser = blocking.into_parser();
+            response
+        } else {
+            body = match time::timeout(read_timeout, reader.read_body(request.headers())).await {
+                Ok(Ok(body)) => body,
+                Ok(Err(e)) => {
+                    return self
+                        .reject_body_async(reader, handler, &request, e, start)
+                        .await
+                }
+                Err(_) => return Exchange::Close,
+            };
+            request.set_body(&body);
+
+            if redirect {
+                self.https_redirect(&request)
+            } else {
+                handler.handle_request(&request).await
+            }
+        };
+        self.compress(&request, &mut response);
+
+        let head_only = matches!(request.method(), Method::HEAD);
+        let upgrade = self.take_upgrade(&mut response, head_only, request.version());
+        let keep_alive = upgrade.is_some() || self.set_connection(&request, &mut response);
+        let (status_code, bytes) = (response.status_code(), body_len(&response, head_only));
+        let keep_alive = send(reader.stream(), response, head_only, keep_alive).await;
+
+        self.log(&request, status_code, bytes, start);
+        match (keep_alive, upgrade) {
+            (true, Some((upgrade, slot))) => Exchange::Upgrade(upgrade, slot),
+            (true, None) => Exchange::KeepAlive,
+            (false, _) => Exchange::Close,
+        }
+    }
+
+    // Answers a request whose body could not be read.
+    async fn reject_body_async(
+        &self,
+        reader: &mut AsyncRequestReader<AsyncStream>,
+        handler: &impl AsyncHandler,
+        request: &Request<'_>,
+        e: ReadError,
+        start: Instant,
+    ) -> Exchange {
+        match e {
+            ReadError::Parse(e) => {
+                let response = handler.handle_bad_request(&e).await;
+                let (status_code, bytes) = (response.status_code(), body_len(&response, false));
+                send(reader.stream(), response, false, false).await;
+                self.log(request, status_code, bytes, start);
+            }
+            ReadError::Io(e) => println!("Failed to read from connection: {}", e),
+        }
+        Exchange::Close
+    }
+}
+
+async fn accept<H: AsyncHandler>(
+    listener: TcpListener,
+    secure: bool,
+    server: Arc<Server>,
+    handler: Arc<H>,
+    mut shutdown: watch::Receiver<bool>,
+) {
+    let mut connections = JoinSet::new();
+
+    loop {
+        let accepted = tokio::select! {
+            accepted = listener.accept() => accepted,
+            _ = shutdown.wait_for(|shutdown| *shutdown) => break,
+        };
+        while connections.try_join_next().is_some() {}
+
+        match accepted {
+            Ok((stream, _)) => {
+                let server = Arc::clone(&server);
+                let handler = Arc::clone(&handler);
+                let shutdown = shutdown.clone();
+                connections.spawn(async move {
+                    server
+                        .handle_connection_async(stream, secure, &*handler, shutdown)
+                        .await
+                });
+            }
+            Err(e) => println!("Failed to establish a connection: {}", e),
+        }
+    }
+
+    let drained = time::timeout(server.shutdown_timeout, async {
+        while connections.join_next().await.is_some() {}
+    })
+    .await;
+    if drained.is_err() {
+        println!(
+            "Closing {} connections still busy after the shutdown timeout",
+            connections.len()
+        );
+        connections.abort_all();
+    }
+}
+
+/// Sends the response and returns whether the connection stays open.
+async fn send(
+    stream: &mut AsyncStream,
+    mut response: Response,
+    head_only: bool,
+    keep_alive: bool,
+) -> bool {
+    if !keep_alive {
+        response.set_header("Connection", "close");
+    }
+
+    let result = async {
+        stream.write_all(response.head().as_bytes()).await?;
+        let body = response.take_body();
+        if !head_only && response.status_code().allows_body() {
+            write_body(stream, body).await?;
+        }
+        stream.flush().await
+    };
+
+    match result.await {
+        Ok(()) => keep_alive,
+        Err(e) => {
+            println!("Failed to send response: {}", e);
+            false
+        }
+    }
+}
+
+async fn write_body(stream: &mut AsyncStream, body: Body) -> Result<(), IoError> {
+    match body {
+        Body::Empty => Ok(()),
+        Body::Bytes(bytes) => stream.write_all(&bytes).await,
+        Body::Reader(reader, len) => {
+            let mut reader = reader.take(len);
+            let mut chunk = vec![0; READ_CHUNK_SIZE];
+            let mut copied = 0;
+
+            loop {
+                // readers are blocking (usually files), keep them off the runtime
+                let read = task::block_in_place(|| reader.read(&mut chunk))?;
+                if read == 0 {
+                    break;
+                }
+                stream.write_all(&chunk[..read]).await?;
+                copied += read as u64;
+            }
+
+            if copied < len {
+                return Err(IoError::new(
+                    ErrorKind::UnexpectedEof,
+                    "body ended before its announced length",
+                ));
+            }
+            Ok(())
+        }
+        // streams are handed over before the response is sent, see `take_upgrade`
+        Body::Stream(_) => Ok(()),
+    }
+}
+
+/// The async counterpart of `RequestReader`, around the same `RequestParser`.
+struct AsyncRequestReader<S> {
+    stream: S,
+    parser: RequestParser,
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncRequestReader<S> {
+    fn new(stream: S, max_head_size: usize, max_body_size: usize) -> Self {
+        Self {
+            stream,
+            parser: RequestParser::new(max_head_size, max_body_size),
+        }
+    }
+
+    fn stream(&mut self) -> &mut S {
+        &mut self.stream
+    }
+
+    fn into_parts(self) -> (S, Vec<u8>) {
+        (self.stream, self.parser.into_buffered())
+    }
+
+    async fn read_head(&mut self) -> Result<Option<Vec<u8>>, ReadError> {
+        loop {
+            if let Some(head) = self.parser.parse_head()? {
+                return Ok(Some(head));
+            }
+            if self.fill().await? == 0 {
+                return self.parser.closed_before_head();
+            }
+        }
+    }
+
+    async fn read_body(&mut self, headers: &Headers<'_>) -> Result<Vec<u8>, ReadError> {
+        if self.parser.start_body(headers)? {
+            self.stream.write_all(CONTINUE).await?;
+            self.stream.flush().await?;
+        }
+
+        loop {
+            if let Some(body) = self.parser.parse_body()? {
+                return Ok(body);
+            }
+            if self.fill().await? == 0 {
+                return Err(unexpected_eof().into());
+            }
+        }
+    }
+
+    async fn fill(&mut self) -> Result<usize, IoError> {
+        let mut chunk = [0; READ_CHUNK_SIZE];
+        let len = self.stream.read(&mut chunk).await?;
+        self.parser.feed(&chunk[..len]);
+        Ok(len)
+    }
+}
+
+// Blocking reads and writes on an async stream, for handlers run in
+// `block_in_place`. Each gives up after `timeout`.
+struct BlockingStream<'a, S> {
+    stream: &'a mut S,
+    timeout: Duration,
+}
+
+impl<S: AsyncRead + Unpin> Read for BlockingStream<'_, S> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, IoError> {
+        block_on(self.timeout, self.stream.read(buf))
+    }
+}
+
+impl<S: AsyncWrite + Unpin> Write for BlockingStream<'_, S> {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, IoError> {
+        block_on(self.timeout, self.stream.write(buf))
+    }
+
+    fn flush(&mut self) -> Result<(), IoError> {
+        block_on(self.timeout, self.stream.flush())
+    }
+}
+
+fn block_on<T>(
+    timeout: Duration,
+    io: impl Future<Output = Result<T, IoError>>,
+) -> Result<T, IoError> {
+    Handle::current()
+        .block_on(time::timeout(timeout, io))
+        .unwrap_or_else(|_| Err(IoError::from(ErrorKind::TimedOut)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use std::net::TcpStream as StdTcpStream;
+    use std::sync::atomic::AtomicBool;
+    use std::thread;
+    use std::time::Duration;
+    use tokio::io::duplex;
+
+    #[tokio::test]
+    async fn reads_heads_and_bodies() {
+        let (mut client, server) = duplex(64);
+        let mut reader = AsyncRequestReader::new(server, 1024, 1024);
+        tokio::spawn(async move {
+            client
+                .write_all(b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\nExpect: 100-continue\r\n\r\n")
+                .await
+                .unwrap();
+            let mut interim = [0; CONTINUE.len()];
+            client.read_exact(&mut interim).await.unwrap();
+            assert_eq!(interim, CONTINUE);
+            client.write_all(b"3\r\nabc\r\n0\r\n\r\nGET").await.unwrap();
+        });
+
+        let head = reader.read_head().await.ok().flatten().unwrap();
+        let head = Request::try_from(&head[..]).unwrap();
+        let body = reader.read_body(head.headers()).await.ok().unwrap();
+        assert_eq!(body, b"abc");
+
+        // the client closed after an incomplete head
+        assert!(matches!(
+            reader.read_head().await,
+            Err(ReadError::Parse(ParseError::InvalidRequest))
+        ));
+    }
+
+    #[tokio::test]
+    async fn rejects_bodies_over_the_limit() {
+        let (mut client, server) = duplex(64);
+        let mut reader = AsyncRequestReader::new(server, 1024, 2);
+        client
+            .write_all(b"POST / HTTP/1.1\r\nContent-Length: 3\r\n\r\nabc")
+            .await
+            .unwrap();
+
+        let head = reader.read_head().await.ok().flatten().unwrap();
+        let head = Request::try_from(&head[..]).unwrap();
+        assert!(matches!(
+            reader.read_body(head.headers()).await,
+            Err(ReadError::Parse(ParseError::PayloadTooLarge))
+        ));
+    }
+
+    // Answers "path body-length". Bodies below /stream are streamed, and
+    // left unread at /stream/unread. /not-modified answers 304 with a body.
+    struct LengthEcho;
+
+    impl Handler for LengthEcho {
+        fn streams_body(&self, request: &Request) -> bool {
+            request.path().starts_with("/stream")
+        }
+
+        fn handle_request(&self, request: &Request) -> Response {
+            let len = match request.body_reader() {
+                Some(_) if request.path() == "/stream/unread" => 0,
+                Some(mut body) => std::io::copy(&mut body, &mut std::io::sink()).unwrap(),
+                None => request.body().len() as u64,
+            };
+            let body = format!("{} {}", request.path(), len);
+            match request.path() {
+                "/not-modified" => Response::new(crate::http::StatusCode::NotModified, Some(body)),
+                _ => Response::new(crate::http::StatusCode::Ok, Some(body)),
+            }
+        }
+    }
+
+    // Serves `LengthEcho` until the flag is set.
+    fn start() -> (String, Arc<AtomicBool>, thread::JoinHandle<()>) {
+        let addr = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .to_string();
+        let server = Server::new(addr.clone()).threads(2);
+        let shutdown = server.shutdown_handle();
+        let running = thread::spawn(move || server.run_async(Blocking(LengthEcho)));
+        (addr, shutdown, running)
+    }
+
+    fn connect(addr: &str) -> StdTcpStream {
+        let stream = (0..100)
+            .find_map(|_| {
+                thread::sleep(Duration::from_millis(20));
+                StdTcpStream::connect(addr).ok()
+            })
+            .unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        stream
+    }
+
+    #[test]
+    fn serves_pipelined_requests() {
+        let (addr, shutdown, running) = start();
+
+        let mut stream = connect(&addr);
+        stream
+            .write_all(b"POST /a HTTP/1.1\r\nContent-Length: 2\r\n\r\nhiGET /b HTTP/1.1\r\nConnection: close\r\n\r\n")
+            .unwrap();
+        let mut response = String::new();
+        std::io::Read::read_to_string(&mut stream, &mut response).unwrap();
+
+        assert_eq!(response.matches("HTTP/1.1 200 OK\r\n").count(), 2);
+        assert!(response.find("/a 2").unwrap() < response.find("/b 0").unwrap());
+
+        shutdown.store(true, Ordering::SeqCst);
+        running.join().unwrap();
+    }
+
+    #[test]
+    fn leaves_out_bodies_of_responses_that_cannot_have_one() {
+        let (addr, shutdown, running) = start();
+
+        let mut stream = connect(&addr);
+        stream
+            .write_all(
+                b"GET /not-modified HTTP/1.1\r\n\r\nGET /b HTTP/1.1\r\nConnection: close\r\n\r\n",
+            )
+            .unwrap();
+        let mut response = String::new();
+        std::io::Read::read_to_string(&mut stream, &mut response).unwrap();
+
+        assert!(response.starts_with("HTTP/1.1 304 Not Modified\r\n"));
+        assert!(!response.contains("/not-modified"));
+        assert!(response.contains("\r\n\r\nHTTP/1.1 200 OK\r\n"));
+        assert!(response.ends_with("/b 0"));
+
+        shutdown.store(true, Ordering::SeqCst);
+        running.join().unwrap();
+    }
+
+    #[test]
+    fn answers_the_first_request_of_a_connection_during_shutdown() {
+        let (addr, shutdown, running) = start();
+        let mut stream = connect(&addr);
+        thread::sleep(Duration::from_millis(100));
+
+        shutdown.store(true, Ordering::SeqCst);
+        thread::sleep(Duration::from_millis(100));
+        stream.write_all(b"GET /late HTTP/1.1\r\n\r\n").unwrap();
+        let mut response = String::new();
+        std::io::Read::read_to_string(&mut stream, &mut response).unwrap();
+
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.contains("Connection: close\r\n"));
+        assert!(response.ends_with("/late 0"));
+        running.join().unwrap();
+    }
+
+    #[test]
+    fn streams_bodies_to_handlers_that_ask() {
+        let (addr, shutdown, running) = start();
+
+        // past the server's max_body_size
+        let data = vec![b'x'; 2 * 1024 * 1024];
+        let mut stream = connect(&addr);
+        let head = format!(
+            "POST /stream HTTP/1.1\r\nContent-Length: {}\r\n\r\n",
+            data.len()
+        );
+        stream.write_all(head.as_bytes()).unwrap();
+        stream.write_all(&data).unwrap();
+        stream
+            .write_all(b"POST /stream/unread HTTP/1.1\r\nContent-Length: 2\r\n\r\nhiGET /c HTTP/1.1\r\n\r\n")
+            .unwrap();
+        let mut response = String::new();
+        std::io::Read::read_to_string(&mut stream, &mut response).unwrap();
+
+        assert!(response.contains("\r\n\r\n/stream 2097152HTTP/1.1 200 OK\r\n"));
+        // the unread body ends the connection
+        assert!(response.contains("Connection: close\r\n"));
+        assert!(response.ends_with("/stream/unread 0"));
+
+        shutdown.store(true, Ordering::SeqCst);
+        running.join().unwrap();
+    }
+}
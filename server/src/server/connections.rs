@@ -0,0 +1,75 @@
+use std::collections::HashMap;
+use std::io;
+use std::net::{Shutdown, TcpStream};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+/// The open connections of a `Server`, so that shutdown can close the idle
+/// ones right away and the busy ones once the deadline has passed.
+#[derive(Default)]
+pub struct Connections {
+    next_id: AtomicU64,
+    streams: Mutex<HashMap<u64, (TcpStream, bool)>>,
+}
+
+impl Connections {
+    /// Starts tracking `stream` until the returned `Connection` is dropped.
+    /// It counts as busy until its first request is answered, so that
+    /// shutdown does not close it while it waits for a worker.
+    pub fn add(self: &Arc<Self>, stream: &TcpStream) -> io::Result<Connection> {
+        let id = self.next_id.fetch_add(1, Ordering::SeqCst);
+        self.lock().insert(id, (stream.try_clone()?, false));
+        Ok(Connection {
+            connections: Arc::clone(self),
+            id,
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.lock().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Unblocks the workers waiting on idle connections, which then see EOF.
+    pub fn close_idle(&self) {
+        for (stream, _) in self.lock().values().filter(|(_, idle)| *idle) {
+            let _ = stream.shutdown(Shutdown::Both);
+        }
+    }
+
+    pub fn close_all(&self) {
+        for (stream, _) in self.lock().values() {
+            let _ = stream.shutdown(Shutdown::Both);
+        }
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, HashMap<u64, (TcpStream, bool)>> {
+        self.streams.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// A connection tracked by `Connections`. Dropping it stops the tracking,
+/// also when a panicking handler unwinds past it, so that the clone of the
+/// stream is closed and shutdown does not wait for it.
+pub struct Connection {
+    connections: Arc<Connections>,
+    id: u64,
+}
+
+impl Connection {
+    /// Idle connections are waiting for the next request.
+    pub fn set_idle(&self, idle: bool) {
+        if let Some((_, is_idle)) = self.connections.lock().get_mut(&self.id) {
+            *is_idle = idle;
+        }
+    }
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        self.connections.lock().remove(&self.id);
+    }
+}
@@ -0,0 +1,169 @@
+use crate::http::upgrade::Connection;
+use rustls::{ServerConnection, StreamOwned};
+
+use std::io::{Read, Result as IoResult, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::time::Duration;
+
+/// A client connection, either plain or wrapped in TLS.
+pub enum Stream {
+    Plain(TcpStream),
+    Tls(Box<StreamOwned<ServerConnection, TcpStream>>),
+}
+
+impl Stream {
+    /// The underlying socket, for timeouts and shutting it down.
+    pub fn tcp(&self) -> &TcpStream {
+        match self {
+            Self::Plain(stream) => stream,
+            Self::Tls(stream) => &stream.sock,
+        }
+    }
+
+    pub fn is_secure(&self) -> bool {
+        matches!(self, Self::Tls(_))
+    }
+
+    pub fn peer_addr(&self) -> IoResult<SocketAddr> {
+        self.tcp().peer_addr()
+    }
+
+    /// Tells a TLS client that no more data follows, so it can tell a
+    /// complete response from a truncated one.
+    pub fn close(&mut self) {
+        if let Self::Tls(stream) = self {
+            stream.conn.send_close_notify();
+            let _ = stream.flush();
+        }
+    }
+}
+
+impl Connection for Stream {
+    fn peer_addr(&self) -> IoResult<SocketAddr> {
+        Stream::peer_addr(self)
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> IoResult<()> {
+        self.tcp().set_read_timeout(timeout)
+    }
+
+    fn close(&mut self) {
+        Stream::close(self)
+    }
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> IoResult<usize> {
+        match self {
+            Self::Plain(stream) => stream.read(buf),
+            Self::Tls(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> IoResult<usize> {
+        match self {
+            Self::Plain(stream) => stream.write(buf),
+            Self::Tls(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> IoResult<()> {
+        match self {
+            Self::Plain(stream) => stream.flush(),
+            Self::Tls(stream) => stream.flush(),
+        }
+    }
+}
+
+/// The async counterpart of `Stream`.
+#[cfg(feature = "async")]
+pub enum AsyncStream {
+    Plain(tokio::net::TcpStream),
+    Tls(Box<tokio_rustls::server::TlsStream<tokio::net::TcpStream>>),
+}
+
+#[cfg(feature = "async")]
+mod async_stream {
+    use super::{AsyncStream, Stream};
+    use rustls::StreamOwned;
+
+    use std::io::Result as IoResult;
+    use std::net::SocketAddr;
+    use std::pin::Pin;
+    use std::task::{Context, Poll};
+    use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+    impl AsyncStream {
+        pub fn is_secure(&self) -> bool {
+            matches!(self, Self::Tls(_))
+        }
+
+        pub fn peer_addr(&self) -> IoResult<SocketAddr> {
+            match self {
+                Self::Plain(stream) => stream.peer_addr(),
+                Self::Tls(stream) => stream.get_ref().0.peer_addr(),
+            }
+        }
+
+        /// The blocking equivalent, for handlers that take over the connection.
+        pub fn into_blocking(self) -> IoResult<Stream> {
+            let into_std = |stream: tokio::net::TcpStream| {
+                let stream = stream.into_std()?;
+                stream.set_nonblocking(false)?;
+                Ok::<_, std::io::Error>(stream)
+            };
+
+            match self {
+                Self::Plain(stream) => Ok(Stream::Plain(into_std(stream)?)),
+                Self::Tls(stream) => {
+                    let (stream, connection) = stream.into_inner();
+                    let stream = into_std(stream)?;
+                    Ok(Stream::Tls(Box::new(StreamOwned::new(connection, stream))))
+                }
+            }
+        }
+    }
+
+    impl AsyncRead for AsyncStream {
+        fn poll_read(
+            self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &mut ReadBuf<'_>,
+        ) -> Poll<IoResult<()>> {
+            match self.get_mut() {
+                Self::Plain(stream) => Pin::new(stream).poll_read(cx, buf),
+                Self::Tls(stream) => Pin::new(stream).poll_read(cx, buf),
+            }
+        }
+    }
+
+    impl AsyncWrite for AsyncStream {
+        fn poll_write(
+            self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &[u8],
+        ) -> Poll<IoResult<usize>> {
+            match self.get_mut() {
+                Self::Plain(stream) => Pin::new(stream).poll_write(cx, buf),
+                Self::Tls(stream) => Pin::new(stream).poll_write(cx, buf),
+            }
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<IoResult<()>> {
+            match self.get_mut() {
+                Self::Plain(stream) => Pin::new(stream).poll_flush(cx),
+                Self::Tls(stream) => Pin::new(stream).poll_flush(cx),
+            }
+        }
+
+        // for TLS this also sends close_notify
+        fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<IoResult<()>> {
+            match self.get_mut() {
+                Self::Plain(stream) => Pin::new(stream).poll_shutdown(cx),
+                Self::Tls(stream) => Pin::new(stream).poll_shutdown(cx),
+            }
+        }
+    }
+}
@@ -0,0 +1,351 @@
+use crate::http::request::matches_host;
+
+use rustls::crypto::{ring, CryptoProvider};
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::server::{ClientHello, ResolvesServerCert};
+use rustls::sign::CertifiedKey;
+use rustls::ServerConfig;
+
+use std::io::{Error as IoError, ErrorKind};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+
+/// Certificates for HTTPS listeners. The default certificate is used when the
+/// client sends no server name (SNI) or one that no other certificate covers.
+pub struct Tls {
+    default: Option<CertificateFiles>,
+    sni: Vec<CertificateFiles>,
+    resolver: Arc<Resolver>,
+    config: Arc<ServerConfig>,
+}
+
+struct CertificateFiles {
+    hostnames: Vec<String>,
+    cert_path: PathBuf,
+    key_path: PathBuf,
+}
+
+#[derive(Debug, Default)]
+struct Resolver {
+    certificates: RwLock<Certificates>,
+}
+
+#[derive(Debug, Default)]
+struct Certificates {
+    default: Option<Arc<CertifiedKey>>,
+    by_hostname: Vec<(String, Arc<CertifiedKey>)>,
+}
+
+impl Tls {
+    /// No certificate is read until `reload` is called.
+    pub fn new() -> Self {
+        let resolver = Arc::new(Resolver::default());
+        let mut config = ServerConfig::builder_with_provider(Arc::new(ring::default_provider()))
+            .with_safe_default_protocol_versions()
+            .expect("the ring provider supports the default protocol versions")
+            .with_no_client_auth()
+            .with_cert_resolver(Arc::clone(&resolver) as Arc<dyn ResolvesServerCert>);
+        config.alpn_protocols = vec![b"http/1.1".to_vec()];
+
+        Self {
+            default: None,
+            sni: Vec::new(),
+            resolver,
+            config: Arc::new(config),
+        }
+    }
+
+    /// The PEM certificate chain and private key used without a better match.
+    pub fn with_default(mut self, cert_path: impl AsRef<Path>, key_path: impl AsRef<Path>) -> Self {
+        self.default = Some(CertificateFiles {
+            hostnames: Vec::new(),
+            cert_path: cert_path.as_ref().to_path_buf(),
+            key_path: key_path.as_ref().to_path_buf(),
+        });
+        self
+    }
+
+    /// A certificate for clients asking for one of `hostnames`, which may
+    /// start with `*.` to match any single label.
+    pub fn with_sni(
+        mut self,
+        hostnames: &[String],
+        cert_path: impl AsRef<Path>,
+        key_path: impl AsRef<Path>,
+    ) -> Self {
+        self.sni.push(CertificateFiles {
+            hostnames: hostnames
+                .iter()
+                .map(|hostname| hostname.to_ascii_lowercase())
+                .collect(),
+            cert_path: cert_path.as_ref().to_path_buf(),
+            key_path: key_path.as_ref().to_path_buf(),
+        });
+        self
+    }
+
+    /// (Re)reads every certificate and key. On error the certificates in use
+    /// are kept, so a bad file never takes the site down.
+    pub fn reload(&self) -> Result<(), IoError> {
+        let provider = ring::default_provider();
+        let mut certificates = Certificates::default();
+
+        if let Some(files) = &self.default {
+            certificates.default = Some(files.load(&provider)?);
+        }
+        for files in &self.sni {
+            let key = files.load(&provider)?;
+            for hostname in &files.hostnames {
+                certificates
+                    .by_hostname
+                    .push((hostname.clone(), Arc::clone(&key)));
+            }
+        }
+        if certificates.default.is_none() && certificates.by_hostname.is_empty() {
+            return Err(IoError::new(
+                ErrorKind::InvalidInput,
+                "no certificate configured",
+            ));
+        }
+
+        *self
+            .resolver
+            .certificates
+            .write()
+            .unwrap_or_else(|e| e.into_inner()) = certificates;
+        Ok(())
+    }
+
+    pub fn server_config(&self) -> Arc<ServerConfig> {
+        Arc::clone(&self.config)
+    }
+}
+
+impl Default for Tls {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl CertificateFiles {
+    fn load(&self, provider: &CryptoProvider) -> Result<Arc<CertifiedKey>, IoError> {
+        let invalid = |path: &Path, e: &dyn std::fmt::Display| {
+            IoError::new(ErrorKind::InvalidData, format!("{}: {}", path.display(), e))
+        };
+
+        let chain = CertificateDer::pem_file_iter(&self.cert_path)
+            .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+            .map_err(|e| invalid(&self.cert_path, &e))?;
+        if chain.is_empty() {
+            return Err(invalid(&self.cert_path, &"no certificate found"));
+        }
+        let key = PrivateKeyDer::from_pem_file(&self.key_path)
+            .map_err(|e| invalid(&self.key_path, &e))?;
+        let key = provider
+            .key_provider
+            .load_private_key(key)
+            .map_err(|e| invalid(&self.key_path, &e))?;
+
+        Ok(Arc::new(CertifiedKey::new(chain, key)))
+    }
+}
+
+impl ResolvesServerCert for Resolver {
+    fn resolve(&self, client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        let certificates = self.certificates.read().unwrap_or_else(|e| e.into_inner());
+
+        let by_name = client_hello.server_name().and_then(|name| {
+            let name = name.to_ascii_lowercase();
+            certificates
+                .by_hostname
+                .iter()
+                .find(|(hostname, _)| *hostname == name)
+                .or_else(|| {
+                    certificates
+                        .by_hostname
+                        .iter()
+                        .find(|(hostname, _)| matches_host(hostname, &name))
+                })
+        });
+
+        match by_name {
+            Some((_, key)) => Some(Arc::clone(key)),
+            None => certificates.default.clone().or_else(|| {
+                certificates
+                    .by_hostname
+                    .first()
+                    .map(|(_, key)| Arc::clone(key))
+            }),
+        }
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+    use rustls::pki_types::{ServerName, UnixTime};
+    use rustls::{
+        ClientConfig, ClientConnection, Connection, DigitallySignedStruct, ServerConnection,
+        SignatureScheme,
+    };
+    use std::fs;
+    use std::process;
+
+    /// Writes a fresh self-signed certificate and key to `dir` and returns
+    /// their paths along with the certificate, to tell which one was served.
+    pub fn certificate(dir: &Path, name: &str, hostnames: &[&str]) -> (PathBuf, PathBuf, Vec<u8>) {
+        let hostnames: Vec<String> = hostnames.iter().map(|name| name.to_string()).collect();
+        let generated = rcgen::generate_simple_self_signed(hostnames).unwrap();
+        let cert_path = dir.join(format!("{}.crt", name));
+        let key_path = dir.join(format!("{}.key", name));
+        fs::write(&cert_path, generated.cert.pem()).unwrap();
+        fs::write(&key_path, generated.key_pair.serialize_pem()).unwrap();
+        (cert_path, key_path, generated.cert.der().to_vec())
+    }
+
+    pub fn cert_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("tls-{}-{}", process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    // Tests only look at which certificate is served, so any one is trusted.
+    #[derive(Debug)]
+    struct TrustAnything;
+
+    impl ServerCertVerifier for TrustAnything {
+        fn verify_server_cert(
+            &self,
+            _: &CertificateDer<'_>,
+            _: &[CertificateDer<'_>],
+            _: &ServerName<'_>,
+            _: &[u8],
+            _: UnixTime,
+        ) -> Result<ServerCertVerified, rustls::Error> {
+            Ok(ServerCertVerified::assertion())
+        }
+
+        fn verify_tls12_signature(
+            &self,
+            _: &[u8],
+            _: &CertificateDer<'_>,
+            _: &DigitallySignedStruct,
+        ) -> Result<HandshakeSignatureValid, rustls::Error> {
+            Ok(HandshakeSignatureValid::assertion())
+        }
+
+        fn verify_tls13_signature(
+            &self,
+            _: &[u8],
+            _: &CertificateDer<'_>,
+            _: &DigitallySignedStruct,
+        ) -> Result<HandshakeSignatureValid, rustls::Error> {
+            Ok(HandshakeSignatureValid::assertion())
+        }
+
+        fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+            ring::default_provider()
+                .signature_verification_algorithms
+                .supported_schemes()
+        }
+    }
+
+    pub fn client(server_name: &str) -> ClientConnection {
+        let config = ClientConfig::builder_with_provider(Arc::new(ring::default_provider()))
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .dangerous()
+            .with_custom_certificate_verifier(Arc::new(TrustAnything))
+            .with_no_client_auth();
+        let server_name = ServerName::try_from(server_name.to_string()).unwrap();
+        ClientConnection::new(Arc::new(config), server_name).unwrap()
+    }
+
+    // Hands over what `from` has to send to `to`.
+    fn transfer(from: &mut Connection, to: &mut Connection) {
+        let mut records = Vec::new();
+        from.write_tls(&mut records).unwrap();
+        to.read_tls(&mut &records[..]).unwrap();
+        to.process_new_packets().unwrap();
+    }
+
+    // An IP address as `server_name` sends no SNI.
+    fn served_certificate(tls: &Tls, server_name: &str) -> Vec<u8> {
+        let mut client = Connection::Client(client(server_name));
+        let mut server = Connection::Server(ServerConnection::new(tls.server_config()).unwrap());
+        while client.is_handshaking() || server.is_handshaking() {
+            transfer(&mut client, &mut server);
+            transfer(&mut server, &mut client);
+        }
+        client.peer_certificates().unwrap()[0].to_vec()
+    }
+
+    #[test]
+    fn picks_certificates_by_server_name() {
+        let dir = cert_dir("sni");
+        let (cert, key, default) = certificate(&dir, "default", &["localhost"]);
+        let (wildcard_cert, wildcard_key, wildcard) =
+            certificate(&dir, "wildcard", &["*.example.com"]);
+        let (www_cert, www_key, www) = certificate(&dir, "www", &["www.example.com"]);
+        let tls = Tls::new()
+            .with_default(cert, key)
+            .with_sni(&["*.Example.com".to_string()], wildcard_cert, wildcard_key)
+            .with_sni(&["www.example.com".to_string()], www_cert, www_key);
+        tls.reload().unwrap();
+
+        // an exact name wins over a wildcard listed before it
+        assert_eq!(served_certificate(&tls, "www.example.com"), www);
+        assert_eq!(served_certificate(&tls, "WWW.example.com"), www);
+        assert_eq!(served_certificate(&tls, "api.example.com"), wildcard);
+        // `*.` covers a single label
+        assert_eq!(served_certificate(&tls, "a.b.example.com"), default);
+        assert_eq!(served_certificate(&tls, "example.com"), default);
+        assert_eq!(served_certificate(&tls, "127.0.0.1"), default);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn falls_back_to_the_first_certificate_without_a_default() {
+        let dir = cert_dir("no-default");
+        let (a_cert, a_key, a) = certificate(&dir, "a", &["a.test"]);
+        let (b_cert, b_key, b) = certificate(&dir, "b", &["b.test"]);
+        let tls = Tls::new()
+            .with_sni(&["a.test".to_string()], a_cert, a_key)
+            .with_sni(&["b.test".to_string()], b_cert, b_key);
+        tls.reload().unwrap();
+
+        assert_eq!(served_certificate(&tls, "b.test"), b);
+        assert_eq!(served_certificate(&tls, "c.test"), a);
+        assert_eq!(served_certificate(&tls, "127.0.0.1"), a);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn keeps_the_certificates_in_use_when_a_reload_fails() {
+        let dir = cert_dir("reload");
+        let (cert, key, old) = certificate(&dir, "site", &["localhost"]);
+        let tls = Tls::new().with_default(&cert, &key);
+        tls.reload().unwrap();
+
+        fs::write(&cert, "not a certificate").unwrap();
+        let e = tls.reload().unwrap_err();
+        assert!(e.to_string().starts_with(&cert.display().to_string()));
+        assert_eq!(served_certificate(&tls, "localhost"), old);
+
+        let (_, _, new) = certificate(&dir, "site", &["localhost"]);
+        tls.reload().unwrap();
+        assert_eq!(served_certificate(&tls, "localhost"), new);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn needs_a_certificate() {
+        assert_eq!(
+            Tls::new().reload().unwrap_err().kind(),
+            ErrorKind::InvalidInput
+        );
+    }
+}
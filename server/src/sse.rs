@@ -0,0 +1,412 @@
+use super::http::{Body, Request, Response, StatusCode};
+
+use std::collections::VecDeque;
+use std::io::{Read, Result as IoResult};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, SyncSender, TrySendError};
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// Comments sent while no event is, so that proxies keep the connection open
+/// and a client that went away is noticed.
+pub const DEFAULT_HEARTBEAT: Duration = Duration::from_secs(15);
+
+/// Events waiting to be written to a client before it counts as too slow.
+pub const DEFAULT_BUFFER_SIZE: usize = 64;
+
+/// One Server-Sent Event. Multi-line data is split over several `data:` lines.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Event {
+    id: Option<String>,
+    event: Option<String>,
+    data: String,
+    retry: Option<Duration>,
+}
+
+/// The body of a `text/event-stream` response: events sent through its
+/// `EventSender`s, with heartbeats in between. It ends when every sender is
+/// dropped, and the senders fail once the client has gone away. At most a
+/// fixed number of events wait for a slow client; `send` then blocks.
+pub struct EventStream {
+    receiver: Receiver<String>,
+    heartbeat: Duration,
+    pending: Vec<u8>,
+}
+
+#[derive(Clone)]
+pub struct EventSender {
+    sender: SyncSender<String>,
+}
+
+/// The client of an `EventSender` is gone.
+#[derive(Debug)]
+pub struct Disconnected;
+
+/// Sends every event to all subscribed clients and keeps the latest ones, so
+/// that a reconnecting client gets what it missed after its `Last-Event-ID`.
+/// A client that falls `buffer_size` events behind is dropped; its stream
+/// ends after those, and it can catch up by reconnecting.
+pub struct Broadcaster {
+    history_size: usize,
+    buffer_size: usize,
+    heartbeat: Duration,
+    state: Mutex<BroadcastState>,
+}
+
+#[derive(Default)]
+struct BroadcastState {
+    next_id: u64,
+    history: VecDeque<(u64, String)>,
+    subscribers: Vec<EventSender>,
+}
+
+impl Event {
+    pub fn new(data: impl Into<String>) -> Self {
+        Self {
+            data: data.into(),
+            ..Self::default()
+        }
+    }
+
+    /// What the client sends back as `Last-Event-ID` when it reconnects.
+    pub fn with_id(mut self, id: impl Into<String>) -> Self {
+        self.id = Some(id.into());
+        self
+    }
+
+    /// The event type; the client's `message` handler gets events without one.
+    pub fn with_event(mut self, event: impl Into<String>) -> Self {
+        self.event = Some(event.into());
+        self
+    }
+
+    /// How long the client waits before reconnecting.
+    pub fn with_retry(mut self, retry: Duration) -> Self {
+        self.retry = Some(retry);
+        self
+    }
+
+    pub fn id(&self) -> Option<&str> {
+        self.id.as_deref()
+    }
+
+    /// The event in wire format, ending with the blank line that dispatches it.
+    pub fn serialize(&self) -> String {
+        let mut serialized = String::new();
+        // a line break in a field would start a new field
+        let single_line = |value: &str| value.replace(['\r', '\n'], " ");
+
+        if let Some(event) = &self.event {
+            serialized.push_str(&format!("event: {}\n", single_line(event)));
+        }
+        if let Some(id) = &self.id {
+            // the id must not contain NUL either
+            serialized.push_str(&format!("id: {}\n", single_line(id).replace('\0', "")));
+        }
+        if let Some(retry) = self.retry {
+            serialized.push_str(&format!("retry: {}\n", retry.as_millis()));
+        }
+        for line in self.data.split('\n') {
+            serialized.push_str(&format!(
+                "data: {}\n",
+                line.strip_suffix('\r').unwrap_or(line)
+            ));
+        }
+        serialized.push('\n');
+        serialized
+    }
+}
+
+impl EventStream {
+    pub fn new() -> (EventSender, Self) {
+        Self::with_buffer_size(DEFAULT_BUFFER_SIZE)
+    }
+
+    /// Lets `buffer_size` events wait for the client, at least one.
+    pub fn with_buffer_size(buffer_size: usize) -> (EventSender, Self) {
+        let (sender, receiver) = mpsc::sync_channel(buffer_size.max(1));
+        let stream = Self {
+            receiver,
+            heartbeat: DEFAULT_HEARTBEAT,
+            pending: Vec::new(),
+        };
+        (EventSender { sender }, stream)
+    }
+
+    pub fn with_heartbeat(mut self, heartbeat: Duration) -> Self {
+        self.heartbeat = heartbeat;
+        self
+    }
+
+    /// A 200 response streaming the events. The server sends it from a thread
+    /// of its own, so an open stream does not hold up other connections.
+    pub fn into_response(self) -> Response {
+        Response::new(StatusCode::Ok, None)
+            .with_header("Content-Type", "text/event-stream")
+            .with_header("Cache-Control", "no-cache")
+            .with_body(Body::stream(self))
+    }
+}
+
+impl Read for EventStream {
+    fn read(&mut self, buf: &mut [u8]) -> IoResult<usize> {
+        if self.pending.is_empty() {
+            self.pending = match self.receiver.recv_timeout(self.heartbeat) {
+                Ok(event) => event.into_bytes(),
+                Err(RecvTimeoutError::Timeout) => b":\n\n".to_vec(),
+                Err(RecvTimeoutError::Disconnected) => return Ok(0),
+            };
+        }
+
+        let len = buf.len().min(self.pending.len());
+        buf[..len].copy_from_slice(&self.pending[..len]);
+        self.pending.drain(..len);
+        Ok(len)
+    }
+}
+
+impl EventSender {
+    pub fn send(&self, event: &Event) -> Result<(), Disconnected> {
+        self.send_raw(event.serialize())
+    }
+
+    /// Sends a comment, which clients ignore.
+    pub fn comment(&self, comment: &str) -> Result<(), Disconnected> {
+        let comment: String = comment
+            .lines()
+            .map(|line| format!(": {}\n", line))
+            .collect();
+        self.send_raw(format!("{}\n", comment))
+    }
+
+    fn send_raw(&self, serialized: String) -> Result<(), Disconnected> {
+        // the stream is dropped once writing to the client fails
+        self.sender.send(serialized).map_err(|_| Disconnected)
+    }
+
+    // Fails rather than waits for a client whose buffer is full.
+    fn try_send_raw(&self, serialized: String) -> Result<(), TrySendError<String>> {
+        self.sender.try_send(serialized)
+    }
+}
+
+impl Broadcaster {
+    /// Keeps the last `history_size` events for clients that reconnect.
+    pub fn new(history_size: usize) -> Self {
+        Self {
+            history_size,
+            buffer_size: DEFAULT_BUFFER_SIZE,
+            heartbeat: DEFAULT_HEARTBEAT,
+            state: Mutex::new(BroadcastState::default()),
+        }
+    }
+
+    /// Events that may wait for a client before it is dropped.
+    pub fn with_buffer_size(mut self, buffer_size: usize) -> Self {
+        self.buffer_size = buffer_size;
+        self
+    }
+
+    pub fn with_heartbeat(mut self, heartbeat: Duration) -> Self {
+        self.heartbeat = heartbeat;
+        self
+    }
+
+    /// Sends `event` to every subscriber, numbering it with the next id
+    /// (which replaces any id it has), without waiting for slow ones: those
+    /// whose buffer is full are dropped. Returns the id.
+    pub fn send(&self, event: Event) -> u64 {
+        let mut state = self.lock();
+        let id = state.next_id;
+        state.next_id += 1;
+
+        let serialized = event.with_id(id.to_string()).serialize();
+        state
+            .subscribers
+            .retain(|subscriber| subscriber.try_send_raw(serialized.clone()).is_ok());
+        state.history.push_back((id, serialized));
+        if state.history.len() > self.history_size {
+            state.history.pop_front();
+        }
+        id
+    }
+
+    /// Subscribes the client making `request` and returns its response. A
+    /// client reconnecting with `Last-Event-ID` first gets the events after
+    /// that one that are still kept.
+    pub fn subscribe(&self, request: &Request) -> Response {
+        let (sender, mut stream) = EventStream::with_buffer_size(self.buffer_size);
+        let last_event_id = last_event_id(request).and_then(|id| id.parse::<u64>().ok());
+
+        let mut state = self.lock();
+        if let Some(last_event_id) = last_event_id {
+            // written before anything sent, however much of the history it is
+            for (_, serialized) in state.history.iter().filter(|(id, _)| *id > last_event_id) {
+                stream.pending.extend_from_slice(serialized.as_bytes());
+            }
+        }
+        state.subscribers.push(sender);
+
+        stream.with_heartbeat(self.heartbeat).into_response()
+    }
+
+    /// Clients that went away are only dropped by the next `send`.
+    pub fn subscribers(&self) -> usize {
+        self.lock().subscribers.len()
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, BroadcastState> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// The id of the last event a reconnecting client received.
+pub fn last_event_id<'a>(request: &Request<'a>) -> Option<&'a str> {
+    request.headers().get("Last-Event-ID").map(str::trim)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::convert::TryFrom;
+
+    fn read_all(stream: &mut impl Read) -> String {
+        let mut read = String::new();
+        stream.read_to_string(&mut read).unwrap();
+        read
+    }
+
+    fn request(head: &[u8]) -> Request<'_> {
+        Request::try_from(head).unwrap()
+    }
+
+    // The events stream of a `subscribe` response.
+    fn events(mut response: Response) -> Box<dyn Read + Send> {
+        match response.take_body() {
+            Body::Stream(reader) => reader,
+            body => panic!("expected a stream, got {:?}", body),
+        }
+    }
+
+    #[test]
+    fn serializes_events() {
+        let event = Event::new("one\r\ntwo\nthree")
+            .with_event("up\ndate")
+            .with_id("7\0\n")
+            .with_retry(Duration::from_secs(2));
+
+        assert_eq!(
+            event.serialize(),
+            "event: up date\nid: 7 \nretry: 2000\ndata: one\ndata: two\ndata: three\n\n"
+        );
+        assert_eq!(Event::new("").serialize(), "data: \n\n");
+    }
+
+    #[test]
+    fn streams_events_and_comments_until_the_senders_are_gone() {
+        let (sender, mut stream) = EventStream::new();
+        sender.send(&Event::new("hi")).unwrap();
+        sender.comment("a\nb").unwrap();
+        drop(sender);
+
+        // small reads pick up where the last one stopped
+        let mut buf = [0; 3];
+        let mut read = Vec::new();
+        loop {
+            match stream.read(&mut buf).unwrap() {
+                0 => break,
+                len => read.extend_from_slice(&buf[..len]),
+            }
+        }
+        assert_eq!(read, b"data: hi\n\n: a\n: b\n\n");
+    }
+
+    #[test]
+    fn sends_heartbeats_while_idle() {
+        let (sender, stream) = EventStream::new();
+        let mut stream = stream.with_heartbeat(Duration::from_millis(10));
+
+        let mut buf = [0; 16];
+        let len = stream.read(&mut buf).unwrap();
+        assert_eq!(&buf[..len], b":\n\n");
+        drop(sender);
+    }
+
+    #[test]
+    fn senders_fail_once_the_stream_is_gone() {
+        let (sender, stream) = EventStream::new();
+        let response = stream.into_response();
+        assert_eq!(response.header("Content-Type"), Some("text/event-stream"));
+        drop(response);
+
+        assert!(sender.send(&Event::new("x")).is_err());
+    }
+
+    #[test]
+    fn broadcasts_numbered_events() {
+        let broadcaster = Broadcaster::new(10);
+        let first = events(broadcaster.subscribe(&request(b"GET / HTTP/1.1\r\n\r\n")));
+        let second = events(broadcaster.subscribe(&request(b"GET / HTTP/1.1\r\n\r\n")));
+
+        assert_eq!(broadcaster.send(Event::new("a").with_id("ignored")), 0);
+        assert_eq!(broadcaster.send(Event::new("b")), 1);
+        drop(broadcaster);
+
+        for mut stream in [first, second] {
+            assert_eq!(
+                read_all(&mut stream),
+                "id: 0\ndata: a\n\nid: 1\ndata: b\n\n"
+            );
+        }
+    }
+
+    #[test]
+    fn replays_missed_events_to_reconnecting_clients() {
+        let broadcaster = Broadcaster::new(3).with_buffer_size(1);
+        for data in ["a", "b", "c", "d", "e"] {
+            broadcaster.send(Event::new(data));
+        }
+
+        // more history than the buffer holds, and only what is still kept
+        let replayed =
+            events(broadcaster.subscribe(&request(b"GET / HTTP/1.1\r\nLast-Event-ID: 0\r\n\r\n")));
+        let fresh = events(broadcaster.subscribe(&request(
+            b"GET / HTTP/1.1\r\nLast-Event-ID: nonsense\r\n\r\n",
+        )));
+        drop(broadcaster);
+
+        for (mut stream, expected) in [
+            (
+                replayed,
+                "id: 2\ndata: c\n\nid: 3\ndata: d\n\nid: 4\ndata: e\n\n",
+            ),
+            (fresh, ""),
+        ] {
+            assert_eq!(read_all(&mut stream), expected);
+        }
+    }
+
+    #[test]
+    fn drops_subscribers_that_fall_behind() {
+        let broadcaster = Broadcaster::new(0).with_buffer_size(2);
+        let mut slow = events(broadcaster.subscribe(&request(b"GET / HTTP/1.1\r\n\r\n")));
+        let gone = broadcaster.subscribe(&request(b"GET / HTTP/1.1\r\n\r\n"));
+        drop(gone);
+
+        broadcaster.send(Event::new("a"));
+        assert_eq!(broadcaster.subscribers(), 1);
+        broadcaster.send(Event::new("b"));
+        assert_eq!(broadcaster.subscribers(), 1);
+        // the third does not fit, and sending does not wait for the client
+        broadcaster.send(Event::new("c"));
+        assert_eq!(broadcaster.subscribers(), 0);
+
+        // what was buffered still arrives, then the stream ends
+        assert_eq!(read_all(&mut slow), "id: 0\ndata: a\n\nid: 1\ndata: b\n\n");
+    }
+
+    #[test]
+    fn reads_the_last_event_id() {
+        let request = request(b"GET / HTTP/1.1\r\nLast-Event-ID:  42 \r\n\r\n");
+        assert_eq!(last_event_id(&request), Some("42"));
+    }
+}
@@ -0,0 +1,160 @@
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+
+type Job = Box<dyn FnOnce() + Send + 'static>;
+
+/// A fixed set of worker threads fed from a queue of bounded length.
+pub struct ThreadPool {
+    workers: Vec<Worker>,
+    sender: Option<Sender<Job>>,
+    pending: Arc<AtomicUsize>,
+    capacity: usize,
+}
+
+impl ThreadPool {
+    /// Creates `size` workers; at most `queue_size` jobs wait while all of them are busy.
+    pub fn new(size: usize, queue_size: usize) -> Self {
+        assert!(size > 0, "a thread pool needs at least one worker");
+
+        let (sender, receiver) = mpsc::channel();
+        let receiver = Arc::new(Mutex::new(receiver));
+        let pending = Arc::new(AtomicUsize::new(0));
+
+        let workers = (0..size)
+            .map(|id| Worker::new(id, Arc::clone(&receiver), Arc::clone(&pending)))
+            .collect();
+
+        Self {
+            workers,
+            sender: Some(sender),
+            pending,
+            capacity: size + queue_size,
+        }
+    }
+
+    /// Whether every worker is busy and the queue is full.
+    pub fn is_full(&self) -> bool {
+        self.pending.load(Ordering::SeqCst) >= self.capacity
+    }
+
+    pub fn execute<F>(&self, f: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        self.pending.fetch_add(1, Ordering::SeqCst);
+        if let Some(sender) = &self.sender {
+            if sender.send(Box::new(f)).is_err() {
+                self.pending.fetch_sub(1, Ordering::SeqCst);
+            }
+        }
+    }
+}
+
+impl Drop for ThreadPool {
+    fn drop(&mut self) {
+        // closing the channel makes every worker leave its loop
+        drop(self.sender.take());
+
+        for worker in &mut self.workers {
+            if let Some(thread) = worker.thread.take() {
+                if thread.join().is_err() {
+                    println!("Worker {} panicked", worker.id);
+                }
+            }
+        }
+    }
+}
+
+struct Worker {
+    id: usize,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Worker {
+    fn new(id: usize, receiver: Arc<Mutex<Receiver<Job>>>, pending: Arc<AtomicUsize>) -> Self {
+        let thread = thread::Builder::new()
+            .name(format!("worker-{}", id))
+            .spawn(move || loop {
+                let job = match receiver.lock() {
+                    Ok(receiver) => receiver.recv(),
+                    Err(_) => break,
+                };
+
+                match job {
+                    Ok(job) => {
+                        if panic::catch_unwind(AssertUnwindSafe(job)).is_err() {
+                            println!("Worker {} recovered from a panicking job", id);
+                        }
+                        pending.fetch_sub(1, Ordering::SeqCst);
+                    }
+                    Err(_) => break,
+                }
+            })
+            .expect("failed to spawn worker thread");
+
+        Self {
+            id,
+            thread: Some(thread),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc::sync_channel;
+    use std::time::Duration;
+
+    #[test]
+    fn runs_every_job() {
+        let (sender, receiver) = mpsc::channel();
+        let pool = ThreadPool::new(3, 10);
+        for i in 0..10 {
+            let sender = sender.clone();
+            pool.execute(move || sender.send(i).unwrap());
+        }
+        drop(pool);
+
+        let mut done: Vec<i32> = receiver.try_iter().collect();
+        done.sort();
+        assert_eq!(done, (0..10).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn is_full_while_workers_and_queue_are_taken() {
+        let pool = ThreadPool::new(1, 1);
+        let (release, wait) = sync_channel::<()>(0);
+        let wait = Arc::new(Mutex::new(wait));
+
+        for _ in 0..2 {
+            let wait = Arc::clone(&wait);
+            pool.execute(move || {
+                let _ = wait.lock().unwrap().recv();
+            });
+        }
+        assert!(pool.is_full());
+
+        release.send(()).unwrap();
+        release.send(()).unwrap();
+        for _ in 0..100 {
+            if !pool.is_full() {
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert!(!pool.is_full());
+    }
+
+    #[test]
+    fn survives_panicking_jobs() {
+        let pool = ThreadPool::new(1, 0);
+        pool.execute(|| panic!("job failed"));
+
+        let (sender, receiver) = mpsc::channel();
+        pool.execute(move || sender.send(()).unwrap());
+        assert!(receiver.recv_timeout(Duration::from_secs(5)).is_ok());
+    }
+}
@@ -0,0 +1,507 @@
+use super::http::multipart::{self, sanitize_filename, Multipart, MultipartError};
+use super::http::{json, percent_encoding, Method, Request, Response, StatusCode};
+use super::server::Handler;
+use super::website_handler;
+
+use std::fmt::Write as _;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, ErrorKind, Read, Write};
+use std::path::{Path, PathBuf};
+
+pub const DEFAULT_MAX_FILES: usize = 10;
+
+/// Saves the files of `multipart/form-data` POST requests into a directory
+/// under the public path, and answers 201 with a JSON list of where they
+/// went. A name that is taken gets a number (`report-1.pdf`) unless
+/// overwriting is on. Fields that are not files are ignored. Bodies are
+/// written out as they arrive, limited by `max_total_size` rather than the
+/// server's `max_body_size`.
+pub struct UploadHandler {
+    public_path: String,
+    dir: String,
+    max_file_size: u64,
+    max_total_size: u64,
+    max_files: usize,
+    overwrite: bool,
+}
+
+/// Why saving failed: the request, which includes reading its body, or the
+/// server writing the files.
+enum Failure {
+    Request(MultipartError),
+    Save(io::Error),
+}
+
+impl From<MultipartError> for Failure {
+    fn from(e: MultipartError) -> Self {
+        Self::Request(e)
+    }
+}
+
+struct SavedFile {
+    field: String,
+    name: String,
+    path: PathBuf,
+    size: u64,
+}
+
+impl UploadHandler {
+    /// Saves into `dir`, a directory within `public_path`.
+    pub fn new(public_path: String, dir: &str) -> Self {
+        // `website_handler::resolve` needs a canonical root
+        let public_path = fs::canonicalize(&public_path)
+            .ok()
+            .and_then(|path| path.to_str().map(str::to_string))
+            .unwrap_or(public_path);
+
+        Self {
+            public_path,
+            dir: dir.trim_matches('/').to_string(),
+            max_file_size: multipart::DEFAULT_MAX_PART_SIZE,
+            max_total_size: multipart::DEFAULT_MAX_TOTAL_SIZE,
+            max_files: DEFAULT_MAX_FILES,
+            overwrite: false,
+        }
+    }
+
+    pub fn with_max_file_size(mut self, max_file_size: u64) -> Self {
+        self.max_file_size = max_file_size;
+        self
+    }
+
+    /// Bytes all files and fields of one request may have together.
+    pub fn with_max_total_size(mut self, max_total_size: u64) -> Self {
+        self.max_total_size = max_total_size;
+        self
+    }
+
+    pub fn with_max_files(mut self, max_files: usize) -> Self {
+        self.max_files = max_files;
+        self
+    }
+
+    /// Replace files of the same name instead of numbering the new ones.
+    pub fn with_overwrite(mut self, overwrite: bool) -> Self {
+        self.overwrite = overwrite;
+        self
+    }
+
+    // Saves every file part; on failure, the files saved so far are removed.
+    fn save_files(
+        &self,
+        dir: &Path,
+        mut multipart: Multipart<impl BufRead>,
+    ) -> Result<Vec<SavedFile>, Failure> {
+        let mut saved: Vec<SavedFile> = Vec::new();
+        let result = (|| -> Result<(), Failure> {
+            while let Some(mut part) = multipart.next_part()? {
+                let filename = match part.filename() {
+                    // a file input left empty
+                    Some("") | None => continue,
+                    Some(filename) => sanitize_filename(filename)
+                        .ok_or(MultipartError::Malformed("unusable file name"))?,
+                };
+                if saved.len() == self.max_files {
+                    return Err(MultipartError::TooManyParts.into());
+                }
+
+                let (path, mut file) = self.create(dir, &filename).map_err(Failure::Save)?;
+                let name = path
+                    .file_name()
+                    .map(|name| name.to_string_lossy().into_owned())
+                    .unwrap_or(filename);
+                saved.push(SavedFile {
+                    field: part.name().to_string(),
+                    name,
+                    path,
+                    size: 0,
+                });
+                // not `io::copy`, which would not tell reading from writing
+                let mut chunk = vec![0; 8192];
+                loop {
+                    let read = part.read(&mut chunk).map_err(MultipartError::from)?;
+                    if read == 0 {
+                        break;
+                    }
+                    file.write_all(&chunk[..read]).map_err(Failure::Save)?;
+                    if let Some(last) = saved.last_mut() {
+                        last.size += read as u64;
+                    }
+                }
+            }
+            Ok(())
+        })();
+
+        match result {
+            Ok(()) => Ok(saved),
+            Err(e) => {
+                for file in &saved {
+                    let _ = fs::remove_file(&file.path);
+                }
+                Err(e)
+            }
+        }
+    }
+
+    // Opens a new file for `filename` in `dir`, numbering the name if taken.
+    fn create(&self, dir: &Path, filename: &str) -> io::Result<(PathBuf, File)> {
+        if self.overwrite {
+            let path = dir.join(filename);
+            // File::create would write through a symlink
+            if path
+                .symlink_metadata()
+                .is_ok_and(|metadata| !metadata.is_file())
+            {
+                return Err(io::Error::new(
+                    ErrorKind::AlreadyExists,
+                    "not a regular file",
+                ));
+            }
+            return File::create(&path).map(|file| (path, file));
+        }
+
+        let (stem, extension) = match filename.rfind('.') {
+            Some(dot) if dot > 0 => filename.split_at(dot),
+            _ => (filename, ""),
+        };
+        for i in 0..1000 {
+            let path = match i {
+                0 => dir.join(filename),
+                i => dir.join(format!("{}-{}{}", stem, i, extension)),
+            };
+            match OpenOptions::new().write(true).create_new(true).open(&path) {
+                Ok(file) => return Ok((path, file)),
+                Err(e) if e.kind() == ErrorKind::AlreadyExists => continue,
+                Err(e) => return Err(e),
+            }
+        }
+        Err(io::Error::new(
+            ErrorKind::AlreadyExists,
+            "no free file name",
+        ))
+    }
+
+    // {"files":[{"field":"avatar","name":"me.png","path":"/uploads/me.png","size":1234}]}
+    fn render_json(&self, saved: &[SavedFile]) -> String {
+        let mut json = String::from("{\"files\":[");
+        for (i, file) in saved.iter().enumerate() {
+            if i > 0 {
+                json.push(',');
+            }
+            let _ = write!(
+                json,
+                "{{\"field\":\"{}\",\"name\":\"{}\",\"path\":\"{}\",\"size\":{}}}",
+                json::escape(&file.field),
+                json::escape(&file.name),
+                json::escape(&self.url_path(&file.name)),
+                file.size
+            );
+        }
+        json.push_str("]}");
+        json
+    }
+
+    fn url_path(&self, name: &str) -> String {
+        match self.dir.as_str() {
+            "" => format!("/{}", percent_encoding::encode(name)),
+            dir => format!("/{}/{}", dir, percent_encoding::encode(name)),
+        }
+    }
+}
+
+impl Handler for UploadHandler {
+    fn streams_body(&self, request: &Request) -> bool {
+        matches!(request.method(), Method::POST)
+    }
+
+    fn handle_request(&self, request: &Request) -> Response {
+        if !matches!(request.method(), Method::POST) {
+            return Response::error(StatusCode::MethodNotAllowed).with_header("Allow", "POST");
+        }
+        let dir = match website_handler::resolve(&self.public_path, &self.dir) {
+            Some(dir) if dir.is_dir() => dir,
+            _ => {
+                println!("Upload directory {} does not exist", self.dir);
+                return Response::error(StatusCode::InternalServerError);
+            }
+        };
+
+        let multipart = match Multipart::from_request(request) {
+            Ok(multipart) => multipart
+                .with_max_part_size(self.max_file_size)
+                .with_max_total_size(self.max_total_size),
+            Err(e) => {
+                return Response::error_with_message(
+                    StatusCode::UnsupportedMediaType,
+                    &e.to_string(),
+                )
+            }
+        };
+
+        match self.save_files(&dir, multipart) {
+            Ok(saved) if saved.is_empty() => {
+                Response::error_with_message(StatusCode::BadRequest, "no files in the request")
+            }
+            Ok(saved) => {
+                let mut response =
+                    Response::new(StatusCode::Created, Some(self.render_json(&saved)))
+                        .with_header("Content-Type", "application/json");
+                if let [file] = saved.as_slice() {
+                    response.set_header("Location", self.url_path(&file.name));
+                }
+                response
+            }
+            Err(Failure::Save(e)) => {
+                println!("Failed to save upload: {}", e);
+                Response::error(StatusCode::InternalServerError)
+            }
+            // the client went away or stalled while sending the body
+            Err(Failure::Request(MultipartError::Io(e))) => match e.kind() {
+                ErrorKind::TimedOut | ErrorKind::WouldBlock => {
+                    Response::error(StatusCode::RequestTimeout)
+                }
+                _ => Response::error_with_message(
+                    StatusCode::BadRequest,
+                    "failed to read the request body",
+                ),
+            },
+            Err(Failure::Request(e)) => {
+                let status_code = match e {
+                    MultipartError::PartTooLarge
+                    | MultipartError::TooLarge
+                    | MultipartError::TooManyParts => StatusCode::PayloadTooLarge,
+                    _ => StatusCode::BadRequest,
+                };
+                Response::error_with_message(status_code, &e.to_string())
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http::{Body, BodyReader};
+    use std::process;
+    use std::sync::Mutex;
+
+    const HEAD: &str = "POST /upload HTTP/1.1\r\n\
+        Content-Type: multipart/form-data; boundary=XyZ\r\n\r\n";
+
+    // A public directory holding an empty `uploads` directory.
+    fn public_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("upload-{}-{}", process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join("uploads")).unwrap();
+        dir
+    }
+
+    fn handler(public: &Path) -> UploadHandler {
+        UploadHandler::new(public.to_string_lossy().into_owned(), "/uploads/")
+    }
+
+    fn form(files: &[(&str, &str, &[u8])]) -> Vec<u8> {
+        let mut body = Vec::new();
+        for (field, filename, contents) in files {
+            body.extend_from_slice(
+                format!(
+                    "--XyZ\r\nContent-Disposition: form-data; name=\"{}\"; filename=\"{}\"\r\n\r\n",
+                    field, filename
+                )
+                .as_bytes(),
+            );
+            body.extend_from_slice(contents);
+            body.extend_from_slice(b"\r\n");
+        }
+        body.extend_from_slice(b"--XyZ--\r\n");
+        body
+    }
+
+    fn post(handler: &UploadHandler, body: &[u8]) -> Response {
+        let mut request = Request::try_from(HEAD.as_bytes()).unwrap();
+        request.set_body(body);
+        handler.handle_request(&request)
+    }
+
+    // Like the server does for a handler that streams the body.
+    fn post_streamed(handler: &UploadHandler, body: &[u8]) -> Response {
+        let mut request = Request::try_from(HEAD.as_bytes()).unwrap();
+        assert!(handler.streams_body(&request));
+        let body = Mutex::new(body);
+        let read = |buf: &mut [u8]| body.lock().unwrap().read(buf);
+        request.set_body_reader(BodyReader::new(&read));
+        handler.handle_request(&request)
+    }
+
+    // Streams `body`, and then fails with `kind` where it would end.
+    fn post_cut_off(handler: &UploadHandler, body: &[u8], kind: ErrorKind) -> Response {
+        let mut request = Request::try_from(HEAD.as_bytes()).unwrap();
+        let body = Mutex::new(body);
+        let read = |buf: &mut [u8]| match body.lock().unwrap().read(buf) {
+            Ok(0) => Err(io::Error::from(kind)),
+            result => result,
+        };
+        request.set_body_reader(BodyReader::new(&read));
+        handler.handle_request(&request)
+    }
+
+    fn body(mut response: Response) -> String {
+        let mut body = Vec::new();
+        match response.take_body() {
+            Body::Empty => {}
+            Body::Bytes(bytes) => body = bytes,
+            Body::Reader(mut reader, _) | Body::Stream(mut reader) => {
+                reader.read_to_end(&mut body).unwrap();
+            }
+        }
+        String::from_utf8(body).unwrap()
+    }
+
+    fn files(dir: &Path) -> Vec<String> {
+        let mut names: Vec<String> = fs::read_dir(dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+            .collect();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn saves_files_and_numbers_taken_names() {
+        let public = public_dir("save");
+        let handler = handler(&public);
+        let uploads = public.join("uploads");
+
+        let response = post(&handler, &form(&[("doc", "../a b.txt", b"first")]));
+        assert_eq!(response.status_code(), StatusCode::Created);
+        assert_eq!(response.header("Location"), Some("/uploads/a%20b.txt"));
+        assert_eq!(
+            body(response),
+            "{\"files\":[{\"field\":\"doc\",\"name\":\"a b.txt\",\"path\":\"/uploads/a%20b.txt\",\"size\":5}]}"
+        );
+
+        let response = post(
+            &handler,
+            &form(&[
+                ("doc", "a b.txt", b"second"),
+                ("other", "a b.txt", b"third"),
+            ]),
+        );
+        assert_eq!(response.status_code(), StatusCode::Created);
+        assert_eq!(response.header("Location"), None);
+        assert_eq!(files(&uploads), ["a b-1.txt", "a b-2.txt", "a b.txt"]);
+        assert_eq!(fs::read(uploads.join("a b.txt")).unwrap(), b"first");
+        assert_eq!(fs::read(uploads.join("a b-2.txt")).unwrap(), b"third");
+
+        let handler = handler.with_overwrite(true);
+        let response = post(&handler, &form(&[("doc", "a b.txt", b"replaced")]));
+        assert_eq!(response.status_code(), StatusCode::Created);
+        assert_eq!(fs::read(uploads.join("a b.txt")).unwrap(), b"replaced");
+        assert_eq!(files(&uploads).len(), 3);
+
+        let _ = fs::remove_dir_all(&public);
+    }
+
+    #[test]
+    fn rejects_requests_without_files() {
+        let public = public_dir("reject");
+        let handler = handler(&public);
+
+        let request = Request::try_from(&b"GET /upload HTTP/1.1\r\n\r\n"[..]).unwrap();
+        assert!(!handler.streams_body(&request));
+        let response = handler.handle_request(&request);
+        assert_eq!(response.status_code(), StatusCode::MethodNotAllowed);
+        assert_eq!(response.header("Allow"), Some("POST"));
+
+        let mut request =
+            Request::try_from(&b"POST /upload HTTP/1.1\r\nContent-Type: text/plain\r\n\r\n"[..])
+                .unwrap();
+        request.set_body(b"hello");
+        let response = handler.handle_request(&request);
+        assert_eq!(response.status_code(), StatusCode::UnsupportedMediaType);
+
+        // an empty file input
+        let response = post(&handler, &form(&[("doc", "", b"")]));
+        assert_eq!(response.status_code(), StatusCode::BadRequest);
+        let response = post(&handler, &form(&[("doc", "..", b"x")]));
+        assert_eq!(response.status_code(), StatusCode::BadRequest);
+        assert!(files(&public.join("uploads")).is_empty());
+
+        let _ = fs::remove_dir_all(&public);
+    }
+
+    #[test]
+    fn removes_saved_files_when_a_limit_is_hit() {
+        let public = public_dir("limits");
+        let uploads = public.join("uploads");
+
+        let handler = handler(&public).with_max_file_size(10);
+        let body = form(&[("a", "a.txt", b"small"), ("b", "b.txt", &[b'x'; 11])]);
+        let response = post(&handler, &body);
+        assert_eq!(response.status_code(), StatusCode::PayloadTooLarge);
+        assert!(files(&uploads).is_empty());
+
+        let handler = handler.with_max_total_size(100);
+        let body = form(&[("a", "a.txt", b"small"), ("b", "b.txt", &[b'x'; 100])]);
+        let response = post(&handler, &body);
+        assert_eq!(response.status_code(), StatusCode::PayloadTooLarge);
+        assert!(files(&uploads).is_empty());
+
+        let handler = handler.with_max_files(1);
+        let body = form(&[("a", "a.txt", b"1"), ("b", "b.txt", b"2")]);
+        let response = post(&handler, &body);
+        assert_eq!(response.status_code(), StatusCode::PayloadTooLarge);
+        assert!(files(&uploads).is_empty());
+
+        // a body cut off halfway
+        let handler = handler.with_max_files(2);
+        let response = post(&handler, &body[..body.len() - 20]);
+        assert_eq!(response.status_code(), StatusCode::BadRequest);
+        assert!(files(&uploads).is_empty());
+
+        let _ = fs::remove_dir_all(&public);
+    }
+
+    #[test]
+    fn tells_client_failures_from_server_ones() {
+        let public = public_dir("failures");
+        let uploads = public.join("uploads");
+        let handler = handler(&public);
+        let body = form(&[("a", "a.txt", b"saved"), ("b", "b.txt", &[b'x'; 1000])]);
+        let cut = &body[..body.len() - 500];
+
+        let response = post_cut_off(&handler, cut, ErrorKind::TimedOut);
+        assert_eq!(response.status_code(), StatusCode::RequestTimeout);
+        let response = post_cut_off(&handler, cut, ErrorKind::WouldBlock);
+        assert_eq!(response.status_code(), StatusCode::RequestTimeout);
+        let response = post_cut_off(&handler, cut, ErrorKind::ConnectionReset);
+        assert_eq!(response.status_code(), StatusCode::BadRequest);
+        assert!(files(&uploads).is_empty());
+
+        // the server cannot write where the file goes
+        fs::create_dir(uploads.join("a.txt")).unwrap();
+        let response = post(&handler.with_overwrite(true), &body);
+        assert_eq!(response.status_code(), StatusCode::InternalServerError);
+        assert_eq!(files(&uploads), ["a.txt"]);
+
+        let _ = fs::remove_dir_all(&public);
+    }
+
+    #[test]
+    fn streams_bodies_larger_than_a_buffer() {
+        let public = public_dir("stream");
+        let handler = handler(&public).with_max_total_size(4 << 20);
+
+        let contents: Vec<u8> = (0..3 << 20).map(|i| (i % 251) as u8).collect();
+        let response = post_streamed(&handler, &form(&[("doc", "big.bin", &contents)]));
+        assert_eq!(response.status_code(), StatusCode::Created);
+        assert_eq!(fs::read(public.join("uploads/big.bin")).unwrap(), contents);
+
+        let handler = handler.with_max_total_size(1 << 20);
+        let response = post_streamed(&handler, &form(&[("doc", "big.bin", &contents)]));
+        assert_eq!(response.status_code(), StatusCode::PayloadTooLarge);
+        assert_eq!(files(&public.join("uploads")), ["big.bin"]);
+
+        let _ = fs::remove_dir_all(&public);
+    }
+}
@@ -0,0 +1,247 @@
+use super::http::request::matches_host;
+use super::http::{ParseError, Request, Response, StatusCode};
+use super::server::Handler;
+
+use std::sync::Arc;
+
+/// Dispatches requests to a handler per site, by the `Host` header. Exact
+/// hostnames win over wildcards like `*.example.com`; requests for any other
+/// host, or without one, go to the default handler.
+pub struct VirtualHosts {
+    hosts: Vec<VirtualHost>,
+    default: Option<Arc<dyn Handler>>,
+}
+
+struct VirtualHost {
+    // lowercase
+    hostnames: Vec<String>,
+    handler: Arc<dyn Handler>,
+}
+
+impl VirtualHosts {
+    pub fn new() -> Self {
+        Self {
+            hosts: Vec::new(),
+            default: None,
+        }
+    }
+
+    /// Serves `hostnames` with `handler`. A hostname that was already added
+    /// keeps its first handler.
+    pub fn with_host<S: AsRef<str>>(
+        mut self,
+        hostnames: &[S],
+        handler: impl Handler + 'static,
+    ) -> Self {
+        self.hosts.push(VirtualHost {
+            hostnames: hostnames
+                .iter()
+                .map(|hostname| hostname.as_ref().trim_end_matches('.').to_ascii_lowercase())
+                .collect(),
+            handler: Arc::new(handler),
+        });
+        self
+    }
+
+    /// Handles requests for unknown hosts, instead of answering 421.
+    pub fn with_default(mut self, handler: impl Handler + 'static) -> Self {
+        self.default = Some(Arc::new(handler));
+        self
+    }
+
+    // The handler for the request's host, or the default one.
+    fn handler(&self, request: &Request) -> Option<&Arc<dyn Handler>> {
+        request
+            .host()
+            .and_then(|host| self.find(host))
+            .or(self.default.as_ref())
+    }
+
+    fn find(&self, host: &str) -> Option<&Arc<dyn Handler>> {
+        let host = host.trim_end_matches('.').to_ascii_lowercase();
+        let matching = |exact: bool| {
+            self.hosts.iter().find(|virtual_host| {
+                virtual_host.hostnames.iter().any(|hostname| {
+                    if exact {
+                        *hostname == host
+                    } else {
+                        matches_host(hostname, &host)
+                    }
+                })
+            })
+        };
+
+        matching(true)
+            .or_else(|| matching(false))
+            .map(|virtual_host| &virtual_host.handler)
+    }
+}
+
+impl Default for VirtualHosts {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Handler for VirtualHosts {
+    fn handle_request(&self, request: &Request) -> Response {
+        match self.handler(request) {
+            Some(handler) => handler.handle_request(request),
+            None => Response::error(StatusCode::MisdirectedRequest),
+        }
+    }
+
+    fn streams_body(&self, request: &Request) -> bool {
+        self.handler(request)
+            .is_some_and(|handler| handler.streams_body(request))
+    }
+
+    // the host of a request that could not be parsed is unknown
+    fn handle_bad_request(&self, e: &ParseError) -> Response {
+        match &self.default {
+            Some(handler) => handler.handle_bad_request(e),
+            None => {
+                println!("Failed to parse request: {}", e);
+                Response::from(e)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::website_handler::WebsiteHandler;
+    use std::convert::TryFrom;
+    use std::fs;
+    use std::process;
+
+    // Answers with `name`, to tell which site got the request.
+    fn site(name: &'static str) -> impl Handler {
+        move |_: &Request| Response::new(StatusCode::Ok, Some(name.to_string()))
+    }
+
+    fn get(hosts: &VirtualHosts, head: &str) -> Response {
+        let head = format!("{}\r\n\r\n", head);
+        hosts.handle_request(&Request::try_from(head.as_bytes()).unwrap())
+    }
+
+    fn site_for(hosts: &VirtualHosts, host: &str) -> String {
+        let mut body = Vec::new();
+        let mut response = get(hosts, &format!("GET / HTTP/1.1\r\nHost: {}", host));
+        response.take_body().write_to(&mut body).unwrap();
+        String::from_utf8(body).unwrap()
+    }
+
+    #[test]
+    fn exact_hostnames_win_over_wildcards() {
+        let hosts = VirtualHosts::new()
+            .with_host(&["*.example.com"], site("wildcard"))
+            .with_host(&["www.example.com", "example.com"], site("www"))
+            .with_default(site("default"));
+
+        assert_eq!(site_for(&hosts, "www.example.com"), "www");
+        assert_eq!(site_for(&hosts, "example.com"), "www");
+        assert_eq!(site_for(&hosts, "blog.example.com"), "wildcard");
+        // one label only
+        assert_eq!(site_for(&hosts, "a.blog.example.com"), "default");
+        assert_eq!(site_for(&hosts, "example.org"), "default");
+    }
+
+    #[test]
+    fn ignores_case_ports_and_trailing_dots() {
+        let hosts = VirtualHosts::new()
+            .with_host(&["Example.COM."], site("example"))
+            .with_default(site("default"));
+
+        assert_eq!(site_for(&hosts, "example.com"), "example");
+        assert_eq!(site_for(&hosts, "EXAMPLE.com:8080"), "example");
+        assert_eq!(site_for(&hosts, "example.com.:443"), "example");
+    }
+
+    #[test]
+    fn keeps_the_first_handler_of_a_hostname() {
+        let hosts = VirtualHosts::new()
+            .with_host(&["example.com"], site("first"))
+            .with_host(&["example.com"], site("second"));
+        assert_eq!(site_for(&hosts, "example.com"), "first");
+    }
+
+    #[test]
+    fn answers_421_for_unknown_hosts_without_a_default() {
+        let hosts = VirtualHosts::new().with_host(&["example.com"], site("example"));
+
+        let response = get(&hosts, "GET / HTTP/1.1\r\nHost: example.org");
+        assert_eq!(response.status_code(), StatusCode::MisdirectedRequest);
+        let response = get(&hosts, "GET / HTTP/1.0");
+        assert_eq!(response.status_code(), StatusCode::MisdirectedRequest);
+
+        let hosts = hosts.with_default(site("default"));
+        let response = get(&hosts, "GET / HTTP/1.0");
+        assert_eq!(response.status_code(), StatusCode::Ok);
+    }
+
+    #[test]
+    fn leaves_bad_requests_to_the_default() {
+        struct Teapot;
+        impl Handler for Teapot {
+            fn handle_request(&self, _request: &Request) -> Response {
+                Response::error(StatusCode::Ok)
+            }
+
+            fn handle_bad_request(&self, _e: &ParseError) -> Response {
+                Response::error(StatusCode::ImATeapot)
+            }
+        }
+
+        let hosts = VirtualHosts::new().with_host(&["example.com"], site("example"));
+        let response = hosts.handle_bad_request(&ParseError::InvalidRequest);
+        assert_eq!(response.status_code(), StatusCode::BadRequest);
+
+        let response = hosts
+            .with_default(Teapot)
+            .handle_bad_request(&ParseError::InvalidRequest);
+        assert_eq!(response.status_code(), StatusCode::ImATeapot);
+    }
+
+    #[test]
+    fn streams_bodies_if_the_host_s_handler_does() {
+        struct Streaming;
+        impl Handler for Streaming {
+            fn handle_request(&self, _request: &Request) -> Response {
+                Response::error(StatusCode::Ok)
+            }
+
+            fn streams_body(&self, _request: &Request) -> bool {
+                true
+            }
+        }
+
+        let hosts = VirtualHosts::new()
+            .with_host(&["upload.example.com"], Streaming)
+            .with_default(site("default"));
+        let request = |head: &'static str| Request::try_from(head.as_bytes()).unwrap();
+        assert!(hosts.streams_body(&request(
+            "POST / HTTP/1.1\r\nHost: upload.example.com\r\n\r\n"
+        )));
+        assert!(!hosts.streams_body(&request("POST / HTTP/1.1\r\nHost: example.com\r\n\r\n")));
+    }
+
+    #[test]
+    fn serves_each_host_from_its_own_directory() {
+        let base = std::env::temp_dir().join(format!("virtual-hosts-{}", process::id()));
+        let _ = fs::remove_dir_all(&base);
+        let mut hosts = VirtualHosts::new();
+        for name in ["a", "b"] {
+            let dir = base.join(name);
+            fs::create_dir_all(&dir).unwrap();
+            fs::write(dir.join("index.html"), name).unwrap();
+            let website = WebsiteHandler::new(dir.to_str().unwrap().to_string());
+            hosts = hosts.with_host(&[format!("{}.example.com", name)], website);
+        }
+
+        assert_eq!(site_for(&hosts, "a.example.com"), "a");
+        assert_eq!(site_for(&hosts, "b.example.com"), "b");
+        fs::remove_dir_all(&base).unwrap();
+    }
+}
@@ -1,47 +1,713 @@
-use super::http::{Method, Request, Response, StatusCode};
+use super::http::{mime, Body, Method, Request, Response, StatusCode};
 use super::server::Handler;
 
+use super::http::compression::{self, Encoding};
+use super::http::date::{fmt_http_date, parse_http_date};
+use super::http::range::{parse_range, ByteRange, MultipartRanges, RangeError};
+
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
 use std::fmt::format;
-use std::fs;
+use std::fs::{self, File, Metadata};
+use std::hash::Hasher;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+mod directory_listing;
+
+/// How `WebsiteHandler` derives the `ETag` of a file.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EtagMode {
+    /// From the size and modification time; cheap, but changes on `touch`.
+    Metadata,
+    /// From a hash of the contents, cached until the file's metadata changes.
+    ContentHash,
+}
 
 pub struct WebsiteHandler {
     public_path: String,
+    mime_types: HashMap<String, String>,
+    etag_mode: EtagMode,
+    etag_cache: Mutex<HashMap<PathBuf, (SystemTime, u64, String)>>,
+    cache_control: Vec<(String, String)>,
+    precompressed: bool,
+    directory_listing: bool,
 }
 
 impl WebsiteHandler {
     pub fn new(public_path: String) -> Self {
-        Self { public_path }
+        // `resolve` compares canonical paths, so the root must be canonical too
+        let public_path = fs::canonicalize(&public_path)
+            .ok()
+            .and_then(|path| path.to_str().map(str::to_string))
+            .unwrap_or(public_path);
+
+        Self {
+            public_path,
+            mime_types: HashMap::new(),
+            etag_mode: EtagMode::Metadata,
+            etag_cache: Mutex::new(HashMap::new()),
+            cache_control: Vec::new(),
+            precompressed: true,
+            directory_listing: false,
+        }
+    }
+
+    pub fn with_etag_mode(mut self, etag_mode: EtagMode) -> Self {
+        self.etag_mode = etag_mode;
+        self
+    }
+
+    /// Sends `Cache-Control: value` for request paths starting with `prefix`.
+    /// The longest matching prefix wins.
+    pub fn with_cache_control(mut self, prefix: &str, value: &str) -> Self {
+        self.cache_control
+            .push((prefix.to_string(), value.to_string()));
+        self
+    }
+
+    /// Whether `file.br` and `file.gz` siblings are served in place of `file`
+    /// to clients that accept that encoding.
+    pub fn with_precompressed(mut self, precompressed: bool) -> Self {
+        self.precompressed = precompressed;
+        self
+    }
+
+    /// Answers requests for directories without an `index.html` with a
+    /// listing of their contents, as HTML or JSON depending on `Accept`.
+    pub fn with_directory_listing(mut self, directory_listing: bool) -> Self {
+        self.directory_listing = directory_listing;
+        self
+    }
+
+    /// Serves files with `extension` (without the dot) as `mime_type`,
+    /// taking precedence over the built-in table.
+    pub fn with_mime_type(mut self, extension: &str, mime_type: &str) -> Self {
+        self.mime_types
+            .insert(extension.to_ascii_lowercase(), mime_type.to_string());
+        self
+    }
+
+    fn mime_type(&self, path: &Path) -> &str {
+        let extension = match path.extension().and_then(|ext| ext.to_str()) {
+            Some(extension) => extension.to_ascii_lowercase(),
+            None => return mime::DEFAULT_MIME_TYPE,
+        };
+
+        match self.mime_types.get(&extension) {
+            Some(mime_type) => mime_type,
+            None => mime::from_extension(&extension).unwrap_or(mime::DEFAULT_MIME_TYPE),
+        }
+    }
+
+    fn resolve(&self, file_path: &str) -> Option<PathBuf> {
+        resolve(&self.public_path, file_path)
+    }
+
+    fn read_file(&self, file_path: &str) -> Option<(PathBuf, File)> {
+        let path = self.resolve(file_path)?;
+        if !path.is_file() {
+            return None;
+        }
+
+        let file = File::open(&path).ok()?;
+        Some((path, file))
+    }
+
+    // The `.gz`/`.br` siblings of `file_path`, if serving them is on.
+    fn precompressed_siblings(&self, file_path: &str) -> Vec<(Encoding, PathBuf, File)> {
+        if !self.precompressed {
+            return Vec::new();
+        }
+        Encoding::ALL
+            .iter()
+            .filter_map(|encoding| {
+                let sibling = format!("{}.{}", file_path, encoding.extension());
+                let (path, file) = self.read_file(&sibling)?;
+                Some((*encoding, path, file))
+            })
+            .collect()
+    }
+
+    fn pick_precompressed(
+        request: &Request,
+        mut siblings: Vec<(Encoding, PathBuf, File)>,
+    ) -> Option<(Encoding, PathBuf, File)> {
+        let accept_encoding = request.headers().get("Accept-Encoding")?;
+        let available: Vec<Encoding> = siblings.iter().map(|(encoding, _, _)| *encoding).collect();
+
+        let encoding = compression::negotiate(accept_encoding, &available)?;
+        let i = available.iter().position(|e| *e == encoding)?;
+        Some(siblings.swap_remove(i))
+    }
+
+    fn cache_control(&self, request_path: &str) -> Option<&str> {
+        self.cache_control
+            .iter()
+            .filter(|(prefix, _)| request_path.starts_with(prefix.as_str()))
+            .max_by_key(|(prefix, _)| prefix.len())
+            .map(|(_, value)| value.as_str())
+    }
+
+    fn etag(&self, path: &Path, metadata: &Metadata) -> io::Result<String> {
+        let modified = metadata.modified()?;
+
+        if self.etag_mode == EtagMode::Metadata {
+            let modified = modified.duration_since(UNIX_EPOCH).unwrap_or_default();
+            return Ok(format!(
+                "\"{:x}-{:x}\"",
+                metadata.len(),
+                modified.as_nanos()
+            ));
+        }
+
+        let mut cache = self.etag_cache.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some((cached_modified, len, etag)) = cache.get(path) {
+            if *cached_modified == modified && *len == metadata.len() {
+                return Ok(etag.clone());
+            }
+        }
+
+        let mut file = File::open(path)?;
+        let mut hasher = DefaultHasher::new();
+        let mut chunk = [0; 8192];
+        loop {
+            let read = file.read(&mut chunk)?;
+            if read == 0 {
+                break;
+            }
+            hasher.write(&chunk[..read]);
+        }
+
+        let etag = format!("\"{:016x}\"", hasher.finish());
+        cache.insert(path.to_path_buf(), (modified, metadata.len(), etag.clone()));
+        Ok(etag)
     }
 
-    fn read_file(&self, file_path: &str) -> Option<String> {
-        let path = format!("{}/{}", self.public_path, file_path);
+    pub fn serve_file(&self, request: &Request, file_path: &str) -> Response {
+        if let Some(dir) = self.resolve(file_path).filter(|path| path.is_dir()) {
+            return self.serve_directory(request, file_path, &dir);
+        }
+
+        let (path, file) = match self.read_file(file_path) {
+            Some(found) => found,
+            None => return Response::error(StatusCode::NotFound),
+        };
+        let mime_type = self.mime_type(&path);
+
+        // the sibling is its own representation, with its own validators and ranges
+        let siblings = self.precompressed_siblings(file_path);
+        // the plain file varies too once there are siblings to choose from
+        let varies = !siblings.is_empty();
+        let (encoding, path, file) = match Self::pick_precompressed(request, siblings) {
+            Some((encoding, path, file)) => (Some(encoding), path, file),
+            None => (None, path, file),
+        };
+
+        let validators = file.metadata().and_then(|metadata| {
+            let etag = self.etag(&path, &metadata)?;
+            Ok((metadata, etag))
+        });
+        let (metadata, etag) = match validators {
+            Ok(validators) => validators,
+            Err(e) => {
+                println!("Failed to read {}: {}", path.display(), e);
+                return Response::error(StatusCode::InternalServerError);
+            }
+        };
+        let modified = metadata.modified().ok();
+
+        let status_code = if is_not_modified(request, &etag, modified) {
+            StatusCode::NotModified
+        } else {
+            StatusCode::Ok
+        };
+
+        let mut response = Response::new(status_code, None).with_header("ETag", etag.as_str());
+        if let Some(modified) = modified {
+            response.set_header("Last-Modified", fmt_http_date(modified));
+        }
+        if let Some(cache_control) = self.cache_control(request.path()) {
+            response.set_header("Cache-Control", cache_control);
+        }
+        if varies {
+            response.add_vary("Accept-Encoding");
+        }
+        if status_code == StatusCode::NotModified {
+            return response;
+        }
+
+        if let Some(encoding) = encoding {
+            response.set_header("Content-Encoding", encoding.as_str());
+        }
+
+        response.set_header("Accept-Ranges", "bytes");
 
-        match fs::canonicalize(path) {
-            Ok(path) => {
-                if path.starts_with(&self.public_path) {
-                    fs::read_to_string(path).ok()
-                } else {
-                    println!("Directory Traversal Attack Attempted: {}", file_path);
-                    None
-                }
+        let range = match request.headers().get("Range") {
+            Some(range)
+                if *request.method() == Method::GET
+                    && if_range_matches(request, &etag, modified) =>
+            {
+                parse_range(range, metadata.len())
             }
-            Err(_) => None,
+            _ => Err(RangeError::Invalid),
+        };
+
+        match range {
+            Ok(ranges) => serve_ranges(response, file, &ranges, metadata.len(), mime_type),
+            Err(RangeError::Unsatisfiable) => Response::error(StatusCode::RangeNotSatisfiable)
+                .with_header("Content-Range", format!("bytes */{}", metadata.len())),
+            Err(RangeError::Invalid) => response
+                .with_header("Content-Type", mime_type)
+                .with_body(Body::Reader(Box::new(file), metadata.len())),
+        }
+    }
+
+    // Without directory listings only the root directory is served, by its index.html.
+    fn serve_directory(&self, request: &Request, file_path: &str, dir: &Path) -> Response {
+        if !self.directory_listing && request.path() != "/" {
+            return Response::error(StatusCode::NotFound);
+        }
+
+        // relative links in the page only work below a trailing slash
+        if !request.path().ends_with('/') {
+            let location = match request.target().split_once('?') {
+                Some((path, query)) => format!("{}/?{}", path, query),
+                None => format!("{}/", request.target()),
+            };
+            return Response::error(StatusCode::MovedPermanently).with_header("Location", location);
+        }
+
+        if dir.join("index.html").is_file() {
+            let index = format!("{}/index.html", file_path.trim_end_matches('/'));
+            return self.serve_file(request, &index);
+        }
+        if !self.directory_listing {
+            return Response::error(StatusCode::NotFound);
+        }
+
+        match directory_listing::read_entries(dir, &self.public_path) {
+            Ok(entries) => directory_listing::render(request, entries),
+            Err(e) => {
+                println!("Failed to list {}: {}", dir.display(), e);
+                Response::error(StatusCode::InternalServerError)
+            }
+        }
+    }
+}
+
+/// The canonical path of `file_path` within `public_path`, which must be
+/// canonical itself; `None` if it does not exist or lies outside.
+pub fn resolve(public_path: &str, file_path: &str) -> Option<PathBuf> {
+    let path = format!("{}/{}", public_path, file_path);
+
+    match fs::canonicalize(path) {
+        Ok(path) => {
+            if path.starts_with(public_path) {
+                Some(path)
+            } else {
+                println!("Directory Traversal Attack Attempted: {}", file_path);
+                None
+            }
+        }
+        Err(_) => None,
+    }
+}
+
+fn serve_ranges(
+    mut response: Response,
+    mut file: File,
+    ranges: &[ByteRange],
+    len: u64,
+    mime_type: &str,
+) -> Response {
+    response.set_status_code(StatusCode::PartialContent);
+
+    if let [range] = ranges {
+        if let Err(e) = file.seek(SeekFrom::Start(range.start)) {
+            println!("Failed to seek: {}", e);
+            return Response::error(StatusCode::InternalServerError);
+        }
+        return response
+            .with_header("Content-Type", mime_type)
+            .with_header("Content-Range", range.content_range(len))
+            .with_body(Body::Reader(Box::new(file), range.len()));
+    }
+
+    let boundary = boundary();
+    let body = MultipartRanges::new(file, ranges, len, mime_type, &boundary);
+    let body_len = body.len();
+    response
+        .with_header(
+            "Content-Type",
+            format!("multipart/byteranges; boundary={}", boundary),
+        )
+        .with_body(Body::Reader(Box::new(body), body_len))
+}
+
+fn boundary() -> String {
+    let mut hasher = DefaultHasher::new();
+    hasher.write_u128(
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_nanos(),
+    );
+    format!("{:016x}", hasher.finish())
+}
+
+// A Range is only honoured if If-Range still matches, using the strong comparison.
+fn if_range_matches(request: &Request, etag: &str, modified: Option<SystemTime>) -> bool {
+    let if_range = match request.headers().get("If-Range") {
+        Some(if_range) => if_range,
+        None => return true,
+    };
+
+    if if_range.starts_with('"') {
+        return if_range == etag;
+    }
+    if if_range.starts_with("W/") {
+        return false;
+    }
+
+    match (parse_http_date(if_range), modified) {
+        (Some(date), Some(modified)) => {
+            let modified = modified.duration_since(UNIX_EPOCH).unwrap_or_default();
+            let date = date.duration_since(UNIX_EPOCH).unwrap_or_default();
+            modified.as_secs() == date.as_secs()
         }
+        _ => false,
+    }
+}
+
+// If-None-Match takes precedence; If-Modified-Since is only consulted without it.
+fn is_not_modified(request: &Request, etag: &str, modified: Option<SystemTime>) -> bool {
+    if let Some(if_none_match) = request.headers().get("If-None-Match") {
+        return if_none_match.split(',').any(|candidate| {
+            let candidate = candidate.trim();
+            candidate == "*" || candidate.trim_start_matches("W/") == etag
+        });
+    }
+
+    let if_modified_since = request
+        .headers()
+        .get("If-Modified-Since")
+        .and_then(parse_http_date);
+    match (if_modified_since, modified) {
+        (Some(since), Some(modified)) => {
+            // Last-Modified only has second resolution
+            let modified = modified.duration_since(UNIX_EPOCH).unwrap_or_default();
+            let since = since.duration_since(UNIX_EPOCH).unwrap_or_default();
+            modified.as_secs() <= since.as_secs()
+        }
+        _ => false,
     }
 }
 
 impl Handler for WebsiteHandler {
-    fn handle_request(&mut self, request: &Request) -> Response {
+    fn handle_request(&self, request: &Request) -> Response {
         match request.method() {
-            Method::GET => match request.path() {
-                "/" => Response::new(StatusCode::Ok, self.read_file("index.html")),
-                "/hello" => Response::new(StatusCode::Ok, self.read_file("hello.html")),
-                path => match self.read_file(path) {
-                    Some(contents) => Response::new(StatusCode::Ok, Some(contents)),
-                    None => Response::new(StatusCode::NotFound, None),
-                },
-            },
-            _ => Response::new(StatusCode::NotFound, None),
+            Method::GET | Method::HEAD => self.serve_file(request, request.path()),
+            _ => Response::error(StatusCode::MethodNotAllowed).with_header("Allow", "GET, HEAD"),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::convert::TryFrom;
+    use std::process;
+
+    // A fresh public directory per test, since tests run in parallel.
+    fn public_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("website-{}-{}", process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn handler(dir: &Path) -> WebsiteHandler {
+        WebsiteHandler::new(dir.to_str().unwrap().to_string())
+    }
+
+    fn get(handler: &WebsiteHandler, head: &str) -> Response {
+        let head = format!("{}\r\n\r\n", head);
+        handler.handle_request(&Request::try_from(head.as_bytes()).unwrap())
+    }
+
+    fn body(mut response: Response) -> Vec<u8> {
+        let mut body = Vec::new();
+        response.take_body().write_to(&mut body).unwrap();
+        body
+    }
+
+    #[test]
+    fn serves_files_with_their_mime_type() {
+        let dir = public_dir("mime");
+        fs::write(dir.join("page.HTML"), "<p>hi</p>").unwrap();
+        fs::write(dir.join("data.bin"), [0, 1, 2]).unwrap();
+        fs::write(dir.join("notes.custom"), "x").unwrap();
+        let handler = handler(&dir).with_mime_type("CUSTOM", "text/x-custom");
+
+        let response = get(&handler, "GET /page.HTML HTTP/1.1");
+        assert_eq!(response.status_code(), StatusCode::Ok);
+        assert_eq!(
+            response.header("Content-Type"),
+            Some("text/html; charset=utf-8")
+        );
+        assert_eq!(body(response), b"<p>hi</p>");
+
+        let response = get(&handler, "GET /data.bin HTTP/1.1");
+        assert_eq!(
+            response.header("Content-Type"),
+            Some(mime::DEFAULT_MIME_TYPE)
+        );
+        assert_eq!(body(response), [0, 1, 2]);
+
+        let response = get(&handler, "GET /notes.custom HTTP/1.1");
+        assert_eq!(response.header("Content-Type"), Some("text/x-custom"));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn answers_404_405_and_refuses_traversal() {
+        let dir = public_dir("errors");
+        fs::create_dir(dir.join("site")).unwrap();
+        fs::write(dir.join("secret.txt"), "secret").unwrap();
+        let handler = handler(&dir.join("site"));
+
+        let response = get(&handler, "GET /missing.txt HTTP/1.1");
+        assert_eq!(response.status_code(), StatusCode::NotFound);
+        let response = get(&handler, "GET /../secret.txt HTTP/1.1");
+        assert_eq!(response.status_code(), StatusCode::NotFound);
+        let response = get(&handler, "GET /%2e%2e/secret.txt HTTP/1.1");
+        assert_eq!(response.status_code(), StatusCode::NotFound);
+
+        let response = get(&handler, "POST /missing.txt HTTP/1.1");
+        assert_eq!(response.status_code(), StatusCode::MethodNotAllowed);
+        assert_eq!(response.header("Allow"), Some("GET, HEAD"));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn serves_the_root_index() {
+        let dir = public_dir("index");
+        fs::write(dir.join("index.html"), "home").unwrap();
+        fs::create_dir(dir.join("sub")).unwrap();
+        let handler = handler(&dir);
+
+        assert_eq!(body(get(&handler, "GET / HTTP/1.1")), b"home");
+        let response = get(&handler, "GET /sub/ HTTP/1.1");
+        assert_eq!(response.status_code(), StatusCode::NotFound);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn answers_conditional_requests() {
+        let dir = public_dir("conditional");
+        fs::write(dir.join("a.txt"), "hello").unwrap();
+        let handler = handler(&dir);
+
+        let response = get(&handler, "GET /a.txt HTTP/1.1");
+        let etag = response.header("ETag").unwrap().to_string();
+        let modified = response.header("Last-Modified").unwrap().to_string();
+
+        let response = get(
+            &handler,
+            &format!("GET /a.txt HTTP/1.1\r\nIf-None-Match: \"x\", W/{}", etag),
+        );
+        assert_eq!(response.status_code(), StatusCode::NotModified);
+        assert_eq!(response.header("ETag"), Some(etag.as_str()));
+        assert!(response.body().is_empty());
+
+        let response = get(&handler, "GET /a.txt HTTP/1.1\r\nIf-None-Match: *");
+        assert_eq!(response.status_code(), StatusCode::NotModified);
+        let response = get(&handler, "GET /a.txt HTTP/1.1\r\nIf-None-Match: \"other\"");
+        assert_eq!(response.status_code(), StatusCode::Ok);
+
+        let response = get(
+            &handler,
+            &format!("GET /a.txt HTTP/1.1\r\nIf-Modified-Since: {}", modified),
+        );
+        assert_eq!(response.status_code(), StatusCode::NotModified);
+        let response = get(
+            &handler,
+            "GET /a.txt HTTP/1.1\r\nIf-Modified-Since: Thu, 01 Jan 1970 00:00:00 GMT",
+        );
+        assert_eq!(response.status_code(), StatusCode::Ok);
+
+        // If-None-Match wins over If-Modified-Since
+        let response = get(
+            &handler,
+            &format!(
+                "GET /a.txt HTTP/1.1\r\nIf-None-Match: \"other\"\r\nIf-Modified-Since: {}",
+                modified
+            ),
+        );
+        assert_eq!(response.status_code(), StatusCode::Ok);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn hashes_contents_for_etags() {
+        let dir = public_dir("etag");
+        fs::write(dir.join("a.txt"), "same").unwrap();
+        fs::write(dir.join("b.txt"), "same").unwrap();
+        fs::write(dir.join("c.txt"), "different").unwrap();
+        let handler = handler(&dir).with_etag_mode(EtagMode::ContentHash);
+
+        let etag = |path: &str| {
+            let response = get(&handler, &format!("GET {} HTTP/1.1", path));
+            response.header("ETag").unwrap().to_string()
+        };
+        assert_eq!(etag("/a.txt"), etag("/b.txt"));
+        assert_ne!(etag("/a.txt"), etag("/c.txt"));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn sets_cache_control_by_the_longest_prefix() {
+        let dir = public_dir("cache");
+        fs::create_dir(dir.join("assets")).unwrap();
+        fs::write(dir.join("assets/app.js"), "").unwrap();
+        fs::write(dir.join("page.html"), "").unwrap();
+        let handler = handler(&dir)
+            .with_cache_control("/", "no-cache")
+            .with_cache_control("/assets/", "max-age=31536000");
+
+        let response = get(&handler, "GET /assets/app.js HTTP/1.1");
+        assert_eq!(response.header("Cache-Control"), Some("max-age=31536000"));
+        let response = get(&handler, "GET /page.html HTTP/1.1");
+        assert_eq!(response.header("Cache-Control"), Some("no-cache"));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn serves_ranges() {
+        let dir = public_dir("ranges");
+        fs::write(dir.join("a.txt"), "0123456789").unwrap();
+        let handler = handler(&dir);
+
+        let response = get(&handler, "GET /a.txt HTTP/1.1\r\nRange: bytes=2-4");
+        assert_eq!(response.status_code(), StatusCode::PartialContent);
+        assert_eq!(response.header("Content-Range"), Some("bytes 2-4/10"));
+        assert_eq!(body(response), b"234");
+
+        let response = get(&handler, "GET /a.txt HTTP/1.1\r\nRange: bytes=0-0,-1");
+        let content_type = response.header("Content-Type").unwrap().to_string();
+        assert!(content_type.starts_with("multipart/byteranges; boundary="));
+        let body = String::from_utf8(body(response)).unwrap();
+        assert!(body.contains("Content-Range: bytes 0-0/10\r\n\r\n0\r\n"));
+        assert!(body.contains("Content-Range: bytes 9-9/10\r\n\r\n9\r\n"));
+
+        let response = get(&handler, "GET /a.txt HTTP/1.1\r\nRange: bytes=20-");
+        assert_eq!(response.status_code(), StatusCode::RangeNotSatisfiable);
+        assert_eq!(response.header("Content-Range"), Some("bytes */10"));
+
+        let response = get(&handler, "GET /a.txt HTTP/1.1\r\nRange: lines=1-2");
+        assert_eq!(response.status_code(), StatusCode::Ok);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn ignores_ranges_when_if_range_does_not_match() {
+        let dir = public_dir("if-range");
+        fs::write(dir.join("a.txt"), "0123456789").unwrap();
+        let handler = handler(&dir);
+        let etag = get(&handler, "GET /a.txt HTTP/1.1")
+            .header("ETag")
+            .unwrap()
+            .to_string();
+
+        let response = get(
+            &handler,
+            &format!(
+                "GET /a.txt HTTP/1.1\r\nRange: bytes=0-1\r\nIf-Range: {}",
+                etag
+            ),
+        );
+        assert_eq!(response.status_code(), StatusCode::PartialContent);
+        let response = get(
+            &handler,
+            "GET /a.txt HTTP/1.1\r\nRange: bytes=0-1\r\nIf-Range: \"stale\"",
+        );
+        assert_eq!(response.status_code(), StatusCode::Ok);
+        let response = get(
+            &handler,
+            &format!(
+                "GET /a.txt HTTP/1.1\r\nRange: bytes=0-1\r\nIf-Range: W/{}",
+                etag
+            ),
+        );
+        assert_eq!(response.status_code(), StatusCode::Ok);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn serves_precompressed_siblings() {
+        let dir = public_dir("precompressed");
+        fs::write(dir.join("app.js"), "plain").unwrap();
+        fs::write(dir.join("app.js.gz"), "gzipped").unwrap();
+        let handler = handler(&dir);
+
+        let response = get(
+            &handler,
+            "GET /app.js HTTP/1.1\r\nAccept-Encoding: br, gzip",
+        );
+        assert_eq!(response.header("Content-Encoding"), Some("gzip"));
+        assert_eq!(
+            response.header("Content-Type"),
+            Some("text/javascript; charset=utf-8")
+        );
+        assert_eq!(response.header("Vary"), Some("Accept-Encoding"));
+        assert_eq!(body(response), b"gzipped");
+
+        let response = get(&handler, "GET /app.js HTTP/1.1");
+        assert_eq!(response.header("Content-Encoding"), None);
+        assert_eq!(response.header("Vary"), Some("Accept-Encoding"));
+        assert_eq!(body(response), b"plain");
+
+        let response = get(&handler, "GET /app.js HTTP/1.1\r\nAccept-Encoding: br");
+        assert_eq!(response.header("Content-Encoding"), None);
+        assert_eq!(response.header("Vary"), Some("Accept-Encoding"));
+
+        // nothing to choose from
+        fs::write(dir.join("other.js"), "plain").unwrap();
+        let response = get(&handler, "GET /other.js HTTP/1.1\r\nAccept-Encoding: gzip");
+        assert_eq!(response.header("Vary"), None);
+
+        let handler = handler.with_precompressed(false);
+        let response = get(&handler, "GET /app.js HTTP/1.1\r\nAccept-Encoding: gzip");
+        assert_eq!(response.header("Vary"), None);
+        assert_eq!(body(response), b"plain");
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn lists_directories_when_enabled() {
+        let dir = public_dir("listing");
+        fs::create_dir(dir.join("sub")).unwrap();
+        fs::write(dir.join("sub/a.txt"), "").unwrap();
+
+        let response = get(&handler(&dir), "GET /sub/ HTTP/1.1");
+        assert_eq!(response.status_code(), StatusCode::NotFound);
+
+        let handler = handler(&dir).with_directory_listing(true);
+        let response = get(&handler, "GET /sub?x=1 HTTP/1.1");
+        assert_eq!(response.status_code(), StatusCode::MovedPermanently);
+        assert_eq!(response.header("Location"), Some("/sub/?x=1"));
+
+        let response = get(&handler, "GET /sub/ HTTP/1.1\r\nAccept: application/json");
+        assert_eq!(response.header("Content-Type"), Some("application/json"));
+        assert!(String::from_utf8(body(response))
+            .unwrap()
+            .contains("\"name\":\"a.txt\""));
+
+        fs::write(dir.join("sub/index.html"), "index").unwrap();
+        assert_eq!(body(get(&handler, "GET /sub/ HTTP/1.1")), b"index");
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,330 @@
+use crate::http::date::fmt_http_date;
+use crate::http::response::escape_html;
+use crate::http::{json, percent_encoding};
+use crate::http::{Request, Response, StatusCode};
+
+use std::cmp::Ordering;
+use std::fmt::Write;
+use std::fs;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+pub struct Entry {
+    pub name: String,
+    pub is_dir: bool,
+    pub size: u64,
+    pub modified: Option<SystemTime>,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum SortKey {
+    Name,
+    Size,
+    Modified,
+}
+
+/// Reads the entries of `dir`, leaving out anything that resolves outside `root`.
+pub fn read_entries(dir: &Path, root: &str) -> std::io::Result<Vec<Entry>> {
+    let mut entries = Vec::new();
+
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let name = match entry.file_name().into_string() {
+            Ok(name) => name,
+            Err(_) => continue,
+        };
+        // follows symlinks, so a link out of the public folder is not listed
+        let path = match fs::canonicalize(entry.path()) {
+            Ok(path) if path.starts_with(root) => path,
+            _ => continue,
+        };
+        let metadata = match fs::metadata(&path) {
+            Ok(metadata) => metadata,
+            Err(_) => continue,
+        };
+
+        entries.push(Entry {
+            name,
+            is_dir: metadata.is_dir(),
+            size: if metadata.is_dir() { 0 } else { metadata.len() },
+            modified: metadata.modified().ok(),
+        });
+    }
+    Ok(entries)
+}
+
+// ?sort=name|size|modified&order=asc|desc; directories always come first
+fn sort(entries: &mut [Entry], request: &Request) -> (SortKey, bool) {
+    let query = request.query_string();
+    let key = match query.and_then(|query| query.get_first("sort")) {
+        Some("size") => SortKey::Size,
+        Some("modified") => SortKey::Modified,
+        _ => SortKey::Name,
+    };
+    let descending = query.and_then(|query| query.get_first("order")) == Some("desc");
+
+    entries.sort_by(|a, b| {
+        let ordering = match key {
+            SortKey::Name => Ordering::Equal,
+            SortKey::Size => a.size.cmp(&b.size),
+            SortKey::Modified => a.modified.cmp(&b.modified),
+        }
+        .then_with(|| a.name.cmp(&b.name));
+        let ordering = if descending {
+            ordering.reverse()
+        } else {
+            ordering
+        };
+        b.is_dir.cmp(&a.is_dir).then(ordering)
+    });
+    (key, descending)
+}
+
+/// Renders the listing as JSON if the client prefers it over HTML, as HTML otherwise.
+pub fn render(request: &Request, mut entries: Vec<Entry>) -> Response {
+    let (key, descending) = sort(&mut entries, request);
+
+    if prefers_json(request) {
+        return Response::new(StatusCode::Ok, Some(render_json(request, &entries)))
+            .with_header("Content-Type", "application/json");
+    }
+    Response::new(
+        StatusCode::Ok,
+        Some(render_html(request, &entries, key, descending)),
+    )
+    .with_header("Content-Type", "text/html; charset=utf-8")
+}
+
+// The higher quality of application/json and text/html wins; on a tie, the first listed.
+fn prefers_json(request: &Request) -> bool {
+    let accept = match request.headers().get("Accept") {
+        Some(accept) => accept,
+        None => return false,
+    };
+
+    let mut best: Option<(&str, f32)> = None;
+    for item in accept.split(',') {
+        let mut parts = item.split(';');
+        let mime_type = parts.next().unwrap_or("").trim();
+        if mime_type != "application/json" && mime_type != "text/html" {
+            continue;
+        }
+        let q = parts
+            .filter_map(|param| param.trim().strip_prefix("q="))
+            .find_map(|q| q.trim().parse::<f32>().ok())
+            .unwrap_or(1.0);
+        if q > 0.0 && best.is_none_or(|(_, best_q)| q > best_q) {
+            best = Some((mime_type, q));
+        }
+    }
+    best.is_some_and(|(mime_type, _)| mime_type == "application/json")
+}
+
+fn render_html(request: &Request, entries: &[Entry], key: SortKey, descending: bool) -> String {
+    let title = format!("Index of {}", escape_html(request.path()));
+    let mut html = format!(
+        "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<title>{0}</title>\n</head>\n<body>\n<h1>{0}</h1>\n<table>\n<tr>",
+        title
+    );
+
+    for (column, param, label) in [
+        (SortKey::Name, "name", "Name"),
+        (SortKey::Size, "size", "Size"),
+        (SortKey::Modified, "modified", "Last modified"),
+    ] {
+        // clicking the current column again flips the order
+        let order = if column == key && !descending {
+            "desc"
+        } else {
+            "asc"
+        };
+        let _ = write!(
+            html,
+            "<th><a href=\"?sort={0}&amp;order={1}\">{2}</a></th>",
+            param, order, label
+        );
+    }
+    html.push_str("</tr>\n");
+
+    if request.path() != "/" {
+        html.push_str("<tr><td><a href=\"../\">../</a></td><td></td><td></td></tr>\n");
+    }
+    for entry in entries {
+        let suffix = if entry.is_dir { "/" } else { "" };
+        let size = if entry.is_dir {
+            "-".to_string()
+        } else {
+            format_size(entry.size)
+        };
+        let modified = entry.modified.map(fmt_http_date).unwrap_or_default();
+        let _ = writeln!(
+            html,
+            "<tr><td><a href=\"{}{}\">{}{}</a></td><td>{}</td><td>{}</td></tr>",
+            percent_encoding::encode(&entry.name),
+            suffix,
+            escape_html(&entry.name),
+            suffix,
+            size,
+            modified
+        );
+    }
+
+    html.push_str("</table>\n</body>\n</html>\n");
+    html
+}
+
+fn render_json(request: &Request, entries: &[Entry]) -> String {
+    let mut json = format!(
+        "{{\"path\":\"{}\",\"entries\":[",
+        json::escape(request.path())
+    );
+
+    for (i, entry) in entries.iter().enumerate() {
+        if i > 0 {
+            json.push(',');
+        }
+        let modified = entry
+            .modified
+            .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+            .map(|modified| modified.as_secs().to_string())
+            .unwrap_or_else(|| "null".to_string());
+        let _ = write!(
+            json,
+            "{{\"name\":\"{}\",\"type\":\"{}\",\"size\":{},\"modified\":{}}}",
+            json::escape(&entry.name),
+            if entry.is_dir { "directory" } else { "file" },
+            entry.size,
+            modified
+        );
+    }
+
+    json.push_str("]}");
+    json
+}
+
+fn format_size(size: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+
+    let mut value = size as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+
+    if unit == 0 {
+        format!("{} B", size)
+    } else {
+        format!("{:.1} {}", value, UNITS[unit])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::convert::TryFrom;
+
+    fn entry(name: &str, is_dir: bool, size: u64) -> Entry {
+        Entry {
+            name: name.to_string(),
+            is_dir,
+            size,
+            modified: None,
+        }
+    }
+
+    fn entries() -> Vec<Entry> {
+        vec![
+            entry("b.txt", false, 10),
+            entry("docs", true, 0),
+            entry("a.txt", false, 2000),
+        ]
+    }
+
+    fn names(entries: &[Entry]) -> Vec<&str> {
+        entries.iter().map(|entry| entry.name.as_str()).collect()
+    }
+
+    fn request(head: &str) -> Request<'_> {
+        Request::try_from(head.as_bytes()).unwrap()
+    }
+
+    #[test]
+    fn sorts_directories_first() {
+        let mut listed = entries();
+        sort(&mut listed, &request("GET /?sort=size HTTP/1.1\r\n\r\n"));
+        assert_eq!(names(&listed), ["docs", "b.txt", "a.txt"]);
+
+        sort(&mut listed, &request("GET /?order=desc HTTP/1.1\r\n\r\n"));
+        assert_eq!(names(&listed), ["docs", "b.txt", "a.txt"]);
+
+        sort(&mut listed, &request("GET / HTTP/1.1\r\n\r\n"));
+        assert_eq!(names(&listed), ["docs", "a.txt", "b.txt"]);
+    }
+
+    #[test]
+    fn prefers_json_only_when_asked() {
+        let prefers = |accept: &str| {
+            prefers_json(&request(&format!(
+                "GET / HTTP/1.1\r\nAccept: {}\r\n\r\n",
+                accept
+            )))
+        };
+
+        assert!(prefers("application/json"));
+        assert!(prefers("text/html;q=0.5, application/json"));
+        assert!(!prefers("text/html, application/json"));
+        assert!(!prefers("application/json;q=0"));
+        assert!(!prefers("*/*"));
+        assert!(!prefers_json(&request("GET / HTTP/1.1\r\n\r\n")));
+    }
+
+    #[test]
+    fn renders_escaped_html_and_json() {
+        let listed = vec![entry("<x>&y.txt", false, 3)];
+
+        let html = render_html(
+            &request("GET /sub/ HTTP/1.1\r\n\r\n"),
+            &listed,
+            SortKey::Name,
+            false,
+        );
+        assert!(html.contains("<title>Index of /sub/</title>"));
+        assert!(html.contains("<a href=\"../\">"));
+        assert!(html.contains("<a href=\"%3Cx%3E%26y.txt\">&lt;x&gt;&amp;y.txt</a>"));
+        assert!(html.contains("href=\"?sort=name&amp;order=desc\""));
+
+        let json = render_json(&request("GET /sub/ HTTP/1.1\r\n\r\n"), &listed);
+        assert_eq!(
+            json,
+            "{\"path\":\"/sub/\",\"entries\":[{\"name\":\"<x>&y.txt\",\"type\":\"file\",\"size\":3,\"modified\":null}]}"
+        );
+    }
+
+    #[test]
+    fn formats_sizes() {
+        assert_eq!(format_size(0), "0 B");
+        assert_eq!(format_size(1023), "1023 B");
+        assert_eq!(format_size(1536), "1.5 KiB");
+        assert_eq!(format_size(5 * 1024 * 1024), "5.0 MiB");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn leaves_out_links_outside_the_root() {
+        let base = std::env::temp_dir().join(format!("listing-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&base);
+        fs::create_dir_all(base.join("root/sub")).unwrap();
+        fs::write(base.join("outside.txt"), "").unwrap();
+        fs::write(base.join("root/in.txt"), "abc").unwrap();
+        std::os::unix::fs::symlink(base.join("outside.txt"), base.join("root/link.txt")).unwrap();
+
+        let root = fs::canonicalize(base.join("root")).unwrap();
+        let mut listed = read_entries(&root, root.to_str().unwrap()).unwrap();
+        listed.sort_by(|a, b| a.name.cmp(&b.name));
+        assert_eq!(names(&listed), ["in.txt", "sub"]);
+        assert_eq!(listed[0].size, 3);
+        assert!(listed[1].is_dir);
+        fs::remove_dir_all(base).unwrap();
+    }
+}
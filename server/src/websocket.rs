@@ -0,0 +1,482 @@
+use super::http::{base64, sha1, Method, Request, Response, StatusCode, Upgraded};
+use super::server::Handler;
+
+use frame::{Frame, FrameError, Opcode};
+use std::io::{Error as IoError, ErrorKind, Read, Result as IoResult, Write};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+mod frame;
+
+pub const DEFAULT_MAX_MESSAGE_SIZE: usize = 1024 * 1024;
+
+/// Close codes (RFC 6455, section 7.4.1).
+pub const CLOSE_NORMAL: u16 = 1000;
+pub const CLOSE_GOING_AWAY: u16 = 1001;
+pub const CLOSE_PROTOCOL_ERROR: u16 = 1002;
+pub const CLOSE_UNSUPPORTED_DATA: u16 = 1003;
+pub const CLOSE_INVALID_DATA: u16 = 1007;
+pub const CLOSE_POLICY_VIOLATION: u16 = 1008;
+pub const CLOSE_MESSAGE_TOO_BIG: u16 = 1009;
+pub const CLOSE_INTERNAL_ERROR: u16 = 1011;
+
+// Appended to the client's key before hashing it into Sec-WebSocket-Accept.
+const ACCEPT_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+// Outgoing messages are split into frames of at most this size.
+const MAX_FRAME_SIZE: usize = 64 * 1024;
+// How long `close` waits for the client to answer with its own close frame.
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(5);
+const READ_CHUNK_SIZE: usize = 8192;
+
+/// Accepts WebSocket handshakes (RFC 6455) and runs `on_connect` with each
+/// connection, on a thread of its own, until it returns.
+///
+/// `read_message` blocks until the client sends something. To push data while
+/// waiting, set a read timeout and send whenever a read times out; a frame
+/// interrupted by the timeout is picked up again by the next read.
+pub struct WebSocketHandler {
+    on_connect: Arc<dyn Fn(WebSocket) + Send + Sync>,
+    protocols: Vec<String>,
+    max_message_size: usize,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Message {
+    Text(String),
+    Binary(Vec<u8>),
+}
+
+/// An open WebSocket connection. Pings are answered and fragmented messages
+/// reassembled while reading; dropping it closes the connection with
+/// `CLOSE_GOING_AWAY` unless `close` was called.
+pub struct WebSocket {
+    stream: Upgraded,
+    buf: Vec<u8>,
+    protocol: Option<String>,
+    max_message_size: usize,
+    // the opcode and payload so far of a fragmented message
+    fragments: Option<(Opcode, Vec<u8>)>,
+    close_sent: bool,
+    closed: bool,
+}
+
+impl WebSocketHandler {
+    pub fn new(on_connect: impl Fn(WebSocket) + Send + Sync + 'static) -> Self {
+        Self {
+            on_connect: Arc::new(on_connect),
+            protocols: Vec::new(),
+            max_message_size: DEFAULT_MAX_MESSAGE_SIZE,
+        }
+    }
+
+    /// A subprotocol to accept when the client offers it in
+    /// `Sec-WebSocket-Protocol`. The first one added that the client offers wins.
+    pub fn with_protocol(mut self, protocol: &str) -> Self {
+        self.protocols.push(protocol.to_string());
+        self
+    }
+
+    /// Messages larger than this, fragmented or not, close the connection
+    /// with `CLOSE_MESSAGE_TOO_BIG`.
+    pub fn with_max_message_size(mut self, max_message_size: usize) -> Self {
+        self.max_message_size = max_message_size;
+        self
+    }
+
+    fn select_protocol(&self, request: &Request) -> Option<String> {
+        let offered: Vec<&str> = request
+            .headers()
+            .get_all("Sec-WebSocket-Protocol")
+            .flat_map(|value| value.split(','))
+            .map(str::trim)
+            .collect();
+
+        self.protocols
+            .iter()
+            .find(|protocol| offered.contains(&protocol.as_str()))
+            .cloned()
+    }
+}
+
+impl Handler for WebSocketHandler {
+    fn handle_request(&self, request: &Request) -> Response {
+        let key = match handshake_key(request) {
+            Ok(key) => key,
+            Err(response) => return response,
+        };
+        let protocol = self.select_protocol(request);
+
+        let on_connect = Arc::clone(&self.on_connect);
+        let max_message_size = self.max_message_size;
+        let selected = protocol.clone();
+        let mut response = Response::upgrade("websocket", move |stream| {
+            on_connect(WebSocket::new(stream, selected, max_message_size))
+        })
+        .with_header("Sec-WebSocket-Accept", accept_key(key));
+        if let Some(protocol) = protocol {
+            response.set_header("Sec-WebSocket-Protocol", protocol);
+        }
+        response
+    }
+}
+
+// Checks the client's handshake and returns its Sec-WebSocket-Key.
+fn handshake_key<'a>(request: &Request<'a>) -> Result<&'a str, Response> {
+    let headers = request.headers();
+
+    if !matches!(request.method(), Method::GET) {
+        return Err(Response::error(StatusCode::MethodNotAllowed).with_header("Allow", "GET"));
+    }
+    if !headers.contains_token("Upgrade", "websocket")
+        || !headers.contains_token("Connection", "upgrade")
+    {
+        return Err(Response::error(StatusCode::UpgradeRequired)
+            .with_header("Upgrade", "websocket")
+            .with_header("Connection", "Upgrade"));
+    }
+    if headers.get("Sec-WebSocket-Version") != Some("13") {
+        return Err(
+            Response::error(StatusCode::UpgradeRequired).with_header("Sec-WebSocket-Version", "13")
+        );
+    }
+
+    match headers.get("Sec-WebSocket-Key") {
+        Some(key) if base64::decode(key.trim()).is_some_and(|key| key.len() == 16) => {
+            Ok(key.trim())
+        }
+        _ => Err(Response::error_with_message(
+            StatusCode::BadRequest,
+            "Missing or invalid Sec-WebSocket-Key",
+        )),
+    }
+}
+
+fn accept_key(key: &str) -> String {
+    base64::encode(&sha1::digest(format!("{}{}", key, ACCEPT_GUID).as_bytes()))
+}
+
+impl WebSocket {
+    fn new(stream: Upgraded, protocol: Option<String>, max_message_size: usize) -> Self {
+        Self {
+            stream,
+            buf: Vec::new(),
+            protocol,
+            max_message_size,
+            fragments: None,
+            close_sent: false,
+            closed: false,
+        }
+    }
+
+    /// The subprotocol agreed on in the handshake.
+    pub fn protocol(&self) -> Option<&str> {
+        self.protocol.as_deref()
+    }
+
+    pub fn peer_addr(&self) -> IoResult<SocketAddr> {
+        self.stream.peer_addr()
+    }
+
+    /// Makes `read_message` give up with `WouldBlock` or `TimedOut` after
+    /// `timeout`; `None` waits forever.
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> IoResult<()> {
+        self.stream.set_read_timeout(timeout)
+    }
+
+    /// Waits for the next text or binary message. Returns `None` once the
+    /// connection is closed, after answering the client's close frame.
+    /// Protocol violations close the connection and come back as errors.
+    pub fn read_message(&mut self) -> IoResult<Option<Message>> {
+        while !self.closed {
+            let frame = match self.read_frame()? {
+                Some(frame) => frame,
+                None => {
+                    self.closed = true;
+                    break;
+                }
+            };
+
+            match frame.opcode {
+                Opcode::Ping if !self.close_sent => {
+                    self.write_frame(Frame::new(true, Opcode::Pong, frame.payload))?
+                }
+                Opcode::Ping | Opcode::Pong => {}
+                Opcode::Close => self.receive_close(&frame.payload)?,
+                Opcode::Continuation => {
+                    let (opcode, mut payload) = match self.fragments.take() {
+                        Some(fragments) => fragments,
+                        None => {
+                            return Err(self.fail(CLOSE_PROTOCOL_ERROR, "unexpected continuation"))
+                        }
+                    };
+                    payload.extend_from_slice(&frame.payload);
+                    match frame.fin {
+                        true => return self.message(opcode, payload).map(Some),
+                        false => self.fragments = Some((opcode, payload)),
+                    }
+                }
+                Opcode::Text | Opcode::Binary => {
+                    if self.fragments.is_some() {
+                        return Err(
+                            self.fail(CLOSE_PROTOCOL_ERROR, "unfinished fragmented message")
+                        );
+                    }
+                    match frame.fin {
+                        true => return self.message(frame.opcode, frame.payload).map(Some),
+                        false => self.fragments = Some((frame.opcode, frame.payload)),
+                    }
+                }
+            }
+        }
+        Ok(None)
+    }
+
+    /// Sends a message, split into several frames if it is large.
+    pub fn send(&mut self, message: &Message) -> IoResult<()> {
+        let (opcode, payload) = match message {
+            Message::Text(text) => (Opcode::Text, text.as_bytes()),
+            Message::Binary(bytes) => (Opcode::Binary, &bytes[..]),
+        };
+        if self.close_sent {
+            return Err(IoError::new(
+                ErrorKind::NotConnected,
+                "connection is closing",
+            ));
+        }
+
+        let mut chunks = payload.chunks(MAX_FRAME_SIZE).peekable();
+        if chunks.peek().is_none() {
+            return self.write_frame(Frame::new(true, opcode, Vec::new()));
+        }
+        let mut opcode = opcode;
+        while let Some(chunk) = chunks.next() {
+            let fin = chunks.peek().is_none();
+            self.write_frame(Frame::new(fin, opcode, chunk.to_vec()))?;
+            opcode = Opcode::Continuation;
+        }
+        Ok(())
+    }
+
+    pub fn send_text(&mut self, text: &str) -> IoResult<()> {
+        self.send(&Message::Text(text.to_string()))
+    }
+
+    pub fn send_binary(&mut self, bytes: &[u8]) -> IoResult<()> {
+        self.send(&Message::Binary(bytes.to_vec()))
+    }
+
+    /// The client answers with a pong, which `read_message` skips.
+    pub fn ping(&mut self, payload: &[u8]) -> IoResult<()> {
+        if payload.len() > 125 {
+            return Err(IoError::new(
+                ErrorKind::InvalidInput,
+                "ping payload too long",
+            ));
+        }
+        self.write_frame(Frame::new(true, Opcode::Ping, payload.to_vec()))
+    }
+
+    /// Starts the close handshake and waits a few seconds for the client to
+    /// finish it. Messages arriving in the meantime are dropped.
+    pub fn close(&mut self, code: u16, reason: &str) -> IoResult<()> {
+        if !self.close_sent {
+            self.send_close(code, reason)?;
+        }
+        self.stream.set_read_timeout(Some(CLOSE_TIMEOUT))?;
+        while !self.closed {
+            match self.read_frame() {
+                Ok(Some(frame)) if frame.opcode == Opcode::Close => self.closed = true,
+                Ok(Some(_)) => {}
+                Ok(None) | Err(_) => self.closed = true,
+            }
+        }
+        Ok(())
+    }
+
+    fn read_frame(&mut self) -> IoResult<Option<Frame>> {
+        loop {
+            let fragments_len = self
+                .fragments
+                .as_ref()
+                .map_or(0, |(_, payload)| payload.len());
+            let budget = self.max_message_size.saturating_sub(fragments_len);
+
+            match Frame::parse(&self.buf, budget) {
+                Ok(Some((frame, used))) => {
+                    self.buf.drain(..used);
+                    return Ok(Some(frame));
+                }
+                Ok(None) => {}
+                Err(FrameError::Protocol(message)) => {
+                    return Err(self.fail(CLOSE_PROTOCOL_ERROR, message))
+                }
+                Err(FrameError::TooLarge) => {
+                    return Err(self.fail(CLOSE_MESSAGE_TOO_BIG, "message too big"))
+                }
+            }
+
+            let mut chunk = [0; READ_CHUNK_SIZE];
+            let len = self.stream.read(&mut chunk)?;
+            if len == 0 {
+                if self.buf.is_empty() {
+                    return Ok(None);
+                }
+                return Err(IoError::new(
+                    ErrorKind::UnexpectedEof,
+                    "connection closed in the middle of a frame",
+                ));
+            }
+            self.buf.extend_from_slice(&chunk[..len]);
+        }
+    }
+
+    fn message(&mut self, opcode: Opcode, payload: Vec<u8>) -> IoResult<Message> {
+        match opcode {
+            Opcode::Binary => Ok(Message::Binary(payload)),
+            _ => match String::from_utf8(payload) {
+                Ok(text) => Ok(Message::Text(text)),
+                Err(_) => Err(self.fail(CLOSE_INVALID_DATA, "text message is not valid UTF-8")),
+            },
+        }
+    }
+
+    // The payload is empty, or a code followed by a UTF-8 reason.
+    fn receive_close(&mut self, payload: &[u8]) -> IoResult<()> {
+        let code = match payload {
+            [] => None,
+            [high, low, reason @ ..] => {
+                let code = u16::from_be_bytes([*high, *low]);
+                if !is_valid_close_code(code) || std::str::from_utf8(reason).is_err() {
+                    return Err(self.fail(CLOSE_PROTOCOL_ERROR, "invalid close frame"));
+                }
+                Some(code)
+            }
+            [_] => return Err(self.fail(CLOSE_PROTOCOL_ERROR, "invalid close frame")),
+        };
+
+        self.closed = true;
+        if !self.close_sent {
+            self.send_close(code.unwrap_or(CLOSE_NORMAL), "")?;
+        }
+        Ok(())
+    }
+
+    fn send_close(&mut self, code: u16, reason: &str) -> IoResult<()> {
+        // the reason has to fit in a control frame along with the code
+        let mut end = reason.len().min(123);
+        while !reason.is_char_boundary(end) {
+            end -= 1;
+        }
+        let mut payload = code.to_be_bytes().to_vec();
+        payload.extend_from_slice(&reason.as_bytes()[..end]);
+
+        self.close_sent = true;
+        self.write_frame(Frame::new(true, Opcode::Close, payload))
+    }
+
+    // Closes the connection after a protocol violation and returns the error to report.
+    fn fail(&mut self, code: u16, message: &str) -> IoError {
+        if !self.close_sent {
+            let _ = self.send_close(code, message);
+        }
+        self.closed = true;
+        IoError::new(ErrorKind::InvalidData, message.to_string())
+    }
+
+    fn write_frame(&mut self, frame: Frame) -> IoResult<()> {
+        frame.write_to(&mut self.stream)?;
+        self.stream.flush()
+    }
+}
+
+impl Drop for WebSocket {
+    fn drop(&mut self) {
+        if !self.close_sent && !self.closed {
+            let _ = self.send_close(CLOSE_GOING_AWAY, "");
+        }
+    }
+}
+
+// Codes a peer may send; 1005, 1006 and 1015 are reserved for local use.
+fn is_valid_close_code(code: u16) -> bool {
+    matches!(code, 1000..=1003 | 1007..=1011 | 3000..=4999)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::convert::TryFrom;
+
+    fn handshake(extra: &str) -> String {
+        format!(
+            "GET /chat HTTP/1.1\r\nHost: x\r\nUpgrade: websocket\r\nConnection: keep-alive, Upgrade\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\nSec-WebSocket-Version: 13\r\n{}\r\n",
+            extra
+        )
+    }
+
+    fn respond(handler: &WebSocketHandler, head: &str) -> Response {
+        handler.handle_request(&Request::try_from(head.as_bytes()).unwrap())
+    }
+
+    #[test]
+    fn computes_the_rfc_accept_key() {
+        assert_eq!(
+            accept_key("dGhlIHNhbXBsZSBub25jZQ=="),
+            "s3pPLMBiTxaQ9kYGzzhZRbK+xOo="
+        );
+    }
+
+    #[test]
+    fn accepts_a_valid_handshake() {
+        let handler = WebSocketHandler::new(|_| {})
+            .with_protocol("chat")
+            .with_protocol("superchat");
+
+        let response = respond(
+            &handler,
+            &handshake("Sec-WebSocket-Protocol: superchat, chat\r\n"),
+        );
+        assert_eq!(response.status_code(), StatusCode::SwitchingProtocols);
+        assert_eq!(
+            response.header("Sec-WebSocket-Accept"),
+            Some("s3pPLMBiTxaQ9kYGzzhZRbK+xOo=")
+        );
+        assert_eq!(response.header("Sec-WebSocket-Protocol"), Some("chat"));
+
+        let response = respond(&handler, &handshake("Sec-WebSocket-Protocol: other\r\n"));
+        assert_eq!(response.header("Sec-WebSocket-Protocol"), None);
+    }
+
+    #[test]
+    fn rejects_invalid_handshakes() {
+        let handler = WebSocketHandler::new(|_| {});
+        let status = |head: String| respond(&handler, &head).status_code();
+
+        assert_eq!(
+            status(handshake("").replacen("GET", "POST", 1)),
+            StatusCode::MethodNotAllowed
+        );
+        assert_eq!(
+            status(handshake("").replace("Upgrade: websocket\r\n", "")),
+            StatusCode::UpgradeRequired
+        );
+        assert_eq!(
+            status(handshake("").replace("Version: 13", "Version: 8")),
+            StatusCode::UpgradeRequired
+        );
+        // the key must decode to 16 bytes
+        assert_eq!(
+            status(handshake("").replace("dGhlIHNhbXBsZSBub25jZQ==", "c2hvcnQ=")),
+            StatusCode::BadRequest
+        );
+    }
+
+    #[test]
+    fn only_accepts_registered_close_codes() {
+        assert!(is_valid_close_code(CLOSE_NORMAL));
+        assert!(is_valid_close_code(3000));
+        assert!(!is_valid_close_code(1005));
+        assert!(!is_valid_close_code(999));
+    }
+}
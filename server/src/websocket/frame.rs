@@ -0,0 +1,286 @@
+use std::io::{Result as IoResult, Write};
+
+/// Control frames carry at most this many bytes and are never fragmented.
+const MAX_CONTROL_PAYLOAD: usize = 125;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Opcode {
+    Continuation,
+    Text,
+    Binary,
+    Close,
+    Ping,
+    Pong,
+}
+
+impl Opcode {
+    fn from_u8(opcode: u8) -> Option<Self> {
+        match opcode {
+            0x0 => Some(Self::Continuation),
+            0x1 => Some(Self::Text),
+            0x2 => Some(Self::Binary),
+            0x8 => Some(Self::Close),
+            0x9 => Some(Self::Ping),
+            0xA => Some(Self::Pong),
+            _ => None,
+        }
+    }
+
+    fn as_u8(self) -> u8 {
+        match self {
+            Self::Continuation => 0x0,
+            Self::Text => 0x1,
+            Self::Binary => 0x2,
+            Self::Close => 0x8,
+            Self::Ping => 0x9,
+            Self::Pong => 0xA,
+        }
+    }
+
+    pub fn is_control(self) -> bool {
+        matches!(self, Self::Close | Self::Ping | Self::Pong)
+    }
+}
+
+#[derive(Debug)]
+pub struct Frame {
+    pub fin: bool,
+    pub opcode: Opcode,
+    pub payload: Vec<u8>,
+}
+
+/// Why a frame was rejected; the connection is closed with the matching code.
+#[derive(Debug, PartialEq, Eq)]
+pub enum FrameError {
+    Protocol(&'static str),
+    TooLarge,
+}
+
+impl Frame {
+    pub fn new(fin: bool, opcode: Opcode, payload: Vec<u8>) -> Self {
+        Self {
+            fin,
+            opcode,
+            payload,
+        }
+    }
+
+    /// Parses a client frame at the start of `buf` and unmasks its payload.
+    /// Returns the frame and the number of bytes it took up, or `None` if
+    /// `buf` does not hold all of it yet. Data frames longer than
+    /// `max_payload` are rejected as soon as their length is known.
+    pub fn parse(buf: &[u8], max_payload: usize) -> Result<Option<(Self, usize)>, FrameError> {
+        if buf.len() < 2 {
+            return Ok(None);
+        }
+        let fin = buf[0] & 0x80 != 0;
+        // no extension is ever negotiated, so the reserved bits must be clear
+        if buf[0] & 0x70 != 0 {
+            return Err(FrameError::Protocol("reserved bits set"));
+        }
+        let opcode =
+            Opcode::from_u8(buf[0] & 0x0F).ok_or(FrameError::Protocol("unknown opcode"))?;
+        if buf[1] & 0x80 == 0 {
+            return Err(FrameError::Protocol("client frames must be masked"));
+        }
+
+        let (len, mut offset) = match buf[1] & 0x7F {
+            126 => match buf.get(2..4) {
+                Some(len) => (u16::from_be_bytes([len[0], len[1]]) as u64, 4),
+                None => return Ok(None),
+            },
+            127 => match buf.get(2..10) {
+                Some(len) => {
+                    let len = u64::from_be_bytes(len.try_into().unwrap());
+                    if len >> 63 != 0 {
+                        return Err(FrameError::Protocol("invalid payload length"));
+                    }
+                    (len, 10)
+                }
+                None => return Ok(None),
+            },
+            len => (len as u64, 2),
+        };
+
+        if opcode.is_control() && (!fin || len > MAX_CONTROL_PAYLOAD as u64) {
+            return Err(FrameError::Protocol("invalid control frame"));
+        }
+        if !opcode.is_control() && len > max_payload as u64 {
+            return Err(FrameError::TooLarge);
+        }
+        let len = len as usize;
+
+        let mask = match buf.get(offset..offset + 4) {
+            Some(mask) => [mask[0], mask[1], mask[2], mask[3]],
+            None => return Ok(None),
+        };
+        offset += 4;
+        let payload = match buf.get(offset..offset + len) {
+            Some(payload) => payload,
+            None => return Ok(None),
+        };
+
+        let payload = payload
+            .iter()
+            .enumerate()
+            .map(|(i, byte)| byte ^ mask[i % 4])
+            .collect();
+        Ok(Some((Self::new(fin, opcode, payload), offset + len)))
+    }
+
+    /// Writes the frame the way a server sends it: unmasked.
+    pub fn write_to(&self, stream: &mut impl Write) -> IoResult<()> {
+        let len = self.payload.len();
+        let mut bytes = Vec::with_capacity(len + 10);
+        bytes.push(if self.fin { 0x80 } else { 0 } | self.opcode.as_u8());
+
+        if len < 126 {
+            bytes.push(len as u8);
+        } else if len <= u16::MAX as usize {
+            bytes.push(126);
+            bytes.extend_from_slice(&(len as u16).to_be_bytes());
+        } else {
+            bytes.push(127);
+            bytes.extend_from_slice(&(len as u64).to_be_bytes());
+        }
+        bytes.extend_from_slice(&self.payload);
+
+        // one write, so that a frame is not split across TLS records
+        stream.write_all(&bytes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MASK: [u8; 4] = [0x37, 0xfa, 0x21, 0x3d];
+
+    // A frame the way a client sends it.
+    fn masked(first: u8, payload: &[u8]) -> Vec<u8> {
+        let mut frame = vec![first];
+        match payload.len() {
+            len if len < 126 => frame.push(0x80 | len as u8),
+            len if len <= u16::MAX as usize => {
+                frame.push(0x80 | 126);
+                frame.extend_from_slice(&(len as u16).to_be_bytes());
+            }
+            len => {
+                frame.push(0x80 | 127);
+                frame.extend_from_slice(&(len as u64).to_be_bytes());
+            }
+        }
+        frame.extend_from_slice(&MASK);
+        frame.extend(
+            payload
+                .iter()
+                .enumerate()
+                .map(|(i, byte)| byte ^ MASK[i % 4]),
+        );
+        frame
+    }
+
+    fn parse(buf: &[u8]) -> Result<Option<(Frame, usize)>, FrameError> {
+        Frame::parse(buf, 1 << 20)
+    }
+
+    #[test]
+    fn unmasks_the_rfc_example() {
+        let buf = [
+            0x81, 0x85, 0x37, 0xfa, 0x21, 0x3d, 0x7f, 0x9f, 0x4d, 0x51, 0x58,
+        ];
+        let (frame, len) = parse(&buf).unwrap().unwrap();
+        assert!(frame.fin);
+        assert_eq!(frame.opcode, Opcode::Text);
+        assert_eq!(frame.payload, b"Hello");
+        assert_eq!(len, buf.len());
+
+        for end in 0..buf.len() {
+            assert!(parse(&buf[..end]).unwrap().is_none());
+        }
+    }
+
+    #[test]
+    fn reads_extended_lengths() {
+        for len in [125, 126, 300, u16::MAX as usize, 70_000] {
+            let payload: Vec<u8> = (0..len).map(|i| i as u8).collect();
+            let mut buf = masked(0x02, &payload);
+            buf.extend_from_slice(b"next");
+
+            let (frame, used) = parse(&buf).unwrap().unwrap();
+            assert!(!frame.fin);
+            assert_eq!(frame.opcode, Opcode::Binary);
+            assert_eq!(frame.payload, payload);
+            assert_eq!(used, buf.len() - 4);
+        }
+    }
+
+    #[test]
+    fn rejects_protocol_violations() {
+        let error = |buf: &[u8]| parse(buf).unwrap_err();
+
+        for rsv in [0x40, 0x20, 0x10] {
+            assert_eq!(
+                error(&masked(0x81 | rsv, b"x")),
+                FrameError::Protocol("reserved bits set")
+            );
+        }
+        assert_eq!(
+            error(&masked(0x83, b"x")),
+            FrameError::Protocol("unknown opcode")
+        );
+        assert_eq!(
+            error(&[0x81, 0x01, b'x']),
+            FrameError::Protocol("client frames must be masked")
+        );
+
+        let mut too_long = vec![0x82, 0x80 | 127];
+        too_long.extend_from_slice(&(1u64 << 63).to_be_bytes());
+        assert_eq!(
+            error(&too_long),
+            FrameError::Protocol("invalid payload length")
+        );
+    }
+
+    #[test]
+    fn limits_control_frames() {
+        let invalid = FrameError::Protocol("invalid control frame");
+
+        assert!(parse(&masked(0x89, &[0; 125])).unwrap().is_some());
+        assert_eq!(parse(&masked(0x89, &[0; 126])).unwrap_err(), invalid);
+        // control frames may not be fragmented
+        assert_eq!(parse(&masked(0x09, b"ping")).unwrap_err(), invalid);
+        assert_eq!(parse(&masked(0x08, b"")).unwrap_err(), invalid);
+    }
+
+    #[test]
+    fn rejects_large_data_frames_from_the_header() {
+        let header = [0x82, 0x80 | 126, 0x01, 0x00];
+        assert_eq!(
+            Frame::parse(&header, 255).unwrap_err(),
+            FrameError::TooLarge
+        );
+        assert!(Frame::parse(&header, 256).unwrap().is_none());
+    }
+
+    #[test]
+    fn writes_unmasked_frames() {
+        let mut out = Vec::new();
+        Frame::new(true, Opcode::Text, b"Hello".to_vec())
+            .write_to(&mut out)
+            .unwrap();
+        assert_eq!(out, [0x81, 0x05, b'H', b'e', b'l', b'l', b'o']);
+
+        for (len, header) in [
+            (126, vec![0x02, 126, 0x00, 0x7e]),
+            (65536, vec![0x02, 127, 0, 0, 0, 0, 0, 1, 0, 0]),
+        ] {
+            let mut out = Vec::new();
+            Frame::new(false, Opcode::Binary, vec![0; len])
+                .write_to(&mut out)
+                .unwrap();
+            assert_eq!(&out[..header.len()], &header[..]);
+            assert_eq!(out.len(), header.len() + len);
+        }
+    }
+}